@@ -0,0 +1,311 @@
+//! Alerts POSTed to `--alert-webhook`, for unattended servers: when errors pile up, when
+//! backup_dir can't be reached anymore, and when a verification finds copies that don't match.
+//! The payload is a JSON object with the kind of alert and the details, along with a `text` that
+//! Slack and the many services that accept Slack's format show as they are:
+//!
+//! ```json
+//! {"text":"evil_mount: backup_dir /mnt/backup can't be reached: ...","alert":"unreachable","host":"server","backup_dir":"/mnt/backup"}
+//! ```
+//!
+//! The POST is made with curl, so HTTPS works like everywhere else curl does
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::output::{self, info, Event};
+
+/// How far back errors are counted towards --alert-errors
+const ERROR_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// How long apart two alerts about errors are at least
+const COOLDOWN: Duration = Duration::from_secs(60 * 60);
+/// How often the alerter checks whether backup_dir can be reached
+const CHECK_EVERY: Duration = Duration::from_secs(30);
+/// How long a POST may take
+const TIMEOUT_SECS: &str = "30";
+
+struct Alerter {
+    url: String,
+    backup_dir: PathBuf,
+    errors: usize,
+}
+
+static ALERTER: OnceLock<Alerter> = OnceLock::new();
+/// When the recent errors happened
+static ERRORS: Mutex<VecDeque<Instant>> = Mutex::new(VecDeque::new());
+static ERRORS_ALERTED_AT: Mutex<Option<Instant>> = Mutex::new(None);
+/// Set while backup_dir can't be reached
+static UNREACHABLE: AtomicBool = AtomicBool::new(false);
+/// How many alerts are being posted
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// Set while reporting that posting an alert failed, so that it isn't alerted about in turn
+    static REPORTING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// POSTs alerts to `url` once `errors` errors happened within ERROR_WINDOW, when `backup_dir`
+/// can't be reached and when a verification finds mismatched copies
+pub fn set(url: String, backup_dir: PathBuf, errors: usize) -> Result<()> {
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err(anyhow!(
+            "--alert-webhook {url} isn't an http:// or https:// URL"
+        ));
+    }
+    let version = Command::new("curl")
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .context("--alert-webhook needs curl to be installed")?;
+    if !version.success() {
+        return Err(anyhow!(
+            "--alert-webhook needs a working curl, `curl --version` failed"
+        ));
+    }
+
+    ALERTER
+        .set(Alerter {
+            url,
+            backup_dir,
+            errors,
+        })
+        .map_err(|_| anyhow!("the alert webhook can only be set once"))?;
+    std::thread::spawn(|| loop {
+        check_reachable();
+        std::thread::sleep(CHECK_EVERY);
+    });
+
+    Ok(())
+}
+
+/// Counts errors towards --alert-errors, and alerts about verifications that found mismatched
+/// copies. Called for every emitted event
+pub fn observe(event: &Event) {
+    let Some(alerter) = ALERTER.get() else {
+        return;
+    };
+
+    match event {
+        Event::Error { path, message } if !REPORTING.get() => {
+            let now = Instant::now();
+            let count = {
+                let mut errors = ERRORS.lock().unwrap();
+                errors.push_back(now);
+                while errors
+                    .front()
+                    .is_some_and(|at| now.duration_since(*at) > ERROR_WINDOW)
+                {
+                    errors.pop_front();
+                }
+                errors.len()
+            };
+            if count < alerter.errors || !cooled_down() {
+                return;
+            }
+
+            let latest = match path {
+                Some(path) => format!("{}: {message}", path.display()),
+                None => message.clone(),
+            };
+            post(
+                "errors",
+                format!(
+                    "{count} errors in the last {} minutes, the latest: {latest}",
+                    ERROR_WINDOW.as_secs() / 60
+                ),
+                serde_json::json!({ "errors": count, "latest": latest }),
+            );
+        }
+        Event::VerifyComplete {
+            mismatched: mismatched @ 1..,
+            missing,
+            extra,
+            ..
+        } => post(
+            "corruption",
+            format!(
+                "verification found {mismatched} copies that don't match work_dir in {}",
+                alerter.backup_dir.display()
+            ),
+            serde_json::json!({ "mismatched": mismatched, "missing": missing, "extra": extra }),
+        ),
+        _ => (),
+    }
+}
+
+/// Whether an alert about errors can be posted again, marking it posted now if so
+fn cooled_down() -> bool {
+    let mut alerted_at = ERRORS_ALERTED_AT.lock().unwrap();
+    if alerted_at.is_some_and(|at| at.elapsed() < COOLDOWN) {
+        return false;
+    }
+    *alerted_at = Some(Instant::now());
+
+    true
+}
+
+fn check_reachable() {
+    let Some(alerter) = ALERTER.get() else {
+        return;
+    };
+
+    let problem = why_unreachable(&alerter.backup_dir);
+    match (problem, UNREACHABLE.load(Ordering::Relaxed)) {
+        (Some(problem), false) => {
+            UNREACHABLE.store(true, Ordering::Relaxed);
+            post(
+                "unreachable",
+                format!(
+                    "backup_dir {} can't be reached: {problem}",
+                    alerter.backup_dir.display()
+                ),
+                serde_json::json!({ "problem": problem }),
+            );
+        }
+        (None, true) => {
+            UNREACHABLE.store(false, Ordering::Relaxed);
+            post(
+                "reachable",
+                format!(
+                    "backup_dir {} can be reached again",
+                    alerter.backup_dir.display()
+                ),
+                serde_json::json!({}),
+            );
+        }
+        _ => (),
+    }
+}
+
+fn why_unreachable(backup_dir: &Path) -> Option<String> {
+    match std::fs::metadata(backup_dir) {
+        Ok(metadata) if !metadata.is_dir() => return Some("it's not a directory".to_string()),
+        Ok(_) => (),
+        Err(err) => return Some(err.to_string()),
+    }
+    std::fs::read_dir(backup_dir)
+        .err()
+        .map(|err| err.to_string())
+}
+
+/// POSTs an alert of the kind `alert` in the background, since alerts are raised from wherever
+/// something went wrong. `details` is merged into the payload
+fn post(alert: &'static str, text: String, details: serde_json::Value) {
+    let Some(alerter) = ALERTER.get() else {
+        return;
+    };
+
+    let mut payload = serde_json::json!({
+        "text": format!("evil_mount: {text}"),
+        "alert": alert,
+        "host": hostname(),
+        "backup_dir": alerter.backup_dir.to_string_lossy(),
+    });
+    if let (Some(payload), serde_json::Value::Object(details)) = (payload.as_object_mut(), details)
+    {
+        payload.extend(details);
+    }
+
+    PENDING.fetch_add(1, Ordering::Relaxed);
+    std::thread::spawn(move || {
+        if let Err(err) = send(&alerter.url, &payload) {
+            REPORTING.set(true);
+            output::emit(&Event::Error {
+                path: None,
+                message: format!("Error posting the {alert} alert to --alert-webhook: {err:#}"),
+            });
+            REPORTING.set(false);
+        } else {
+            info!("Posted the {alert} alert to --alert-webhook");
+        }
+        PENDING.fetch_sub(1, Ordering::Relaxed);
+    });
+}
+
+/// POSTs `payload` to `url` with curl. Both go to curl as a config on its stdin, since a webhook URL
+/// usually has its secret in it and any user can read the command line of a process
+pub fn send(url: &str, payload: &serde_json::Value) -> Result<()> {
+    let mut child = Command::new("curl")
+        .args([
+            "--silent",
+            "--show-error",
+            "--fail",
+            "--max-time",
+            TIMEOUT_SECS,
+        ])
+        .args(["--header", "Content-Type: application/json"])
+        .args(["--config", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Error running curl")?;
+    child.stdin.take().expect("stdin is piped").write_all(
+        format!(
+            "url = {}\ndata-binary = {}\n",
+            quote(url),
+            quote(&serde_json::to_string(payload)?)
+        )
+        .as_bytes(),
+    )?;
+
+    let output = child.wait_with_output()?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(anyhow!(
+            "curl {}: {}",
+            crate::profiles::describe(output.status),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
+
+/// `value` as a string in a curl config, which takes backslash escapes between the quotes
+fn quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+/// The name of this machine, so that alerts from several servers can be told apart
+pub fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| {
+            std::fs::read_to_string("/etc/hostname")
+                .ok()
+                .map(|name| name.trim().to_string())
+        })
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Waits for the alerts being posted, before exiting
+pub async fn wait() {
+    while PENDING.load(Ordering::Relaxed) > 0 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
@@ -0,0 +1,62 @@
+//! Capping how fast copies write to backup_dir with `--bandwidth-limit`, so that a sync to a NAS
+//! or over a slow link leaves room for everything else using it. The limit is shared by every copy
+//! running at once and can change on a reload. Clones don't count, since they don't move any data
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// The most bytes a second copies may write, or 0 for no limit
+static LIMIT: AtomicU64 = AtomicU64::new(0);
+/// When everything copies were let through so far is written at the limit
+static CAUGHT_UP_AT: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Sets the most bytes a second copies may write from now on. None or 0 lifts the limit
+pub fn set_limit(limit: Option<u64>) {
+    LIMIT.store(limit.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Counts `bytes` that were just written, and blocks until writing more wouldn't go over the limit
+pub fn throttle(bytes: u64) {
+    let limit = LIMIT.load(Ordering::Relaxed);
+    if limit == 0 {
+        return;
+    }
+
+    let now = Instant::now();
+    let wait = {
+        let mut caught_up_at = CAUGHT_UP_AT.lock().unwrap();
+        let until = caught_up_at.filter(|at| *at > now).unwrap_or(now)
+            + Duration::from_secs_f64(bytes as f64 / limit as f64);
+        *caught_up_at = Some(until);
+        until - now
+    };
+    std::thread::sleep(wait);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copies_share_the_limit() {
+        set_limit(Some(1000));
+        let start = Instant::now();
+        let copies: Vec<_> = (0..2)
+            .map(|_| std::thread::spawn(|| throttle(100)))
+            .collect();
+        for copy in copies {
+            copy.join().unwrap();
+        }
+        set_limit(None);
+
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        let start = Instant::now();
+        throttle(1_000_000);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,198 @@
+//! A quick IO benchmark of both directories on the first run, to pick the copy chunk size, how
+//! many files to read at once and how many threads hash them, instead of constants that suit
+//! neither a USB stick nor a fast SSD. The choices are kept in the state dir so that later runs
+//! start right away, and flags given on the command line always win over them
+//!
+//! Copy chunk sizes are compared by writing a probe file to backup_dir and syncing it. Read
+//! concurrency is compared by hashing a sample of the files already in each directory, after
+//! dropping them from the page cache
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{copy, hashing, output::info, platform, recursive_dir, state_dir, WalkOptions};
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Calibration {
+    /// Used for `--copy-buffer-size`
+    pub buffer_size: usize,
+    /// Used for verify's `--jobs-per-device`
+    pub jobs_per_device: usize,
+    /// How many threads hash files while reconciling
+    pub hashing_threads: usize,
+}
+
+/// What's used for whatever the benchmark can't measure, like reads of an empty work_dir, and
+/// when calibrating fails
+pub const DEFAULT: Calibration = Calibration {
+    buffer_size: copy::DEFAULT_BUFFER_SIZE,
+    jobs_per_device: 4,
+    hashing_threads: 4,
+};
+
+const BUFFER_SIZES: [usize; 4] = [64 * 1024, 256 * 1024, 1024 * 1024, 4 * 1024 * 1024];
+const CONCURRENCIES: [usize; 4] = [1, 2, 4, 8];
+
+/// How much is written for each chunk size
+const PROBE_SIZE: usize = 8 * 1024 * 1024;
+/// How much of each directory is hashed for each concurrency, at most
+const SAMPLE_SIZE: u64 = 16 * 1024 * 1024;
+const SAMPLE_FILES: usize = 256;
+/// Smaller samples are over too quickly to tell concurrencies apart
+const MIN_SAMPLE_SIZE: u64 = 4 * 1024 * 1024;
+
+fn calibration_path(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("calibration.json")
+}
+
+/// The stored calibration of work_dir and backup_dir, calibrating them first if there isn't one
+/// yet or `recalibrate` is set
+pub fn load_or_run(
+    work_dir: &Path,
+    backup_dir: &Path,
+    walk_options: &WalkOptions,
+    recalibrate: bool,
+) -> Result<Calibration> {
+    let path = calibration_path(backup_dir);
+    if !recalibrate {
+        // A calibration that can't be parsed, like one from an older version, is just run again
+        if let Some(calibration) = std::fs::read(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_slice(&contents).ok())
+        {
+            return Ok(calibration);
+        }
+    }
+
+    info!("Calibrating for the disks of work_dir and backup_dir...");
+    let started = Instant::now();
+    let buffer_size = fastest_buffer_size(backup_dir)?;
+    let work_concurrency = fastest_concurrency(work_dir, walk_options);
+    let backup_concurrency = fastest_concurrency(backup_dir, walk_options);
+    let concurrency = match (work_concurrency, backup_concurrency) {
+        // Reads are limited per device, so the slower directory decides
+        (Some(work), Some(backup)) => Some(work.min(backup)),
+        (work, backup) => work.or(backup),
+    };
+    let cpus = std::thread::available_parallelism().map_or(1, |cpus| cpus.get());
+
+    let calibration = Calibration {
+        buffer_size,
+        jobs_per_device: concurrency.unwrap_or(DEFAULT.jobs_per_device),
+        hashing_threads: concurrency.unwrap_or(DEFAULT.hashing_threads).min(cpus),
+    };
+    info!(
+        "Calibrated in {:.1}s: copying {} KiB at a time, reading {} files at once per device, hashing with {} threads",
+        started.elapsed().as_secs_f64(),
+        calibration.buffer_size / 1024,
+        calibration.jobs_per_device,
+        calibration.hashing_threads
+    );
+
+    let temp_path = path.with_extension("json.tmp");
+    std::fs::create_dir_all(state_dir(backup_dir))?;
+    std::fs::write(&temp_path, serde_json::to_vec(&calibration)?)?;
+    std::fs::rename(&temp_path, &path)
+        .with_context(|| anyhow!("Error saving {}", path.display()))?;
+
+    Ok(calibration)
+}
+
+/// The chunk size that writes a probe file to backup_dir the fastest
+fn fastest_buffer_size(backup_dir: &Path) -> Result<usize> {
+    std::fs::create_dir_all(state_dir(backup_dir))?;
+    let probe_path = state_dir(backup_dir).join("calibration.probe");
+    let data = vec![0xa5; PROBE_SIZE];
+
+    let mut fastest = (DEFAULT.buffer_size, Duration::MAX);
+    for buffer_size in BUFFER_SIZES {
+        let started = Instant::now();
+        let result = (|| {
+            let mut file = File::create(&probe_path)?;
+            for chunk in data.chunks(buffer_size) {
+                file.write_all(chunk)?;
+            }
+            file.sync_all()
+        })();
+        let elapsed = started.elapsed();
+        let _ = std::fs::remove_file(&probe_path);
+
+        result.with_context(|| anyhow!("Error writing {}", probe_path.display()))?;
+        if elapsed < fastest.1 {
+            fastest = (buffer_size, elapsed);
+        }
+    }
+
+    Ok(fastest.0)
+}
+
+/// How many files at once hash a sample of `root` the fastest, or none if it has too little to
+/// hash. More files at once only win when they're clearly faster, since they cost more memory and
+/// threads
+fn fastest_concurrency(root: &Path, walk_options: &WalkOptions) -> Option<usize> {
+    let mut sample = Vec::new();
+    let mut sample_size = 0;
+    for file_info in recursive_dir(root, walk_options) {
+        if sample.len() >= SAMPLE_FILES || sample_size >= SAMPLE_SIZE {
+            break;
+        }
+        let Ok(metadata) = file_info.metadata() else {
+            continue;
+        };
+        if metadata.len() == 0 || File::open(file_info.path()).is_err() {
+            continue;
+        }
+        sample_size += metadata.len();
+        sample.push(file_info.into_path());
+    }
+    if sample_size < MIN_SAMPLE_SIZE {
+        return None;
+    }
+
+    CONCURRENCIES
+        .into_iter()
+        .map(|concurrency| {
+            drop_cached(&sample);
+            let started = Instant::now();
+            hash_all(&sample, concurrency);
+            (concurrency, started.elapsed())
+        })
+        .reduce(|fastest, (concurrency, elapsed)| {
+            match elapsed.as_secs_f64() < fastest.1.as_secs_f64() * 0.9 {
+                true => (concurrency, elapsed),
+                false => fastest,
+            }
+        })
+        .map(|(concurrency, _)| concurrency)
+}
+
+/// Hashes every file in `paths` with `concurrency` threads, ignoring errors
+fn hash_all(paths: &[PathBuf], concurrency: usize) {
+    let next = AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        for _ in 0..concurrency {
+            scope.spawn(|| {
+                while let Some(path) = paths.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let _ = hashing::hash_fresh(path);
+                }
+            });
+        }
+    });
+}
+
+/// Asks the kernel to forget the cached contents of `paths`, so that hashing them reads the disk.
+/// Where that isn't possible, every pass but the first reads from the cache
+fn drop_cached(paths: &[PathBuf]) {
+    for path in paths {
+        if let Ok(file) = File::open(path) {
+            platform::drop_cached(&file);
+        }
+    }
+}
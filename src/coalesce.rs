@@ -0,0 +1,73 @@
+//! Backing up onto a mounted cloud drive, like an rclone, Google Drive or Dropbox mount, with
+//! `--target-profile=cloud`. Every file written there costs a round trip to the server, which
+//! makes writing small files one at a time as they change slow. Instead, the small files that
+//! changed during a cycle are held back and copied together at its end, many at once, so that
+//! their round trips overlap. Larger files are copied as usual, since their round trips are
+//! dwarfed by the data sent
+//!
+//! The reconciliation at startup copies as many files at once too, unless `--init-concurrency`
+//! says otherwise
+
+use clap::ValueEnum;
+use std::{
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use tokio::sync::{Notify, Semaphore, SemaphorePermit};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TargetProfile {
+    /// A local disk or a network filesystem that's quick to answer
+    #[default]
+    Local,
+    /// A mount of cloud storage, where every write waits on the server
+    Cloud,
+}
+
+impl TargetProfile {
+    /// How many files reconciling copies at once by default
+    pub fn init_concurrency(self) -> usize {
+        match self {
+            Self::Local => 4,
+            Self::Cloud => CONCURRENCY,
+        }
+    }
+}
+
+/// Files up to this size are held back until the end of a cycle
+const SMALL: u64 = 1024 * 1024;
+/// How many small files are copied at once
+pub const CONCURRENCY: usize = 16;
+
+static CLOUD: AtomicBool = AtomicBool::new(false);
+/// Lets the copies held back go, at the end of every cycle
+static FLUSHED: Notify = Notify::const_new();
+static TURNS: Semaphore = Semaphore::const_new(CONCURRENCY);
+
+pub fn set(profile: TargetProfile) {
+    CLOUD.store(profile == TargetProfile::Cloud, Ordering::Relaxed);
+}
+
+/// Whether copying `path` waits for the end of the cycle
+pub async fn is_held_back(path: &Path) -> bool {
+    CLOUD.load(Ordering::Relaxed)
+        && tokio::fs::metadata(path)
+            .await
+            .is_ok_and(|metadata| metadata.len() <= SMALL)
+}
+
+/// Waits for the end of the cycle and a turn to copy, if copying `path` is held back. The turn is
+/// over once the permit is dropped
+pub async fn wait_turn(path: &Path) -> Option<SemaphorePermit<'static>> {
+    if !is_held_back(path).await {
+        return None;
+    }
+
+    FLUSHED.notified().await;
+    TURNS.acquire().await.ok()
+}
+
+/// Lets the copies held back during this cycle go
+pub fn flush() {
+    FLUSHED.notify_waiters();
+}
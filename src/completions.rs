@@ -0,0 +1,377 @@
+//! Shell completions for `evil_mount completions SHELL`, written from the same definitions that
+//! parse the command line, so that they can't fall behind it. Flags, subcommands, the values of
+//! flags that take one of a few, and paths are completed
+//!
+//! The man page of `evil_mount man` is written from them too, see [`crate::man`]
+
+use clap::{builder::StyledStr, Arg, ArgAction, Command, ValueEnum};
+use std::{any::TypeId, fmt::Write, path::PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Shell {
+    /// Source from ~/.bashrc, or save to /usr/share/bash-completion/completions/evil_mount
+    Bash,
+    /// Save as _evil_mount in a directory on $fpath
+    Zsh,
+    /// Save to ~/.config/fish/completions/evil_mount.fish
+    Fish,
+}
+
+/// The completion script for `shell`
+pub fn generate(shell: Shell, mut command: Command) -> String {
+    command.build();
+    let mut commands = Vec::new();
+    walk(Vec::new(), &command, &mut commands);
+
+    match shell {
+        Shell::Bash => bash(command.get_name(), &commands),
+        Shell::Zsh => zsh(command.get_name(), &commands),
+        Shell::Fish => fish(command.get_name(), &commands),
+    }
+}
+
+/// `command` and everything below it, each with the names of the subcommands leading to it
+pub fn walk<'a>(
+    path: Vec<&'a str>,
+    command: &'a Command,
+    all: &mut Vec<(Vec<&'a str>, &'a Command)>,
+) {
+    all.push((path.clone(), command));
+    for subcommand in subcommands(command) {
+        let mut path = path.clone();
+        path.push(subcommand.get_name());
+        walk(path, subcommand, all);
+    }
+}
+
+/// The subcommands of `command` that show up in its help, apart from `help` itself
+pub fn subcommands(command: &Command) -> impl Iterator<Item = &Command> {
+    command
+        .get_subcommands()
+        .filter(|sub| !sub.is_hide_set() && sub.get_name() != "help")
+}
+
+/// The flags of `command` that show up in its help
+pub fn options(command: &Command) -> impl Iterator<Item = &Arg> {
+    command
+        .get_arguments()
+        .filter(|arg| !arg.is_positional() && !arg.is_hide_set())
+}
+
+/// The positional arguments of `command` that show up in its help
+pub fn positionals(command: &Command) -> impl Iterator<Item = &Arg> {
+    command
+        .get_arguments()
+        .filter(|arg| arg.is_positional() && !arg.is_hide_set())
+}
+
+fn takes_value(arg: &Arg) -> bool {
+    arg.get_action().takes_values()
+}
+
+fn is_path(arg: &Arg) -> bool {
+    arg.get_value_parser().type_id() == TypeId::of::<PathBuf>()
+}
+
+/// The values `arg` takes, if it only takes a few
+pub fn values(arg: &Arg) -> Vec<String> {
+    arg.get_possible_values()
+        .iter()
+        .filter(|value| !value.is_hide_set())
+        .map(|value| value.get_name().to_string())
+        .collect()
+}
+
+/// The name of the value `arg` takes, like `DIR`
+pub fn value_name(arg: &Arg) -> String {
+    arg.get_value_names()
+        .and_then(|names| names.first())
+        .map(|name| name.to_string())
+        .unwrap_or_else(|| arg.get_id().as_str().to_uppercase())
+}
+
+/// The first sentence of a help text, on one line
+fn summary(help: Option<&StyledStr>) -> String {
+    let help = help.map(StyledStr::to_string).unwrap_or_default();
+    let help = help.split_whitespace().collect::<Vec<_>>().join(" ");
+    match help.find(". ") {
+        Some(end) => help[..end].to_string(),
+        None => help.trim_end_matches('.').to_string(),
+    }
+}
+
+/// The flags of `arg`, like `-w` and `--work-dir`
+fn flags(arg: &Arg) -> Vec<String> {
+    let short = arg.get_short().map(|short| format!("-{short}"));
+    let long = arg.get_long().map(|long| format!("--{long}"));
+    short.into_iter().chain(long).collect()
+}
+
+fn bash(name: &str, commands: &[(Vec<&str>, &Command)]) -> String {
+    let function = format!("_{name}");
+    let key = |path: &[&str]| {
+        std::iter::once(name)
+            .chain(path.iter().copied())
+            .collect::<Vec<_>>()
+            .join("__")
+    };
+    let mut script = String::new();
+
+    let _ = writeln!(script, "{function}() {{");
+    let _ = writeln!(script, "    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\"");
+    let _ = writeln!(script, "    local prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"");
+    let _ = writeln!(script, "    local command={name} i");
+    let _ = writeln!(script, "    for ((i = 1; i < COMP_CWORD; i++)); do");
+    let _ = writeln!(
+        script,
+        "        case \"${{command}}__${{COMP_WORDS[i]}}\" in"
+    );
+    let paths: Vec<_> = commands.iter().skip(1).map(|(path, _)| key(path)).collect();
+    let _ = writeln!(script, "            {})", paths.join("|"));
+    let _ = writeln!(
+        script,
+        "                command=\"${{command}}__${{COMP_WORDS[i]}}\" ;;"
+    );
+    let _ = writeln!(script, "        esac");
+    let _ = writeln!(script, "    done");
+    let _ = writeln!(script);
+    let _ = writeln!(script, "    case \"$command\" in");
+
+    for (path, command) in commands {
+        let _ = writeln!(script, "        {})", key(path));
+        let _ = writeln!(script, "            case \"$prev\" in");
+        for arg in options(command).filter(|arg| takes_value(arg)) {
+            // A value that's neither of a few nor a path can't be completed
+            let completion = match (values(arg), is_path(arg)) {
+                (values, _) if !values.is_empty() => {
+                    format!("($(compgen -W \"{}\" -- \"$cur\"))", values.join(" "))
+                }
+                (_, true) => "($(compgen -f -- \"$cur\"))".to_string(),
+                _ => "()".to_string(),
+            };
+            let _ = writeln!(
+                script,
+                "                {}) COMPREPLY={completion}; return ;;",
+                flags(arg).join("|")
+            );
+        }
+        let _ = writeln!(script, "            esac");
+
+        let words: Vec<_> = options(command)
+            .flat_map(flags)
+            .chain(subcommands(command).map(|sub| sub.get_name().to_string()))
+            .chain(positionals(command).flat_map(values))
+            .collect();
+        let files = match positionals(command).any(is_path) {
+            true => " $(compgen -f -- \"$cur\")",
+            false => "",
+        };
+        let _ = writeln!(
+            script,
+            "            COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"){files}) ;;",
+            words.join(" ")
+        );
+    }
+
+    let _ = writeln!(script, "    esac");
+    let _ = writeln!(script, "}}");
+    let _ = writeln!(script);
+    let _ = writeln!(script, "complete -F {function} {name}");
+    script
+}
+
+/// `text` safe inside single quotes and the brackets of an `_arguments` spec
+fn zsh_quote(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('\'', "'\\''")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+}
+
+fn zsh(name: &str, commands: &[(Vec<&str>, &Command)]) -> String {
+    let function = |path: &[&str]| {
+        std::iter::once(format!("_{name}"))
+            .chain(path.iter().map(|name| name.to_string()))
+            .collect::<Vec<_>>()
+            .join("__")
+    };
+    let mut script = format!("#compdef {name}\n");
+
+    for (path, command) in commands {
+        let subcommands: Vec<_> = subcommands(command).collect();
+
+        let mut specs = Vec::new();
+        for arg in options(command) {
+            let repeated = match arg.get_action() {
+                ArgAction::Append | ArgAction::Count => "*",
+                _ => "",
+            };
+            let value = match (takes_value(arg), values(arg), is_path(arg)) {
+                (false, _, _) => String::new(),
+                (true, values, _) if !values.is_empty() => {
+                    format!(":{}:({})", value_name(arg), values.join(" "))
+                }
+                (true, _, true) => format!(":{}:_files", value_name(arg)),
+                (true, _, false) => format!(":{}: ", value_name(arg)),
+            };
+            for flag in flags(arg) {
+                specs.push(format!(
+                    "'{repeated}{flag}[{}]{value}'",
+                    zsh_quote(&summary(arg.get_help()))
+                ));
+            }
+        }
+        for arg in positionals(command) {
+            let many = arg.get_num_args().is_some_and(|num| num.max_values() > 1);
+            let prefix = match (many, arg.is_required_set()) {
+                (true, _) => "*:",
+                (false, true) => ":",
+                (false, false) => "::",
+            };
+            let action = match (values(arg), is_path(arg)) {
+                (values, _) if !values.is_empty() => format!("({})", values.join(" ")),
+                (_, true) => "_files".to_string(),
+                _ => " ".to_string(),
+            };
+            specs.push(format!("'{prefix}{}:{action}'", value_name(arg)));
+        }
+        if !subcommands.is_empty() {
+            specs.push(format!("': :{}_commands'", function(path)));
+            specs.push("'*:: :->args'".to_string());
+        }
+
+        let _ = writeln!(script);
+        let _ = writeln!(script, "{}() {{", function(path));
+        let _ = writeln!(script, "    local context state state_descr line");
+        let _ = writeln!(script, "    typeset -A opt_args");
+        let _ = write!(script, "    _arguments -s -C");
+        for spec in &specs {
+            let _ = write!(script, " \\\n        {spec}");
+        }
+        let _ = writeln!(script);
+        if !subcommands.is_empty() {
+            let _ = writeln!(script, "    case $state in");
+            let _ = writeln!(script, "        args)");
+            let _ = writeln!(script, "            case $line[1] in");
+            for subcommand in &subcommands {
+                let mut sub_path = path.clone();
+                sub_path.push(subcommand.get_name());
+                let _ = writeln!(
+                    script,
+                    "                {}) {} ;;",
+                    subcommand.get_name(),
+                    function(&sub_path)
+                );
+            }
+            let _ = writeln!(script, "            esac ;;");
+            let _ = writeln!(script, "    esac");
+        }
+        let _ = writeln!(script, "}}");
+
+        if !subcommands.is_empty() {
+            let _ = writeln!(script);
+            let _ = writeln!(script, "{}_commands() {{", function(path));
+            let _ = writeln!(script, "    local commands; commands=(");
+            for subcommand in &subcommands {
+                let _ = writeln!(
+                    script,
+                    "        '{}:{}'",
+                    subcommand.get_name(),
+                    summary(subcommand.get_about()).replace('\'', "'\\''")
+                );
+            }
+            let _ = writeln!(script, "    )");
+            let _ = writeln!(script, "    _describe -t commands 'command' commands");
+            let _ = writeln!(script, "}}");
+        }
+    }
+
+    let _ = writeln!(script);
+    let _ = writeln!(script, "if [ \"$funcstack[1]\" = \"_{name}\" ]; then");
+    let _ = writeln!(script, "    _{name} \"$@\"");
+    let _ = writeln!(script, "else");
+    let _ = writeln!(script, "    compdef _{name} {name}");
+    let _ = writeln!(script, "fi");
+    script
+}
+
+/// `text` safe inside single quotes
+fn fish_quote(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+fn fish(name: &str, commands: &[(Vec<&str>, &Command)]) -> String {
+    let mut script = String::new();
+
+    for (path, command) in commands {
+        // Where the options of this command are completed
+        let condition = match path.is_empty() {
+            true => "__fish_use_subcommand".to_string(),
+            false => path
+                .iter()
+                .map(|name| format!("__fish_seen_subcommand_from {name}"))
+                .collect::<Vec<_>>()
+                .join("; and "),
+        };
+        let subcommands: Vec<_> = subcommands(command).collect();
+
+        for arg in options(command) {
+            let mut line = format!("complete -c {name} -n '{condition}'");
+            if let Some(short) = arg.get_short() {
+                let _ = write!(line, " -s {short}");
+            }
+            if let Some(long) = arg.get_long() {
+                let _ = write!(line, " -l {long}");
+            }
+            let values = values(arg);
+            match (takes_value(arg), values.is_empty(), is_path(arg)) {
+                (false, _, _) => (),
+                (true, false, _) => {
+                    let _ = write!(line, " -x -a '{}'", fish_quote(&values.join(" ")));
+                }
+                (true, true, true) => line.push_str(" -r -F"),
+                (true, true, false) => line.push_str(" -x"),
+            }
+            let _ = writeln!(
+                script,
+                "{line} -d '{}'",
+                fish_quote(&summary(arg.get_help()))
+            );
+        }
+
+        for arg in positionals(command) {
+            let values = values(arg);
+            if !values.is_empty() {
+                let _ = writeln!(
+                    script,
+                    "complete -c {name} -n '{condition}' -f -a '{}'",
+                    fish_quote(&values.join(" "))
+                );
+            }
+        }
+
+        // Below the top, subcommands are only offered until one of them is given
+        let listing = match path.is_empty() {
+            true => condition,
+            false => format!(
+                "{condition}; and not __fish_seen_subcommand_from {}",
+                subcommands
+                    .iter()
+                    .map(|sub| sub.get_name())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+        };
+        for subcommand in &subcommands {
+            let _ = writeln!(
+                script,
+                "complete -c {name} -n '{listing}' -f -a {} -d '{}'",
+                subcommand.get_name(),
+                fish_quote(&summary(subcommand.get_about()))
+            );
+        }
+    }
+
+    script
+}
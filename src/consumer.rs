@@ -0,0 +1,91 @@
+//! `--mirror-consumer`, for running evil_mount on the backup machine only, against a work_dir
+//! that's a mirror another evil_mount or rsync keeps up to date from the source host. The mirror
+//! is never written to: the startup reconciliation always copies from it, even when backup_dir
+//! looks newer, and restoring into it is refused. Versions, the deletion grace period and the
+//! scheduled verification all happen on this side, so the source host only has to push its files
+//!
+//! Whatever pushes to the mirror may write a file in place, so a file is only copied once it
+//! hasn't been modified for [`SETTLE`], and the temporary files of evil_mount's own copies are
+//! left out. With `--versions`, a file deleted from the mirror keeps its last copy as its newest
+//! version, so that the mirror losing a file doesn't lose its backup too
+
+use anyhow::Result;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime},
+};
+use tokio::fs;
+
+use crate::versions;
+
+/// How long a file in the mirror has to go unmodified before it's copied
+const SETTLE: Duration = Duration::from_secs(10);
+
+/// The temporary files of another evil_mount copying into the mirror, directly or through a copier
+pub const EXCLUDES: &[&str] = &["*.evil_mount.tmp", "*.evil_mount-copier"];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Whether work_dir is a mirror that's never written to
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Whether `path` in the mirror is done changing and can be copied. Always true without
+/// `--mirror-consumer`
+pub async fn is_settled(path: &Path) -> bool {
+    if !is_enabled() {
+        return true;
+    }
+
+    match fs::metadata(path)
+        .await
+        .and_then(|metadata| metadata.modified())
+    {
+        // A modify time in the future, from a skewed clock, would never settle
+        Ok(modified) => SystemTime::now()
+            .duration_since(modified)
+            .map_or(true, |age| age >= SETTLE),
+        // Left for the copy to report
+        Err(_) => true,
+    }
+}
+
+/// Keeps the copies in `path`, a file or directory in backup_dir that's about to be deleted, as
+/// their newest versions. Does nothing without `--mirror-consumer` and `--versions`
+pub async fn keep_deleted(path: &Path) -> Result<()> {
+    if !is_enabled() {
+        return Ok(());
+    }
+
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => dirs.push(path.to_path_buf()),
+        Ok(_) => files.push(path.to_path_buf()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    }
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let list: &mut Vec<PathBuf> = match entry.file_type()?.is_dir() {
+                true => &mut dirs,
+                false => &mut files,
+            };
+            list.push(entry.path());
+        }
+    }
+
+    for file in files {
+        versions::keep(&file).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,121 @@
+//! Keeping symlinks from leading a sync outside of work_dir and backup_dir. Walks never follow
+//! them, but a directory can be swapped for a symlink between being walked and being copied, and a
+//! symlink planted in backup_dir, like `docs -> /etc`, would have the copies and deletions under it
+//! land outside of the backup
+//!
+//! So the files a copy reads and writes are opened beneath their directory, with openat2 and
+//! RESOLVE_BENEATH on Linux, which fails for anything that would resolve outside of it. Everything
+//! deleted, renamed or overwritten in backup_dir is checked to have no symlink among the
+//! directories on its way, and where openat2 isn't there, neither do the files copied. Turned off
+//! by `--allow-symlink-escapes`, except in the copier, which always stays inside its backup_dir
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use crate::platform;
+
+struct Root {
+    path: PathBuf,
+    /// Opened once, for opening files beneath it
+    dir: File,
+}
+
+static ROOTS: OnceLock<Vec<Root>> = OnceLock::new();
+
+/// Keeps every path under one of `roots` inside of it from now on
+pub fn set(roots: &[&Path]) -> Result<()> {
+    let roots = roots
+        .iter()
+        .map(|path| {
+            let dir =
+                File::open(path).with_context(|| anyhow!("Error opening {}", path.display()))?;
+            Ok(Root {
+                path: path.to_path_buf(),
+                dir,
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    ROOTS
+        .set(roots)
+        .map_err(|_| anyhow!("the directories to stay in can only be set once"))
+}
+
+/// The directory `path` has to stay in, and its path relative to it
+fn root_of(path: &Path) -> Option<(&'static Root, &Path)> {
+    ROOTS
+        .get()?
+        .iter()
+        .filter_map(|root| Some((root, path.strip_prefix(&root.path).ok()?)))
+        // One directory inside of the other is the innermost one's
+        .min_by_key(|(_, relative)| relative.components().count())
+}
+
+fn escape(path: &Path, root: &Root) -> io::Error {
+    io::Error::other(format!(
+        "Not following {}, a symlink on its way leads outside of {}",
+        path.display(),
+        root.path.display()
+    ))
+}
+
+/// Fails if any of the directories between `path` and the directory it has to stay in is a
+/// symlink. `path` itself may be one, since deleting or replacing it doesn't follow it
+pub fn check(path: &Path) -> io::Result<()> {
+    let Some((root, relative)) = root_of(path) else {
+        return Ok(());
+    };
+
+    let mut dir = root.path.clone();
+    let mut components = relative.components().peekable();
+    while let Some(component) = components.next() {
+        if components.peek().is_none() {
+            break;
+        }
+        dir.push(component);
+        match std::fs::symlink_metadata(&dir) {
+            Ok(metadata) if metadata.file_type().is_symlink() => return Err(escape(path, root)),
+            Ok(_) => (),
+            // Nothing left to follow on the way
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+/// Opens `path` for reading, failing if that leads outside of the directory it has to stay in
+pub fn open(path: &Path) -> io::Result<File> {
+    open_contained(path, false)
+}
+
+/// Creates or truncates `path` for writing, failing if that leads outside of the directory it has
+/// to stay in
+pub fn create(path: &Path) -> io::Result<File> {
+    open_contained(path, true)
+}
+
+fn open_contained(path: &Path, create: bool) -> io::Result<File> {
+    let Some((root, relative)) = root_of(path) else {
+        return match create {
+            true => File::create(path),
+            false => File::open(path),
+        };
+    };
+
+    match platform::open_beneath(&root.dir, relative, create) {
+        Ok(Some(file)) => Ok(file),
+        Ok(None) => {
+            check(path)?;
+            platform::open_no_follow(path, create)
+        }
+        Err(err) if platform::is_escape(&err) => Err(escape(path, root)),
+        Err(err) => Err(err),
+    }
+}
@@ -0,0 +1,395 @@
+//! A control socket for a running `sync`, and the `ctl` client that talks to it. It's a Unix
+//! socket, or a named pipe on Windows.
+//!
+//! The protocol is one JSON object per line in each direction: the client sends a [`Request`]
+//! such as `{"command":"resync","path":"src"}` and the daemon answers with a [`Response`]
+
+// Builds without the socket still parse `ctl` requests, only to refuse them
+#![cfg_attr(
+    not(all(any(unix, windows), feature = "control-socket")),
+    allow(dead_code)
+)]
+
+use anyhow::{anyhow, Result};
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+};
+
+use crate::{
+    deletion, encoded_path, full_pass,
+    output::info,
+    pause::{self, PAUSED},
+    platform, protect, relative_to, reload, resync_in_pass, status, SyncOptions, SyncReport,
+};
+
+#[derive(Subcommand, Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// Show what the daemon is doing
+    Status,
+    /// Stop copying and deleting until resumed
+    Pause,
+    /// Start syncing again after a pause. Doesn't override the pause sentinel file
+    Resume,
+    /// Sync everything that's pending right now, and wait for it to finish
+    #[command(alias = "sync-now")]
+    #[serde(alias = "sync_now")]
+    Flush,
+    /// Reconcile a subtree of work_dir by hash, copying anything that differs
+    Resync {
+        /// The subtree to reconcile, relative to work_dir
+        #[serde(with = "encoded_path")]
+        path: PathBuf,
+    },
+    /// Apply the exclude and interval of the daemon's profile as they are in the --profiles file now
+    Reload,
+    /// Let the next pass delete more than --max-delete allows
+    AllowDeletes,
+    /// Print the copies, deletions, moves, errors and finished cycles as they happen, one JSON
+    /// object per line like `--output json`, until interrupted
+    Events,
+    /// Let a held back deletion or overwrite of a --protect file happen, or all of them without a
+    /// path
+    Confirm {
+        /// The file, relative to backup_dir
+        #[serde(default, with = "encoded_path::option")]
+        path: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+}
+
+impl Response {
+    pub fn ok(result: serde_json::Value) -> Self {
+        Self {
+            ok: true,
+            error: None,
+            result: Some(result),
+        }
+    }
+
+    pub fn error(err: anyhow::Error) -> Self {
+        Self {
+            ok: false,
+            error: Some(format!("{err:#}")),
+            result: None,
+        }
+    }
+}
+
+/// What the control socket needs to know about the sync it controls
+#[derive(Clone)]
+pub struct Context {
+    pub work_dir: PathBuf,
+    pub backup_dir: PathBuf,
+    pub options: SyncOptions,
+}
+
+fn report_json(report: &SyncReport) -> serde_json::Value {
+    serde_json::json!({
+        "copied": report.copied,
+        "moved": report.moved,
+        "deleted": report.deleted,
+        "errors": report.errors,
+    })
+}
+
+async fn handle(request: Request, context: &Context) -> Result<serde_json::Value> {
+    Ok(match request {
+        Request::Status => {
+            status::snapshot(&context.work_dir, &context.backup_dir, &context.options)?
+        }
+        Request::Pause => {
+            PAUSED.store(true, Ordering::Relaxed);
+            info!("Paused syncing");
+            serde_json::json!({ "paused": true })
+        }
+        Request::Resume => {
+            PAUSED.store(false, Ordering::Relaxed);
+            info!("Resumed syncing");
+            serde_json::json!({ "paused": false })
+        }
+        Request::Flush => {
+            ensure_running()?;
+            report_json(&full_pass::run([]).await?)
+        }
+        Request::Resync { path } => {
+            ensure_running()?;
+            report_json(
+                &resync_in_pass(
+                    &context.work_dir,
+                    &context.backup_dir,
+                    &path,
+                    &context.options,
+                )
+                .await?,
+            )
+        }
+        Request::Reload => reload::reload(&context.options)?,
+        Request::Events => unreachable!("serve_connection streams the events itself"),
+        Request::AllowDeletes => {
+            deletion::ALLOW_ONCE.store(true, Ordering::Relaxed);
+            info!("Allowing the next pass to delete more than --max-delete allows");
+            serde_json::json!({ "held_back": deletion::held_back() })
+        }
+        Request::Confirm { path } => {
+            let path = path
+                .as_deref()
+                .map(|path| relative_to(path, &context.backup_dir))
+                .transpose()?;
+            let confirmed: Vec<_> = protect::confirm(path)?
+                .iter()
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect();
+            serde_json::json!({ "confirmed": confirmed })
+        }
+    })
+}
+
+/// Fails while syncing is paused, since a pass asked for wouldn't run until it resumes
+fn ensure_running() -> Result<()> {
+    match pause::is_paused() {
+        true => Err(anyhow!("Syncing is paused, resume it first")),
+        false => Ok(()),
+    }
+}
+
+/// Answers the requests on one connection until the client hangs up
+#[cfg(all(any(unix, windows), feature = "control-socket"))]
+async fn serve_connection<S>(stream: S, context: Context)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+{
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(Request::Events) => return stream_events(&mut writer).await,
+            Ok(request) => match handle(request, &context).await {
+                Ok(result) => Response::ok(result),
+                Err(err) => Response::error(err),
+            },
+            Err(err) => Response::error(anyhow!("Invalid request: {err}")),
+        };
+
+        let mut response = serde_json::to_vec(&response).expect("responses serialize");
+        response.push(b'\n');
+        if writer.write_all(&response).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Writes every event to `writer` as it happens, until the client hangs up
+#[cfg(all(any(unix, windows), feature = "control-socket"))]
+async fn stream_events(writer: &mut (impl tokio::io::AsyncWrite + Unpin)) {
+    use tokio::{io::AsyncWriteExt, sync::broadcast::error::RecvError};
+
+    let mut events = crate::output::subscribe();
+    loop {
+        let line = match events.recv().await {
+            Ok(event) => serde_json::to_vec(&event),
+            // The client reads slower than the events come, and is told how many it missed
+            Err(RecvError::Lagged(missed)) => {
+                serde_json::to_vec(&serde_json::json!({ "event": "missed", "count": missed }))
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let mut line = line.expect("events serialize");
+        line.push(b'\n');
+        if writer.write_all(&line).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Sends `request` over a fresh connection and waits for the response
+#[cfg(all(any(unix, windows), feature = "control-socket"))]
+async fn exchange<S>(stream: S, request: &Request) -> Result<Response>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+{
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (reader, mut writer) = tokio::io::split(stream);
+
+    let mut request = serde_json::to_vec(request)?;
+    request.push(b'\n');
+    writer.write_all(&request).await?;
+
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| anyhow!("The daemon closed the connection without responding"))?;
+
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(all(unix, feature = "control-socket"))]
+pub async fn serve(socket_path: PathBuf, context: Context) -> Result<()> {
+    use tokio::net::UnixListener;
+
+    let listener = match crate::systemd::take_listener() {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            info!("Listening for control commands on the socket systemd passed in");
+            UnixListener::from_std(listener)?
+        }
+        None => {
+            // A socket left behind by a previous run would make binding fail
+            match tokio::fs::remove_file(&socket_path).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => (),
+            }
+            let listener = UnixListener::bind(&socket_path)?;
+            info!(
+                "Listening for control commands on {}",
+                socket_path.display()
+            );
+            listener
+        }
+    };
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::task::spawn(serve_connection(stream, context.clone()));
+    }
+}
+
+/// The named pipe `--control-socket` stands for on Windows. A name under `\\.\pipe\` is used as
+/// it is, and anything else becomes a pipe named after it
+#[cfg(all(windows, feature = "control-socket"))]
+fn pipe_name(socket_path: &Path) -> String {
+    const PREFIX: &str = r"\\.\pipe\";
+
+    let socket_path = socket_path.to_string_lossy();
+    match socket_path.starts_with(PREFIX) {
+        true => socket_path.into_owned(),
+        false => {
+            let name: String = socket_path
+                .chars()
+                .map(|c| match c {
+                    '\\' | '/' | ':' => '-',
+                    c => c,
+                })
+                .collect();
+            format!("{PREFIX}evil_mount-{name}")
+        }
+    }
+}
+
+#[cfg(all(windows, feature = "control-socket"))]
+pub async fn serve(socket_path: PathBuf, context: Context) -> Result<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let name = pipe_name(&socket_path);
+    // Refuses to share the name with another running sync
+    let mut server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(&name)?;
+    info!("Listening for control commands on {name}");
+
+    loop {
+        server.connect().await?;
+        // A new instance takes over listening before this one is handed to its client
+        let connected = std::mem::replace(&mut server, ServerOptions::new().create(&name)?);
+        tokio::task::spawn(serve_connection(connected, context.clone()));
+    }
+}
+
+#[cfg(not(all(any(unix, windows), feature = "control-socket")))]
+pub async fn serve(_socket_path: PathBuf, _context: Context) -> Result<()> {
+    Err(unsupported())
+}
+
+/// Sends a single request to a running daemon and waits for its response
+#[cfg(all(any(unix, windows), feature = "control-socket"))]
+pub async fn send(socket_path: &Path, request: &Request) -> Result<Response> {
+    exchange(connect(socket_path).await?, request).await
+}
+
+/// Asks a running daemon for its events, and calls `on_event` with each one as it happens until
+/// the daemon goes away
+#[cfg(all(any(unix, windows), feature = "control-socket"))]
+pub async fn follow(socket_path: &Path, mut on_event: impl FnMut(&str)) -> Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (reader, mut writer) = tokio::io::split(connect(socket_path).await?);
+    let mut request = serde_json::to_vec(&Request::Events)?;
+    request.push(b'\n');
+    writer.write_all(&request).await?;
+
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        on_event(&line);
+    }
+
+    Ok(())
+}
+
+#[cfg(all(unix, feature = "control-socket"))]
+async fn connect(socket_path: &Path) -> Result<tokio::net::UnixStream> {
+    use anyhow::Context as _;
+
+    tokio::net::UnixStream::connect(socket_path)
+        .await
+        .with_context(|| anyhow!("Error connecting to {}", socket_path.display()))
+        .map_err(crate::exit::unreachable)
+}
+
+#[cfg(all(windows, feature = "control-socket"))]
+async fn connect(socket_path: &Path) -> Result<tokio::net::windows::named_pipe::NamedPipeClient> {
+    use tokio::net::windows::named_pipe::ClientOptions;
+
+    /// What opening a pipe fails with while all of its instances are taken
+    const ERROR_PIPE_BUSY: i32 = 231;
+
+    let name = pipe_name(socket_path);
+    let mut attempts = 0;
+    loop {
+        match ClientOptions::new().open(&name) {
+            Ok(stream) => return Ok(stream),
+            // Another client got the listening instance first, and a new one is on its way
+            Err(err) if err.raw_os_error() == Some(ERROR_PIPE_BUSY) && attempts < 20 => {
+                attempts += 1;
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+            Err(err) => {
+                return Err(crate::exit::unreachable(
+                    anyhow!(err).context(format!("Error connecting to {name}")),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(not(all(any(unix, windows), feature = "control-socket")))]
+pub async fn send(_socket_path: &Path, _request: &Request) -> Result<Response> {
+    Err(unsupported())
+}
+
+#[cfg(not(all(any(unix, windows), feature = "control-socket")))]
+pub async fn follow(_socket_path: &Path, _on_event: impl FnMut(&str)) -> Result<()> {
+    Err(unsupported())
+}
+
+pub fn unsupported() -> anyhow::Error {
+    match platform::CAPABILITIES.control_socket {
+        true => anyhow!("This evil_mount was built without the control socket, enable the control-socket feature to use it"),
+        false => anyhow!("The control socket isn't supported on this platform"),
+    }
+}
@@ -0,0 +1,169 @@
+//! Sharing work_dir or backup_dir with another sync tool, like Syncthing, Dropbox or OneDrive,
+//! each recognized by what it leaves at the root of a folder it syncs. Once one is found, its
+//! metadata and temporary files are left out of the backup, and out of the deletions in a
+//! backup_dir it syncs, where removing them would break it. Those tools pull in changes in bursts,
+//! so a changed file in a work_dir one syncs is only copied once it looked the same two checks in a
+//! row. New files need no waiting, since they only show up once their temporary file is renamed
+//! into place
+//!
+//! The setups that fight with the other tool are warned about at startup, and every conflict copy
+//! it makes is warned about once, since the backup keeps both sides
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use crate::output::{self, info, Event};
+
+struct Tool {
+    name: &'static str,
+    /// Whether it syncs the folder rooted at the directory
+    syncs: fn(&Path) -> bool,
+    /// Gitignore style globs of its metadata and temporary files
+    excludes: &'static [&'static str],
+    /// What the names of the conflict copies it makes contain
+    conflict_marker: &'static str,
+}
+
+const TOOLS: &[Tool] = &[
+    Tool {
+        name: "Syncthing",
+        syncs: |dir| dir.join(".stfolder").exists(),
+        excludes: &[
+            ".stfolder",
+            ".stversions",
+            ".syncthing.*.tmp",
+            "~syncthing~*.tmp",
+        ],
+        conflict_marker: ".sync-conflict-",
+    },
+    Tool {
+        name: "Dropbox",
+        syncs: |dir| dir.join(".dropbox").exists() || dir.join(".dropbox.cache").exists(),
+        excludes: &[".dropbox", ".dropbox.cache", ".dropbox.attr"],
+        conflict_marker: " (conflicted copy ",
+    },
+    Tool {
+        name: "OneDrive",
+        syncs: |dir| {
+            dir.join(ONEDRIVE_MARKER).exists()
+                || dir.file_name().is_some_and(|name| {
+                    let name = name.to_string_lossy();
+                    name == "OneDrive"
+                        || name.starts_with("OneDrive - ")
+                        || name.starts_with("OneDrive-")
+                })
+        },
+        excludes: &[ONEDRIVE_MARKER],
+        conflict_marker: "-safeBackup-",
+    },
+];
+
+/// The hidden file OneDrive keeps at the root of the folder it syncs on Windows
+const ONEDRIVE_MARKER: &str = ".849C9593-D756-4E56-8D6E-42412F2A707B";
+
+/// Set when another tool syncs work_dir, so changed files wait to stop changing
+static SETTLING: AtomicBool = AtomicBool::new(false);
+/// The tools found in either directory
+static FOUND: Mutex<Vec<&'static Tool>> = Mutex::new(Vec::new());
+/// Conflict copies already warned about, relative to the walk root
+static CONFLICTS: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// The folder `tool` syncs that `dir` is in, if any
+fn synced_folder(tool: &Tool, dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .find(|ancestor| (tool.syncs)(ancestor))
+        .map(Path::to_path_buf)
+}
+
+/// Looks for other sync tools in work_dir and backup_dir, returning the excludes that keep out of
+/// their way
+pub fn detect(work_dir: &Path, backup_dir: &Path) -> Vec<String> {
+    let mut excludes = Vec::new();
+
+    for tool in TOOLS {
+        let work_folder = synced_folder(tool, work_dir);
+        let backup_folder = synced_folder(tool, backup_dir);
+        if work_folder.is_none() && backup_folder.is_none() {
+            continue;
+        }
+
+        excludes.extend(tool.excludes.iter().map(|exclude| exclude.to_string()));
+        FOUND.lock().unwrap().push(tool);
+        if let Some(folder) = &work_folder {
+            SETTLING.store(true, Ordering::Relaxed);
+            info!(
+                "{} syncs {}, leaving its files out and waiting for files to stop changing before copying them",
+                tool.name,
+                folder.display()
+            );
+        }
+
+        let warning = match (&work_folder, &backup_folder) {
+            (Some(work_folder), Some(backup_folder)) if work_folder == backup_folder => Some(
+                "both work_dir and backup_dir are in it, so every change is synced twice, and a file restored on another device can bring a deleted one back into work_dir",
+            ),
+            (_, Some(_)) => Some(
+                "whatever it brings into backup_dir from other devices is overwritten or deleted again to match work_dir, so have it only send changes from backup_dir",
+            ),
+            _ => None,
+        };
+        if let Some(warning) = warning {
+            let folder = backup_folder.as_deref().unwrap_or(backup_dir);
+            output::emit(&Event::OtherSyncTool {
+                tool: tool.name,
+                path: folder,
+                warning,
+            });
+        }
+    }
+
+    excludes
+}
+
+/// Whether `modify_time`, seen now, can be copied. Called every time a changed file is checked,
+/// with what it was seen at the last time
+pub fn settled(last_seen: &mut Option<u64>, modify_time: u64) -> bool {
+    if !SETTLING.load(Ordering::Relaxed) {
+        return true;
+    }
+
+    last_seen.replace(modify_time) == Some(modify_time)
+}
+
+/// Warns about `path` the first time it's walked, if it's a conflict copy from another sync tool.
+/// `root` is the root of the walk
+pub fn found(root: &Path, path: &Path) {
+    let Some(name) = path.file_name() else {
+        return;
+    };
+    let name = name.to_string_lossy();
+    let Some(tool) = FOUND
+        .lock()
+        .unwrap()
+        .iter()
+        .copied()
+        .find(|tool| name.contains(tool.conflict_marker))
+    else {
+        return;
+    };
+
+    let relative_path = path.strip_prefix(root).unwrap_or(path);
+    if CONFLICTS
+        .lock()
+        .unwrap()
+        .insert(relative_path.to_path_buf())
+    {
+        output::emit(&Event::OtherSyncTool {
+            tool: tool.name,
+            path,
+            warning:
+                "this is a conflict copy, which is backed up like any other file until it's resolved",
+        });
+    }
+}
@@ -0,0 +1,464 @@
+//! Splitting a sync over two processes, so that a privileged process that can read all of work_dir
+//! feeds an unprivileged `copier` that owns backup_dir, or the other way around. The sync process
+//! scans work_dir and backup_dir as usual, but sends every change it would make to the files in
+//! backup_dir to the copier instead: the contents of new copies, deletions, renames and new
+//! directories. The copier only ever writes inside its own backup_dir, refusing any path that would
+//! leave it
+//!
+//! The protocol is the one of the control socket, one JSON object per line in each direction, with
+//! the contents of a written file following its request as raw bytes, and then a byte that's 1 if
+//! the copier should keep them. The sync only sends that once it checked that the file didn't
+//! change while it was being sent, so a copy torn by a concurrent write is thrown away like it is
+//! without a copier. Every change is a connection of its own, so that copies run as concurrently
+//! as they do without a copier
+//!
+//! The sync process still keeps its state in the state dir itself, so that has to stay writable by
+//! it
+
+// Builds without the socket still parse `--copier`, only to refuse it
+#![cfg_attr(not(all(unix, feature = "control-socket")), allow(dead_code))]
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::OnceLock,
+};
+use tokio::fs;
+
+#[cfg(all(unix, feature = "control-socket"))]
+use crate::races;
+use crate::{containment, encoded_path, platform, read_only};
+
+/// A change to backup_dir, with paths relative to it
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Change {
+    /// Replaces `path` with the `len` bytes that follow the request
+    Write {
+        #[serde(with = "encoded_path")]
+        path: PathBuf,
+        len: u64,
+        mode: Option<u32>,
+        owner: Option<(u32, u32)>,
+        /// Left out by versions that didn't keep modify times
+        #[serde(default)]
+        modified: Option<std::time::SystemTime>,
+    },
+    Remove {
+        #[serde(with = "encoded_path")]
+        path: PathBuf,
+        dir: bool,
+    },
+    Rename {
+        #[serde(with = "encoded_path")]
+        from: PathBuf,
+        #[serde(with = "encoded_path")]
+        to: PathBuf,
+    },
+    CreateDir {
+        #[serde(with = "encoded_path")]
+        path: PathBuf,
+    },
+}
+
+struct Copier {
+    socket_path: PathBuf,
+    backup_dir: PathBuf,
+}
+
+static COPIER: OnceLock<Copier> = OnceLock::new();
+
+/// Sends the changes to `backup_dir` to the copier listening on `socket_path` from now on
+pub fn set(socket_path: PathBuf, backup_dir: PathBuf) -> Result<()> {
+    if !cfg!(all(unix, feature = "control-socket")) {
+        return Err(unsupported());
+    }
+
+    COPIER
+        .set(Copier {
+            socket_path,
+            backup_dir,
+        })
+        .map_err(|_| anyhow!("the copier can only be set once"))
+}
+
+pub fn unsupported() -> anyhow::Error {
+    match cfg!(unix) {
+        true => anyhow!("This evil_mount was built without the control socket, enable the control-socket feature to use a copier"),
+        false => anyhow!("Copier processes are only supported on Unix"),
+    }
+}
+
+/// The copier that changes to `path` go to, and `path` relative to its backup_dir, if any do
+fn copier_for(path: &Path) -> Option<(&'static Copier, &Path)> {
+    let copier = COPIER.get()?;
+    let relative_path = path.strip_prefix(&copier.backup_dir).ok()?;
+
+    Some((copier, relative_path))
+}
+
+/// Whether changes to `path` go to a copier rather than being made here
+pub fn handles(path: &Path) -> bool {
+    copier_for(path).is_some()
+}
+
+/// The contents of a write, from `source` as it was when `before` was taken
+struct Contents<'a> {
+    file: fs::File,
+    source: &'a Path,
+    before: &'a std::fs::Metadata,
+}
+
+/// Has the copier replace `to` with a copy of `from`, along with its permissions and owner. Fails
+/// with [`crate::races::Changed`], leaving `to` as it was, if `from` no longer looks like `before` once
+/// it's been sent
+pub async fn write(from: &Path, to: &Path, before: &std::fs::Metadata) -> Result<()> {
+    let Some((copier, relative_path)) = copier_for(to) else {
+        return Err(anyhow!("{} isn't in the copier's backup_dir", to.display()));
+    };
+
+    let file = fs::File::from_std(containment::open(from)?);
+    let metadata = file.metadata().await?;
+    let change = Change::Write {
+        path: relative_path.to_path_buf(),
+        len: metadata.len(),
+        mode: platform::mode(&metadata).map(|(mode, _)| mode),
+        owner: platform::owner(&metadata),
+        modified: metadata.modified().ok(),
+    };
+    let contents = Contents {
+        file,
+        source: from,
+        before,
+    };
+
+    request(copier, &change, Some(contents)).await
+}
+
+/// Removes `path`, a directory with everything in it if `dir` is set
+pub async fn remove(path: &Path, dir: bool) -> Result<()> {
+    match copier_for(path) {
+        Some((copier, relative_path)) => {
+            let change = Change::Remove {
+                path: relative_path.to_path_buf(),
+                dir,
+            };
+            request(copier, &change, None).await
+        }
+        None => {
+            containment::check(path)?;
+            read_only::unlock(path)?;
+            match dir {
+                true => fs::remove_dir_all(path).await?,
+                false => fs::remove_file(path).await?,
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Renames `from` to `to`, creating any missing parent directories
+pub async fn rename(from: &Path, to: &Path) -> Result<()> {
+    match (copier_for(from), copier_for(to)) {
+        (Some((copier, relative_from)), Some((_, relative_to))) => {
+            let change = Change::Rename {
+                from: relative_from.to_path_buf(),
+                to: relative_to.to_path_buf(),
+            };
+            request(copier, &change, None).await
+        }
+        _ => {
+            containment::check(from)?;
+            containment::check(to)?;
+            read_only::unlock(from)?;
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::rename(from, to).await?;
+            Ok(())
+        }
+    }
+}
+
+/// Makes the directory `path` along with any missing parents
+pub async fn create_dir_all(path: &Path) -> Result<()> {
+    match copier_for(path) {
+        Some((copier, relative_path)) => {
+            let change = Change::CreateDir {
+                path: relative_path.to_path_buf(),
+            };
+            request(copier, &change, None).await
+        }
+        None => {
+            containment::check(path)?;
+            Ok(fs::create_dir_all(path).await?)
+        }
+    }
+}
+
+/// Sends `change` to the copier, followed by the rest of `contents` for a write, and waits for it
+/// to be made
+#[cfg(all(unix, feature = "control-socket"))]
+async fn request(copier: &Copier, change: &Change, contents: Option<Contents<'_>>) -> Result<()> {
+    use anyhow::Context;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    let stream = tokio::net::UnixStream::connect(&copier.socket_path)
+        .await
+        .with_context(|| {
+            anyhow!(
+                "Error connecting to the copier at {}",
+                copier.socket_path.display()
+            )
+        })?;
+    let (reader, mut writer) = stream.into_split();
+
+    let mut line = serde_json::to_vec(change)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    let mut unchanged = Ok(());
+    if let (Some(contents), Change::Write { len, .. }) = (contents, change) {
+        let sent = tokio::io::copy(&mut contents.file.take(*len), &mut writer).await?;
+        // Hanging up short tells the copier to throw away what it got
+        if sent != *len {
+            return Err(anyhow!(
+                "The file shrank while it was being sent to the copier"
+            ));
+        }
+        unchanged = races::ensure_unchanged(contents.source, contents.before);
+        writer.write_all(&[unchanged.is_ok() as u8]).await?;
+    }
+
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| anyhow!("The copier closed the connection without responding"))?;
+    let response: crate::control::Response = serde_json::from_str(&line)?;
+    // The copier threw the copy away, which is only an error because the file changed
+    unchanged?;
+    match response.ok {
+        true => Ok(()),
+        false => Err(anyhow!(response.error.unwrap_or_default())),
+    }
+}
+
+#[cfg(not(all(unix, feature = "control-socket")))]
+async fn request(
+    _copier: &Copier,
+    _change: &Change,
+    _contents: Option<Contents<'_>>,
+) -> Result<()> {
+    Err(unsupported())
+}
+
+/// `relative_path` inside `backup_dir`, as long as it stays inside
+fn resolve(backup_dir: &Path, relative_path: &Path) -> Result<PathBuf> {
+    let inside = relative_path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    match inside && relative_path.components().next().is_some() {
+        true => {
+            let path = backup_dir.join(relative_path);
+            containment::check(&path)?;
+            Ok(path)
+        }
+        false => Err(anyhow!(
+            "{} isn't a path inside backup_dir",
+            relative_path.display()
+        )),
+    }
+}
+
+/// Makes the changes sync processes started with `--copier` send to `socket_path`, writing only
+/// inside `backup_dir`. Runs until the process is stopped
+#[cfg(all(unix, feature = "control-socket"))]
+pub async fn serve(backup_dir: PathBuf, socket_path: PathBuf) -> Result<()> {
+    use crate::output::{self, info, Event};
+
+    // A socket left behind by a previous run would make binding fail
+    match fs::remove_file(&socket_path).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+        _ => (),
+    }
+    containment::set(&[&backup_dir])?;
+    let listener = tokio::net::UnixListener::bind(&socket_path)?;
+    info!(
+        "Copying into {} for the sync connecting to {}",
+        backup_dir.display(),
+        socket_path.display()
+    );
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let backup_dir = backup_dir.clone();
+
+        tokio::task::spawn(async move {
+            if let Err(err) = serve_connection(stream, &backup_dir).await {
+                output::emit(&Event::Error {
+                    path: None,
+                    message: format!("Error talking to the sync process: {err:#}"),
+                });
+            }
+        });
+    }
+}
+
+#[cfg(not(all(unix, feature = "control-socket")))]
+pub async fn serve(_backup_dir: PathBuf, _socket_path: PathBuf) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(all(unix, feature = "control-socket"))]
+async fn serve_connection(stream: tokio::net::UnixStream, backup_dir: &Path) -> Result<()> {
+    use crate::control::Response;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+        // The contents of a write may follow a request that can't be parsed, so nothing after it
+        // can be trusted to be a request
+        let change: Change =
+            serde_json::from_str(&line).map_err(|err| anyhow!("Invalid request: {err}"))?;
+
+        let response = match apply(change, backup_dir, &mut reader).await {
+            Ok(()) => Response::ok(serde_json::Value::Null),
+            Err(err) => Response::error(err),
+        };
+        let mut response = serde_json::to_vec(&response).expect("responses serialize");
+        response.push(b'\n');
+        writer.write_all(&response).await?;
+    }
+}
+
+#[cfg(all(unix, feature = "control-socket"))]
+async fn apply<R>(change: Change, backup_dir: &Path, reader: &mut R) -> Result<()>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+
+    match change {
+        Change::Write {
+            path,
+            len,
+            mode,
+            owner,
+            modified,
+        } => {
+            let mut contents = reader.take(len);
+            let received = match resolve(backup_dir, &path) {
+                Ok(path) => receive(&path, len, &mut contents, mode, owner, modified)
+                    .await
+                    .map(|temp_path| (path, temp_path)),
+                Err(err) => Err(err),
+            };
+            // Skips over whatever is left of the contents, to get to whether to keep them
+            tokio::io::copy(&mut contents, &mut tokio::io::sink()).await?;
+            let keep = matches!(contents.into_inner().read_u8().await, Ok(1));
+
+            let (path, temp_path) = received?;
+            let result = match keep {
+                true => fs::rename(&temp_path, &path).await.map_err(Into::into),
+                false => Err(anyhow!(
+                    "The file changed while it was being sent, so the copy was thrown away"
+                )),
+            };
+            if result.is_err() {
+                let _ = fs::remove_file(&temp_path).await;
+            }
+            result
+        }
+        Change::Remove { path, dir } => {
+            let path = resolve(backup_dir, &path)?;
+            let result = match dir {
+                true => fs::remove_dir_all(&path).await,
+                false => fs::remove_file(&path).await,
+            };
+            match result {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            }
+        }
+        Change::Rename { from, to } => {
+            let (from, to) = (resolve(backup_dir, &from)?, resolve(backup_dir, &to)?);
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            Ok(fs::rename(from, to).await?)
+        }
+        Change::CreateDir { path } => Ok(fs::create_dir_all(resolve(backup_dir, &path)?).await?),
+    }
+}
+
+/// Writes the `len` bytes from `contents` to a temporary file next to `path` and returns it, for
+/// `path` to only ever be replaced by a complete copy
+#[cfg(all(unix, feature = "control-socket"))]
+async fn receive<R>(
+    path: &Path,
+    len: u64,
+    contents: &mut R,
+    mode: Option<u32>,
+    owner: Option<(u32, u32)>,
+    modified: Option<std::time::SystemTime>,
+) -> Result<PathBuf>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncWriteExt;
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".evil_mount-copier");
+    let temp_path = PathBuf::from(temp_path);
+
+    let file = async {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::File::create(&temp_path).await
+    }
+    .await;
+    let mut file = file?;
+
+    let result = async {
+        let received = tokio::io::copy(contents, &mut file).await?;
+        if received != len {
+            return Err(anyhow!(
+                "The sync process hung up after {received} of {len} bytes"
+            ));
+        }
+        file.flush().await?;
+        if let Some(modified) = modified {
+            platform::set_modified(&temp_path, modified)?;
+        }
+        if let Some(mode) = mode {
+            match platform::set_mode(&temp_path, mode) {
+                Err(err) if err.kind() != std::io::ErrorKind::PermissionDenied => {
+                    return Err(err.into())
+                }
+                _ => (),
+            }
+        }
+        if let Some(owner) = owner {
+            crate::ownership::set_owner(&temp_path, owner)?;
+        }
+        Ok(())
+    }
+    .await;
+
+    match result {
+        Ok(()) => Ok(temp_path),
+        Err(err) => {
+            let _ = fs::remove_file(&temp_path).await;
+            Err(err)
+        }
+    }
+}
@@ -0,0 +1,378 @@
+//! Copying a file into place, by cloning it where --reflink allows and by streaming its contents
+//! otherwise, through the kernel with sendfile on Linux. With `--verify-writes` every copy is read
+//! back and compared with its source before it counts as done. The buffer size and readahead can
+//! be tuned for slow targets like USB and network drives
+//!
+//! The sync writes each copy to a [`temp_path`] next to where it goes and renames it into place
+//! once it's complete, keeping the modify time of its source
+//!
+//! `evil_mount copy` makes a single copy the same way with [`copy_verified`], for scripts that
+//! want the copy to be checked and put in place atomically without running a sync
+
+use anyhow::{anyhow, Context};
+use blake3::{Hash, Hasher};
+use serde::Serialize;
+use std::{
+    fs::{File, Metadata},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use crate::{
+    bandwidth, containment, encoded_path, exit, hashing, open_files,
+    output::info,
+    ownership, platform, races,
+    reflink::{self, Reflink},
+    units,
+};
+
+/// Set by `--verify-writes`
+pub static VERIFY_WRITES: AtomicBool = AtomicBool::new(false);
+
+/// The chunk size used when neither `--copy-buffer-size` nor a calibration picked one
+pub const DEFAULT_BUFFER_SIZE: usize = 256 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Tuning {
+    /// How much of a file is read and written at once
+    pub buffer_size: usize,
+    /// How far ahead of what's being copied the kernel is asked to read, or 0 to leave that to it
+    pub readahead: u64,
+}
+
+static TUNING: OnceLock<Tuning> = OnceLock::new();
+
+/// Sets how files are streamed for the rest of the process
+pub fn set_tuning(tuning: Tuning) {
+    TUNING
+        .set(tuning)
+        .expect("the copy tuning can only be set once");
+}
+
+fn tuning() -> Tuning {
+    TUNING.get().copied().unwrap_or(Tuning {
+        buffer_size: DEFAULT_BUFFER_SIZE,
+        readahead: 0,
+    })
+}
+
+/// Parses `--copy-buffer-size`, which takes the same sizes as the limits but can't be 0
+pub fn parse_buffer_size(size: &str) -> Result<usize, String> {
+    match units::parse_size(size)? {
+        0 => Err("the copy buffer size must be more than 0".to_string()),
+        size => usize::try_from(size).map_err(|_| format!("{size} is too large a buffer")),
+    }
+}
+
+/// Where a copy to `to` is written before it's renamed over it, next to it so that the rename
+/// doesn't cross filesystems
+pub fn temp_path(to: &Path) -> anyhow::Result<PathBuf> {
+    let name = to
+        .file_name()
+        .ok_or_else(|| anyhow!("{} has no file name", to.display()))?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(TEMP_SUFFIX);
+
+    Ok(to.with_file_name(temp_name))
+}
+
+/// Ends the name of every [`temp_path`]
+const TEMP_SUFFIX: &str = ".evil_mount.tmp";
+
+/// Whether `name` is that of a copy still being written, which nothing but the copy should touch
+pub fn is_temp(name: &std::ffi::OsStr) -> bool {
+    name.as_encoded_bytes().ends_with(TEMP_SUFFIX.as_bytes())
+}
+
+/// Copies `from` to `to`, which must not exist, along with its permissions and modify time
+pub async fn copy(from: &Path, to: &Path) -> io::Result<()> {
+    let (from, to) = (from.to_path_buf(), to.to_path_buf());
+
+    tokio::task::spawn_blocking(move || {
+        let _held = open_files::take(2);
+        let result = copy_blocking(&from, &to, VERIFY_WRITES.load(Ordering::Relaxed));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&to);
+        }
+        result.map(|_| ())
+    })
+    .await?
+}
+
+/// A copy made by [`copy_verified`]
+#[derive(Debug, Serialize)]
+pub struct Verified {
+    #[serde(serialize_with = "encoded_path::lossy")]
+    pub source: PathBuf,
+    #[serde(serialize_with = "encoded_path::lossy")]
+    pub destination: PathBuf,
+    pub bytes: u64,
+    #[serde(serialize_with = "hex")]
+    pub hash: Hash,
+    /// How many tries it took
+    pub attempts: u32,
+}
+
+fn hex<S: serde::Serializer>(hash: &Hash, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hash.to_hex())
+}
+
+/// Copies the single file `from` to `to` the way the sync does with `--verify-writes`, on its own:
+/// into a temporary file next to `to` that's renamed over it once its contents were read back and
+/// match, so `to` is never left half written. The copy gets the permissions, modification time
+/// and, for root, the owner of `from`. A failed try, like one that raced with a write to `from`,
+/// is tried again up to `retries` more times, waiting a little longer each time. When `to` is a
+/// directory the copy goes inside it
+pub async fn copy_verified(from: &Path, to: &Path, retries: u32) -> anyhow::Result<Verified> {
+    // Trying again wouldn't make a file appear
+    let metadata = std::fs::metadata(from)
+        .with_context(|| anyhow!("Error reading {}", from.display()))
+        .map_err(exit::unreachable)?;
+    if !metadata.is_file() {
+        return Err(anyhow!("{} isn't a file", from.display()));
+    }
+    let to = match std::fs::metadata(to).is_ok_and(|metadata| metadata.is_dir()) {
+        true => to.join(
+            from.file_name()
+                .ok_or_else(|| anyhow!("{} has no file name", from.display()))?,
+        ),
+        false => to.to_path_buf(),
+    };
+
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let (source, destination) = (from.to_path_buf(), to.clone());
+        let result = tokio::task::spawn_blocking(move || {
+            let _held = open_files::take(2);
+            copy_verified_blocking(&source, &destination)
+        })
+        .await?;
+
+        match result {
+            Ok((bytes, hash)) => {
+                return Ok(Verified {
+                    source: from.to_path_buf(),
+                    destination: to,
+                    bytes,
+                    hash,
+                    attempts,
+                })
+            }
+            Err(err) if attempts > retries => return Err(err),
+            Err(err) => {
+                info!("Copying {} failed, trying again: {err:#}", from.display());
+                tokio::time::sleep(RETRY_WAIT * attempts).await;
+            }
+        }
+    }
+}
+
+/// How long [`copy_verified`] waits after the first failed try, and then that much more after
+/// every other one
+const RETRY_WAIT: Duration = Duration::from_secs(1);
+
+fn copy_verified_blocking(from: &Path, to: &Path) -> anyhow::Result<(u64, Hash)> {
+    let before = std::fs::metadata(from)?;
+    let temp_path = temp_path(to)?;
+
+    // Left behind by a try that was killed
+    if let Err(err) = std::fs::remove_file(&temp_path) {
+        if err.kind() != io::ErrorKind::NotFound {
+            return Err(err.into());
+        }
+    }
+    let result = copy_into_place(from, to, &temp_path, &before);
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+
+    result.map(|hash| (before.len(), hash))
+}
+
+/// Copies `from` to `temp_path` and renames it to `to` once it checks out
+fn copy_into_place(
+    from: &Path,
+    to: &Path,
+    temp_path: &Path,
+    before: &Metadata,
+) -> anyhow::Result<Hash> {
+    let hash = copy_blocking(from, temp_path, true)
+        .with_context(|| anyhow!("Error copying from {} to {}", from.display(), to.display()))?
+        .expect("verified copies are hashed");
+    races::ensure_unchanged(from, before)?;
+
+    File::options().write(true).open(temp_path)?.sync_all()?;
+    ownership::copy_owner(from, temp_path)?;
+    std::fs::rename(temp_path, to)
+        .with_context(|| anyhow!("Error moving the copy into place at {}", to.display()))?;
+    hashing::record(to, hash);
+
+    Ok(hash)
+}
+
+/// Copies `from` to `to`, returning the hash of what was written when it was checked against the
+/// source
+fn copy_blocking(from: &Path, to: &Path, verify: bool) -> io::Result<Option<Hash>> {
+    // clonefile on macOS takes paths rather than files opened beneath their directories
+    containment::check(from)?;
+    containment::check(to)?;
+    let metadata = std::fs::metadata(from)?;
+    // The hash of the source, when it was computed along the way
+    let hash = match reflink::mode() {
+        Reflink::Auto => match platform::clone_file(from, to) {
+            Ok(()) => None,
+            Err(err) if platform::is_unsupported(&err) => {
+                let _ = std::fs::remove_file(to);
+                copy_contents(from, to, verify)?
+            }
+            Err(err) => return Err(err),
+        },
+        Reflink::Always => {
+            platform::clone_file(from, to)?;
+            None
+        }
+        Reflink::Never => copy_contents(from, to, verify)?,
+    };
+
+    let hash = match verify {
+        true => Some(verify_written(from, to, hash)?),
+        false => hash,
+    };
+    copy_modified(&metadata, to)?;
+    // What was written is known, so deduplicating or signing the copy doesn't read it again
+    if let Some(hash) = hash {
+        hashing::record(to, hash);
+    }
+
+    Ok(hash.filter(|_| verify))
+}
+
+/// Checks that what made it to the disk at `to` is what was copied from `from`, returning its hash.
+/// `hash` is the hash of the source, if it was computed along the way
+fn verify_written(from: &Path, to: &Path, hash: Option<Hash>) -> io::Result<Hash> {
+    let source_hash = match hash {
+        Some(hash) => hash,
+        None => crate::hash_file(from).map_err(io::Error::other)?,
+    };
+    match hash_written(to)? == source_hash {
+        true => Ok(source_hash),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} doesn't match {} after copying it",
+                to.display(),
+                from.display()
+            ),
+        )),
+    }
+}
+
+/// Copies the contents of `from` to `to` without cloning, returning their hash if it was computed
+/// along the way. Copies that are going to be verified are streamed through a hasher so that the
+/// source isn't read twice, and the rest are sent without passing through userspace where possible
+fn copy_contents(from: &Path, to: &Path, verify: bool) -> io::Result<Option<Hash>> {
+    if !verify && send(from, to)? {
+        return Ok(None);
+    }
+
+    Ok(Some(stream(from, to)?))
+}
+
+/// Copies the contents of `from` to `to` with sendfile, returning false without copying anything
+/// if the platform or the filesystems don't support it
+fn send(from: &Path, to: &Path) -> io::Result<bool> {
+    let tuning = tuning();
+    let source = containment::open(from)?;
+    let destination = containment::create(to)?;
+
+    let mut position = 0;
+    let mut advised = 0;
+    loop {
+        if tuning.readahead > 0 && position + tuning.readahead / 2 >= advised {
+            platform::will_need(&source, advised, tuning.readahead);
+            advised += tuning.readahead;
+        }
+
+        match platform::send_file(&destination, &source, tuning.buffer_size) {
+            Ok(0) => break,
+            Ok(sent) => {
+                position += sent as u64;
+                bandwidth::throttle(sent as u64);
+            }
+            Err(err) if position == 0 && platform::is_unsupported(&err) => return Ok(false),
+            Err(err) => return Err(err),
+        }
+    }
+
+    copy_permissions(&source.metadata()?, to)?;
+    Ok(true)
+}
+
+/// Copies the contents of `from` to `to`, hashing them on the way. [`std::fs::copy`] isn't used,
+/// since it's free to clone the file on its own
+fn stream(from: &Path, to: &Path) -> io::Result<Hash> {
+    let tuning = tuning();
+    let mut source = containment::open(from)?;
+    let mut destination = containment::create(to)?;
+    let mut hasher = Hasher::new();
+
+    let mut buffer = vec![0; tuning.buffer_size];
+    let mut position = 0;
+    // How far the kernel was asked to read ahead so far
+    let mut advised = 0;
+    loop {
+        // Asking again halfway through keeps the kernel a full window ahead
+        if tuning.readahead > 0 && position + tuning.readahead / 2 >= advised {
+            platform::will_need(&source, advised, tuning.readahead);
+            advised += tuning.readahead;
+        }
+
+        match source.read(&mut buffer)? {
+            0 => break,
+            read => {
+                hasher.update(&buffer[..read]);
+                destination.write_all(&buffer[..read])?;
+                position += read as u64;
+                bandwidth::throttle(read as u64);
+            }
+        }
+    }
+
+    copy_permissions(&source.metadata()?, to)?;
+    Ok(hasher.finalize())
+}
+
+/// Gives `to` the permissions in `metadata`. Filesystems without permissions of their own, like
+/// Android's shared storage or FAT disks, refuse to change them, which isn't worth failing a copy
+/// over
+pub fn copy_permissions(metadata: &Metadata, to: &Path) -> io::Result<()> {
+    match std::fs::set_permissions(to, metadata.permissions()) {
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => Ok(()),
+        result => result,
+    }
+}
+
+/// Gives `to` the modify time in `metadata`, so that the copy doesn't look newer than what it's a
+/// copy of. Filesystems that refuse, like some of Android's shared storage, get to keep their own
+fn copy_modified(metadata: &Metadata, to: &Path) -> io::Result<()> {
+    match platform::set_modified(to, metadata.modified()?) {
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => Ok(()),
+        result => result,
+    }
+}
+
+/// Hashes what actually made it to the disk at `path`, rather than what's still in the page cache
+fn hash_written(path: &Path) -> io::Result<Hash> {
+    let file = File::open(path)?;
+    file.sync_all()?;
+    platform::drop_cached(&file);
+
+    hashing::hash_reader(&mut &file)
+}
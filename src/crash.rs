@@ -0,0 +1,218 @@
+//! Crash reports, so that a panic in the field can be diagnosed from more than the last lines of a
+//! log. The panic hook writes the backtrace, the latest events and how evil_mount was started to a
+//! file in the state dir, and points at it for bug reports. Only the latest reports are kept,
+//! since a task that keeps panicking is restarted and would otherwise fill up the disk
+//!
+//! `--filter` commands are left out of the report, since those are free form shell commands that
+//! can hold credentials
+
+use std::{
+    backtrace::Backtrace,
+    collections::VecDeque,
+    ffi::OsString,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{output::Event, platform, state_dir, supervisor::panic_message};
+
+/// How many of the latest events go into a report
+const RECENT_EVENTS: usize = 50;
+/// How many reports are kept
+const MAX_REPORTS: usize = 20;
+
+/// The latest events as JSON, oldest first, along with when they happened in seconds since the
+/// epoch
+static EVENTS: Mutex<VecDeque<(u64, String)>> = Mutex::new(VecDeque::new());
+/// How many reports this process wrote, so that two in the same second get different names
+static REPORTS: AtomicUsize = AtomicUsize::new(0);
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+fn crashes_dir(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("crashes")
+}
+
+/// Keeps the latest events for the next report. Called for every emitted event
+pub fn observe(event: &Event) {
+    let Ok(event) = serde_json::to_string(event) else {
+        return;
+    };
+
+    let mut events = EVENTS.lock().unwrap();
+    if events.len() == RECENT_EVENTS {
+        events.pop_front();
+    }
+    events.push_back((now_secs(), event));
+}
+
+/// Writes a crash report to the state dir of `backup_dir` whenever anything panics, on top of
+/// the usual panic message
+pub fn install(backup_dir: &Path) {
+    let dir = crashes_dir(backup_dir);
+    let command_line = redacted_command_line(std::env::args_os());
+    let default_hook = std::panic::take_hook();
+
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+
+        let report = report(info, &command_line);
+        let path = dir.join(format!(
+            "crash-{}-{}-{}.txt",
+            now_secs(),
+            std::process::id(),
+            REPORTS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let saved = std::fs::create_dir_all(&dir).and_then(|_| std::fs::write(&path, report));
+        match saved {
+            Ok(()) => {
+                eprintln!(
+                    "Saved a crash report to {}. Please attach it when reporting this bug",
+                    path.display()
+                );
+                prune(&dir);
+            }
+            Err(err) => eprintln!("Error saving a crash report to {}: {err}", path.display()),
+        }
+    }));
+}
+
+fn report(info: &std::panic::PanicHookInfo, command_line: &str) -> String {
+    let mut report = String::new();
+    let thread = std::thread::current();
+    let _ = writeln!(
+        report,
+        "evil_mount {} panicked at {} on thread {}",
+        env!("CARGO_PKG_VERSION"),
+        now_secs(),
+        thread.name().unwrap_or("<unnamed>")
+    );
+    let _ = writeln!(
+        report,
+        "Message: {}",
+        panic_message(info.payload()).unwrap_or("<none>")
+    );
+    if let Some(location) = info.location() {
+        let _ = writeln!(report, "Location: {location}");
+    }
+    let _ = writeln!(report, "Command line: {command_line}");
+    let _ = writeln!(
+        report,
+        "Platform: {} {}, capabilities: {}",
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        serde_json::to_string(&platform::CAPABILITIES).unwrap_or_default()
+    );
+
+    let _ = writeln!(report, "\nBacktrace:\n{}", Backtrace::force_capture());
+
+    let _ = writeln!(report, "Latest events, oldest first:");
+    // The panic may have happened while the events were locked
+    match EVENTS.try_lock() {
+        Ok(events) => {
+            for (time, event) in events.iter() {
+                let _ = writeln!(report, "{time} {event}");
+            }
+        }
+        Err(_) => report += "<unavailable>\n",
+    }
+
+    report
+}
+
+/// The arguments evil_mount was started with, with the commands of `--filter` left out
+fn redacted_command_line(args: impl Iterator<Item = OsString>) -> String {
+    let mut redact_next = false;
+    args.map(|arg| {
+        let arg = arg.to_string_lossy().into_owned();
+        if std::mem::take(&mut redact_next) {
+            return redact_filter(&arg);
+        }
+        match arg.strip_prefix("--filter=") {
+            Some(filter) => format!("--filter={}", redact_filter(filter)),
+            None => {
+                redact_next = arg == "--filter";
+                arg
+            }
+        }
+    })
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+fn redact_filter(filter: &str) -> String {
+    match filter.split_once('=') {
+        Some((glob, _)) => format!("{glob}=<redacted>"),
+        None => "<redacted>".to_string(),
+    }
+}
+
+/// Removes all but the latest MAX_REPORTS reports
+fn prune(dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut reports: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let modified = entry.metadata().ok()?.modified().ok()?;
+            Some((modified, entry.path()))
+        })
+        .collect();
+    reports.sort();
+
+    let excess = reports.len().saturating_sub(MAX_REPORTS);
+    for (_, path) in reports.into_iter().take(excess) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command_line(args: &[&str]) -> String {
+        redacted_command_line(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn filter_commands_are_redacted() {
+        assert_eq!(
+            command_line(&[
+                "evil_mount",
+                "--filter",
+                "*.env=sed s/SECRET=.*/SECRET=/",
+                "--filter=*.jpg=exiftool -gps:all= -",
+                "--work-dir",
+                "w"
+            ]),
+            "evil_mount --filter *.env=<redacted> --filter=*.jpg=<redacted> --work-dir w"
+        );
+    }
+
+    #[test]
+    fn filter_without_a_glob_is_redacted() {
+        assert_eq!(
+            command_line(&["evil_mount", "--filter", "oops"]),
+            "evil_mount --filter <redacted>"
+        );
+    }
+
+    #[test]
+    fn other_arguments_are_kept() {
+        assert_eq!(
+            command_line(&["evil_mount", "--exclude", "*.tmp", "sync", "--once"]),
+            "evil_mount --exclude *.tmp sync --once"
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! Storing identical files once with `--dedup`. Every copy in backup_dir is a hard link to a blob
+//! in the state dir named after its blake3 hash, so a file that exists many times in work_dir takes
+//! up space once, and the links in backup_dir double as the manifest that maps paths to contents.
+//! Verification, restores, snapshots and moves keep working on backup_dir as they always have
+//!
+//! Hard links share permissions and owners as well as contents, so those are part of a blob's name
+//! too. A blob that nothing in backup_dir links to anymore is removed by the next collection
+//!
+//! Copies are never modified in place, they're replaced, which is what keeps a change to one file
+//! from showing up in every other file with the same contents
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use crate::{hash_file, output::info, platform, state_dir};
+
+/// How long apart blobs nothing links to are looked for
+const COLLECT_EVERY: Duration = Duration::from_secs(60 * 60);
+
+static BACKUP_DIR: OnceLock<PathBuf> = OnceLock::new();
+static COLLECTED_AT: Mutex<Option<Instant>> = Mutex::new(None);
+
+fn blobs_dir(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("blobs")
+}
+
+/// Whether `--dedup` is on
+pub fn is_enabled() -> bool {
+    BACKUP_DIR.get().is_some()
+}
+
+/// Stores the copies in `backup_dir` as links to blobs, after checking that its filesystem supports
+/// hard links
+pub fn set(backup_dir: &Path) -> Result<()> {
+    if !platform::CAPABILITIES.unix_metadata {
+        return Err(anyhow!(
+            "--dedup needs hard link counts, which aren't available on this platform"
+        ));
+    }
+
+    let dir = blobs_dir(backup_dir);
+    std::fs::create_dir_all(&dir).with_context(|| anyhow!("Error creating {}", dir.display()))?;
+    let probe = dir.join(".probe");
+    let link = dir.join(".probe-link");
+    let _ = std::fs::remove_file(&link);
+    std::fs::write(&probe, b"")?;
+    let linked = std::fs::hard_link(&probe, &link);
+    let _ = std::fs::remove_file(&probe);
+    let _ = std::fs::remove_file(&link);
+    linked.with_context(|| {
+        anyhow!(
+            "--dedup needs a filesystem with hard links, which {} doesn't seem to support",
+            backup_dir.display()
+        )
+    })?;
+
+    BACKUP_DIR
+        .set(backup_dir.to_path_buf())
+        .map_err(|_| anyhow!("dedup can only be set once"))
+}
+
+/// Replaces the fresh copy at `dst_path` with a link to the blob of its contents, or makes it the
+/// blob if there's none yet. Does nothing without `--dedup` or for files outside backup_dir, like
+/// the ones restored
+pub async fn store(dst_path: &Path) -> Result<()> {
+    let Some(backup_dir) = BACKUP_DIR.get() else {
+        return Ok(());
+    };
+    if !dst_path.starts_with(backup_dir) {
+        return Ok(());
+    }
+
+    let dst_path = dst_path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        store_blocking(backup_dir, &dst_path)
+            .with_context(|| anyhow!("Error deduplicating {}", dst_path.display()))
+    })
+    .await?
+}
+
+fn store_blocking(backup_dir: &Path, dst_path: &Path) -> Result<()> {
+    let metadata = std::fs::symlink_metadata(dst_path)?;
+    if !metadata.is_file() {
+        return Ok(());
+    }
+
+    let name = blob_name(&hash_file(dst_path)?, &metadata);
+    // Spread over subdirectories so that none of them grows huge
+    let blob = blobs_dir(backup_dir).join(&name[..2]).join(&name);
+    std::fs::create_dir_all(blob.parent().unwrap())?;
+
+    match std::fs::hard_link(dst_path, &blob) {
+        Ok(()) => return Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => (),
+        Err(err) => return Err(err.into()),
+    }
+
+    // Links to a temporary name first, so that dst_path is never missing
+    let mut temp_path = dst_path.as_os_str().to_owned();
+    temp_path.push(".evil_mount-dedup");
+    let temp_path = PathBuf::from(temp_path);
+    let _ = std::fs::remove_file(&temp_path);
+    std::fs::hard_link(&blob, &temp_path)?;
+    if let Err(err) = std::fs::rename(&temp_path, dst_path) {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(err.into());
+    }
+    // The blob may be older than the file in work_dir, which would make it look outdated
+    platform::touch(dst_path)?;
+
+    Ok(())
+}
+
+/// The name of the blob for contents with `hash`, and the permissions and owner in `metadata`
+fn blob_name(hash: &blake3::Hash, metadata: &std::fs::Metadata) -> String {
+    let (mode, _) = platform::mode(metadata).unwrap_or_default();
+    let (uid, gid) = platform::owner(metadata).unwrap_or_default();
+
+    format!("{}-{:o}-{uid}-{gid}", hash.to_hex(), mode & 0o7777)
+}
+
+/// Removes the blobs nothing in backup_dir links to anymore, at most once every COLLECT_EVERY.
+/// Does nothing without `--dedup`
+pub async fn collect_garbage() {
+    let Some(backup_dir) = BACKUP_DIR.get() else {
+        return;
+    };
+    {
+        let mut collected_at = COLLECTED_AT.lock().unwrap();
+        if collected_at.is_some_and(|at| at.elapsed() < COLLECT_EVERY) {
+            return;
+        }
+        *collected_at = Some(Instant::now());
+    }
+
+    let removed = tokio::task::spawn_blocking(|| collect_blocking(&blobs_dir(backup_dir))).await;
+    if let Ok(removed @ 1..) = removed {
+        info!("Removed {removed} blobs that no file in backup_dir uses anymore");
+    }
+}
+
+fn collect_blocking(dir: &Path) -> usize {
+    let Ok(subdirs) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+
+    let mut removed = 0;
+    for subdir in subdirs.filter_map(|entry| entry.ok()) {
+        let Ok(blobs) = std::fs::read_dir(subdir.path()) else {
+            continue;
+        };
+        for blob in blobs.filter_map(|entry| entry.ok()) {
+            let unused = blob.metadata().is_ok_and(|metadata| {
+                metadata.is_file() && platform::link_count(&metadata) == Some(1)
+            });
+            if unused && std::fs::remove_file(blob.path()).is_ok() {
+                removed += 1;
+            }
+        }
+    }
+
+    removed
+}
@@ -0,0 +1,345 @@
+//! Deciding when a file that disappeared from work_dir is removed from the backup. Deleting right
+//! away turns a transient move into a delete followed by a full recopy, so a file can instead be
+//! required to stay missing for a grace period first
+//!
+//! With `--max-delete`, a pass that would delete more than that from the backup deletes nothing
+//! and raises an error instead, until it's allowed through once with `ctl allow-deletes` or for as
+//! long as a sentinel file exists at the root of work_dir
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    encoded_path,
+    output::{self, info, Event},
+    state_dir, trace, units,
+};
+
+/// How long `after-grace` waits when no duration is given
+const DEFAULT_GRACE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DeletePolicy {
+    /// Delete files as soon as they're found missing
+    #[default]
+    Immediate,
+    /// Delete files once they've been missing for at least this long
+    AfterGrace(Duration),
+    /// Never delete anything from the backup
+    Never,
+}
+
+impl FromStr for DeletePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None => match s {
+                "immediate" => Ok(Self::Immediate),
+                "never" => Ok(Self::Never),
+                "after-grace" => Ok(Self::AfterGrace(DEFAULT_GRACE)),
+                _ => Err(anyhow!(
+                    "expected immediate, never or after-grace[:DURATION], got {s}"
+                )),
+            },
+            Some(("after-grace", grace)) => units::parse_duration(grace)
+                .map(Self::AfterGrace)
+                .map_err(|err| anyhow!("Invalid grace period {grace}: {err}")),
+            Some(_) => Err(anyhow!("only after-grace takes a duration, got {s}")),
+        }
+    }
+}
+
+impl fmt::Display for DeletePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Immediate => write!(f, "immediate"),
+            Self::AfterGrace(grace) => write!(f, "after-grace:{}", grace.as_secs()),
+            Self::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// When each path waiting out the grace period was first found missing, in seconds since the epoch
+static MISSING_SINCE: Mutex<BTreeMap<PathBuf, u64>> = Mutex::new(BTreeMap::new());
+
+impl DeletePolicy {
+    /// Whether `path`, whose counterpart in work_dir no longer exists, should be deleted now
+    pub fn should_delete(self, path: &Path) -> bool {
+        let missing_for = match self {
+            Self::AfterGrace(_) => {
+                let now = now_secs();
+                let missing_since = *MISSING_SINCE
+                    .lock()
+                    .unwrap()
+                    .entry(path.to_path_buf())
+                    .or_insert(now);
+                now.saturating_sub(missing_since)
+            }
+            Self::Immediate | Self::Never => 0,
+        };
+        let delete = self.decide(missing_for);
+        trace::record(|| trace::Record::Missing {
+            path: path.to_path_buf(),
+            policy: self.to_string(),
+            missing_for,
+            delete,
+        });
+
+        delete
+    }
+
+    /// Whether a copy whose counterpart has been missing from work_dir for `missing_for` seconds
+    /// should be deleted now
+    pub fn decide(self, missing_for: u64) -> bool {
+        match self {
+            Self::Immediate => true,
+            Self::Never => false,
+            Self::AfterGrace(grace) => missing_for >= grace.as_secs(),
+        }
+    }
+}
+
+/// How much a single pass may delete from the backup, from `--max-delete`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MaxDelete {
+    /// At most this many files
+    Files(u64),
+    /// At most this percentage of the files in the backup
+    Percent(f64),
+}
+
+impl FromStr for MaxDelete {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_suffix('%') {
+            Some(percent) => match percent.parse() {
+                Ok(percent @ 0.0..=100.0) => Ok(Self::Percent(percent)),
+                _ => Err(anyhow!("expected a percentage from 0% to 100%, got {s}")),
+            },
+            None => s
+                .parse()
+                .map(Self::Files)
+                .with_context(|| anyhow!("expected a number of files or a percentage, got {s}")),
+        }
+    }
+}
+
+impl MaxDelete {
+    /// Whether deleting `deletions` of the `files` in the backup is within the limit
+    pub fn within(self, deletions: u64, files: u64) -> bool {
+        match self {
+            Self::Files(max) => deletions <= max,
+            Self::Percent(percent) => deletions as f64 <= files as f64 * percent / 100.0,
+        }
+    }
+}
+
+impl fmt::Display for MaxDelete {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Files(files) => write!(f, "{files}"),
+            Self::Percent(percent) => write!(f, "{percent}%"),
+        }
+    }
+}
+
+/// While a file with this name exists at the root of work_dir, `--max-delete` doesn't apply. It's
+/// never synced itself
+pub const ALLOW_SENTINEL_NAME: &str = ".evil_mount_allow_deletes";
+
+/// Set by `ctl allow-deletes`, and used up by the next pass that deletes more than --max-delete
+/// allows
+pub static ALLOW_ONCE: AtomicBool = AtomicBool::new(false);
+/// How many deletions the last pass held back for going over --max-delete
+static HELD_BACK: AtomicU64 = AtomicU64::new(0);
+
+/// Whether a pass may go ahead and delete the `deletions` files missing from `work_dir`, out of
+/// the `files` in the backup. Holding them back raises an error once for each number held back
+pub fn allow(max_delete: Option<MaxDelete>, deletions: u64, files: u64, work_dir: &Path) -> bool {
+    let Some(max_delete) = max_delete else {
+        return true;
+    };
+    let within = max_delete.within(deletions, files);
+    trace::record(|| trace::Record::Deletions {
+        max_delete: max_delete.to_string(),
+        deletions,
+        files,
+        within,
+    });
+    if within {
+        if HELD_BACK.swap(0, Ordering::Relaxed) > 0 {
+            info!(
+                "Deletions are back within --max-delete {max_delete}, no longer holding them back"
+            );
+        }
+        return true;
+    }
+
+    let sentinel = work_dir.join(ALLOW_SENTINEL_NAME);
+    if sentinel.exists() || ALLOW_ONCE.swap(false, Ordering::Relaxed) {
+        info!("Deleting {deletions} files, more than --max-delete {max_delete} allows, since that was allowed");
+        HELD_BACK.store(0, Ordering::Relaxed);
+        return true;
+    }
+
+    if HELD_BACK.swap(deletions, Ordering::Relaxed) != deletions {
+        output::emit(&Event::Error {
+            path: None,
+            message: format!(
+                "Not deleting the {deletions} files missing from work_dir, more than --max-delete {max_delete} allows. If that's intended, run `evil_mount ctl allow-deletes` or create {}",
+                sentinel.display()
+            ),
+        });
+    }
+
+    false
+}
+
+/// How many deletions the last pass held back for going over --max-delete
+pub fn held_back() -> u64 {
+    HELD_BACK.load(Ordering::Relaxed)
+}
+
+/// How many files are waiting out their grace period
+pub fn pending() -> usize {
+    MISSING_SINCE.lock().unwrap().len()
+}
+
+/// Stops waiting to delete `path`, because it either exists in work_dir again or has been deleted
+pub fn forget(path: &Path) {
+    MISSING_SINCE.lock().unwrap().remove(path);
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+fn state_path(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("pending_deletions.json")
+}
+
+/// Loads the files that were already waiting out their grace period when the last run ended, so
+/// that runs from cron and restarts don't start every wait over
+pub fn load(backup_dir: &Path) -> Result<()> {
+    let path = state_path(backup_dir);
+
+    let contents = match std::fs::read(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let pending: BTreeMap<PathBuf, u64> = encoded_path::keys::from_json(&contents)
+        .with_context(|| anyhow!("Error parsing {}", path.display()))?;
+
+    MISSING_SINCE.lock().unwrap().extend(pending);
+
+    Ok(())
+}
+
+pub fn save(backup_dir: &Path) -> Result<()> {
+    let path = state_path(backup_dir);
+    let temp_path = path.with_extension("json.tmp");
+    let contents = encoded_path::keys::to_json(&*MISSING_SINCE.lock().unwrap())?;
+
+    std::fs::create_dir_all(state_dir(backup_dir))?;
+    std::fs::write(&temp_path, contents)?;
+    std::fs::rename(&temp_path, &path)
+        .with_context(|| anyhow!("Error saving {}", path.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delete_policies() {
+        assert_eq!(
+            "immediate".parse::<DeletePolicy>().unwrap(),
+            DeletePolicy::Immediate
+        );
+        assert_eq!(
+            "never".parse::<DeletePolicy>().unwrap(),
+            DeletePolicy::Never
+        );
+        assert_eq!(
+            "after-grace".parse::<DeletePolicy>().unwrap(),
+            DeletePolicy::AfterGrace(DEFAULT_GRACE)
+        );
+        assert_eq!(
+            "after-grace:1h".parse::<DeletePolicy>().unwrap(),
+            DeletePolicy::AfterGrace(Duration::from_secs(60 * 60))
+        );
+    }
+
+    #[test]
+    fn invalid_delete_policies() {
+        for policy in [
+            "sometimes",
+            "",
+            "after-grace:soon",
+            "never:1h",
+            "immediate:0s",
+        ] {
+            assert!(policy.parse::<DeletePolicy>().is_err(), "{policy}");
+        }
+    }
+
+    #[test]
+    fn delete_policies_parse_back() {
+        for policy in [
+            DeletePolicy::Immediate,
+            DeletePolicy::Never,
+            DeletePolicy::AfterGrace(Duration::from_secs(90)),
+        ] {
+            assert_eq!(policy.to_string().parse::<DeletePolicy>().unwrap(), policy);
+        }
+    }
+
+    #[test]
+    fn grace_period() {
+        let policy = DeletePolicy::AfterGrace(Duration::from_secs(60));
+        assert!(!policy.decide(59));
+        assert!(policy.decide(60));
+        assert!(DeletePolicy::Immediate.decide(0));
+        assert!(!DeletePolicy::Never.decide(u64::MAX));
+    }
+
+    #[test]
+    fn max_deletes() {
+        assert_eq!("10".parse::<MaxDelete>().unwrap(), MaxDelete::Files(10));
+        assert_eq!("5%".parse::<MaxDelete>().unwrap(), MaxDelete::Percent(5.0));
+        assert_eq!(
+            "0.5%".parse::<MaxDelete>().unwrap(),
+            MaxDelete::Percent(0.5)
+        );
+        for max_delete in ["101%", "-1%", "%", "-1", "ten", ""] {
+            assert!(max_delete.parse::<MaxDelete>().is_err(), "{max_delete}");
+        }
+    }
+
+    #[test]
+    fn within_max_delete() {
+        assert!(MaxDelete::Files(3).within(3, 10));
+        assert!(!MaxDelete::Files(3).within(4, 10));
+        assert!(MaxDelete::Percent(10.0).within(10, 100));
+        assert!(!MaxDelete::Percent(10.0).within(11, 100));
+        assert!(MaxDelete::Percent(0.0).within(0, 0));
+    }
+}
@@ -0,0 +1,179 @@
+//! The changes a sync pass makes to backup_dir, found first and then applied in one place. A pass
+//! walks work_dir and backup_dir to find what to create, update, rename and delete, and only then
+//! applies it, renames and copies first and deletions last
+//!
+//! Everything else that writes to backup_dir while the watch loop runs, like the sync task of each
+//! file and the journal recovery, copies through [`copy`] too, while the scheduled verification and
+//! `resync` ask the loop for a [full pass](crate::full_pass). Copies and renames
+//! go ahead side by side, while a deletion waits for every one of them to finish and holds off new
+//! ones until it's done, so it never looks at backup_dir while a copy is half written
+
+use anyhow::Result;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs,
+    sync::{RwLock, RwLockReadGuard},
+};
+
+use crate::{
+    convert_backup_path_to_work_path, convert_work_path_to_backup_path, copy_to_dst,
+    delete_if_removed, deletion::DeletePolicy, journal, moves, needs_copy, routes, SyncReport,
+};
+
+/// A difference between work_dir and backup_dir
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// A file in work_dir that has no copy yet
+    Create(PathBuf),
+    /// A file in work_dir whose copy is out of date
+    Update(PathBuf),
+    /// A file in work_dir, `to`, that was moved from where the copy `from` in backup_dir belongs
+    Rename { from: PathBuf, to: PathBuf },
+    /// A copy in backup_dir whose file is gone from work_dir
+    Delete(PathBuf),
+}
+
+impl Change {
+    /// The path the change is for, in work_dir for everything but deletions
+    pub fn path(&self) -> &Path {
+        match self {
+            Change::Create(path) | Change::Update(path) | Change::Delete(path) => path,
+            Change::Rename { to, .. } => to,
+        }
+    }
+}
+
+/// Held shared by copies and renames, and exclusively by deletions
+static WRITING: RwLock<()> = RwLock::const_new(());
+
+/// Holds off deletions until the guard is dropped, for writes to backup_dir made without [`copy`]
+pub async fn hold() -> RwLockReadGuard<'static, ()> {
+    WRITING.read().await
+}
+
+/// What `path` in work_dir needs, if its copy isn't up to date
+pub async fn find(path: &Path, work_dir: &Path, backup_dir: &Path) -> Result<Option<Change>> {
+    if !needs_copy(path, work_dir, backup_dir).await? {
+        return Ok(None);
+    }
+
+    Ok(Some(differs(path, work_dir, backup_dir).await?))
+}
+
+/// What `path` in work_dir needs when its copy is known not to be up to date, whatever the modify
+/// times say
+pub async fn differs(path: &Path, work_dir: &Path, backup_dir: &Path) -> Result<Change> {
+    let backup_path = convert_work_path_to_backup_path(
+        path.to_path_buf(),
+        work_dir.to_path_buf(),
+        backup_dir.to_path_buf(),
+    )?;
+    Ok(match fs::try_exists(&backup_path).await? {
+        true => Change::Update(path.to_path_buf()),
+        false => Change::Create(path.to_path_buf()),
+    })
+}
+
+/// The copies in backup_dir that the renames among `changes` move away, which aren't deleted even
+/// though their files are gone
+pub fn renamed(changes: &[Change]) -> HashSet<PathBuf> {
+    changes
+        .iter()
+        .filter_map(|change| match change {
+            Change::Rename { from, .. } => Some(from.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Copies `path` from work_dir into backup_dir once no deletion is being made, returning where
+/// the copy went
+pub async fn copy(path: &Path, work_dir: &Path, backup_dir: &Path) -> Result<PathBuf> {
+    let _writing = WRITING.read().await;
+    let _in_flight = journal::begin(journal::Operation::Copy {
+        path: path.to_path_buf(),
+        from_dir: work_dir.to_path_buf(),
+        to_dir: backup_dir.to_path_buf(),
+    });
+    copy_to_dst(
+        path.to_path_buf(),
+        work_dir.to_path_buf(),
+        backup_dir.to_path_buf(),
+    )
+    .await
+}
+
+/// Makes `change` to backup_dir and reports what it did. Everything is checked again first, since
+/// work_dir may have changed since the change was found
+pub async fn apply(
+    change: &Change,
+    work_dir: &Path,
+    backup_dir: &Path,
+    policy: DeletePolicy,
+    report: &mut SyncReport,
+) -> Result<()> {
+    match change {
+        Change::Create(path) | Change::Update(path) => {
+            let dst_path = copy(path, work_dir, backup_dir).await?;
+            report.record_copy(path, &dst_path);
+        }
+        Change::Rename { from, to } => {
+            rename(from, to, work_dir, backup_dir, report).await?;
+            // Anything that changed about it besides the path still needs copying
+            if needs_copy(to, work_dir, backup_dir).await? {
+                let dst_path = copy(to, work_dir, backup_dir).await?;
+                report.record_copy(to, &dst_path);
+            }
+        }
+        Change::Delete(path) => {
+            let _writing = WRITING.write().await;
+            let _in_flight = journal::begin(journal::Operation::Delete {
+                path: path.clone(),
+                work_dir: work_dir.to_path_buf(),
+                backup_dir: backup_dir.to_path_buf(),
+            });
+            if delete_if_removed(path, work_dir, backup_dir, policy).await? {
+                report.record_delete(path);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Renames the copy `from` to where `to` belongs, if the file it was the copy of is really gone and
+/// nothing is in the way
+async fn rename(
+    from: &Path,
+    to: &Path,
+    work_dir: &Path,
+    backup_dir: &Path,
+    report: &mut SyncReport,
+) -> Result<()> {
+    let old_work_path = convert_backup_path_to_work_path(
+        from.to_path_buf(),
+        work_dir.to_path_buf(),
+        backup_dir.to_path_buf(),
+    )?;
+    let backup_path = convert_work_path_to_backup_path(
+        to.to_path_buf(),
+        work_dir.to_path_buf(),
+        backup_dir.to_path_buf(),
+    )?;
+    // A copy routed elsewhere is copied rather than renamed, there may be no renaming across
+    let same_root = routes::root_of(backup_dir, from) == routes::root_of(backup_dir, &backup_path);
+    if same_root
+        && !fs::try_exists(&old_work_path).await?
+        && fs::try_exists(from).await?
+        && !fs::try_exists(&backup_path).await?
+    {
+        let _writing = WRITING.read().await;
+        moves::rename(from, &backup_path).await?;
+        report.record_move(from, &backup_path);
+    }
+
+    Ok(())
+}
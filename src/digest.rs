@@ -0,0 +1,334 @@
+//! A daily digest of what changed in backup_dir with `--digest`, for the people looking after it
+//! rather than a machine: how many files were added, modified, moved and deleted, and the largest
+//! new ones. A day with far more changes than usual, like every file being rewritten by
+//! ransomware or a log that never stops growing, stands out at a glance
+//!
+//! The counts are kept in `digest.json` in the state directory until the digest goes out, so they
+//! survive restarts and add up over runs of `sync --once` too. The digest is logged, mailed with
+//! sendmail, or POSTed to a webhook like the alerts are
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    alert,
+    output::{self, info, Event},
+    space, state_dir, stats,
+};
+
+/// How long a digest covers
+const DAY: u64 = 24 * 60 * 60;
+/// How often the counts are saved and the digest checked for being due
+const CHECK_EVERY: Duration = Duration::from_secs(60);
+/// How many of the largest new files a digest lists
+const LARGEST: usize = 5;
+
+/// Where `--digest` sends the digest
+#[derive(Clone, Debug)]
+pub enum Target {
+    /// Printed along with the rest of the output
+    Log,
+    /// Mailed to this address with sendmail
+    Mail(String),
+    /// POSTed to this URL, like the alerts of --alert-webhook
+    Webhook(String),
+}
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "log" {
+            return Ok(Self::Log);
+        }
+        if let Some(address) = s.strip_prefix("mailto:") {
+            return match address.contains('@') {
+                true => Ok(Self::Mail(address.to_string())),
+                false => Err(anyhow!("{address} isn't an email address")),
+            };
+        }
+        match s.starts_with("http://") || s.starts_with("https://") {
+            true => Ok(Self::Webhook(s.to_string())),
+            false => Err(anyhow!(
+                "expected log, mailto:ADDRESS or an http:// or https:// URL, got {s}"
+            )),
+        }
+    }
+}
+
+/// What changed since the last digest
+#[derive(Default, Serialize, Deserialize)]
+struct Counts {
+    /// When they started being counted, in seconds since the epoch
+    since: u64,
+    added: u64,
+    added_bytes: u64,
+    modified: u64,
+    modified_bytes: u64,
+    moved: u64,
+    deleted: u64,
+    /// The largest files added, relative to backup_dir, largest first
+    largest: Vec<(String, u64)>,
+}
+
+struct Digester {
+    target: Target,
+    backup_dir: PathBuf,
+    counts: Mutex<Counts>,
+}
+
+static DIGESTER: OnceLock<Digester> = OnceLock::new();
+/// Copies in flight that create their file in backup_dir, rather than replace it
+static NEW: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+/// Set when the counts changed since they were saved
+static UNSAVED: AtomicBool = AtomicBool::new(false);
+/// How many digests are being sent
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+fn counts_path(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("digest.json")
+}
+
+/// Sends a digest of the changes to `backup_dir` to `target` every day from now on, picking up
+/// the counts of earlier runs
+pub fn set(target: Target, backup_dir: &Path) -> Result<()> {
+    let path = counts_path(backup_dir);
+    let counts = match std::fs::read(&path) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .with_context(|| anyhow!("Error parsing {}", path.display()))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Counts {
+            since: now(),
+            ..Counts::default()
+        },
+        Err(err) => return Err(err.into()),
+    };
+
+    DIGESTER
+        .set(Digester {
+            target,
+            backup_dir: backup_dir.to_path_buf(),
+            counts: Mutex::new(counts),
+        })
+        .map_err(|_| anyhow!("the digest can only be set once"))?;
+    *NEW.lock().unwrap() = Some(HashSet::new());
+    std::thread::spawn(|| loop {
+        std::thread::sleep(CHECK_EVERY);
+        check();
+    });
+
+    Ok(())
+}
+
+/// Notes that the copy about to be made to `dst_path` creates it rather than replacing it
+pub fn copying_new(dst_path: &Path) {
+    if let Some(new) = NEW.lock().unwrap().as_mut() {
+        new.insert(dst_path.to_path_buf());
+    }
+}
+
+/// Counts the changes to backup_dir. Called for every emitted event
+pub fn observe(event: &Event) {
+    let Some(digester) = DIGESTER.get() else {
+        return;
+    };
+
+    let mut counts = digester.counts.lock().unwrap();
+    match event {
+        Event::FileCopied {
+            source,
+            destination,
+        } => {
+            let Ok(relative_path) = destination.strip_prefix(&digester.backup_dir) else {
+                return;
+            };
+            let len = std::fs::metadata(source).map_or(0, |metadata| metadata.len());
+            let new = NEW
+                .lock()
+                .unwrap()
+                .as_mut()
+                .is_some_and(|new| new.remove(*destination));
+            match new {
+                true => {
+                    counts.added += 1;
+                    counts.added_bytes += len;
+                    let largest = &mut counts.largest;
+                    largest.push((relative_path.to_string_lossy().into_owned(), len));
+                    largest.sort_by(|(_, a), (_, b)| b.cmp(a));
+                    largest.truncate(LARGEST);
+                }
+                false => {
+                    counts.modified += 1;
+                    counts.modified_bytes += len;
+                }
+            }
+        }
+        Event::FileMoved { .. } => counts.moved += 1,
+        Event::FileDeleted { path } if path.starts_with(&digester.backup_dir) => {
+            counts.deleted += 1
+        }
+        _ => return,
+    }
+    UNSAVED.store(true, Ordering::Relaxed);
+}
+
+/// Saves the counts if they changed, and sends the digest if it's due
+fn check() {
+    let Some(digester) = DIGESTER.get() else {
+        return;
+    };
+
+    let mut counts = digester.counts.lock().unwrap();
+    if now() >= counts.since + DAY {
+        send(digester, &counts);
+        *counts = Counts {
+            since: now(),
+            ..Counts::default()
+        };
+        UNSAVED.store(true, Ordering::Relaxed);
+    }
+
+    let result = match UNSAVED.swap(false, Ordering::Relaxed) {
+        true => save(&digester.backup_dir, &counts),
+        false => Ok(()),
+    };
+    // Emitting the error comes back to observe, which needs the counts
+    drop(counts);
+    if let Err(err) = result {
+        output::emit(&Event::Error {
+            path: None,
+            message: format!("Error saving the digest's counts: {err:#}"),
+        });
+    }
+}
+
+fn save(backup_dir: &Path, counts: &Counts) -> Result<()> {
+    let path = counts_path(backup_dir);
+    let temp_path = path.with_extension("json.tmp");
+    std::fs::create_dir_all(state_dir(backup_dir))?;
+    std::fs::write(&temp_path, serde_json::to_vec(counts)?)?;
+    std::fs::rename(&temp_path, &path).with_context(|| anyhow!("Error saving {}", path.display()))
+}
+
+/// The digest of `counts`, as a subject line and a body
+fn describe(backup_dir: &Path, counts: &Counts) -> (String, String) {
+    let subject = format!(
+        "evil_mount digest for {}: {} added, {} modified, {} moved, {} deleted",
+        backup_dir.display(),
+        counts.added,
+        counts.modified,
+        counts.moved,
+        counts.deleted
+    );
+
+    let mut body = format!(
+        "Changes to {} on {} since {}:\n\n",
+        backup_dir.display(),
+        alert::hostname(),
+        stats::date(counts.since / DAY)
+    );
+    body.push_str(&format!(
+        "{} files added ({})\n{} files modified ({})\n{} files moved\n{} files deleted\n",
+        counts.added,
+        space::size(counts.added_bytes),
+        counts.modified,
+        space::size(counts.modified_bytes),
+        counts.moved,
+        counts.deleted
+    ));
+    if !counts.largest.is_empty() {
+        body.push_str("\nLargest new files:\n");
+        for (path, len) in &counts.largest {
+            body.push_str(&format!("  {path} ({})\n", space::size(*len)));
+        }
+    }
+
+    (subject, body)
+}
+
+/// Sends the digest of `counts` in the background
+fn send(digester: &'static Digester, counts: &Counts) {
+    let (subject, body) = describe(&digester.backup_dir, counts);
+    let payload = serde_json::json!({
+        "text": format!("{subject}\n{body}"),
+        "host": alert::hostname(),
+        "backup_dir": digester.backup_dir.to_string_lossy(),
+        "since": counts.since,
+        "added": counts.added,
+        "added_bytes": counts.added_bytes,
+        "modified": counts.modified,
+        "modified_bytes": counts.modified_bytes,
+        "moved": counts.moved,
+        "deleted": counts.deleted,
+        "largest": counts.largest,
+    });
+
+    PENDING.fetch_add(1, Ordering::Relaxed);
+    std::thread::spawn(move || {
+        let result = match &digester.target {
+            Target::Log => {
+                info!("{subject}\n{}", body.trim_end());
+                Ok(())
+            }
+            Target::Mail(address) => mail(address, &subject, &body),
+            Target::Webhook(url) => alert::send(url, &payload),
+        };
+        if let Err(err) = result {
+            output::emit(&Event::Error {
+                path: None,
+                message: format!("Error sending the digest: {err:#}"),
+            });
+        }
+        PENDING.fetch_sub(1, Ordering::Relaxed);
+    });
+}
+
+fn mail(address: &str, subject: &str, body: &str) -> Result<()> {
+    let mut child = Command::new("sendmail")
+        .args(["-t", "-i"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Error running sendmail")?;
+    write!(
+        child.stdin.take().expect("stdin is piped"),
+        "To: {address}\nSubject: {subject}\nContent-Type: text/plain; charset=utf-8\n\n{body}"
+    )?;
+
+    let output = child.wait_with_output()?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(anyhow!(
+            "sendmail {}: {}",
+            crate::profiles::describe(output.status),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
+
+/// Saves the counts and sends the digest if it's due, waiting for it to be sent, before exiting
+pub async fn finish() {
+    check();
+    while PENDING.load(Ordering::Relaxed) > 0 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
@@ -0,0 +1,24 @@
+//! Directories as sync objects of their own, so that an empty directory made in work_dir shows up
+//! in backup_dir, and one removed from work_dir doesn't linger there. Files bring their parent
+//! directories along when they're copied, so this only matters for directories without any
+
+use anyhow::Result;
+use std::path::Path;
+use tokio::fs;
+
+use crate::copier;
+
+/// Makes the directory `path` if it doesn't exist yet, returning whether it had to
+pub async fn create(path: &Path) -> Result<bool> {
+    if fs::try_exists(path).await? {
+        return Ok(false);
+    }
+    copier::create_dir_all(path).await?;
+
+    Ok(true)
+}
+
+/// Whether the directory `path` has nothing in it, counting files that walks leave out
+pub async fn is_empty(path: &Path) -> Result<bool> {
+    Ok(fs::read_dir(path).await?.next_entry().await?.is_none())
+}
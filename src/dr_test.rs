@@ -0,0 +1,119 @@
+//! Disaster recovery rehearsals. `dr-test` restores the whole backup into a throwaway directory
+//! and verifies it, so that it's proven rather than assumed that the backup can be restored, and
+//! records how that went next to the backup
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{output::info, ownership, restore, state_dir, SyncOptions};
+
+/// How one rehearsal went
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Rehearsal {
+    /// When it started, in seconds since the epoch
+    pub time: u64,
+    /// The snapshot that was restored, or none for the current contents of backup_dir
+    pub snapshot: Option<String>,
+    pub restored: u64,
+    pub errors: u64,
+    pub verified: u64,
+    pub failed: u64,
+    pub duration_secs: f64,
+}
+
+impl Rehearsal {
+    pub fn passed(&self) -> bool {
+        self.errors == 0 && self.failed == 0
+    }
+}
+
+fn history_path(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("dr_tests.jsonl")
+}
+
+/// Restores everything in backup_dir, or in one of its snapshots, into `into` and verifies it.
+/// Without `into` a directory in the system's temp dir is used. It's removed afterwards unless
+/// `keep` is set
+pub async fn run(
+    backup_dir: &Path,
+    snapshot: Option<&str>,
+    into: Option<&Path>,
+    keep: bool,
+    options: &SyncOptions,
+) -> Result<Rehearsal> {
+    let target = match into {
+        Some(into) => into.to_path_buf(),
+        None => std::env::temp_dir().join(format!("evil_mount-dr-test-{}", std::process::id())),
+    };
+    let is_empty = match std::fs::read_dir(&target) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => true,
+        Err(err) => return Err(err.into()),
+    };
+    if !is_empty {
+        return Err(anyhow!(
+            "{} isn't empty, dr-test only restores into a throwaway directory",
+            target.display()
+        ));
+    }
+    std::fs::create_dir_all(&target)
+        .with_context(|| anyhow!("Error creating {}", target.display()))?;
+
+    info!("Restoring into {}...", target.display());
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0);
+    let started = Instant::now();
+    let owner_map = ownership::OwnerMap::new(backup_dir, None)?;
+    let result = restore(&target, backup_dir, snapshot, &[], &owner_map, options).await;
+
+    match keep {
+        true => info!("Kept the restored files in {}", target.display()),
+        false => std::fs::remove_dir_all(&target)
+            .with_context(|| anyhow!("Error removing {}", target.display()))?,
+    }
+
+    let (report, check) = result?;
+    let rehearsal = Rehearsal {
+        time,
+        snapshot: snapshot.map(str::to_string),
+        restored: report.copied,
+        errors: report.errors,
+        verified: check.verified.len() as u64,
+        failed: check.failed.len() as u64,
+        duration_secs: started.elapsed().as_secs_f64(),
+    };
+    record(backup_dir, &rehearsal)?;
+
+    Ok(rehearsal)
+}
+
+/// Appends `rehearsal` to the history kept in the state dir
+fn record(backup_dir: &Path, rehearsal: &Rehearsal) -> Result<()> {
+    let path = history_path(backup_dir);
+    std::fs::create_dir_all(state_dir(backup_dir))?;
+
+    let mut file = std::fs::File::options()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| anyhow!("Error opening {}", path.display()))?;
+    writeln!(file, "{}", serde_json::to_string(rehearsal)?)?;
+
+    Ok(())
+}
+
+/// The most recent rehearsal of backup_dir, if there was one
+pub fn last(backup_dir: &Path) -> Option<Rehearsal> {
+    std::fs::read_to_string(history_path(backup_dir))
+        .ok()?
+        .lines()
+        .rev()
+        .find_map(|line| serde_json::from_str(line).ok())
+}
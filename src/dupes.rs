@@ -0,0 +1,131 @@
+//! `evil_mount dupes`: the files in work_dir with the same contents at different paths, and how
+//! much space keeping one of each would save. Only files that share their size with another one
+//! are hashed, with the same hashing the sync uses, and hard links to a file already seen are left
+//! out, since they take no space of their own
+
+use blake3::Hash;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    encoded_path,
+    hashing::hash_file,
+    output::{self, Event},
+    platform, recursive_dir, space, WalkOptions,
+};
+
+/// Files with the same contents
+#[derive(Debug, Serialize)]
+pub struct Group {
+    #[serde(serialize_with = "hex")]
+    pub hash: Hash,
+    /// The size of each of them
+    pub size: u64,
+    /// Relative to work_dir, sorted
+    #[serde(serialize_with = "encoded_path::lossy_list")]
+    pub paths: Vec<PathBuf>,
+}
+
+impl Group {
+    /// What keeping only one of them would save
+    pub fn wasted(&self) -> u64 {
+        self.size * (self.paths.len() as u64 - 1)
+    }
+}
+
+fn hex<S: serde::Serializer>(hash: &Hash, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hash.to_hex())
+}
+
+/// The groups of duplicates in `work_dir`, the ones wasting the most space first. Files that can't
+/// be read are reported and left out
+pub fn find(work_dir: &Path, walk_options: &WalkOptions) -> Vec<Group> {
+    let mut by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    let mut seen = HashSet::new();
+    for file_info in recursive_dir(work_dir, walk_options) {
+        let Ok(metadata) = file_info.metadata() else {
+            continue;
+        };
+        // Empty files are all the same, without taking any space
+        if metadata.len() == 0 {
+            continue;
+        }
+        if let Some(id) = platform::file_id(&metadata) {
+            if !seen.insert(id) {
+                continue;
+            }
+        }
+        by_size
+            .entry(metadata.len())
+            .or_default()
+            .push(file_info.into_path());
+    }
+
+    let candidates: Vec<_> = by_size
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .flat_map(|(size, paths)| paths.into_iter().map(move |path| (size, path)))
+        .collect();
+    let hashed: Vec<_> = candidates
+        .into_par_iter()
+        .filter_map(|(size, path)| match hash_file(&path) {
+            Ok(hash) => Some((hash, size, path)),
+            Err(err) => {
+                output::emit(&Event::Error {
+                    path: Some(&path),
+                    message: format!("Error hashing: {err:#}"),
+                });
+                None
+            }
+        })
+        .collect();
+
+    let mut by_hash: HashMap<(Hash, u64), Vec<PathBuf>> = HashMap::new();
+    for (hash, size, path) in hashed {
+        let relative_path = path.strip_prefix(work_dir).unwrap_or(&path).to_path_buf();
+        by_hash.entry((hash, size)).or_default().push(relative_path);
+    }
+
+    let mut groups: Vec<_> = by_hash
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|((hash, size), mut paths)| {
+            paths.sort();
+            Group { hash, size, paths }
+        })
+        .collect();
+    groups.sort_by(|a, b| b.wasted().cmp(&a.wasted()).then(a.paths.cmp(&b.paths)));
+
+    groups
+}
+
+/// Lists `groups` for reading in a terminal, with what could be saved in total
+pub fn render(groups: &[Group]) -> String {
+    let mut report = String::new();
+    for group in groups {
+        report += &format!(
+            "{} copies of {}, {} to save:\n",
+            group.paths.len(),
+            space::size(group.size),
+            space::size(group.wasted())
+        );
+        for path in &group.paths {
+            report += &format!("  {}\n", path.display());
+        }
+        report.push('\n');
+    }
+
+    let files: usize = groups.iter().map(|group| group.paths.len()).sum();
+    let wasted: u64 = groups.iter().map(Group::wasted).sum();
+    report += &format!(
+        "{} groups of duplicates, {files} files, {} could be saved by keeping one of each\n",
+        groups.len(),
+        space::size(wasted)
+    );
+
+    report
+}
@@ -0,0 +1,219 @@
+//! Paths in the JSON kept in the state dir, which serde can only write when they're valid UTF-8.
+//! One that is gets written as it is, so the files read the same as before, and one that isn't as
+//! a NUL followed by the hex of its bytes, which no real path can start with. Use with
+//! `#[serde(with = "encoded_path")]`, `"encoded_path::option"` for one that may not be there,
+//! `"encoded_path::keys"` for a map keyed by path, or `"encoded_path::set"` for a set of them
+//!
+//! What's only shown, like the events of `--output json`, goes through [`lossy`] instead
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    borrow::Cow,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::platform;
+
+/// Starts a path written as the hex of its bytes
+const RAW: char = '\0';
+
+/// `path` as a string that [`decode`] turns back into it
+pub fn encode(path: &Path) -> Cow<'_, str> {
+    if let Some(text) = path.to_str() {
+        if !text.starts_with(RAW) {
+            return Cow::Borrowed(text);
+        }
+    }
+
+    let mut text = String::from(RAW);
+    for byte in platform::path_bytes(path) {
+        let _ = write!(text, "{byte:02x}");
+    }
+    Cow::Owned(text)
+}
+
+/// The path [`encode`] turned into `text`, or None if it's not something it could have made
+pub fn decode(text: &str) -> Option<PathBuf> {
+    let Some(hex) = text.strip_prefix(RAW) else {
+        return Some(PathBuf::from(text));
+    };
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|start| u8::from_str_radix(hex.get(start..start + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    platform::path_from_bytes(bytes)
+}
+
+pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&encode(path))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    decoded(&Cow::<str>::deserialize(deserializer)?)
+}
+
+fn decoded<E: de::Error>(text: &str) -> Result<PathBuf, E> {
+    decode(text).ok_or_else(|| E::custom(format!("{text:?} isn't an encoded path")))
+}
+
+/// Paths that may not be there
+pub mod option {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        path: &Option<PathBuf>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match path {
+            Some(path) => serializer.serialize_some(&encode(path)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<PathBuf>, D::Error> {
+        Option::<Cow<str>>::deserialize(deserializer)?
+            .map(|text| decoded(&text))
+            .transpose()
+    }
+}
+
+/// Maps keyed by path, with the keys encoded
+pub mod keys {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    pub fn serialize<'a, S, M, V>(map: &'a M, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        &'a M: IntoIterator<Item = (&'a PathBuf, &'a V)>,
+        V: Serialize + 'a,
+    {
+        serializer.collect_map(map.into_iter().map(|(path, value)| (encode(path), value)))
+    }
+
+    pub fn deserialize<'de, D, M, V>(deserializer: D) -> Result<M, D::Error>
+    where
+        D: Deserializer<'de>,
+        M: FromIterator<(PathBuf, V)>,
+        V: Deserialize<'de>,
+    {
+        BTreeMap::<String, V>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(text, value)| Ok((decoded(&text)?, value)))
+            .collect()
+    }
+
+    /// `map` as JSON, for a map that's saved on its own
+    pub fn to_json<'a, M, V>(map: &'a M) -> serde_json::Result<Vec<u8>>
+    where
+        &'a M: IntoIterator<Item = (&'a PathBuf, &'a V)>,
+        V: Serialize + 'a,
+    {
+        let mut json = Vec::new();
+        serialize(map, &mut serde_json::Serializer::new(&mut json))?;
+
+        Ok(json)
+    }
+
+    /// The map [`to_json`] saved as `json`
+    pub fn from_json<'a, M, V>(json: &'a [u8]) -> serde_json::Result<M>
+    where
+        M: FromIterator<(PathBuf, V)>,
+        V: Deserialize<'a>,
+    {
+        let mut deserializer = serde_json::Deserializer::from_slice(json);
+        let map = deserialize(&mut deserializer)?;
+        deserializer.end()?;
+
+        Ok(map)
+    }
+}
+
+/// Sets of paths, with every path encoded
+pub mod set {
+    use super::*;
+
+    pub fn serialize<'a, S, M>(set: &'a M, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        &'a M: IntoIterator<Item = &'a PathBuf>,
+    {
+        serializer.collect_seq(set.into_iter().map(|path| encode(path)))
+    }
+
+    pub fn deserialize<'de, D, M>(deserializer: D) -> Result<M, D::Error>
+    where
+        D: Deserializer<'de>,
+        M: FromIterator<PathBuf>,
+    {
+        Vec::<Cow<str>>::deserialize(deserializer)?
+            .iter()
+            .map(|text| decoded(text))
+            .collect()
+    }
+}
+
+/// Writes `path` for people to read, with whatever isn't UTF-8 replaced
+pub fn lossy<S: Serializer>(path: &impl AsRef<Path>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.as_ref().to_string_lossy())
+}
+
+/// [`lossy`] for a path that may not be there
+pub fn lossy_option<S: Serializer>(
+    path: &Option<impl AsRef<Path>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match path {
+        Some(path) => lossy(path, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// [`lossy`] for every path in `paths`
+pub fn lossy_list<S: Serializer, P: AsRef<Path>>(
+    paths: &impl AsRef<[P]>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(
+        paths
+            .as_ref()
+            .iter()
+            .map(|path| path.as_ref().to_string_lossy()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn utf8_paths_are_kept_as_they_are() {
+        let path = Path::new("dir/file.txt");
+        assert_eq!(encode(path), "dir/file.txt");
+        assert_eq!(decode("dir/file.txt").as_deref(), Some(path));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn other_paths_round_trip() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"dir/bad\xff"));
+        let encoded = encode(path);
+        assert!(encoded.starts_with('\0'));
+        assert_eq!(decode(&encoded).as_deref(), Some(path));
+
+        let map = BTreeMap::from([(path.to_path_buf(), 1), (PathBuf::from("good"), 2)]);
+        let json = keys::to_json(&map).unwrap();
+        let decoded: BTreeMap<PathBuf, i32> = keys::from_json(&json).unwrap();
+        assert_eq!(decoded, map);
+    }
+}
@@ -0,0 +1,140 @@
+//! The exit codes of evil_mount, for scripts that wrap it and need to tell a typo in the arguments
+//! from an unplugged disk or a backup that doesn't match:
+//!
+//! - 0: everything worked
+//! - 1: it ran, but some files failed to sync, restore or check
+//! - 2: the arguments, a profile or a file they point at are wrong, like clap's usage errors
+//! - 3: backup_dir couldn't be set up, or the startup reconciliation failed
+//! - 4: backup_dir, work_dir, a daemon's control socket or the mirror couldn't be reached
+//! - 5: a verification found the backup doesn't match what it was checked against
+//! - 130: stopped by Ctrl-C before finishing, like a shell reports a process killed by SIGINT
+//!
+//! Failing returns an [`Error`], whose kind is the exit code. The checks of the arguments and the
+//! setup of backup_dir say which kind their errors are with [`Stage`], while deeper down an error
+//! can be marked as one that couldn't be reached wherever it's raised, which it stays
+
+use std::{fmt, process::ExitCode};
+
+/// An exit code besides 0
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Code {
+    Errors = 1,
+    Config = 2,
+    Init = 3,
+    Unreachable = 4,
+    Mismatch = 5,
+    Interrupted = 130,
+}
+
+impl From<Code> for ExitCode {
+    fn from(code: Code) -> Self {
+        ExitCode::from(code as u8)
+    }
+}
+
+/// What made evil_mount give up, by what a script would do about it
+#[derive(Debug)]
+pub enum Error {
+    /// The arguments, a profile or a file they point at are wrong
+    Config(anyhow::Error),
+    /// backup_dir couldn't be set up, or the startup reconciliation failed
+    Init(anyhow::Error),
+    /// backup_dir, work_dir, a daemon's control socket or the mirror couldn't be reached
+    Unreachable(anyhow::Error),
+    /// Anything else that failed once it was running
+    Failed(anyhow::Error),
+}
+
+impl Error {
+    pub fn code(&self) -> Code {
+        match self {
+            Error::Config(_) => Code::Config,
+            Error::Init(_) => Code::Init,
+            Error::Unreachable(_) => Code::Unreachable,
+            Error::Failed(_) => Code::Errors,
+        }
+    }
+
+    /// The error itself, for printing with its causes
+    pub fn inner(&self) -> &anyhow::Error {
+        match self {
+            Error::Config(err)
+            | Error::Init(err)
+            | Error::Unreachable(err)
+            | Error::Failed(err) => err,
+        }
+    }
+
+    /// `err` as the kind it was marked as where it was raised, or as the kind `or` makes
+    fn marked_or(err: anyhow::Error, or: fn(anyhow::Error) -> Error) -> Self {
+        let marked = err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<Error>())
+            .map(Error::code);
+
+        match marked {
+            Some(Code::Config) => Error::Config(err),
+            Some(Code::Init) => Error::Init(err),
+            Some(Code::Unreachable) => Error::Unreachable(err),
+            Some(_) => Error::Failed(err),
+            None => or(err),
+        }
+    }
+}
+
+/// An error from running, unless it was marked otherwise
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        Error::marked_or(err, Error::Failed)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Failed(err.into())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Failed(err.into())
+    }
+}
+
+/// Says which stage of the startup an error happened in
+pub trait Stage<T> {
+    /// Fails with a [`Error::Config`], for an error while the arguments are checked
+    fn config_error(self) -> Result<T, Error>;
+    /// Fails with a [`Error::Init`], for an error while backup_dir is set up
+    fn init_error(self) -> Result<T, Error>;
+}
+
+impl<T, E: Into<anyhow::Error>> Stage<T> for Result<T, E> {
+    fn config_error(self) -> Result<T, Error> {
+        self.map_err(|err| Error::marked_or(err.into(), Error::Config))
+    }
+
+    fn init_error(self) -> Result<T, Error> {
+        self.map_err(|err| Error::marked_or(err.into(), Error::Init))
+    }
+}
+
+/// Shows the error it wraps, whose causes stay its own, so marking an error doesn't change how
+/// it's printed
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.inner())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.inner().source()
+    }
+}
+
+/// Marks `err` as something that couldn't be reached, wherever it's raised
+pub fn unreachable(err: anyhow::Error) -> anyhow::Error {
+    Error::Unreachable(err).into()
+}
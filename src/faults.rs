@@ -0,0 +1,113 @@
+//! Faults injected on purpose, so that tests can check a sync survives the races and crashes they
+//! stand for. `EVIL_MOUNT_FAULTS` lists them, comma separated, as `KIND=PATH`, and each one hits the
+//! first file whose path ends with PATH:
+//!
+//! - `vanish`: the file is deleted from work_dir just before it's copied
+//! - `change`: the file is written to while it's being copied
+//! - `crash-before-rename`: evil_mount dies, like it would on a power cut, once the file's copy is
+//!   written next to where it goes but before it's moved into place
+//! - `crash-after-rename`: evil_mount dies right after moving the file's copy into place
+//! - `crash-before-delete`: evil_mount dies just before deleting the file's copy
+//!
+//! Nothing is injected without the variable
+
+use anyhow::{anyhow, Result};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    Vanish,
+    Change,
+    CrashBeforeRename,
+    CrashAfterRename,
+    CrashBeforeDelete,
+}
+
+struct Injected {
+    fault: Fault,
+    path: PathBuf,
+    hit: AtomicBool,
+}
+
+static FAULTS: OnceLock<Vec<Injected>> = OnceLock::new();
+
+/// Reads the faults to inject from `EVIL_MOUNT_FAULTS`, failing for one it doesn't know
+pub fn load() -> Result<()> {
+    let Some(spec) = std::env::var_os("EVIL_MOUNT_FAULTS") else {
+        return Ok(());
+    };
+    let spec = spec
+        .into_string()
+        .map_err(|_| anyhow!("EVIL_MOUNT_FAULTS isn't UTF-8"))?;
+    let faults = spec
+        .split(',')
+        .filter(|fault| !fault.is_empty())
+        .map(parse)
+        .collect::<Result<_>>()?;
+
+    let _ = FAULTS.set(faults);
+    Ok(())
+}
+
+fn parse(fault: &str) -> Result<Injected> {
+    let Some((kind, path)) = fault.split_once('=') else {
+        return Err(anyhow!("Invalid fault {fault}, expected KIND=PATH"));
+    };
+    let fault = match kind {
+        "vanish" => Fault::Vanish,
+        "change" => Fault::Change,
+        "crash-before-rename" => Fault::CrashBeforeRename,
+        "crash-after-rename" => Fault::CrashAfterRename,
+        "crash-before-delete" => Fault::CrashBeforeDelete,
+        _ => return Err(anyhow!("Unknown fault {kind}")),
+    };
+
+    Ok(Injected {
+        fault,
+        path: PathBuf::from(path),
+        hit: AtomicBool::new(false),
+    })
+}
+
+/// Whether `fault` hits `path` now. Each injected fault only hits once
+fn hits(fault: Fault, path: &Path) -> bool {
+    FAULTS.get().is_some_and(|faults| {
+        faults.iter().any(|injected| {
+            injected.fault == fault
+                && path.ends_with(&injected.path)
+                && !injected.hit.swap(true, Ordering::Relaxed)
+        })
+    })
+}
+
+/// Deletes `path` from work_dir, if it's to vanish before it's copied
+pub fn before_copy(path: &Path) {
+    if hits(Fault::Vanish, path) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Writes to `path` in work_dir, if it's to change while it's being copied
+pub fn during_copy(path: &Path) {
+    if hits(Fault::Change, path) {
+        if let Ok(mut file) = std::fs::File::options().append(true).open(path) {
+            let _ = file.write_all(b" changed");
+        }
+    }
+}
+
+/// Dies on the spot if `fault` is one of the crashes and hits `path`, leaving everything the way a
+/// power cut would
+pub fn crash_if(fault: Fault, path: &Path) {
+    if hits(fault, path) {
+        eprintln!("Injected a crash at {}", path.display());
+        std::process::abort();
+    }
+}
@@ -0,0 +1,190 @@
+//! Content filters run while copying, like stripping the GPS position out of photos or redacting
+//! secrets from `.env` files. A filter is a shell command that reads the original on stdin and
+//! writes what should be backed up to stdout. Since the backup then differs from the original,
+//! the hash of the original is recorded to tell whether it changed since
+
+use anyhow::{anyhow, Context, Result};
+use blake3::Hash;
+use ignore::overrides::{Override, OverrideBuilder};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+    sync::{Mutex, OnceLock},
+};
+
+use crate::{containment, copy, encoded_path, hash_file, state_dir};
+
+/// A `GLOB=COMMAND` pair from `--filter`
+#[derive(Clone, Debug)]
+pub struct Filter {
+    glob: String,
+    command: String,
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('=') {
+            Some((glob, command)) if !glob.is_empty() && !command.is_empty() => Ok(Self {
+                glob: glob.to_string(),
+                command: command.to_string(),
+            }),
+            _ => Err(anyhow!("expected GLOB=COMMAND, got {s}")),
+        }
+    }
+}
+
+struct Filters {
+    work_dir: PathBuf,
+    backup_dir: PathBuf,
+    filters: Vec<(Override, String)>,
+}
+
+static FILTERS: OnceLock<Filters> = OnceLock::new();
+
+/// The hash of the original of every filtered file, keyed by its path relative to work_dir
+static ORIGINALS: Mutex<BTreeMap<PathBuf, String>> = Mutex::new(BTreeMap::new());
+
+/// Sets the filters for copies from work_dir into backup_dir, and loads the hashes recorded by
+/// earlier runs. Must be called before anything is copied
+pub fn set(work_dir: &Path, backup_dir: &Path, filters: Vec<Filter>) -> Result<()> {
+    let filters = filters
+        .into_iter()
+        .map(|filter| {
+            let mut overrides = OverrideBuilder::new(work_dir);
+            overrides
+                .add(&filter.glob)
+                .with_context(|| anyhow!("Invalid filter pattern {}", filter.glob))?;
+            Ok((overrides.build()?, filter.command))
+        })
+        .collect::<Result<_>>()?;
+
+    FILTERS
+        .set(Filters {
+            work_dir: work_dir.to_path_buf(),
+            backup_dir: backup_dir.to_path_buf(),
+            filters,
+        })
+        .map_err(|_| anyhow!("the filters can only be set once"))?;
+
+    let path = originals_path(backup_dir);
+    let contents = match std::fs::read(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let saved: BTreeMap<PathBuf, String> = encoded_path::keys::from_json(&contents)
+        .with_context(|| anyhow!("Error parsing {}", path.display()))?;
+    ORIGINALS.lock().unwrap().extend(saved);
+
+    Ok(())
+}
+
+fn originals_path(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("filtered.json")
+}
+
+/// The command that filters `path` on its way into the backup, if any. Only files in work_dir
+/// are filtered, so restores copy the filtered contents back as they are
+pub fn for_path(path: &Path) -> Option<&'static str> {
+    let filters = FILTERS.get()?;
+    let relative_path = path.strip_prefix(&filters.work_dir).ok()?;
+
+    filters
+        .filters
+        .iter()
+        .find(|(filter, _)| filter.matched(relative_path, false).is_whitelist())
+        .map(|(_, command)| command.as_str())
+}
+
+/// The recorded hash of the original of a filtered file, by its path relative to work_dir
+pub fn original_hash(relative_path: &Path) -> Option<Hash> {
+    let originals = ORIGINALS.lock().unwrap();
+    Hash::from_hex(originals.get(relative_path)?).ok()
+}
+
+/// Whether the filtered file at `path` in work_dir still has the contents it was last backed up
+/// with
+pub fn is_unchanged(path: &Path) -> Result<bool> {
+    let Some(relative_path) = FILTERS
+        .get()
+        .and_then(|filters| path.strip_prefix(&filters.work_dir).ok())
+    else {
+        return Ok(false);
+    };
+
+    match original_hash(relative_path) {
+        Some(original_hash) => Ok(hash_file(path)? == original_hash),
+        None => Ok(false),
+    }
+}
+
+/// Runs `command` with `from` on stdin and `to` on stdout, then records the hash of `from`
+pub async fn apply(command: &str, from: &Path, to: &Path) -> Result<()> {
+    let (command, from, to) = (command.to_string(), from.to_path_buf(), to.to_path_buf());
+
+    tokio::task::spawn_blocking(move || {
+        let source = containment::open(&from)?;
+        let destination = containment::create(&to)?;
+        let status = Command::new("sh")
+            .args(["-c", &command])
+            .stdin(source.try_clone()?)
+            .stdout(destination)
+            .stderr(Stdio::inherit())
+            .status()
+            .with_context(|| anyhow!("Error running filter {command}"))?;
+        if !status.success() {
+            let _ = std::fs::remove_file(&to);
+            return Err(anyhow!("Filter {command} failed with {status}"));
+        }
+        copy::copy_permissions(&source.metadata()?, &to)?;
+
+        record(&from, hash_file(&from)?)
+    })
+    .await?
+}
+
+/// Records the hash of the original of a filtered file, saving them all right away since filtered
+/// files are few
+fn record(path: &Path, hash: Hash) -> Result<()> {
+    let Some(filters) = FILTERS.get() else {
+        return Ok(());
+    };
+    let Ok(relative_path) = path.strip_prefix(&filters.work_dir) else {
+        return Ok(());
+    };
+
+    let mut originals = ORIGINALS.lock().unwrap();
+    originals.insert(relative_path.to_path_buf(), hash.to_hex().to_string());
+
+    let path = originals_path(&filters.backup_dir);
+    let temp_path = path.with_extension("json.tmp");
+    std::fs::create_dir_all(state_dir(&filters.backup_dir))?;
+    std::fs::write(&temp_path, encoded_path::keys::to_json(&*originals)?)?;
+    std::fs::rename(&temp_path, &path)
+        .with_context(|| anyhow!("Error saving {}", path.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_and_command() {
+        let filter: Filter = "*.jpg=exiftool -gps:all= -".parse().unwrap();
+        assert_eq!(filter.glob, "*.jpg");
+        assert_eq!(filter.command, "exiftool -gps:all= -");
+    }
+
+    #[test]
+    fn invalid_filters() {
+        for filter in ["", "*.jpg", "*.jpg=", "=cat"] {
+            assert!(filter.parse::<Filter>().is_err(), "{filter}");
+        }
+    }
+}
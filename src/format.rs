@@ -0,0 +1,121 @@
+//! The version of the layout of everything evil_mount keeps in the state dir, like the watch state,
+//! the manifests, the stats and the snapshots, so that a release can change how any of it is
+//! stored without orphaning existing backups. Any change that an older release couldn't read, or
+//! that would misread an older layout, bumps VERSION and adds a migration from the previous one
+//!
+//! Opening a backup_dir upgrades its state dir one version at a time, recording each step, so that
+//! an interrupted upgrade picks up where it stopped. A state dir from a newer release is refused
+//! rather than misread
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::{locks::BACKUP_DIR_LOCK, output::info, state_dir};
+
+/// The version this release reads and writes
+pub const VERSION: u32 = 1;
+
+struct Migration {
+    /// What it does, for the log
+    description: &'static str,
+    /// Rewrites the state dir of a backup_dir
+    migrate: fn(&Path) -> Result<()>,
+}
+
+/// Brings a state dir from each version to the next, in order. `MIGRATIONS[n]` upgrades version n
+const MIGRATIONS: [Migration; VERSION as usize] = [Migration {
+    // State dirs from before formats were versioned have the layout of version 1
+    description: "recording the format version",
+    migrate: |_| Ok(()),
+}];
+
+#[derive(Serialize, Deserialize)]
+struct Format {
+    version: u32,
+}
+
+fn format_path(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("format.json")
+}
+
+/// The version of the state dir of `backup_dir`. One without a version but with other state is
+/// from before formats were versioned, and none at all is new
+fn version(backup_dir: &Path) -> Result<Option<u32>> {
+    let path = format_path(backup_dir);
+    match std::fs::read(&path) {
+        Ok(contents) => {
+            let format: Format = serde_json::from_slice(&contents)
+                .with_context(|| anyhow!("Error parsing {}", path.display()))?;
+            Ok(Some(format.version))
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            // The lock is taken before the format is recorded
+            let has_state = std::fs::read_dir(state_dir(backup_dir)).is_ok_and(|entries| {
+                entries
+                    .flatten()
+                    .any(|entry| entry.file_name() != BACKUP_DIR_LOCK)
+            });
+            Ok(has_state.then_some(0))
+        }
+        Err(err) => Err(anyhow!(err).context(format!("Error reading {}", path.display()))),
+    }
+}
+
+/// Whether `backup_dir` has a state dir yet, of any version
+pub fn exists(backup_dir: &Path) -> Result<bool> {
+    Ok(version(backup_dir)?.is_some())
+}
+
+/// Fails if the state dir of `backup_dir` is from a newer release, for commands that only read it
+pub fn check(backup_dir: &Path) -> Result<()> {
+    match version(backup_dir)? {
+        Some(version) if version > VERSION => Err(anyhow!(
+            "{} was written by a newer evil_mount (format version {version}, this one understands up to {VERSION}), upgrade evil_mount to use it",
+            backup_dir.display()
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Migrates the state dir of `backup_dir` to the current version, or records the current version
+/// in a new one
+pub fn upgrade(backup_dir: &Path) -> Result<()> {
+    check(backup_dir)?;
+    let Some(mut version) = version(backup_dir)? else {
+        return save(backup_dir, VERSION);
+    };
+
+    while version < VERSION {
+        let migration = &MIGRATIONS[version as usize];
+        info!(
+            "Upgrading the state of {} to format version {}: {}",
+            backup_dir.display(),
+            version + 1,
+            migration.description
+        );
+        (migration.migrate)(backup_dir).with_context(|| {
+            anyhow!(
+                "Error upgrading the state of {} to format version {}",
+                backup_dir.display(),
+                version + 1
+            )
+        })?;
+        version += 1;
+        save(backup_dir, version)?;
+    }
+
+    Ok(())
+}
+
+fn save(backup_dir: &Path, version: u32) -> Result<()> {
+    let path = format_path(backup_dir);
+    let temp_path = path.with_extension("json.tmp");
+
+    std::fs::create_dir_all(state_dir(backup_dir))?;
+    std::fs::write(&temp_path, serde_json::to_vec(&Format { version })?)?;
+    std::fs::rename(&temp_path, &path)
+        .with_context(|| anyhow!("Error saving {}", path.display()))?;
+
+    Ok(())
+}
@@ -0,0 +1,101 @@
+//! Asking the watch loop for a full pass. While the loop runs it's the only thing that changes
+//! backup_dir as a whole, so resuming after a pause, `flush` and `resync` through the control
+//! socket and the repairs of the scheduled verification don't run passes of their own next to it.
+//! They ask for one instead, and the next cycle compares every file in work_dir with its copy,
+//! walks backup_dir rather than trusting its listing, and copies the files it was told differ
+//! whatever their modify times say
+//!
+//! Requests made before a cycle starts are all answered by it, so a burst of them is one pass
+
+use anyhow::{anyhow, Result};
+use std::{
+    collections::BTreeSet,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+use tokio::sync::{oneshot, Notify};
+
+use crate::SyncReport;
+
+/// The next full pass, as asked for so far
+struct Requests {
+    /// Files in work_dir to copy even if they look up to date
+    forced: BTreeSet<PathBuf>,
+    /// Who waits for the report of the pass
+    waiting: Vec<oneshot::Sender<SyncReport>>,
+}
+
+static REQUESTS: Mutex<Requests> = Mutex::new(Requests {
+    forced: BTreeSet::new(),
+    waiting: Vec::new(),
+});
+/// Set while a full pass was asked for and hasn't started
+static ASKED: AtomicBool = AtomicBool::new(false);
+/// Wakes the watch loop from waiting for the next cycle
+static WAKE: Notify = Notify::const_new();
+
+/// Asks for a full pass that also copies the `forced` files in work_dir, and waits for its report.
+/// Fails if the watch loop stops before running it
+pub async fn run(forced: impl IntoIterator<Item = PathBuf>) -> Result<SyncReport> {
+    let (sender, receiver) = oneshot::channel();
+    {
+        let mut requests = REQUESTS.lock().unwrap();
+        requests.forced.extend(forced);
+        requests.waiting.push(sender);
+    }
+    ask();
+
+    receiver
+        .await
+        .map_err(|_| anyhow!("The watch loop stopped before the pass ran"))
+}
+
+/// Asks for a full pass without waiting for it
+pub fn ask() {
+    ASKED.store(true, Ordering::Relaxed);
+    WAKE.notify_one();
+}
+
+/// Whether a full pass was asked for since the last one started
+pub fn is_asked() -> bool {
+    ASKED.load(Ordering::Relaxed)
+}
+
+/// Waits until a full pass is asked for
+pub async fn asked() {
+    if !is_asked() {
+        WAKE.notified().await;
+    }
+}
+
+/// A full pass the watch loop is running
+pub struct Pass {
+    /// Files in work_dir to copy even if they look up to date
+    pub forced: BTreeSet<PathBuf>,
+    waiting: Vec<oneshot::Sender<SyncReport>>,
+}
+
+impl Pass {
+    /// Hands the report of the pass to everyone who asked for it
+    pub fn finish(self, report: &SyncReport) {
+        for sender in self.waiting {
+            let _ = sender.send(report.clone());
+        }
+    }
+}
+
+/// Takes what was asked for, for the watch loop at the start of a cycle, if a full pass was
+pub fn take() -> Option<Pass> {
+    if !ASKED.swap(false, Ordering::Relaxed) {
+        return None;
+    }
+    let mut requests = REQUESTS.lock().unwrap();
+
+    Some(Pass {
+        forced: std::mem::take(&mut requests.forced),
+        waiting: std::mem::take(&mut requests.waiting),
+    })
+}
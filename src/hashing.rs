@@ -0,0 +1,119 @@
+//! The one place files are hashed. Reconciling, moves, `--verify-writes`, `--dedup`, filters,
+//! metadata-only files, verification and signed manifests all ask here, so a file hashed by one of
+//! them isn't read again by the next one in the same cycle. A copy records the hash of what it
+//! wrote, so deduplicating or signing the copy doesn't read it back either
+//!
+//! A hash is only reused while the file's size, modify time and inode stay the same, and the
+//! remembered hashes are forgotten at the start of every pass. Verification reads everything
+//! anyway, since what's on the disk is what it's there to check
+
+use anyhow::Result;
+use blake3::{Hash, Hasher};
+use std::{
+    collections::HashMap,
+    fs::Metadata,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use crate::{open_files, platform};
+
+/// The most hashes remembered at once, to bound memory during a verification of a huge tree
+const MAX_REMEMBERED: usize = 100_000;
+
+/// What identifies the contents of a file without reading it
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    len: u64,
+    modified: Option<SystemTime>,
+    id: Option<(u64, u64)>,
+}
+
+impl Stamp {
+    fn of(metadata: &Metadata) -> Self {
+        Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            id: platform::file_id(metadata),
+        }
+    }
+}
+
+static REMEMBERED: Mutex<Option<HashMap<PathBuf, (Stamp, Hash)>>> = Mutex::new(None);
+
+/// The hash of the contents of `path`, reading it only if it changed since it was last hashed
+pub fn hash_file(path: &Path) -> Result<Hash> {
+    let _held = open_files::take(1);
+    let mut file = std::fs::File::open(path)?;
+    let stamp = Stamp::of(&file.metadata()?);
+    if let Some(hash) = lookup(path, stamp) {
+        return Ok(hash);
+    }
+
+    let hash = hash_reader(&mut file)?;
+    remember(path, stamp, hash);
+
+    Ok(hash)
+}
+
+/// The hash of the contents of `path`, read from the file even if it's remembered
+pub fn hash_fresh(path: &Path) -> Result<Hash> {
+    let _held = open_files::take(1);
+    let mut file = std::fs::File::open(path)?;
+    let stamp = Stamp::of(&file.metadata()?);
+    let hash = hash_reader(&mut file)?;
+    remember(path, stamp, hash);
+
+    Ok(hash)
+}
+
+/// Hashes everything `reader` gives, for contents that have to be read no matter what, like to
+/// check what made it to the disk. Record the result with [`record`] to spare others the reading
+pub fn hash_reader(reader: &mut impl Read) -> io::Result<Hash> {
+    let mut hasher = Hasher::new();
+    io::copy(reader, &mut hasher)?;
+
+    Ok(hasher.finalize())
+}
+
+/// Remembers that the contents of `path`, as they are now, hash to `hash`
+pub fn record(path: &Path, hash: Hash) {
+    if let Ok(metadata) = std::fs::metadata(path) {
+        remember(path, Stamp::of(&metadata), hash);
+    }
+}
+
+fn lookup(path: &Path, stamp: Stamp) -> Option<Hash> {
+    let remembered = REMEMBERED.lock().unwrap();
+    match remembered.as_ref()?.get(path) {
+        Some(&(remembered_stamp, hash)) if remembered_stamp == stamp => Some(hash),
+        _ => None,
+    }
+}
+
+fn remember(path: &Path, stamp: Stamp, hash: Hash) {
+    let mut remembered = REMEMBERED.lock().unwrap();
+    let remembered = remembered.get_or_insert_with(HashMap::new);
+    if remembered.len() >= MAX_REMEMBERED {
+        remembered.clear();
+    }
+    remembered.insert(path.to_path_buf(), (stamp, hash));
+}
+
+/// Remembers the hash of `from` for `to` instead, once `from` was renamed there
+pub fn moved(from: &Path, to: &Path) {
+    let mut remembered = REMEMBERED.lock().unwrap();
+    let Some(remembered) = remembered.as_mut() else {
+        return;
+    };
+    if let Some(entry) = remembered.remove(from) {
+        remembered.insert(to.to_path_buf(), entry);
+    }
+}
+
+/// Forgets every hash, at the start of a pass, so that none outlives a change the stamp can't see
+pub fn forget() {
+    *REMEMBERED.lock().unwrap() = None;
+}
@@ -0,0 +1,175 @@
+//! A tiny HTTP server for liveness and readiness probes, like the ones Kubernetes sends.
+//!
+//! `/healthz` fails once the sync loop hasn't finished a cycle in a while, and `/readyz` fails
+//! until the startup reconciliation is done. Both answer with a JSON body describing how far
+//! behind syncing is
+
+use anyhow::Result;
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{output::Event, pause, space, watchdog, TRACKED_FILES};
+
+/// If no cycle finished for this long while syncing isn't paused, the syncer counts as stuck
+const MAX_CYCLE_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// Set once the startup reconciliation is done and the sync loop is running
+pub static READY: AtomicBool = AtomicBool::new(false);
+/// When the last cycle finished, in seconds since the epoch
+static LAST_CYCLE: AtomicU64 = AtomicU64::new(0);
+/// When the last cycle without any errors finished, in seconds since the epoch
+static LAST_SUCCESSFUL_CYCLE: AtomicU64 = AtomicU64::new(0);
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+static LAST_CYCLE_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+/// Keeps track of finished cycles and errors. Called for every emitted event
+pub fn observe(event: &Event) {
+    match event {
+        Event::Error { .. } => {
+            ERRORS.fetch_add(1, Ordering::Relaxed);
+        }
+        Event::CycleComplete { errors, .. } => {
+            let now = now_secs();
+            LAST_CYCLE.store(now, Ordering::Relaxed);
+            LAST_CYCLE_ERRORS.store(*errors, Ordering::Relaxed);
+            if *errors == 0 {
+                LAST_SUCCESSFUL_CYCLE.store(now, Ordering::Relaxed);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// When the last cycle finished, in seconds since the epoch, if one did yet
+pub fn last_cycle() -> Option<u64> {
+    match LAST_CYCLE.load(Ordering::Relaxed) {
+        0 => None,
+        last_cycle => Some(last_cycle),
+    }
+}
+
+/// When the last cycle without any errors finished, in seconds since the epoch, if one did yet
+pub fn last_successful_cycle() -> Option<u64> {
+    match LAST_SUCCESSFUL_CYCLE.load(Ordering::Relaxed) {
+        0 => None,
+        last_successful_cycle => Some(last_successful_cycle),
+    }
+}
+
+fn is_stalled() -> bool {
+    let sync_lag = last_cycle().map(|last_cycle| now_secs().saturating_sub(last_cycle));
+    watchdog::is_stalled()
+        || READY.load(Ordering::Relaxed)
+            && !pause::is_paused()
+            && sync_lag.is_none_or(|lag| lag > MAX_CYCLE_AGE.as_secs())
+}
+
+/// How far behind syncing is and how many errors there were
+pub fn status() -> serde_json::Value {
+    let now = now_secs();
+    let ago = |timestamp: u64| (timestamp != 0).then(|| now.saturating_sub(timestamp));
+
+    serde_json::json!({
+        "ready": READY.load(Ordering::Relaxed),
+        "paused": pause::is_paused(),
+        "stalled": is_stalled(),
+        "sync_lag_secs": ago(LAST_CYCLE.load(Ordering::Relaxed)),
+        "last_successful_cycle_secs_ago": ago(LAST_SUCCESSFUL_CYCLE.load(Ordering::Relaxed)),
+        "errors": ERRORS.load(Ordering::Relaxed),
+        "last_cycle_errors": LAST_CYCLE_ERRORS.load(Ordering::Relaxed),
+        "tracked_files": TRACKED_FILES.load(Ordering::Relaxed),
+        "backup_free_bytes": space::free(),
+        "low_on_space": space::is_low(),
+    })
+}
+
+/// The status code and body for a probe of `path`
+#[cfg(feature = "health-endpoint")]
+fn respond(path: &str) -> (&'static str, serde_json::Value) {
+    let healthy = match path {
+        "/healthz" => !is_stalled(),
+        "/readyz" => READY.load(Ordering::Relaxed),
+        _ => return ("404 Not Found", serde_json::json!({ "error": "not found" })),
+    };
+    match healthy {
+        true => ("200 OK", status()),
+        false => ("503 Service Unavailable", status()),
+    }
+}
+
+/// Where the health checks are served, bound up front so that an address in use fails the start
+#[cfg(feature = "health-endpoint")]
+pub type Listener = std::net::TcpListener;
+#[cfg(not(feature = "health-endpoint"))]
+pub type Listener = std::convert::Infallible;
+
+#[cfg(feature = "health-endpoint")]
+pub fn bind(addr: SocketAddr) -> Result<Listener> {
+    use anyhow::Context;
+
+    let listener = std::net::TcpListener::bind(addr)
+        .with_context(|| anyhow::anyhow!("Error listening for health checks on {addr}"))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+#[cfg(not(feature = "health-endpoint"))]
+pub fn bind(_addr: SocketAddr) -> Result<Listener> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "health-endpoint")]
+pub async fn serve(listener: Listener) -> Result<()> {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    let addr = listener.local_addr()?;
+    let listener = TcpListener::from_std(listener)?;
+    crate::output::info!("Serving health checks on http://{addr}");
+
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+
+        tokio::task::spawn(async move {
+            // Only the request line matters, and it always fits in the first read
+            let mut request = [0; 1024];
+            let Ok(read) = stream.read(&mut request).await else {
+                return;
+            };
+            let request = String::from_utf8_lossy(&request[..read]);
+            let path = request.split_whitespace().nth(1).unwrap_or("/");
+            let path = path.split('?').next().unwrap_or(path);
+
+            let (status, body) = respond(path);
+            let body = body.to_string();
+            let response = format!(
+                "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+#[cfg(not(feature = "health-endpoint"))]
+pub async fn serve(listener: Listener) -> Result<()> {
+    match listener {}
+}
+
+pub fn unsupported() -> anyhow::Error {
+    anyhow::anyhow!(
+        "This evil_mount was built without health checks, enable the health-endpoint feature to use --health-addr"
+    )
+}
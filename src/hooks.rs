@@ -0,0 +1,223 @@
+//! Commands run on sync events with `--on-copy`, `--on-delete` and `--on-error`, like to
+//! invalidate a cache or send a notification. They run through the shell one at a time, in the
+//! order of the events, on a thread of their own so that a slow hook doesn't hold up syncing.
+//! What happened is in the environment:
+//!
+//! - `EVIL_MOUNT_EVENT`: `copy`, `delete` or `error`
+//! - `EVIL_MOUNT_PATH`: the file that was copied, the one deleted from backup_dir, or the one the
+//!   error is about. Left unset for errors that aren't about a file
+//! - `EVIL_MOUNT_DESTINATION`: where a file was copied to
+//! - `EVIL_MOUNT_MESSAGE`: what went wrong, for errors
+
+use anyhow::{anyhow, Result};
+use std::{
+    cell::Cell,
+    ffi::OsString,
+    path::Path,
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, SyncSender, TrySendError},
+        Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use crate::{
+    output::{self, info, Event},
+    platform, profiles,
+};
+
+/// How many hooks can wait to run before new ones are skipped
+const QUEUE_LEN: usize = 1000;
+
+pub struct Hooks {
+    pub on_copy: Option<String>,
+    pub on_delete: Option<String>,
+    pub on_error: Option<String>,
+}
+
+struct Run {
+    event: &'static str,
+    command: String,
+    env: Vec<(&'static str, OsString)>,
+}
+
+static QUEUE: OnceLock<SyncSender<Run>> = OnceLock::new();
+static HOOKS: OnceLock<Hooks> = OnceLock::new();
+/// How many hooks are queued or running
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+/// The hook running now
+static RUNNING: Mutex<Option<Child>> = Mutex::new(None);
+/// Set once the hooks were stopped on the way out, to skip the ones still queued
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// Set while reporting a problem with a hook, so that it doesn't run --on-error in turn
+    static REPORTING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs the commands in `hooks` from now on. Does nothing if none are set
+pub fn set(hooks: Hooks) -> Result<()> {
+    if hooks.on_copy.is_none() && hooks.on_delete.is_none() && hooks.on_error.is_none() {
+        return Ok(());
+    }
+
+    let (sender, receiver) = mpsc::sync_channel::<Run>(QUEUE_LEN);
+    std::thread::spawn(move || {
+        for run in receiver {
+            if STOPPED.load(Ordering::Relaxed) {
+                PENDING.fetch_sub(1, Ordering::Relaxed);
+                continue;
+            }
+            if let Err(err) = execute(&run) {
+                report(format!(
+                    "Error running --on-{} hook {}: {err:#}",
+                    run.event, run.command
+                ));
+            }
+            PENDING.fetch_sub(1, Ordering::Relaxed);
+        }
+    });
+
+    QUEUE
+        .set(sender)
+        .map_err(|_| anyhow!("hooks can only be set once"))?;
+    HOOKS
+        .set(hooks)
+        .map_err(|_| anyhow!("hooks can only be set once"))
+}
+
+/// Queues the hook for `event`, if there is one. Called for every emitted event
+pub fn observe(event: &Event) {
+    let Some(hooks) = HOOKS.get() else {
+        return;
+    };
+    if REPORTING.get() {
+        return;
+    }
+
+    let path = |path: &Path| ("EVIL_MOUNT_PATH", path.as_os_str().to_owned());
+    let (event, command, env) = match event {
+        Event::FileCopied {
+            source,
+            destination,
+        } => (
+            "copy",
+            &hooks.on_copy,
+            vec![
+                path(source),
+                ("EVIL_MOUNT_DESTINATION", destination.as_os_str().to_owned()),
+            ],
+        ),
+        Event::FileDeleted { path: deleted } => ("delete", &hooks.on_delete, vec![path(deleted)]),
+        Event::Error {
+            path: error_path,
+            message,
+        } => {
+            let mut env = vec![("EVIL_MOUNT_MESSAGE", message.into())];
+            env.extend(error_path.map(path));
+            ("error", &hooks.on_error, env)
+        }
+        _ => return,
+    };
+    let Some(command) = command else {
+        return;
+    };
+
+    let run = Run {
+        event,
+        command: command.clone(),
+        env,
+    };
+    PENDING.fetch_add(1, Ordering::Relaxed);
+    if let Err(TrySendError::Full(_)) = QUEUE.get().expect("set with HOOKS").try_send(run) {
+        PENDING.fetch_sub(1, Ordering::Relaxed);
+        report(format!(
+            "Skipping hooks, {QUEUE_LEN} are already waiting to run"
+        ));
+    }
+}
+
+/// Waits for the hooks that are queued or running to finish, before exiting, for up to `timeout`.
+/// The ones that don't make it in time are stopped
+pub async fn wait(timeout: Duration) {
+    let finished = tokio::time::timeout(timeout, async {
+        while PENDING.load(Ordering::Relaxed) > 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await;
+    if finished.is_err() {
+        info!(
+            "Stopping the hooks still running after {}s",
+            timeout.as_secs()
+        );
+        stop();
+    }
+}
+
+/// Kills the hook that's running and skips the ones still queued, for when they can't be waited
+/// for any longer
+pub fn stop() {
+    STOPPED.store(true, Ordering::Relaxed);
+    if let Some(child) = RUNNING.lock().unwrap().as_mut() {
+        platform::kill_process_group(child);
+    }
+}
+
+fn execute(run: &Run) -> Result<()> {
+    let mut command = match cfg!(windows) {
+        true => {
+            let mut command = Command::new("cmd");
+            command.args(["/C", &run.command]);
+            command
+        }
+        false => {
+            let mut command = Command::new("sh");
+            command.args(["-c", &run.command]);
+            command
+        }
+    };
+    // Of its own, so that whatever it started can be killed along with it
+    platform::own_process_group(&mut command);
+    let child = command
+        .env("EVIL_MOUNT_EVENT", run.event)
+        .envs(run.env.iter().map(|(name, value)| (name, value)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()?;
+
+    // Polled rather than waited on, so that stop can kill it in the meantime
+    let mut running = RUNNING.lock().unwrap();
+    *running = Some(child);
+    let status = loop {
+        // stop may have come first, on its way out
+        if STOPPED.load(Ordering::Relaxed) {
+            platform::kill_process_group(running.as_mut().expect("set above"));
+        }
+        match running.as_mut().expect("set above").try_wait()? {
+            Some(status) => break status,
+            None => {
+                drop(running);
+                std::thread::sleep(Duration::from_millis(50));
+                running = RUNNING.lock().unwrap();
+            }
+        }
+    };
+    *running = None;
+
+    match status.success() {
+        true => Ok(()),
+        false => Err(anyhow!("{}", profiles::describe(status))),
+    }
+}
+
+fn report(message: String) {
+    REPORTING.set(true);
+    output::emit(&Event::Error {
+        path: None,
+        message,
+    });
+    REPORTING.set(false);
+}
@@ -0,0 +1,47 @@
+//! Deferring heavy work, like the startup reconciliation or a verification pass, until the machine
+//! isn't being used, so evil_mount stays polite on shared desktops
+
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use crate::{output::info, platform, SHOULD_SHUTDOWN};
+
+/// How long the user must not have touched the keyboard or mouse
+const MIN_INPUT_IDLE: Duration = Duration::from_secs(5 * 60);
+/// The highest one minute load average, per CPU, that still counts as idle
+const MAX_LOAD_PER_CPU: f64 = 0.5;
+/// Heavy work is only ever deferred this long, so a machine that's never idle still gets backed up
+const MAX_WAIT: Duration = Duration::from_secs(60 * 60);
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Waits until the machine looks idle before doing `what`
+pub async fn wait_for_idle(what: &str) {
+    let start = Instant::now();
+    let mut announced = false;
+
+    while !is_idle() {
+        if start.elapsed() >= MAX_WAIT {
+            info!("The system still isn't idle, {what} anyway");
+            return;
+        }
+        if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+            return;
+        }
+        if !announced {
+            info!("Waiting for the system to become idle before {what}...");
+            announced = true;
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+fn is_idle() -> bool {
+    let input_idle =
+        platform::input_idle_time().is_none_or(|idle_time| idle_time >= MIN_INPUT_IDLE);
+    let load_idle = platform::load_per_cpu().is_none_or(|load| load <= MAX_LOAD_PER_CPU);
+
+    input_idle && load_idle
+}
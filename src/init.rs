@@ -0,0 +1,72 @@
+//! `evil_mount init`: sets up a backup_dir before the first sync, creating it if it doesn't exist
+//! and recording the format of its state directory. Given work_dir too, it checks the two can be
+//! synced, catching a mistyped path or a backup_dir inside work_dir before anything is copied
+//!
+//! Running it again on a backup_dir that's already set up changes nothing
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+use crate::{check_backup_dir, check_local, exit, format, overlap, state_dir};
+
+/// What `init` did
+#[derive(Debug, Serialize)]
+pub struct Initialized {
+    pub backup_dir: PathBuf,
+    pub work_dir: Option<PathBuf>,
+    /// Whether backup_dir had to be created
+    pub created: bool,
+    /// Whether backup_dir was already set up
+    pub existed: bool,
+}
+
+/// Sets up `backup_dir`, to back up `work_dir` if it's given
+pub fn run(work_dir: Option<&Path>, backup_dir: &Path) -> Result<Initialized> {
+    check_local(backup_dir)?;
+    if let Some(work_dir) = work_dir {
+        if !work_dir.is_dir() {
+            return Err(exit::unreachable(anyhow!("work_dir must be a directory!")));
+        }
+        overlap::check(("backup_dir", backup_dir), ("work_dir", work_dir))?;
+    }
+
+    let created = !backup_dir.exists();
+    std::fs::create_dir_all(backup_dir)
+        .with_context(|| anyhow!("Error creating {}", backup_dir.display()))?;
+    check_backup_dir(backup_dir)?;
+    let existed = format::exists(backup_dir)?;
+    format::upgrade(backup_dir)?;
+
+    Ok(Initialized {
+        backup_dir: backup_dir.to_path_buf(),
+        work_dir: work_dir.map(Path::to_path_buf),
+        created,
+        existed,
+    })
+}
+
+/// What `init` did, and how to start syncing
+pub fn render(initialized: &Initialized) -> String {
+    let backup_dir = initialized.backup_dir.display();
+    let mut report = match (initialized.existed, initialized.created) {
+        (true, _) => format!("{backup_dir} is already set up\n"),
+        (false, true) => format!(
+            "Created {backup_dir} and its state in {}\n",
+            state_dir(&initialized.backup_dir).display()
+        ),
+        (false, false) => format!(
+            "Set up {backup_dir}, with its state in {}\n",
+            state_dir(&initialized.backup_dir).display()
+        ),
+    };
+    let work_dir = initialized
+        .work_dir
+        .as_ref()
+        .map_or("[directory]".to_string(), |dir| dir.display().to_string());
+    report += &format!(
+        "Start syncing with: evil_mount --work-dir={work_dir} --backup-dir={backup_dir} sync\n"
+    );
+
+    report
+}
@@ -0,0 +1,338 @@
+//! An append-only journal of the changes being made to backup_dir, so that a crash in the middle
+//! of one doesn't leave it in a state nothing knows about. Every copy, deletion and commit of a
+//! transactional directory is written to `journal.jsonl` in the state dir and flushed to disk
+//! before it starts, and marked done once it's over. The next start finishes or undoes whatever
+//! wasn't marked done before syncing anything
+//!
+//! A copy is only ever renamed into place once it's complete, so one that was cut off leaves just
+//! its temporary file, which is removed. It's made again if the file is still there, and otherwise
+//! left to the deletions of the next pass. A deletion is looked at again, and the staged files of
+//! a transactional directory that hadn't been renamed into place yet are. Marking an operation
+//! done isn't flushed, since one that's finished again has nothing left to do
+//!
+//! Paths are written absolute, so that a start from another directory finishes the same
+//! operations
+//!
+//! The journal starts over whenever nothing is in flight and it has grown past
+//! [`COMPACT_AFTER`], and at every start once it has been recovered
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tokio::fs;
+
+use crate::{
+    convert_work_path_to_backup_path, copy, delete_if_removed,
+    deletion::DeletePolicy,
+    diff, encoded_path,
+    output::{self, info, Event},
+    read_only, state_dir, SyncReport,
+};
+
+/// How large the journal may get before it starts over, once nothing is in flight
+const COMPACT_AFTER: u64 = 1 << 20;
+
+/// A change to backup_dir, with what's needed to finish it again
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    /// Copying `path` from `from_dir` to where it belongs in `to_dir`
+    Copy {
+        #[serde(with = "encoded_path")]
+        path: PathBuf,
+        #[serde(with = "encoded_path")]
+        from_dir: PathBuf,
+        #[serde(with = "encoded_path")]
+        to_dir: PathBuf,
+    },
+    /// Deleting `path` from `backup_dir` if it's gone from `work_dir`
+    Delete {
+        #[serde(with = "encoded_path")]
+        path: PathBuf,
+        #[serde(with = "encoded_path")]
+        work_dir: PathBuf,
+        #[serde(with = "encoded_path")]
+        backup_dir: PathBuf,
+    },
+    /// Renaming the staged copies of a transactional directory into place
+    Commit { files: Vec<Staged> },
+}
+
+impl Operation {
+    /// The same operation with every path absolute
+    fn absolute(self) -> io::Result<Self> {
+        use std::path::absolute;
+
+        Ok(match self {
+            Operation::Copy {
+                path,
+                from_dir,
+                to_dir,
+            } => Operation::Copy {
+                path: absolute(path)?,
+                from_dir: absolute(from_dir)?,
+                to_dir: absolute(to_dir)?,
+            },
+            Operation::Delete {
+                path,
+                work_dir,
+                backup_dir,
+            } => Operation::Delete {
+                path: absolute(path)?,
+                work_dir: absolute(work_dir)?,
+                backup_dir: absolute(backup_dir)?,
+            },
+            Operation::Commit { files } => Operation::Commit {
+                files: files
+                    .into_iter()
+                    .map(|file| {
+                        Ok(Staged {
+                            path: absolute(file.path)?,
+                            staged_path: absolute(file.staged_path)?,
+                            dst_path: absolute(file.dst_path)?,
+                        })
+                    })
+                    .collect::<io::Result<_>>()?,
+            },
+        })
+    }
+}
+
+/// A file of a transactional directory, staged to be renamed into place
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Staged {
+    /// In work_dir
+    #[serde(with = "encoded_path")]
+    pub path: PathBuf,
+    #[serde(with = "encoded_path")]
+    pub staged_path: PathBuf,
+    #[serde(with = "encoded_path")]
+    pub dst_path: PathBuf,
+}
+
+/// A line of the journal
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Line {
+    Begin {
+        id: u64,
+        #[serde(flatten)]
+        operation: Operation,
+    },
+    Done {
+        done: u64,
+    },
+}
+
+struct Journal {
+    path: PathBuf,
+    file: File,
+    next_id: u64,
+    in_flight: HashSet<u64>,
+    /// How much was written since it last started over
+    written: u64,
+}
+
+static JOURNAL: Mutex<Option<Journal>> = Mutex::new(None);
+
+fn journal_path(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("journal.jsonl")
+}
+
+/// An operation written to the journal, marked done when dropped
+pub struct InFlight(Option<u64>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let Some(id) = self.0 else {
+            return;
+        };
+        let mut journal = JOURNAL.lock().unwrap();
+        let Some(journal) = journal.as_mut() else {
+            return;
+        };
+
+        journal.in_flight.remove(&id);
+        let result = match journal.in_flight.is_empty() && journal.written > COMPACT_AFTER {
+            true => journal.start_over(),
+            false => journal.write(&Line::Done { done: id }, false),
+        };
+        if let Err(err) = result {
+            output::emit(&Event::Error {
+                path: Some(&journal.path),
+                message: format!("Error writing to the journal: {err}"),
+            });
+        }
+    }
+}
+
+impl Journal {
+    fn write(&mut self, line: &Line, flush: bool) -> io::Result<()> {
+        let mut text = serde_json::to_string(line)?;
+        text.push('\n');
+        self.file.write_all(text.as_bytes())?;
+        self.written += text.len() as u64;
+        if flush {
+            self.file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    fn start_over(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.written = 0;
+        self.file.sync_data()
+    }
+}
+
+/// Writes `operation` to the journal before it's started, if the journal is open. The operation
+/// is marked done when the returned guard is dropped. Failing to write it is reported, and the
+/// operation goes ahead anyway, only without being finished after a crash
+pub fn begin(operation: Operation) -> InFlight {
+    let mut journal = JOURNAL.lock().unwrap();
+    let Some(journal) = journal.as_mut() else {
+        return InFlight(None);
+    };
+
+    let id = journal.next_id;
+    journal.next_id += 1;
+    let written = operation
+        .absolute()
+        .and_then(|operation| journal.write(&Line::Begin { id, operation }, true));
+    if let Err(err) = written {
+        output::emit(&Event::Error {
+            path: Some(&journal.path),
+            message: format!("Error writing to the journal, going ahead without it: {err}"),
+        });
+        return InFlight(None);
+    }
+    journal.in_flight.insert(id);
+
+    InFlight(Some(id))
+}
+
+/// The operations in the journal of `backup_dir` that weren't marked done, in the order they were
+/// started. Lines cut off by the crash are left out
+fn unfinished(path: &Path) -> Result<Vec<Operation>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(anyhow!(err).context(format!("Error reading {}", path.display()))),
+    };
+
+    let mut operations = BTreeMap::new();
+    for line in BufReader::new(file).lines() {
+        match serde_json::from_str(&line?) {
+            Ok(Line::Begin { id, operation }) => {
+                operations.insert(id, operation);
+            }
+            Ok(Line::Done { done }) => {
+                operations.remove(&done);
+            }
+            Err(_) => (),
+        }
+    }
+
+    Ok(operations.into_values().collect())
+}
+
+/// Finishes what the last run on `backup_dir` left unfinished, then opens its journal for this run
+pub async fn recover(backup_dir: &Path, policy: DeletePolicy) -> Result<()> {
+    let path = journal_path(backup_dir);
+    let operations = unfinished(&path)?;
+    if !operations.is_empty() {
+        info!(
+            "Finishing {} changes to backup_dir the last run was in the middle of...",
+            operations.len()
+        );
+    }
+
+    let mut report = SyncReport::default();
+    for operation in operations {
+        if let Err((path, err)) = finish(operation, policy, &mut report).await {
+            report.record_error(&path, err);
+        }
+    }
+
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|file| {
+            file.set_len(0)?;
+            Ok(file)
+        })
+        .with_context(|| anyhow!("Error opening {}", path.display()))?;
+    *JOURNAL.lock().unwrap() = Some(Journal {
+        path,
+        file,
+        next_id: 0,
+        in_flight: HashSet::new(),
+        written: 0,
+    });
+
+    Ok(())
+}
+
+/// Finishes or undoes `operation`, returning the path it failed on if it did
+async fn finish(
+    operation: Operation,
+    policy: DeletePolicy,
+    report: &mut SyncReport,
+) -> Result<(), (PathBuf, anyhow::Error)> {
+    match operation {
+        Operation::Copy {
+            path,
+            from_dir,
+            to_dir,
+        } => match fs::try_exists(&path).await.unwrap_or(true) {
+            // Copying again starts by removing what the last try left
+            true => match diff::copy(&path, &from_dir, &to_dir).await {
+                Ok(dst_path) => report.record_copy(&path, &dst_path),
+                Err(err) => return Err((path, err)),
+            },
+            // The copy at the destination, if any, is whole, and whether it should go is up to
+            // the deletions like for any other file
+            false => {
+                let temp_path = convert_work_path_to_backup_path(path.clone(), from_dir, to_dir)
+                    .and_then(|dst_path| copy::temp_path(&dst_path))
+                    .map_err(|err| (path, err))?;
+                match fs::remove_file(&temp_path).await {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                        return Err((temp_path, err.into()))
+                    }
+                    _ => (),
+                }
+            }
+        },
+        Operation::Delete {
+            path,
+            work_dir,
+            backup_dir,
+        } => match delete_if_removed(&path, &work_dir, &backup_dir, policy).await {
+            Ok(true) => report.record_delete(&path),
+            Ok(false) => (),
+            Err(err) => return Err((path, err)),
+        },
+        Operation::Commit { files } => {
+            for file in files {
+                read_only::unlock(&file.dst_path).map_err(|err| (file.dst_path.clone(), err))?;
+                match fs::rename(&file.staged_path, &file.dst_path).await {
+                    Ok(()) => report.record_copy(&file.path, &file.dst_path),
+                    // Renamed into place before the crash
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                    Err(err) => return Err((file.dst_path, err.into())),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,110 @@
+//! Guards against a runaway process in work_dir filling up the backup disk. Files over
+//! `--max-file-size` are skipped, and nothing more is copied while work_dir holds more than
+//! `--max-total-size` bytes or `--max-files` files
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+};
+
+use crate::{
+    output::{self, info, Event},
+    stats::TreeSize,
+};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    pub max_file_size: Option<u64>,
+    pub max_total_size: Option<u64>,
+    pub max_files: Option<u64>,
+}
+
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+
+/// Files that were skipped for being too large, so that each is only warned about once
+static TOO_LARGE: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+/// Set while work_dir is over --max-total-size or --max-files
+static EXCEEDED: AtomicBool = AtomicBool::new(false);
+
+/// Sets the limits for the rest of the process. Must be called before anything is synced
+pub fn set(limits: Limits) {
+    LIMITS.set(limits).expect("the limits can only be set once");
+}
+
+fn limits() -> Limits {
+    LIMITS.get().copied().unwrap_or_default()
+}
+
+/// Whether `path` is over --max-file-size and shouldn't be copied. Warns the first time
+pub fn is_too_large(path: &Path) -> bool {
+    let Some(max_file_size) = limits().max_file_size else {
+        return false;
+    };
+    let Ok(size) = std::fs::metadata(path).map(|metadata| metadata.len()) else {
+        return false;
+    };
+
+    let mut too_large = TOO_LARGE.lock().unwrap();
+    match size > max_file_size {
+        true => {
+            if too_large.insert(path.to_path_buf()) {
+                output::emit(&Event::TooLarge { path, size });
+            }
+            true
+        }
+        false => {
+            too_large.remove(path);
+            false
+        }
+    }
+}
+
+/// Which limit `tree_size` is over, if any
+fn exceeded_limit(tree_size: &TreeSize) -> Option<String> {
+    let limits = limits();
+    if let Some(max_total_size) = limits.max_total_size.filter(|max| tree_size.bytes > *max) {
+        return Some(format!(
+            "holds over {max_total_size} bytes, more than --max-total-size allows"
+        ));
+    }
+    if let Some(max_files) = limits.max_files.filter(|max| tree_size.files > *max) {
+        return Some(format!(
+            "holds over {max_files} files, more than --max-files allows"
+        ));
+    }
+
+    None
+}
+
+/// Checks the part of work_dir counted so far against --max-total-size and --max-files, returning
+/// whether copying should stop. Going over the limits alerts with an error once, and copying stays
+/// stopped until a full scan finds work_dir back under them
+pub fn is_exceeded(tree_size: &TreeSize) -> bool {
+    if let Some(limit) = exceeded_limit(tree_size) {
+        if !EXCEEDED.swap(true, Ordering::Relaxed) {
+            output::emit(&Event::Error {
+                path: None,
+                message: format!("work_dir {limit}, so syncing is paused until it shrinks"),
+            });
+        }
+    }
+
+    EXCEEDED.load(Ordering::Relaxed)
+}
+
+/// Whether copying is stopped because work_dir was over the limits at its last scan
+pub fn exceeded() -> bool {
+    EXCEEDED.load(Ordering::Relaxed)
+}
+
+/// Resumes copying if a full scan of work_dir, which added up to `tree_size`, is back under the
+/// limits
+pub fn finish_scan(tree_size: &TreeSize) {
+    if exceeded_limit(tree_size).is_none() && EXCEEDED.swap(false, Ordering::Relaxed) {
+        info!("work_dir is back under the size limits, resuming syncing");
+    }
+}
@@ -0,0 +1,135 @@
+//! The files in backup_dir, kept in memory by the watch loop so that its deletion pass doesn't
+//! walk the whole backup every cycle. The listing is walked once, then kept up to date from the
+//! copies, moves and deletions evil_mount makes itself, and walked again every hour to pick up
+//! anything changed behind its back
+//!
+//! The deletion pass compares it with the work_dir paths the cycle's scan just saw, and only the
+//! few files that differ are looked at on disk, the same way as without the listing
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{output::Event, recursive_dir_finding, routes, state_dir, Found, WalkOptions};
+
+/// How long the listing is trusted before it's walked again
+const MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// A change to backup_dir seen while the listing was being walked, to apply once it's done
+enum Change {
+    Added(PathBuf),
+    Removed(PathBuf),
+}
+
+struct Listing {
+    /// backup_dir and the directories of the routes
+    roots: Vec<PathBuf>,
+    state_dir: PathBuf,
+    files: HashSet<PathBuf>,
+    walked_at: Instant,
+    /// Set while walking, since what the walk already passed won't see the changes
+    changes: Option<Vec<Change>>,
+}
+
+static LISTING: Mutex<Option<Listing>> = Mutex::new(None);
+
+/// The files in backup_dir, walking it if the listing is missing or too old. A walk adds what it
+/// comes across to `found`
+pub fn files(backup_dir: &Path, walk: &WalkOptions, found: &Found) -> HashSet<PathBuf> {
+    {
+        let mut listing = LISTING.lock().unwrap();
+        match listing.as_mut() {
+            Some(listing) if listing.walked_at.elapsed() < MAX_AGE => {
+                return listing.files.clone();
+            }
+            Some(listing) => listing.changes = Some(Vec::new()),
+            None => {
+                let mut roots = vec![backup_dir.to_path_buf()];
+                roots.extend(routes::dirs(backup_dir));
+                *listing = Some(Listing {
+                    roots,
+                    state_dir: state_dir(backup_dir),
+                    files: HashSet::new(),
+                    walked_at: Instant::now(),
+                    changes: Some(Vec::new()),
+                });
+            }
+        }
+    }
+
+    // Walked without holding the lock, which every event waits for
+    let roots = std::iter::once(backup_dir.to_path_buf()).chain(routes::dirs(backup_dir));
+    let mut files: HashSet<PathBuf> = roots
+        .flat_map(|root| recursive_dir_finding(&root, walk, found))
+        .map(|file_info| file_info.into_path())
+        .collect();
+
+    let mut listing = LISTING.lock().unwrap();
+    let listing = listing.as_mut().expect("the listing was set up above");
+    for change in listing.changes.take().unwrap_or_default() {
+        match change {
+            Change::Added(path) => files.insert(path),
+            Change::Removed(path) => files.remove(&path),
+        };
+    }
+    listing.files = files.clone();
+    listing.walked_at = Instant::now();
+
+    files
+}
+
+/// Walks backup_dir again next time, for when what gets walked changed
+pub fn invalidate() {
+    *LISTING.lock().unwrap() = None;
+}
+
+/// Drops `path` from the listing, for a file found to be gone
+pub fn forget(path: &Path) {
+    if let Some(listing) = LISTING.lock().unwrap().as_mut() {
+        listing.change(Change::Removed(path.to_path_buf()));
+    }
+}
+
+impl Listing {
+    fn change(&mut self, change: Change) {
+        let path = match &change {
+            Change::Added(path) | Change::Removed(path) => path,
+        };
+        if !self.roots.iter().any(|root| path.starts_with(root))
+            || path.starts_with(&self.state_dir)
+        {
+            return;
+        }
+
+        match &change {
+            Change::Added(path) => self.files.insert(path.clone()),
+            Change::Removed(path) => self.files.remove(path),
+        };
+        if let Some(changes) = &mut self.changes {
+            changes.push(change);
+        }
+    }
+}
+
+/// Keeps the listing up to date with the changes to backup_dir. Called for every emitted event
+pub fn observe(event: &Event) {
+    let mut listing = LISTING.lock().unwrap();
+    let Some(listing) = listing.as_mut() else {
+        return;
+    };
+
+    match event {
+        Event::FileCopied { destination, .. } => {
+            listing.change(Change::Added(destination.to_path_buf()))
+        }
+        Event::FileMoved { from, to } => {
+            listing.change(Change::Removed(from.to_path_buf()));
+            listing.change(Change::Added(to.to_path_buf()));
+        }
+        Event::FileDeleted { path } => listing.change(Change::Removed(path.to_path_buf())),
+        _ => (),
+    }
+}
@@ -0,0 +1,140 @@
+//! One writer at a time per destination path. The startup reconciliation, the watch loop, the sync
+//! task of each file, scheduled verifications and control commands can all decide to write the
+//! same file at once, and two copies racing into one destination can leave it torn or deleted.
+//! Everything that writes, renames or deletes a destination holds its lock while doing so, which
+//! also tells shutdown how many writes are still in flight
+//!
+//! The same goes for whole processes: only one at a time may write to a backup_dir, so a second
+//! sync started by mistake fails before touching anything rather than fighting the first
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+use tokio::sync::OwnedMutexGuard;
+
+use crate::{platform, state_dir};
+
+type PathLock = tokio::sync::Mutex<()>;
+
+/// The lock of every path that's held or being waited for. Entries stay behind once their lock is
+/// dropped until the map is pruned
+static LOCKS: Mutex<BTreeMap<PathBuf, Weak<PathLock>>> = Mutex::new(BTreeMap::new());
+/// How large the map may grow before it's pruned again
+static PRUNE_AT: AtomicUsize = AtomicUsize::new(1024);
+/// How many guards are held right now
+static HELD: AtomicUsize = AtomicUsize::new(0);
+
+/// Held while writing a destination path
+pub struct PathGuard {
+    _guard: OwnedMutexGuard<()>,
+}
+
+impl Drop for PathGuard {
+    fn drop(&mut self) {
+        HELD.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Waits until no destination is being written
+pub async fn wait_for_writes() {
+    while HELD.load(Ordering::Relaxed) > 0 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// How many destinations are being written right now
+pub fn writes_in_flight() -> usize {
+    HELD.load(Ordering::Relaxed)
+}
+
+/// The destinations being written right now
+pub fn held() -> Vec<PathBuf> {
+    LOCKS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, lock)| lock.upgrade().is_some_and(|lock| lock.try_lock().is_err()))
+        .map(|(path, _)| path.clone())
+        .collect()
+}
+
+/// Waits until nothing else is writing `path`, and keeps others from writing it until the guard
+/// is dropped
+pub async fn lock(path: &Path) -> PathGuard {
+    let lock = {
+        let mut locks = LOCKS.lock().unwrap();
+
+        let lock = match locks.get(path).and_then(Weak::upgrade) {
+            Some(lock) => lock,
+            None => {
+                let lock = Arc::new(PathLock::new(()));
+                locks.insert(path.to_path_buf(), Arc::downgrade(&lock));
+                lock
+            }
+        };
+
+        if locks.len() >= PRUNE_AT.load(Ordering::Relaxed) {
+            locks.retain(|_, lock| lock.strong_count() > 0);
+            PRUNE_AT.store((locks.len() * 2).max(1024), Ordering::Relaxed);
+        }
+
+        lock
+    };
+
+    let guard = lock.lock_owned().await;
+    HELD.fetch_add(1, Ordering::Relaxed);
+
+    PathGuard { _guard: guard }
+}
+
+/// The file in the state dir that the lock of a whole backup_dir is taken on
+pub const BACKUP_DIR_LOCK: &str = "lock";
+
+/// Held by the process writing to a backup_dir, until it exits
+pub struct BackupDirGuard {
+    _file: File,
+}
+
+/// Takes the lock of `backup_dir` for this process, failing if another one holds it
+pub fn lock_backup_dir(backup_dir: &Path) -> Result<BackupDirGuard> {
+    let path = state_dir(backup_dir).join(BACKUP_DIR_LOCK);
+    std::fs::create_dir_all(state_dir(backup_dir))?;
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| anyhow!("Error opening {}", path.display()))?;
+
+    match platform::try_lock(&file)? {
+        true => Ok(BackupDirGuard { _file: file }),
+        false => Err(anyhow!(
+            "Another evil_mount is already writing to {}, stop it first",
+            backup_dir.display()
+        )),
+    }
+}
+
+/// Locks two paths, like both ends of a rename, always in the same order so that two renames in
+/// opposite directions can't wait on each other forever
+pub async fn lock_both(a: &Path, b: &Path) -> (PathGuard, Option<PathGuard>) {
+    match a.cmp(b) {
+        std::cmp::Ordering::Less => {
+            let first = lock(a).await;
+            (first, Some(lock(b).await))
+        }
+        std::cmp::Ordering::Greater => {
+            let first = lock(b).await;
+            (first, Some(lock(a).await))
+        }
+        std::cmp::Ordering::Equal => (lock(a).await, None),
+    }
+}
@@ -1,330 +1,3554 @@
 use anyhow::{anyhow, Context, Result};
-use blake3::{Hash, Hasher};
-use ignore::DirEntry;
+use futures::StreamExt;
+use ignore::{overrides::OverrideBuilder, DirEntry};
 use rayon::prelude::*;
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet},
     fs::FileType,
-    path::{Path, PathBuf},
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    process::ExitCode,
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
-        Arc,
+        Arc, Mutex, RwLock,
     },
-    time::{Duration, UNIX_EPOCH},
-};
-use tokio::{
-    fs::{self, remove_dir_all, remove_file},
-    io,
-    task::JoinHandle,
-    time::Instant,
+    time::{Duration, Instant, UNIX_EPOCH},
 };
+use tokio::{fs, io, sync::Notify, task::JoinHandle};
+
+use clap::{error::ErrorKind, CommandFactory, FromArgMatches, Parser, Subcommand};
 
-use clap::Parser;
+mod alert;
+mod bandwidth;
+mod calibration;
+mod coalesce;
+mod completions;
+mod consumer;
+mod containment;
+mod control;
+mod cooperation;
+mod copier;
+mod copy;
+mod crash;
+mod dedup;
+mod deletion;
+mod diff;
+mod digest;
+mod directories;
+mod dr_test;
+mod dupes;
+mod encoded_path;
+mod exit;
+mod faults;
+mod filters;
+mod format;
+mod full_pass;
+mod hashing;
+mod health;
+mod hooks;
+mod idle;
+mod init;
+mod journal;
+mod limits;
+mod listing;
+mod locks;
+mod man;
+mod metadata_only;
+mod mirror;
+mod moves;
+mod notify;
+mod open_files;
+mod output;
+mod overlap;
+mod ownership;
+mod pause;
+mod platform;
+mod presence;
+mod profiles;
+mod protect;
+mod pull;
+mod races;
+mod read_only;
+mod reflink;
+mod reload;
+mod retry;
+mod routes;
+mod same_contents;
+mod sanitize;
+mod scheduled_verify;
+mod secrets;
+mod shutdown;
+mod signed_manifest;
+mod slots;
+mod snapshots;
+mod space;
+mod special;
+mod stats;
+mod status;
+mod stignore;
+mod supervisor;
+mod system;
+mod systemd;
+mod trace;
+mod transactional;
+mod units;
+mod unreadable;
+mod verify;
+mod versions;
+mod watch_state;
+mod watchdog;
+
+use deletion::{DeletePolicy, MaxDelete};
+use exit::Stage;
+use hashing::hash_file;
+use output::{info, Event, LogLevel, OutputFormat};
+use watch_state::WatchState;
 
 /// A program to backup files to a different directory
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// The directory that you will be working in, will be completely cleared
+    /// The directory that you will be working in. If backup_dir is newer, it will be made to match it.
+    /// Required by everything except `ctl` and `report`
     #[arg(short, long)]
-    work_dir: PathBuf,
+    work_dir: Option<PathBuf>,
 
-    /// The directory that will be copied to. Used to initialize source dir
+    /// The directory that will be copied to. Used to initialize source dir. Required by everything
+    /// except `ctl`
     #[arg(short, long)]
-    backup_dir: PathBuf,
+    backup_dir: Option<PathBuf>,
+
+    /// Sync every work_dir and backup_dir pair in this JSON file of profiles at once, each with
+    /// its own flags, instead of a single pair. Only works with `sync`
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["work_dir", "backup_dir"])]
+    profiles: Option<PathBuf>,
+
+    /// The --profiles file and the profile in it that this sync was started for, so that it can
+    /// reload its settings. Set by --profiles for the syncs it starts
+    #[arg(long, value_name = "FILE", hide = true, requires = "profile_name")]
+    profile_file: Option<PathBuf>,
+
+    #[arg(long, value_name = "NAME", hide = true, requires = "profile_file")]
+    profile_name: Option<String>,
+
+    /// Don't descend into directories on a different filesystem than the directory being walked
+    #[arg(long)]
+    one_file_system: bool,
+
+    /// Skip paths matching this gitignore style glob, relative to work_dir. Can be given multiple times
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Copy the files matching the gitignore style GLOB, relative to work_dir, to DIR rather than
+    /// backup_dir, like `*.psd=/mnt/nas/design`. Can be given multiple times, the first match wins
+    #[arg(long, value_name = "GLOB=DIR")]
+    route: Vec<routes::Route>,
+
+    /// Back up a whole operating system rooted at work_dir. Implies --one-file-system, excludes
+    /// pseudo filesystems, swapfiles and runtime state, and never overwrites work_dir at startup
+    #[arg(long)]
+    system_backup: bool,
+
+    /// Run on the backup machine against a work_dir that's a mirror another evil_mount or rsync
+    /// keeps up to date, without ever writing to it. Files are only copied once they stop
+    /// changing, and with --versions a file deleted from the mirror keeps its last copy as a version
+    #[arg(long)]
+    mirror_consumer: bool,
+
+    /// Keep work_dir a copy of a directory on another machine, pulled with rsync over SSH before
+    /// every pass, so the backup host starts the syncs. Implies --mirror-consumer
+    #[arg(long, value_name = "[USER@]HOST:PATH")]
+    pull_from: Option<String>,
+
+    /// Leave out files that look like secrets, such as private keys, browser cookies and wallets,
+    /// warning about each one
+    #[arg(long)]
+    exclude_secrets: bool,
+
+    /// Also skip what the patterns in the .stignore at the root of work_dir match, like Syncthing
+    /// does
+    #[arg(long)]
+    stignore: bool,
+
+    /// Fail instead of skipping files and directories that can't be read because of their
+    /// permissions
+    #[arg(long)]
+    require_all_readable: bool,
+
+    /// When to remove files from the backup that no longer exist in work_dir: `immediate`, `never`,
+    /// or `after-grace[:DURATION]` to wait until a file has been missing that long, like `10m` (5
+    /// minutes by default)
+    #[arg(long, value_name = "POLICY", default_value_t = DeletePolicy::Immediate)]
+    delete: DeletePolicy,
+
+    /// Delete nothing in a pass that would delete more than this many files from the backup, or
+    /// this percentage of them like `10%`, and raise an error instead. `ctl allow-deletes` lets
+    /// the next such pass through
+    #[arg(long, value_name = "LIMIT")]
+    max_delete: Option<MaxDelete>,
+
+    /// Never delete or overwrite the copies of files matching this gitignore style glob, relative
+    /// to work_dir, without a `ctl confirm` for that file. Can be given multiple times
+    #[arg(long, value_name = "GLOB")]
+    protect: Vec<String>,
+
+    /// The most backup_dir should hold, like `500G`, if that's less than the free space on its
+    /// filesystem. Used to forecast when it will be full
+    #[arg(long, value_name = "SIZE", value_parser = units::parse_size)]
+    quota: Option<u64>,
+
+    /// Keep at least this much free on backup_dir's filesystem, like `5G`. Copies that would leave
+    /// less fail, and syncing pauses while there's less
+    #[arg(long, value_name = "SIZE", default_value = "100M", value_parser = units::parse_size)]
+    min_free: u64,
+
+    /// Skip files larger than this, like `10G`, with a warning
+    #[arg(long, value_name = "SIZE", value_parser = units::parse_size)]
+    max_file_size: Option<u64>,
+
+    /// Stop copying and raise an error while work_dir holds more than this, like `500G`, so that a
+    /// runaway process can't fill up the backup disk
+    #[arg(long, value_name = "SIZE", value_parser = units::parse_size)]
+    max_total_size: Option<u64>,
+
+    /// Stop copying and raise an error while work_dir holds more than this many files
+    #[arg(long, value_name = "COUNT")]
+    max_files: Option<u64>,
+
+    /// Only sync while this file, relative to work_dir, exists, like one kept at the root of the
+    /// mount work_dir is on. Syncing pauses whenever work_dir disappears either way
+    #[arg(long, value_name = "PATH")]
+    mount_marker: Option<PathBuf>,
+
+    /// Whether to clone files instead of copying them on filesystems that support it, like Btrfs,
+    /// XFS and APFS. `auto` falls back to copying, and `always` fails instead
+    #[arg(long, value_enum, default_value_t = reflink::Reflink::Auto)]
+    reflink: reflink::Reflink,
+
+    /// Pipe files matching GLOB through the shell command COMMAND on their way into the backup,
+    /// like `*.env=sed 's/=.*/=REDACTED/'`. Can be given multiple times, and the first match wins
+    #[arg(long, value_name = "GLOB=COMMAND")]
+    filter: Vec<filters::Filter>,
+
+    /// What to do with FIFOs, sockets and device files, whose contents can't be copied: `skip` them
+    /// with a warning, or `recreate` an equivalent node in the backup
+    #[arg(long, value_enum, default_value_t = special::SpecialFiles::Skip)]
+    special_files: special::SpecialFiles,
+
+    /// Whether to escape names backup_dir can't store, like `a:b` or `CON` on exFAT or NTFS, rather
+    /// than failing to copy them. `auto` does on FAT, exFAT and NTFS, and on Windows
+    #[arg(long, value_enum, default_value_t = sanitize::SanitizeNames::Auto)]
+    sanitize_names: sanitize::SanitizeNames,
+
+    /// Only record the size, modify time and hash of files matching this glob instead of copying
+    /// them, for huge media that can be downloaded again. Can be given multiple times
+    #[arg(long, value_name = "GLOB")]
+    metadata_only: Vec<String>,
+
+    /// A directory in work_dir whose changed files reach backup_dir all together or not at all
+    /// within a cycle, like a project saved as several files. Can be given multiple times
+    #[arg(long, value_name = "DIR")]
+    transactional: Vec<PathBuf>,
+
+    /// How much of a file is read and written at once while copying it. Larger buffers help
+    /// targets with high latency, like network drives. Picked by the calibration when not given
+    #[arg(long, value_name = "SIZE", value_parser = copy::parse_buffer_size)]
+    copy_buffer_size: Option<usize>,
+
+    /// Copy at most this many files at once. With --profiles the limit is shared by every profile,
+    /// so that an idle one leaves its share to the busy ones
+    #[arg(long, value_name = "COUNT")]
+    max_concurrent_copies: Option<usize>,
+
+    /// Keep at most this many files open at once for copying, hashing and walking the trees. By
+    /// default, whatever the process's limit on open files leaves room for
+    #[arg(long, value_name = "COUNT")]
+    max_open_files: Option<usize>,
+
+    /// Let copies and deletions follow symlinks that lead outside of work_dir or backup_dir, like a
+    /// directory in backup_dir that's a symlink to elsewhere
+    #[arg(long)]
+    allow_symlink_escapes: bool,
+
+    /// The directory of slots --max-concurrent-copies shares with other processes, set by
+    /// --profiles for the syncs it starts
+    #[arg(
+        long,
+        value_name = "DIR",
+        hide = true,
+        requires = "max_concurrent_copies"
+    )]
+    copy_slots: Option<PathBuf>,
+
+    /// Benchmark the disks again to pick the copy buffer size, how many files are read at once and
+    /// how many threads hash them, instead of using what the first run picked
+    #[arg(long)]
+    recalibrate: bool,
+
+    /// Ask the kernel to read this far ahead of what's being copied, like `8M`, which helps slow
+    /// sources like USB drives. By default the kernel decides
+    #[arg(long, value_name = "SIZE", default_value = "0", value_parser = units::parse_size)]
+    readahead: u64,
+
+    /// Copy at most this many bytes a second, like `10M`, shared by every copy running at once.
+    /// Clones don't count
+    #[arg(long, value_name = "SIZE", value_parser = units::parse_size)]
+    bandwidth_limit: Option<u64>,
+
+    /// Before overwriting a file in backup_dir, keep up to this many of its previous copies in the
+    /// state dir, as `.evil_mount/versions/PATH.~1~` for the newest
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    versions: usize,
+
+    /// Store files with identical contents in backup_dir once, as hard links to blobs in the state
+    /// dir. Needs a backup_dir filesystem with hard links
+    #[arg(long)]
+    dedup: bool,
+
+    /// Read every copy back and compare it with its source by hash before counting it as synced
+    #[arg(long)]
+    verify_writes: bool,
+
+    /// Take write permissions away from the copies in backup_dir after every sync, and as root on
+    /// Linux make them immutable, so that nothing edits them by accident. evil_mount unlocks a
+    /// copy itself before replacing, renaming or deleting it
+    #[arg(long)]
+    lock_backup: bool,
+
+    /// How far apart a file's modify times in work_dir and backup_dir can be, like `2s`, while
+    /// still comparing their contents to tell whether it changed, for backups on NFS or SMB mounts
+    /// whose timestamps are coarse or whose clocks are skewed
+    #[arg(long, value_name = "DURATION", default_value_t = 0, value_parser = units::parse_secs)]
+    mtime_tolerance: u64,
+
+    /// How many files the startup reconciliation copies at once, which speeds up populating a new
+    /// backup of many small files. 4 by default, 16 with --target-profile=cloud
+    #[arg(long, value_name = "COUNT")]
+    init_concurrency: Option<usize>,
+
+    /// What kind of storage backup_dir is on. `cloud`, for mounts of cloud storage like rclone's,
+    /// holds small files back to copy them together at the end of every cycle
+    #[arg(long, value_enum, default_value_t = coalesce::TargetProfile::Local)]
+    target_profile: coalesce::TargetProfile,
+
+    /// How long the watch loop waits between looking for new and deleted files, like `30s` or `5m`
+    #[arg(long, value_name = "DURATION", default_value_t = 5, value_parser = units::parse_secs)]
+    interval: u64,
+
+    /// Only look for changes to files once per --interval pass instead of every 2 seconds, which
+    /// saves battery on phones. On by default under Termux
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true")]
+    poll_only: Option<bool>,
+
+    /// Verify the whole backup in the background this often, like `6h`, repairing any corruption
+    /// or drift it finds. It reads one file per device at a time and waits for the machine to be
+    /// idle unless --no-idle-wait is given
+    #[arg(long, value_name = "INTERVAL", value_parser = units::parse_interval)]
+    verify_interval: Option<Duration>,
+
+    /// How long shutting down waits for copies that are in flight to finish before giving up on
+    /// them, in which case the next start reconciles everything
+    #[arg(long, value_name = "DURATION", default_value_t = 60, value_parser = units::parse_secs)]
+    shutdown_timeout: u64,
+
+    /// Don't flush backup_dir to disk when shutting down. The next start still knows the shutdown
+    /// was clean, but a power cut soon after can lose what was saved
+    #[arg(long)]
+    no_shutdown_fsync: bool,
+
+    /// After every cycle that changed backup_dir, mirror it to this rsync daemon destination, like
+    /// `rsync://host/module/path`, with the rsync command. Only what changed is sent
+    #[arg(long, value_name = "URL")]
+    mirror_to: Option<String>,
+
+    /// Run this shell command after every file copied, with its path in `EVIL_MOUNT_PATH` and its copy
+    /// in `EVIL_MOUNT_DESTINATION`
+    #[arg(long, value_name = "CMD")]
+    on_copy: Option<String>,
+
+    /// Run this shell command after every file deleted from backup_dir, with its path in
+    /// `EVIL_MOUNT_PATH`
+    #[arg(long, value_name = "CMD")]
+    on_delete: Option<String>,
+
+    /// Run this shell command after every error, with the message in `EVIL_MOUNT_MESSAGE` and the
+    /// file it's about, if any, in `EVIL_MOUNT_PATH`
+    #[arg(long, value_name = "CMD")]
+    on_error: Option<String>,
+
+    /// Raise a desktop notification when errors pile up or the backup falls behind. Needs
+    /// notify-send, or osascript on macOS
+    #[arg(long)]
+    notify: bool,
+
+    /// How many errors within 10 minutes raise a notification with --notify
+    #[arg(long, value_name = "COUNT", default_value_t = 5, requires = "notify")]
+    notify_errors: usize,
+
+    /// How long without a cycle that finished without errors raises a notification with --notify,
+    /// like `1h`
+    #[arg(
+        long,
+        value_name = "DURATION",
+        default_value_t = 60 * 60,
+        value_parser = units::parse_secs,
+        requires = "notify"
+    )]
+    notify_behind: u64,
+
+    /// POST a JSON alert to this URL, like a Slack incoming webhook, when errors pile up, backup_dir
+    /// can't be reached or a verification finds copies that don't match. Needs curl
+    #[arg(long, value_name = "URL")]
+    alert_webhook: Option<String>,
+
+    /// Append everything each decision to copy or delete was based on to this file, one JSON object
+    /// per line, for `replay` to run them again. Meant for tracking down bugs, it grows quickly
+    #[arg(long, value_name = "FILE")]
+    trace: Option<PathBuf>,
+
+    /// How many errors within 10 minutes raise an alert with --alert-webhook
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = 10,
+        requires = "alert_webhook"
+    )]
+    alert_errors: usize,
+
+    /// Send a daily digest of what changed in backup_dir: `log` to print it, `mailto:ADDRESS` to
+    /// mail it with sendmail, or an http:// or https:// URL to POST it to like --alert-webhook
+    #[arg(long, value_name = "TARGET")]
+    digest: Option<digest::Target>,
+
+    /// After every cycle that changed backup_dir, save a manifest of the size and hash of every
+    /// file in it to the state dir, signed with this ed25519 SSH private key for `verify
+    /// --manifest`. Needs ssh-keygen
+    #[arg(long, value_name = "KEY")]
+    sign_manifest: Option<PathBuf>,
+
+    /// How to report progress. `json` prints newline delimited JSON events on stdout
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// What gets printed besides errors: `warning` adds warnings, like files being skipped, and
+    /// `info` progress messages too
+    #[arg(long, value_enum, default_value_t = LogLevel::Info)]
+    log_level: LogLevel,
+
+    /// Listen for control commands on this Unix socket while syncing, and where `ctl` connects to.
+    /// On Windows it's a named pipe, like `\\.\pipe\evil_mount`
+    #[arg(long, value_name = "PATH")]
+    control_socket: Option<PathBuf>,
+
+    /// Leave every change to backup_dir to an `evil_mount copier` listening on this socket, which
+    /// can run as another user, and only read backup_dir here
+    #[arg(long, value_name = "PATH")]
+    copier: Option<PathBuf>,
+
+    /// Serve `/healthz` and `/readyz` over HTTP on this address while syncing, like `127.0.0.1:8080`
+    #[arg(long, value_name = "ADDR")]
+    health_addr: Option<SocketAddr>,
+
+    /// Start heavy work, like the startup reconciliation or verification, right away instead of
+    /// waiting for the keyboard, mouse and CPU to be idle first
+    #[arg(long)]
+    no_idle_wait: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Sync work_dir into backup_dir. This is the default when no subcommand is given
+    Sync {
+        /// Perform a single reconciliation pass and exit instead of watching for changes.
+        /// Exits with a non-zero status if any file failed to sync
+        #[arg(long)]
+        once: bool,
+    },
+    /// Copy files from backup_dir back into work_dir
+    Restore {
+        /// Print how to rebuild the machine from a --system-backup on new disks instead of restoring
+        #[arg(long, conflicts_with_all = ["at", "paths"])]
+        system_plan: bool,
+
+        /// Restore from the named snapshot instead of the current contents of backup_dir
+        #[arg(long, value_name = "SNAPSHOT")]
+        at: Option<String>,
+
+        /// Translate owners with this file of `OLD:NEW` uid and `gid OLD:NEW` gid lines, for
+        /// machines where they're numbered differently. Owners are otherwise matched by name
+        #[arg(long, value_name = "FILE")]
+        owner_map: Option<PathBuf>,
+
+        /// Files or directories to restore, relative to work_dir. Restores everything if none are given
+        paths: Vec<PathBuf>,
+    },
+    /// Hash both directories and check that backup_dir has exactly the same contents as work_dir
+    Verify {
+        /// How many files to read at once from each device. Picked by the calibration when not given
+        #[arg(long)]
+        jobs_per_device: Option<usize>,
+
+        /// Start over instead of resuming an interrupted verification
+        #[arg(long)]
+        restart: bool,
+
+        /// Check backup_dir against its signed manifest from `--sign-manifest` instead of against
+        /// work_dir, which isn't needed then
+        #[arg(long)]
+        manifest: bool,
+
+        /// The public key the manifest must be signed with, like `KEY.pub`. Without it, only
+        /// damage to the manifest is caught, not a manifest signed again by someone else
+        #[arg(long, value_name = "PUBKEY", requires = "manifest")]
+        trusted_key: Option<PathBuf>,
+    },
+    /// Rehearse disaster recovery: restore everything into a throwaway directory, verify it, and
+    /// record the result in backup_dir. Doesn't need --work-dir
+    DrTest {
+        /// Restore the named snapshot instead of the current contents of backup_dir
+        #[arg(long, value_name = "SNAPSHOT")]
+        at: Option<String>,
+
+        /// Restore into this directory, which must be empty, instead of one in the temp dir
+        #[arg(long, value_name = "DIR")]
+        into: Option<PathBuf>,
+
+        /// Leave the restored files in place afterwards for inspection
+        #[arg(long)]
+        keep: bool,
+    },
+    /// Save what backup_dir holds now as a snapshot for `restore --at` and `dr-test --at`, made of
+    /// hard links to its files. Doesn't need --work-dir
+    Snapshot {
+        /// What to name it, instead of after the current time like `2026-10-14T173500Z`
+        name: Option<String>,
+    },
+    /// Show how syncing went over the last days, from the stats kept in backup_dir
+    Report {
+        /// How far back to look, in days like `30d` or weeks like `4w`
+        #[arg(long, default_value = "30d", value_parser = units::parse_days)]
+        last: u64,
+        /// Also list the COUNT files that were copied the most bytes of, like browser profiles and
+        /// VM images rewritten all day, which may be worth excluding
+        #[arg(long, value_name = "COUNT", num_args = 0..=1, default_missing_value = "20")]
+        churn: Option<usize>,
+    },
+    /// Create backup_dir if needed and set up its state directory, checking that it can hold a
+    /// backup of work_dir when that's given too. Syncing works without it, this just catches a
+    /// mistyped path before the first sync
+    Init,
+    /// List the files in work_dir with the same contents at different paths, and how much space
+    /// keeping one of each would save. Doesn't need --backup-dir
+    Dupes,
+    /// Copy a single file the way the sync does with --verify-writes: into a temporary file next to
+    /// DESTINATION that replaces it once it's read back and matches SOURCE, keeping its permissions, modification
+    /// time and, for root, its owner. Doesn't need --work-dir or --backup-dir
+    Copy {
+        source: PathBuf,
+        /// Where the copy goes, or a directory to copy it into
+        destination: PathBuf,
+        /// How many more times a failed copy is tried before giving up
+        #[arg(long, default_value_t = 3)]
+        retries: u32,
+    },
+    /// Make the changes to backup_dir for a sync started with --copier, as the user this runs as.
+    /// Doesn't need --work-dir
+    Copier {
+        /// The socket to listen on, which the sync's --copier points at
+        #[arg(long, value_name = "PATH")]
+        listen: PathBuf,
+    },
+    /// Reconcile a subtree of work_dir by hash right away, copying anything that differs, like
+    /// after restoring files by hand. Asks the daemon through --control-socket when given
+    Resync {
+        /// The subtree to reconcile, relative to work_dir
+        path: PathBuf,
+    },
+    /// Show what a sync is doing. Asks the daemon through --control-socket when given, and
+    /// otherwise reads the snapshot it last saved in backup_dir
+    Status {
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Run the decisions recorded with --trace again without touching any disk, and show where
+    /// they come out differently. Doesn't need --work-dir or --backup-dir
+    Replay {
+        /// The trace to replay
+        file: PathBuf,
+    },
+    /// Send a command to a running sync through its --control-socket
+    Ctl {
+        #[command(subcommand)]
+        request: control::Request,
+    },
+    /// Print the completion script for a shell. Doesn't need --work-dir or --backup-dir
+    Completions {
+        /// The shell to complete in
+        shell: completions::Shell,
+    },
+    /// Print the man page, in roff. Doesn't need --work-dir or --backup-dir
+    Man,
+}
+
+/// Name of the directory inside backup_dir that holds evil_mount's own state, such as snapshots.
+/// It is never synced, deleted or restored
+const STATE_DIR_NAME: &str = ".evil_mount";
+
+/// Files with this name anywhere in the tree hold gitignore style patterns of paths to skip, merged
+/// with the patterns of parent directories the same way `.gitignore` files are. They're synced like
+/// any other file, so the backup is walked with the same rules
+const IGNORE_FILE_NAME: &str = ".evil_mountignore";
+
+/// Fails unless backup_dir is a local directory, pointing out how to use a remote one
+fn check_backup_dir(backup_dir: &Path) -> Result<()> {
+    check_local(backup_dir)?;
+    match backup_dir.is_dir() {
+        true => Ok(()),
+        false => Err(exit::unreachable(anyhow!(
+            "backup_dir must be a directory!"
+        ))),
+    }
+}
+
+/// Fails if backup_dir is a URL rather than a local path, pointing out how to use a remote one
+fn check_local(backup_dir: &Path) -> Result<()> {
+    // Everything reads and writes backup_dir through the filesystem, so a remote one has to be
+    // mounted first
+    if let Some((scheme, _)) = backup_dir.to_str().and_then(|dir| dir.split_once("://")) {
+        return Err(match scheme {
+            "rsync" => anyhow!(
+                "backup_dir {} is an rsync URL, but only local directories are supported. Back up to a local directory and pass --mirror-to {} to mirror it to the rsync server",
+                backup_dir.display(),
+                backup_dir.display()
+            ),
+            _ => anyhow!(
+                "backup_dir {} is a {scheme} URL, but only local directories are supported. Mount it first, like with davfs2 or rclone mount for WebDAV, and pass the mount point",
+                backup_dir.display()
+            ),
+        });
+    }
+
+    Ok(())
+}
+
+fn state_dir(backup_dir: &Path) -> PathBuf {
+    backup_dir.join(STATE_DIR_NAME)
 }
 
 static SHOULD_SHUTDOWN: AtomicBool = AtomicBool::new(false);
+/// How many seconds apart the modify times of work_dir and backup_dir can be and still be too
+/// close to tell which is newer, for network filesystems with coarse or skewed timestamps
+static MTIME_TOLERANCE: AtomicU64 = AtomicU64::new(0);
+/// How many files in work_dir are currently being watched for changes
+static TRACKED_FILES: AtomicU64 = AtomicU64::new(0);
+/// Set by `--poll-only`, which has sync tasks look at their file once per pass of the watch loop
+static POLL_ONLY: AtomicBool = AtomicBool::new(false);
+/// Wakes the sync tasks with `--poll-only`, once per pass of the watch loop
+static POLLED: Notify = Notify::const_new();
+
+/// Options controlling which entries recursive_dir yields
+#[derive(Clone, Debug, Default)]
+struct WalkOptions {
+    /// Don't descend into directories on a different filesystem than the walk root
+    same_file_system: bool,
+    /// Gitignore style globs, relative to the walk root, of paths to skip. Shared by every clone, so
+    /// that reloading the config changes them everywhere
+    excludes: Arc<RwLock<Vec<String>>>,
+    /// Skip files that look like secrets, see [`secrets::PATTERNS`]
+    exclude_secrets: bool,
+}
+
+impl WalkOptions {
+    fn new(same_file_system: bool, excludes: Vec<String>, exclude_secrets: bool) -> Result<Self> {
+        // Make sure every glob is valid up front, so walking can't fail later on
+        build_overrides(Path::new("/"), &excludes)?;
+
+        Ok(Self {
+            same_file_system,
+            excludes: Arc::new(RwLock::new(excludes)),
+            exclude_secrets,
+        })
+    }
 
-enum TruthSourceKind {
-    WorkDir,
-    BackupDir,
+    fn overrides(&self, root: &Path) -> Result<ignore::overrides::Override> {
+        build_overrides(root, &self.excludes.read().unwrap())
+    }
+
+    fn excludes(&self) -> Vec<String> {
+        self.excludes.read().unwrap().clone()
+    }
+
+    /// Replaces the excludes of this and every clone, if they're all valid
+    fn set_excludes(&self, excludes: Vec<String>) -> Result<()> {
+        build_overrides(Path::new("/"), &excludes)?;
+        *self.excludes.write().unwrap() = excludes;
+
+        Ok(())
+    }
+}
+
+fn build_overrides(root: &Path, excludes: &[String]) -> Result<ignore::overrides::Override> {
+    let mut overrides = OverrideBuilder::new(root);
+    for exclude in excludes {
+        overrides
+            .add(&format!("!{exclude}"))
+            .with_context(|| anyhow!("Invalid exclude pattern {exclude}"))?;
+    }
+
+    Ok(overrides.build()?)
+}
+
+/// Options controlling how work_dir is synced into backup_dir
+#[derive(Clone, Debug, Default)]
+struct SyncOptions {
+    walk: WalkOptions,
+    delete_policy: DeletePolicy,
+    max_delete: Option<MaxDelete>,
+    /// The most backup_dir should hold, for forecasting when it will be full
+    quota: Option<u64>,
+    /// How many seconds the watch loop waits between passes. Shared by every clone, so that
+    /// reloading the config changes it everywhere
+    interval: Arc<AtomicU64>,
+    /// How often the watch loop verifies the whole backup, if at all
+    verify_interval: Option<Duration>,
+    /// How long shutting down waits for copies in flight
+    shutdown_timeout: Duration,
+    /// Whether shutting down flushes backup_dir to disk before marking the shutdown clean
+    shutdown_fsync: bool,
+    /// How many files reconciling copies at once
+    init_concurrency: usize,
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
+    let result = run().await;
+    output::flush_errors();
+    match result {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Error: {:?}", err.inner());
+            err.code().into()
+        }
+    }
+}
+
+async fn run() -> Result<ExitCode, exit::Error> {
+    // Kept for --profiles to tell which flags were given on the command line
+    let matches = Args::command().get_matches();
     let Args {
         work_dir,
         backup_dir,
-    } = Args::parse();
-    // Ensure that source_dir and backup_dir are folders
-    if !work_dir.is_dir() {
-        return Err(anyhow!("work_dir must be a directory!"));
+        profiles,
+        profile_file,
+        profile_name,
+        one_file_system,
+        mut exclude,
+        route,
+        exclude_secrets,
+        stignore,
+        system_backup,
+        mirror_consumer,
+        pull_from,
+        require_all_readable,
+        delete,
+        max_delete,
+        protect,
+        quota,
+        min_free,
+        max_file_size,
+        max_total_size,
+        max_files,
+        mount_marker,
+        reflink,
+        filter,
+        special_files,
+        sanitize_names,
+        metadata_only,
+        transactional,
+        copy_buffer_size,
+        max_concurrent_copies,
+        max_open_files,
+        allow_symlink_escapes,
+        copy_slots,
+        recalibrate,
+        readahead,
+        bandwidth_limit,
+        versions,
+        dedup,
+        verify_writes,
+        lock_backup,
+        mtime_tolerance,
+        poll_only,
+        init_concurrency,
+        target_profile,
+        interval,
+        verify_interval,
+        shutdown_timeout,
+        no_shutdown_fsync,
+        mirror_to,
+        on_copy,
+        on_delete,
+        on_error,
+        notify,
+        notify_errors,
+        notify_behind,
+        alert_webhook,
+        alert_errors,
+        digest,
+        trace,
+        sign_manifest,
+        output,
+        log_level,
+        control_socket,
+        copier,
+        health_addr,
+        no_idle_wait,
+        command,
+    } = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    output::set_format(output);
+    output::set_level(log_level);
+    faults::load().config_error()?;
+    if platform::CAPABILITIES.signals && profile_name.is_some() {
+        reload::catch_sighup();
+    }
+    match &command {
+        Some(Command::Completions { shell }) => {
+            print!("{}", completions::generate(*shell, Args::command()));
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Man) => {
+            print!("{}", man::render(Args::command()));
+            return Ok(ExitCode::SUCCESS);
+        }
+        _ => (),
+    }
+    let init_concurrency = init_concurrency.unwrap_or(target_profile.init_concurrency());
+    coalesce::set(target_profile);
+    if init_concurrency == 0 {
+        return Err(exit::Error::Config(anyhow!(
+            "--init-concurrency must be more than 0"
+        )));
+    }
+    if health_addr.is_some() && !cfg!(feature = "health-endpoint") {
+        return Err(exit::Error::Config(health::unsupported()));
+    }
+    if control_socket.is_some()
+        && !(platform::CAPABILITIES.control_socket && cfg!(feature = "control-socket"))
+    {
+        return Err(exit::Error::Config(control::unsupported()));
+    }
+    reflink::set_mode(reflink);
+    bandwidth::set_limit(bandwidth_limit);
+    special::set_mode(special_files);
+    copy::VERIFY_WRITES.store(verify_writes, Ordering::Relaxed);
+    unreadable::REQUIRE_ALL.store(require_all_readable, Ordering::Relaxed);
+    limits::set(limits::Limits {
+        max_file_size,
+        max_total_size,
+        max_files,
+    });
+
+    if let Some(Command::Replay { file }) = &command {
+        let replay = trace::replay(file)?;
+        info!(
+            "Replayed {} decisions, {} came out differently",
+            replay.decisions, replay.diverged
+        );
+
+        return Ok(match replay.diverged {
+            0 => ExitCode::SUCCESS,
+            _ => exit::Code::Mismatch.into(),
+        });
+    }
+
+    if let Some(profiles) = &profiles {
+        let once = match &command {
+            Some(Command::Sync { once }) => *once,
+            None => false,
+            Some(Command::Status { json }) => {
+                let statuses = profiles::status(profiles)?;
+                match *json || matches!(output, OutputFormat::Json) {
+                    true => println!("{}", serde_json::to_string_pretty(&statuses)?),
+                    false => {
+                        for (name, snapshot) in &statuses {
+                            println!("== {name} ==");
+                            match snapshot.get("error").and_then(|err| err.as_str()) {
+                                Some(err) => println!("Unavailable: {err}\n"),
+                                None => println!("{}", status::render(snapshot)),
+                            }
+                        }
+                    }
+                }
+                return Ok(ExitCode::SUCCESS);
+            }
+            Some(_) => Args::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--profiles only works with sync and status, run other commands with --work-dir and --backup-dir of a profile",
+                )
+                .exit(),
+        };
+        let shared = profiles::shared_flags(&matches, &Args::command()).config_error()?;
+        return Ok(profiles::run(profiles, once, output, shared, max_concurrent_copies).await?);
     }
-    if !backup_dir.is_dir() {
-        return Err(anyhow!("backup_dir must be a directory!"));
+
+    if let Some(Command::Ctl { request }) = &command {
+        let Some(control_socket) = control_socket else {
+            Args::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "ctl needs --control-socket to know which daemon to talk to",
+                )
+                .exit();
+        };
+
+        if let control::Request::Events = request {
+            control::follow(&control_socket, |event| println!("{event}")).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        let response = control::send(&control_socket, request).await?;
+        return match response.ok {
+            true => {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&response.result.unwrap_or_default())?
+                );
+                Ok(ExitCode::SUCCESS)
+            }
+            false => Err(anyhow!(response.error.unwrap_or_default()).into()),
+        };
     }
 
-    println!("Checking the modification times of the directories",);
+    if let (Some(Command::Resync { path }), Some(control_socket)) = (&command, &control_socket) {
+        let request = control::Request::Resync { path: path.clone() };
+        let response = control::send(control_socket, &request).await?;
+        let report = match response.ok {
+            true => response.result.unwrap_or_default(),
+            false => return Err(anyhow!(response.error.unwrap_or_default()).into()),
+        };
+        let count = |field: &str| report.get(field).and_then(|count| count.as_u64());
+        info!(
+            "Copied {} files, {} errors",
+            count("copied").unwrap_or_default(),
+            count("errors").unwrap_or_default()
+        );
 
-    let work_dir_modify_time = dir_modify_time(&work_dir).await?;
-    let backup_dir_modify_time = dir_modify_time(&backup_dir).await?;
+        return Ok(match count("errors") {
+            Some(0) => ExitCode::SUCCESS,
+            _ => ExitCode::FAILURE,
+        });
+    }
 
-    let (source_of_truth, dir_to_init, truth_source_kind) =
-        match work_dir_modify_time > backup_dir_modify_time {
-            true => (&work_dir, &backup_dir, TruthSourceKind::WorkDir),
-            false => (&backup_dir, &work_dir, TruthSourceKind::BackupDir),
+    if let Some(Command::Init) = &command {
+        let Some(backup_dir) = &backup_dir else {
+            Args::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "init needs --backup-dir to know what to set up",
+                )
+                .exit();
         };
 
-    println!("Clearing {}...", dir_to_init.display());
-    while let Ok(Some(file_info)) = fs::read_dir(&dir_to_init)
-        .await
-        .with_context(|| anyhow!("Error reading the source directory"))?
-        .next_entry()
-        .await
+        let initialized = init::run(work_dir.as_deref(), backup_dir).init_error()?;
+        match output {
+            OutputFormat::Json => println!("{}", serde_json::to_string(&initialized)?),
+            OutputFormat::Text => print!("{}", init::render(&initialized)),
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(Command::Dupes) = &command {
+        let Some(work_dir) = work_dir else {
+            Args::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "dupes needs --work-dir to know where to look",
+                )
+                .exit();
+        };
+
+        let walk = WalkOptions::new(one_file_system || system_backup, exclude, exclude_secrets)
+            .config_error()?;
+        let groups = tokio::task::spawn_blocking(move || dupes::find(&work_dir, &walk))
+            .await
+            .map_err(anyhow::Error::from)?;
+        match output {
+            OutputFormat::Json => println!(
+                "{}",
+                serde_json::json!({
+                    "groups": groups,
+                    "wasted": groups.iter().map(dupes::Group::wasted).sum::<u64>(),
+                })
+            ),
+            OutputFormat::Text => print!("{}", dupes::render(&groups)),
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(Command::Copy {
+        source,
+        destination,
+        retries,
+    }) = &command
     {
-        let path = file_info.path();
-        match path.is_dir() {
-            true => remove_dir_all(&path).await.with_context(|| anyhow!("Error removing directory {path:?}"))?,
-            false => match file_type(&path).await.unwrap().is_file() {
-                true => remove_file(&path).await.with_context(|| anyhow!("Error removing file {path:?}"))?,
-                // not really sure what to do here
-                false => todo!(),
-            },
+        let copied = copy::copy_verified(source, destination, *retries).await?;
+        match output {
+            OutputFormat::Json => println!("{}", serde_json::to_string(&copied)?),
+            OutputFormat::Text => info!(
+                "Copied {} to {}, {} with hash {}",
+                copied.source.display(),
+                copied.destination.display(),
+                space::size(copied.bytes),
+                copied.hash.to_hex()
+            ),
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(Command::Report { last, churn }) = &command {
+        let Some(backup_dir) = &backup_dir else {
+            Args::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "report needs --backup-dir to know where the stats are",
+                )
+                .exit();
+        };
+
+        format::check(backup_dir)?;
+        let days = stats::last_days(backup_dir, *last)?;
+        let forecast = stats::forecast(&days, quota);
+        let churned = match churn {
+            Some(count) => Some(stats::most_churned(backup_dir, *last, *count)?),
+            None => None,
+        };
+        match output {
+            OutputFormat::Json => {
+                let mut report = serde_json::json!({
+                    "days": days.into_iter().collect::<HashMap<_, _>>(),
+                    "forecast": forecast,
+                });
+                if let Some(churned) = churned {
+                    report["churn"] = churned
+                        .into_iter()
+                        .map(|(path, churn)| {
+                            serde_json::json!({
+                                "path": path,
+                                "copies": churn.copies,
+                                "bytes": churn.bytes,
+                            })
+                        })
+                        .collect();
+                }
+                println!("{report}");
+            }
+            OutputFormat::Text => {
+                print!("{}", stats::render_report(&days, forecast.as_ref()));
+                if let Some(churned) = churned {
+                    print!("{}", stats::render_churn(&churned, &days));
+                }
+            }
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(Command::Status { json }) = &command {
+        let snapshot = match (&control_socket, &backup_dir) {
+            (Some(control_socket), _) => {
+                let response = control::send(control_socket, &control::Request::Status).await?;
+                match response.ok {
+                    true => response.result.unwrap_or_default(),
+                    false => return Err(anyhow!(response.error.unwrap_or_default()).into()),
+                }
+            }
+            (None, Some(backup_dir)) => {
+                format::check(backup_dir)?;
+                let mut snapshot = status::load(backup_dir)?;
+                // A rehearsal may have run since the snapshot was saved
+                snapshot["last_dr_test"] = serde_json::to_value(dr_test::last(backup_dir))?;
+                snapshot
+            }
+            (None, None) => Args::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "status needs --control-socket or --backup-dir to know which sync to show",
+                )
+                .exit(),
+        };
+
+        match *json || matches!(output, OutputFormat::Json) {
+            true => println!("{}", serde_json::to_string_pretty(&snapshot)?),
+            false => print!("{}", status::render(&snapshot)),
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(Command::Verify {
+        manifest: true,
+        trusted_key,
+        ..
+    }) = &command
+    {
+        let Some(backup_dir) = &backup_dir else {
+            Args::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "verify --manifest needs --backup-dir to know what to check",
+                )
+                .exit();
+        };
+        check_backup_dir(backup_dir)?;
+        format::check(backup_dir)?;
+
+        let walk = WalkOptions::new(one_file_system, exclude, exclude_secrets)?;
+        let report = signed_manifest::verify(backup_dir, trusted_key.as_deref(), &walk).await?;
+        info!(
+            "{} files match the manifest, {} missing, {} extra, {} mismatched, {} errors",
+            report.matched,
+            report.missing.len(),
+            report.extra.len(),
+            report.mismatched.len(),
+            report.errors
+        );
+
+        return Ok(match report.is_ok() {
+            true => ExitCode::SUCCESS,
+            false => ExitCode::FAILURE,
+        });
+    }
+
+    if let Some(Command::Copier { listen }) = &command {
+        let Some(backup_dir) = &backup_dir else {
+            Args::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "copier needs --backup-dir to know where to write",
+                )
+                .exit();
+        };
+        check_backup_dir(backup_dir)?;
+
+        copier::serve(backup_dir.clone(), listen.clone()).await?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(Command::Snapshot { name }) = &command {
+        let Some(backup_dir) = &backup_dir else {
+            Args::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "snapshot needs --backup-dir to know what to snapshot",
+                )
+                .exit();
+        };
+        check_backup_dir(backup_dir)?;
+        format::upgrade(backup_dir)?;
+
+        let taken = snapshots::take(backup_dir, name.as_deref()).await?;
+        match output {
+            OutputFormat::Json => println!("{}", serde_json::to_string(&taken)?),
+            OutputFormat::Text => info!(
+                "Took snapshot {}, linked {} files and copied {} files",
+                taken.name, taken.linked, taken.copied
+            ),
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(Command::DrTest { at, into, keep }) = &command {
+        let Some(backup_dir) = &backup_dir else {
+            Args::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "dr-test needs --backup-dir to know what to restore",
+                )
+                .exit();
+        };
+        check_backup_dir(backup_dir)?;
+        format::upgrade(backup_dir)?;
+
+        copy::set_tuning(copy::Tuning {
+            buffer_size: copy_buffer_size.unwrap_or(copy::DEFAULT_BUFFER_SIZE),
+            readahead,
+        });
+        let options = SyncOptions {
+            walk: WalkOptions::new(one_file_system, exclude, exclude_secrets)?,
+            delete_policy: delete,
+            max_delete,
+            quota,
+            interval: Arc::new(AtomicU64::new(interval)),
+            verify_interval,
+            shutdown_timeout: Duration::from_secs(shutdown_timeout),
+            shutdown_fsync: !no_shutdown_fsync,
+            init_concurrency,
         };
+        let rehearsal =
+            dr_test::run(backup_dir, at.as_deref(), into.as_deref(), *keep, &options).await?;
+        match output {
+            OutputFormat::Json => println!("{}", serde_json::to_string(&rehearsal)?),
+            OutputFormat::Text => info!(
+                "Disaster recovery rehearsal {} in {:.1}s: restored {} files with {} errors, {} verified, {} failed verification",
+                match rehearsal.passed() {
+                    true => "passed",
+                    false => "FAILED",
+                },
+                rehearsal.duration_secs,
+                rehearsal.restored,
+                rehearsal.errors,
+                rehearsal.verified,
+                rehearsal.failed
+            ),
+        }
+
+        return Ok(match (rehearsal.errors, rehearsal.failed) {
+            (0, 0) => ExitCode::SUCCESS,
+            (0, _) => exit::Code::Mismatch.into(),
+            _ => exit::Code::Errors.into(),
+        });
+    }
+
+    let (Some(work_dir), Some(backup_dir)) = (work_dir, backup_dir) else {
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "--work-dir and --backup-dir are required",
+            )
+            .exit();
+    };
+
+    // Everything that can refuse the arguments runs before anything is written, so that a mistake
+    // never leaves backup_dir half set up or a reconciliation half done
+    if !work_dir.is_dir() {
+        return Err(exit::Error::Unreachable(anyhow!(
+            "work_dir must be a directory!"
+        )));
+    }
+    overlap::check(("backup_dir", &backup_dir), ("work_dir", &work_dir)).config_error()?;
+    check_backup_dir(&backup_dir).config_error()?;
+    format::check(&backup_dir).config_error()?;
+
+    if system_backup {
+        system::check(&work_dir).config_error()?;
+        exclude.extend(system::DEFAULT_EXCLUDES.iter().map(|s| s.to_string()));
+    }
+    exclude.extend(cooperation::detect(&work_dir, &backup_dir));
+    let mirror_consumer = mirror_consumer || pull_from.is_some();
+    if let Some(pull_from) = pull_from {
+        pull::set(pull_from, work_dir.clone()).config_error()?;
+    }
+    if mirror_consumer {
+        if matches!(
+            command,
+            Some(Command::Restore {
+                system_plan: false,
+                ..
+            })
+        ) {
+            return Err(exit::Error::Config(anyhow!("--mirror-consumer never writes to work_dir, so restore by passing the directory to restore into as --work-dir without it")));
+        }
+        consumer::enable();
+        exclude.extend(consumer::EXCLUDES.iter().map(|s| s.to_string()));
+    }
+    let options = SyncOptions {
+        walk: WalkOptions::new(one_file_system || system_backup, exclude, exclude_secrets)
+            .config_error()?,
+        delete_policy: delete,
+        max_delete,
+        quota,
+        interval: Arc::new(AtomicU64::new(interval)),
+        verify_interval,
+        shutdown_timeout: Duration::from_secs(shutdown_timeout),
+        shutdown_fsync: !no_shutdown_fsync,
+        init_concurrency,
+    };
+    let through_copier = copier.is_some();
+    if let Some(copier) = copier {
+        // Each of these writes to backup_dir beyond what the copier does
+        let unsupported = [
+            (dedup, "--dedup"),
+            (versions > 0, "--versions"),
+            (!filter.is_empty(), "--filter"),
+            (!metadata_only.is_empty(), "--metadata-only"),
+            (!transactional.is_empty(), "--transactional"),
+            (
+                special_files == special::SpecialFiles::Recreate,
+                "--special-files recreate",
+            ),
+            (verify_writes, "--verify-writes"),
+            (lock_backup, "--lock-backup"),
+            (!route.is_empty(), "--route"),
+        ];
+        if let Some((_, flag)) = unsupported.iter().find(|(used, _)| *used) {
+            return Err(exit::Error::Config(anyhow!(
+                "{flag} writes to backup_dir itself, which doesn't work with --copier"
+            )));
+        }
+        copier::set(copier, backup_dir.clone()).config_error()?;
+    }
+    filters::set(&work_dir, &backup_dir, filter).config_error()?;
+    sanitize::set(&backup_dir, sanitize_names).config_error()?;
+    routes::set(route, &work_dir, &backup_dir).config_error()?;
+    if stignore {
+        stignore::set(&work_dir).config_error()?;
+    }
+    metadata_only::set(&work_dir, &backup_dir, &metadata_only).config_error()?;
+    transactional::set(&work_dir, &transactional).config_error()?;
+    protect::set(&backup_dir, &protect).config_error()?;
+    versions::set(&backup_dir, versions).config_error()?;
+    presence::set(&work_dir, mount_marker).config_error()?;
+    space::set(&backup_dir, min_free);
+    MTIME_TOLERANCE.store(mtime_tolerance, Ordering::Relaxed);
+    if mtime_tolerance > 0 || dedup {
+        same_contents::load(&backup_dir).config_error()?;
+    }
+    // Termux sets TERMUX_VERSION for everything started from it
+    let poll_only = poll_only.unwrap_or_else(|| {
+        let termux = std::env::var_os("TERMUX_VERSION").is_some();
+        if termux {
+            info!("Running under Termux, so files are only looked at once per pass to save battery. Pass --poll-only=false to look at them every 2 seconds");
+        }
+        termux
+    });
+    POLL_ONLY.store(poll_only, Ordering::Relaxed);
+    hooks::set(hooks::Hooks {
+        on_copy,
+        on_delete,
+        on_error,
+    })
+    .config_error()?;
+    if notify {
+        notify::set(notify_errors, Duration::from_secs(notify_behind)).config_error()?;
+    }
+    if let Some(alert_webhook) = alert_webhook {
+        alert::set(alert_webhook, backup_dir.clone(), alert_errors).config_error()?;
+    }
+    if let Some(digest) = digest {
+        digest::set(digest, &backup_dir).config_error()?;
+    }
+    if let Some(mirror_to) = mirror_to {
+        mirror::set(backup_dir.clone(), mirror_to).config_error()?;
+    }
+    if let (Some(profile_file), Some(profile_name)) = (profile_file, profile_name) {
+        reload::set_source(profile_file, profile_name).config_error()?;
+    }
+    if max_concurrent_copies == Some(0) {
+        return Err(exit::Error::Config(anyhow!(
+            "--max-concurrent-copies must be more than 0"
+        )));
+    }
+    open_files::set(max_open_files).config_error()?;
+    let syncing = matches!(command, Some(Command::Sync { .. }) | None);
+    let health = match (health_addr, &command) {
+        (Some(health_addr), Some(Command::Sync { once: false }) | None) => {
+            Some(health::bind(health_addr).config_error()?)
+        }
+        _ => None,
+    };
+
+    // Only now is anything written. The checks left need to write to tell, like whether backup_dir
+    // takes hard links, and none of them deletes or overwrites anything
+    // A copier makes the changes as a user that may be the only one able to write backup_dir
+    let writes = match command {
+        Some(Command::Restore { system_plan, .. }) => !system_plan,
+        Some(Command::Resync { .. }) => true,
+        _ => syncing,
+    };
+    let _lock = match writes && !through_copier {
+        true => Some(locks::lock_backup_dir(&backup_dir).init_error()?),
+        false => None,
+    };
+    routes::create_dirs().init_error()?;
+    {
+        let mut roots = vec![work_dir.as_path(), backup_dir.as_path()];
+        roots.extend(routes::all_dirs());
+        overlap::set(&roots);
+    }
+    if !allow_symlink_escapes {
+        let mut roots = vec![work_dir.as_path(), backup_dir.as_path()];
+        roots.extend(routes::all_dirs());
+        containment::set(&roots).init_error()?;
+    }
+    if dedup {
+        dedup::set(&backup_dir).init_error()?;
+    }
+    read_only::set(&backup_dir, lock_backup).init_error()?;
+    if let Some(sign_manifest) = sign_manifest {
+        signed_manifest::set(&backup_dir, sign_manifest, &options.walk).init_error()?;
+    }
+    if let Some(max_concurrent_copies) = max_concurrent_copies {
+        let dir = copy_slots.unwrap_or_else(|| state_dir(&backup_dir).join("copy_slots"));
+        slots::set(dir, max_concurrent_copies).init_error()?;
+    }
+    if let Some(trace) = trace {
+        trace::set(&trace, &work_dir, &backup_dir).init_error()?;
+    }
+    format::upgrade(&backup_dir).init_error()?;
+    crash::install(&backup_dir);
+
+    let calibration = match command {
+        Some(Command::Restore {
+            system_plan: true, ..
+        }) => calibration::DEFAULT,
+        _ => calibration::load_or_run(&work_dir, &backup_dir, &options.walk, recalibrate)
+            .unwrap_or_else(|err| {
+                output::emit(&Event::Error {
+                    path: None,
+                    message: format!("Calibration failed, using the defaults: {err:#}"),
+                });
+                calibration::DEFAULT
+            }),
+    };
+    copy::set_tuning(copy::Tuning {
+        buffer_size: copy_buffer_size.unwrap_or(calibration.buffer_size),
+        readahead,
+    });
+    // Only fails if something already started the pool, which leaves its own size in place
+    let _ = rayon::ThreadPoolBuilder::new()
+        .num_threads(calibration.hashing_threads)
+        .build_global();
+
+    if system_backup && syncing {
+        if let Err(err) = system::capture_metadata(&work_dir, &backup_dir) {
+            output::emit(&Event::Error {
+                path: None,
+                message: format!("Error capturing system metadata: {err:#}"),
+            });
+        }
+    }
+    if syncing {
+        if let Err(err) = ownership::save_names(&backup_dir) {
+            output::emit(&Event::Error {
+                path: None,
+                message: format!("Error saving the names of users and groups: {err:#}"),
+            });
+        }
+    }
+    let grace_period = matches!(options.delete_policy, DeletePolicy::AfterGrace(_));
+    if grace_period && syncing {
+        deletion::load(&backup_dir).init_error()?;
+    }
+    // Restoring only reads backup_dir, and leaves what the last sync was in the middle of to the next
+    let restoring = matches!(command, Some(Command::Restore { .. }));
+    if writes && !through_copier && !restoring {
+        journal::recover(&backup_dir, options.delete_policy)
+            .await
+            .init_error()?;
+    }
+    let pulled = match writes && !restoring {
+        true => pull::pull().await,
+        false => true,
+    };
+
+    match command {
+        Some(Command::Sync { once: true }) => {
+            // Stopping early leaves whatever was synced so far, and the next pass picks up the rest
+            tokio::task::spawn(async {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    info!("Stopping after the copies in flight...");
+                    SHOULD_SHUTDOWN.store(true, Ordering::Relaxed);
+                }
+            });
+            let started = Instant::now();
+            let report = sync_once(&work_dir, &backup_dir, &options).await;
+            status::LAST_CYCLE_MILLIS
+                .store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+            info!(
+                "Copied {} files, deleted {} files, {} errors",
+                report.copied, report.deleted, report.errors
+            );
+            if grace_period {
+                deletion::save(&backup_dir)?;
+            }
+            save_stats(&backup_dir, report.tree_size);
+            save_manifest(&backup_dir).await;
+            save_status(&work_dir, &backup_dir, &options, Some("stopped"));
+            unreadable::check()?;
+            let mirrored = mirror::wait().await;
+            hooks::wait(options.shutdown_timeout).await;
+            notify::wait().await;
+            alert::wait().await;
+            digest::finish().await;
+
+            Ok(
+                match (
+                    report.errors == 0 && !limits::exceeded(),
+                    mirrored && pulled,
+                ) {
+                    _ if SHOULD_SHUTDOWN.load(Ordering::Relaxed) => exit::Code::Interrupted.into(),
+                    (true, true) => ExitCode::SUCCESS,
+                    (true, false) => exit::Code::Unreachable.into(),
+                    (false, _) => exit::Code::Errors.into(),
+                },
+            )
+        }
+        Some(Command::Restore {
+            system_plan: true, ..
+        }) => {
+            print!("{}", system::restore_plan(&backup_dir)?);
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::Restore {
+            at,
+            owner_map,
+            paths,
+            ..
+        }) => {
+            let owner_map = ownership::OwnerMap::new(&backup_dir, owner_map.as_deref())?;
+            let (report, check) = restore(
+                &work_dir,
+                &backup_dir,
+                at.as_deref(),
+                &paths,
+                &owner_map,
+                &options,
+            )
+            .await?;
+            info!("Restored {} files, {} errors", report.copied, report.errors);
+            let (metadata_only_files, _) = metadata_only::totals();
+            if metadata_only_files > 0 {
+                info!("{metadata_only_files} files were only recorded with --metadata-only, so they weren't restored and have to be downloaded again");
+            }
+            info!(
+                "Verified {} restored files against the backup, {} failed",
+                check.verified.len(),
+                check.failed.len()
+            );
+
+            Ok(match (report.errors, check.failed.is_empty()) {
+                (0, true) => ExitCode::SUCCESS,
+                (0, false) => exit::Code::Mismatch.into(),
+                _ => exit::Code::Errors.into(),
+            })
+        }
+        Some(Command::Verify {
+            jobs_per_device,
+            restart,
+            ..
+        }) => {
+            if !no_idle_wait {
+                idle::wait_for_idle("verifying").await;
+            }
+
+            let report = verify::verify(
+                &work_dir,
+                &backup_dir,
+                &options.walk,
+                jobs_per_device.unwrap_or(calibration.jobs_per_device),
+                restart,
+                true,
+            )
+            .await?;
+            info!(
+                "{} files match, {} missing, {} extra, {} mismatched, {} errors",
+                report.matched,
+                report.missing.len(),
+                report.extra.len(),
+                report.mismatched.len(),
+                report.errors
+            );
+            save_stats(&backup_dir, None);
+            alert::wait().await;
+            unreadable::check()?;
+
+            Ok(match (report.is_ok(), report.errors) {
+                (true, _) => ExitCode::SUCCESS,
+                (false, 0) => exit::Code::Mismatch.into(),
+                (false, _) => exit::Code::Errors.into(),
+            })
+        }
+        Some(Command::Resync { path }) => {
+            let report = resync(&work_dir, &backup_dir, &path, &options).await?;
+            info!("Copied {} files, {} errors", report.copied, report.errors);
+
+            Ok(match report.errors == 0 {
+                true => ExitCode::SUCCESS,
+                false => ExitCode::FAILURE,
+            })
+        }
+        Some(
+            Command::Ctl { .. }
+            | Command::Report { .. }
+            | Command::Status { .. }
+            | Command::DrTest { .. }
+            | Command::Snapshot { .. }
+            | Command::Copier { .. }
+            | Command::Replay { .. }
+            | Command::Dupes
+            | Command::Copy { .. }
+            | Command::Init
+            | Command::Completions { .. }
+            | Command::Man,
+        ) => {
+            unreachable!(
+                "ctl, report, status, dr-test, snapshot, copier and replay are handled before validating directories"
+            )
+        }
+        Some(Command::Sync { once: false }) | None => {
+            watch(
+                work_dir,
+                backup_dir,
+                options,
+                system_backup,
+                control_socket,
+                health,
+                !no_idle_wait,
+            )
+            .await?;
+            Ok(ExitCode::SUCCESS)
+        }
+    }
+}
+
+async fn watch(
+    work_dir: PathBuf,
+    backup_dir: PathBuf,
+    options: SyncOptions,
+    system_backup: bool,
+    control_socket: Option<PathBuf>,
+    health: Option<health::Listener>,
+    idle_wait: bool,
+) -> Result<(), exit::Error> {
+    systemd::set();
+    retry::load(&backup_dir).init_error()?;
+    let last_run = shutdown::take_marker(&backup_dir).init_error()?;
+
+    // Started before the startup reconciliation, so that probes can tell it's still running
+    if let Some(health) = health {
+        tokio::task::spawn(async move {
+            if let Err(err) = health::serve(health).await {
+                output::emit(&Event::Error {
+                    path: None,
+                    message: format!("Health check server failed: {err:#}"),
+                });
+            }
+        });
+    }
+
+    if last_run == shutdown::LastRun::Unclean {
+        info!("The last run didn't shut down cleanly, checking everything instead of trusting what it saved");
+    }
+    // A state saved by a run that then crashed may be missing whatever happened afterwards
+    let state = WatchState::take(&backup_dir)
+        .init_error()?
+        .filter(|_| last_run == shutdown::LastRun::Clean);
+    let known_modify_times = match state {
+        Some(state) => {
+            info!("Found the state of the last clean shutdown, syncing what changed since then...");
+            let (report, known_modify_times) =
+                warm_start(&work_dir, &backup_dir, &options, state).await;
+            info!(
+                "Synced {}! Copied {} files, deleted {} files, {} errors",
+                backup_dir.display(),
+                report.copied,
+                report.deleted,
+                report.errors
+            );
+
+            known_modify_times
+        }
+        None => {
+            info!("Checking the modification times of the directories");
+
+            let work_dir_modify_time = dir_modify_time(&work_dir, &options.walk)
+                .await
+                .init_error()?;
+            let backup_dir_modify_time = dir_modify_time(&backup_dir, &options.walk)
+                .await
+                .init_error()?;
+
+            // Wiping the root of a running system because the backup looks newer would be catastrophic
+            let work_dir_is_newer = work_dir_modify_time + MTIME_TOLERANCE.load(Ordering::Relaxed)
+                > backup_dir_modify_time;
+            let (source_of_truth, dir_to_init) =
+                match system_backup || consumer::is_enabled() || work_dir_is_newer {
+                    true => (&work_dir, &backup_dir),
+                    false => (&backup_dir, &work_dir),
+                };
+
+            if idle_wait {
+                idle::wait_for_idle("reconciling").await;
+            }
+
+            info!(
+                "Reconciling {} with the contents of {}...",
+                dir_to_init.display(),
+                source_of_truth.display()
+            );
+            let report = reconcile(source_of_truth, dir_to_init, &options)
+                .await
+                .init_error()?;
+            info!(
+                "Reconciled {}! Copied {} files, deleted {} files, {} errors",
+                dir_to_init.display(),
+                report.copied,
+                report.deleted,
+                report.errors
+            );
+
+            HashMap::new()
+        }
+    };
+    read_only::lock_all(&backup_dir, &options.walk).await;
+    unreadable::check()?;
+    health::READY.store(true, Ordering::Relaxed);
+    systemd::ready();
+    watchdog::start(options.interval.clone());
+
+    if let Some(control_socket) = control_socket.clone() {
+        let context = control::Context {
+            work_dir: work_dir.clone(),
+            backup_dir: backup_dir.clone(),
+            options: options.clone(),
+        };
+        tokio::task::spawn(async move {
+            if let Err(err) = control::serve(control_socket, context).await {
+                output::emit(&Event::Error {
+                    path: None,
+                    message: format!("Control socket failed: {err:#}"),
+                });
+            }
+        });
+    }
+
+    tokio::task::spawn(output::expire_errors());
+    tokio::task::spawn({
+        let work_dir = work_dir.clone();
+        supervisor::supervise("pause watcher", move || {
+            let task = pause::watch(work_dir.clone());
+            async move {
+                task.await;
+                Ok(())
+            }
+        })
+    });
+    if platform::CAPABILITIES.signals && reload::has_source() {
+        tokio::task::spawn({
+            let options = options.clone();
+            async move {
+                if let Err(err) = reload::reload_on_sighup(options).await {
+                    output::emit(&Event::Error {
+                        path: None,
+                        message: format!("Error listening for SIGHUP: {err}"),
+                    });
+                }
+            }
+        });
+    }
+    if let Some(verify_interval) = options.verify_interval {
+        let (work_dir, backup_dir, options) =
+            (work_dir.clone(), backup_dir.clone(), options.clone());
+        tokio::task::spawn(supervisor::supervise("scheduled verification", move || {
+            let task = scheduled_verify::watch(
+                work_dir.clone(),
+                backup_dir.clone(),
+                options.clone(),
+                verify_interval,
+                idle_wait,
+            );
+            async move {
+                task.await;
+                Ok(())
+            }
+        }));
+    }
+    if platform::CAPABILITIES.signals {
+        tokio::task::spawn(async {
+            if let Err(err) = pause::toggle_on_sigusr1().await {
+                output::emit(&Event::Error {
+                    path: None,
+                    message: format!("Error listening for SIGUSR1: {err}"),
+                });
+            }
+        });
+    }
+
+    let copy_task = {
+        let (backup_dir, options) = (backup_dir.clone(), options.clone());
+        let mut known_modify_times = Some(known_modify_times);
+        // After a restart nothing is known to be in sync anymore, so every file is compared again
+        tokio::task::spawn(supervisor::supervise("copy loop", move || {
+            copy_files(
+                work_dir.clone(),
+                backup_dir.clone(),
+                options.clone(),
+                known_modify_times.take().unwrap_or_default(),
+            )
+        }))
+    };
+
+    tokio::signal::ctrl_c().await?;
+
+    SHOULD_SHUTDOWN.store(true, Ordering::Relaxed);
+    systemd::stopping();
+    match locks::writes_in_flight() {
+        0 => info!("Waiting for tokio tasks to shutdown..."),
+        writes => info!("Waiting for {writes} copies in flight to finish..."),
+    }
+
+    // The copy loop saves the state for the next start on its way out, once nothing is being
+    // written anymore
+    let finished = tokio::time::timeout(options.shutdown_timeout, async {
+        locks::wait_for_writes().await;
+        let finished = copy_task.await;
+        mirror::wait().await;
+        hooks::wait(options.shutdown_timeout).await;
+        notify::wait().await;
+        alert::wait().await;
+        digest::finish().await;
+        finished
+    })
+    .await;
+    let clean = finished.is_ok();
+    if !clean {
+        hooks::stop();
+        info!(
+            "Timed out after {}s with {} copies still in flight, the next start will reconcile everything",
+            options.shutdown_timeout.as_secs(),
+            locks::writes_in_flight()
+        );
+    }
+
+    if matches!(options.delete_policy, DeletePolicy::AfterGrace(_)) {
+        if let Err(err) = deletion::save(&backup_dir) {
+            output::emit(&Event::Error {
+                path: None,
+                message: format!("Error saving the pending deletions: {err:#}"),
+            });
+        }
+    }
+
+    if clean {
+        if let Err(err) = shutdown::mark_clean(&backup_dir, options.shutdown_fsync) {
+            output::emit(&Event::Error {
+                path: None,
+                message: format!("Error marking the shutdown as clean: {err:#}"),
+            });
+        }
+    }
+
+    if let Some(control_socket) = control_socket {
+        // Nothing else will clean it up, and a stale socket only confuses `ctl`. One systemd passed
+        // in is its to clean up
+        if !systemd::SOCKET_ACTIVATED.load(Ordering::Relaxed) {
+            let _ = fs::remove_file(control_socket).await;
+        }
+    }
+
+    info!("Done!");
+
+    Ok(())
+}
+
+/// Counts of what a single reconciliation pass did
+#[derive(Clone, Debug, Default)]
+struct SyncReport {
+    copied: u64,
+    moved: u64,
+    deleted: u64,
+    errors: u64,
+    /// The size of work_dir, if the pass scanned all of it
+    tree_size: Option<stats::TreeSize>,
+}
+
+impl SyncReport {
+    fn record_copy(&mut self, source: &Path, destination: &Path) {
+        self.copied += 1;
+        output::emit(&Event::FileCopied {
+            source,
+            destination,
+        });
+    }
+
+    fn record_move(&mut self, from: &Path, to: &Path) {
+        self.moved += 1;
+        output::emit(&Event::FileMoved { from, to });
+    }
+
+    fn record_delete(&mut self, path: &Path) {
+        self.deleted += 1;
+        output::emit(&Event::FileDeleted { path });
+    }
+
+    fn record_error(&mut self, path: &Path, err: anyhow::Error) {
+        // Whatever becomes of it is up to the next pass
+        if races::classify(path, &err) == Some(races::Race::Gone) {
+            return;
+        }
+        if unreadable::classify(path, &err) {
+            return;
+        }
+
+        self.errors += 1;
+        output::emit(&Event::Error {
+            path: Some(path),
+            message: format!("{err:#}"),
+        });
+    }
+
+    /// Reports the end of a pass
+    fn complete(&self) {
+        unreadable::summarize();
+        output::emit(&Event::CycleComplete {
+            copied: self.copied,
+            moved: self.moved,
+            deleted: self.deleted,
+            errors: self.errors,
+        });
+    }
+}
+
+/// Copies every new or changed file from work_dir into backup_dir, then removes anything from
+/// backup_dir that no longer exists in work_dir. New files with the same contents as one that's
+/// gone from work_dir are treated as moved, and renamed in backup_dir instead of copied. The whole
+/// diff is found before any of it is applied. Errors are printed and counted rather than aborting
+/// the pass
+async fn sync_once(work_dir: &Path, backup_dir: &Path, options: &SyncOptions) -> SyncReport {
+    hashing::forget();
+    let mut report = SyncReport::default();
+    // Only looked for once the first new file shows up, since it means walking backup_dir
+    let mut orphans = None;
+    let mut tree_size = stats::TreeSize::default();
+
+    let mut changes = Vec::new();
+    let found = Found::default();
+
+    for file_info in recursive_dir_finding(work_dir, &options.walk, &found) {
+        if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+            break;
+        }
+        let path = file_info.path();
+        tree_size.count(&file_info);
+        if limits::is_exceeded(&tree_size) || limits::is_too_large(path) {
+            continue;
+        }
+        // Left for the next pass while the mirror may still be writing it
+        if !consumer::is_settled(path).await {
+            continue;
+        }
+
+        match find_change(path, work_dir, backup_dir, options, &mut orphans).await {
+            Ok(Some(change)) => changes.push(change),
+            Ok(None) => (),
+            Err(err) => report.record_error(path, err),
+        }
+    }
+    // A pass cut short didn't see the whole tree, so it neither deletes nor counts as a cycle
+    if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+        return report;
+    }
+    limits::finish_scan(&tree_size);
+    let renamed = diff::renamed(&changes);
+    let mut transactions = transactional::Transactions::new(work_dir, backup_dir);
+    let removed: Vec<_> = removed_files(work_dir, backup_dir, options, None, &renamed, &found)
+        .await
+        .into_iter()
+        .filter_map(|path| transactions.take(diff::Change::Delete(path)))
+        .map(|deletion| deletion.path().to_path_buf())
+        .collect();
+    let changes: Vec<_> = changes
+        .into_iter()
+        .filter_map(|change| transactions.take(change))
+        .collect();
+
+    let mut changed = Vec::new();
+    for change in changes {
+        if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+            return report;
+        }
+        let result = diff::apply(
+            &change,
+            work_dir,
+            backup_dir,
+            options.delete_policy,
+            &mut report,
+        )
+        .await;
+        if let Err(err) = result {
+            match races::classify(change.path(), &err) {
+                Some(races::Race::Changed) => changed.push(change),
+                _ => report.record_error(change.path(), err),
+            }
+        }
+    }
+
+    // Files that changed while they were being copied get another go once the rest is done
+    for attempt in 1..=races::ATTEMPTS {
+        for change in std::mem::take(&mut changed) {
+            let result = diff::apply(
+                &change,
+                work_dir,
+                backup_dir,
+                options.delete_policy,
+                &mut report,
+            )
+            .await;
+            match result {
+                Ok(()) => (),
+                Err(err)
+                    if attempt < races::ATTEMPTS
+                        && races::classify(change.path(), &err) == Some(races::Race::Changed) =>
+                {
+                    changed.push(change)
+                }
+                Err(err) => report.record_error(change.path(), err),
+            }
+        }
+    }
+
+    let outcome = transactions.apply(options.delete_policy, &mut report).await;
+    for (path, err) in outcome.failed {
+        report.record_error(&path, err);
+    }
+    delete_files(removed, work_dir, backup_dir, options, &mut report).await;
+    if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+        return report;
+    }
+    dedup::collect_garbage().await;
+    sync_special_files(work_dir, backup_dir, options, &found, &mut report).await;
+    sync_directories(work_dir, backup_dir, options, &found, &mut report).await;
+    read_only::lock_all(backup_dir, &options.walk).await;
+    report.tree_size = Some(tree_size);
+    report.complete();
+
+    report
+}
+
+/// What `path` in work_dir needs to be in sync, if anything. A new file may have been renamed
+/// from one of the `orphans`, the copies left without a file, which are found the first time
+/// they're needed
+async fn find_change(
+    path: &Path,
+    work_dir: &Path,
+    backup_dir: &Path,
+    options: &SyncOptions,
+    orphans: &mut Option<moves::Orphans>,
+) -> Result<Option<diff::Change>> {
+    let Some(change) = diff::find(path, work_dir, backup_dir).await? else {
+        return Ok(None);
+    };
+
+    let backup_path = convert_work_path_to_backup_path(
+        path.to_path_buf(),
+        work_dir.to_path_buf(),
+        backup_dir.to_path_buf(),
+    )?;
+    // Renaming the old copy would delete it, which --delete=never forbids
+    // Orphans are only looked for in backup_dir, and can't be renamed into a route elsewhere
+    // A transactional directory's files are copied all together, so none are renamed
+    if options.delete_policy != DeletePolicy::Never
+        && routes::root_of(backup_dir, &backup_path) == backup_dir
+        && matches!(change, diff::Change::Create(_))
+        && !transactional::contains(path)
+    {
+        let orphans = match orphans {
+            Some(orphans) => orphans,
+            None => orphans.insert(moves::Orphans::find(work_dir, backup_dir, &options.walk).await),
+        };
+        if !orphans.is_empty() {
+            if let Some(from) = orphans.adopt(path).await? {
+                return Ok(Some(diff::Change::Rename {
+                    from,
+                    to: path.to_path_buf(),
+                }));
+            }
+        }
+    }
+
+    Ok(Some(change))
+}
+
+/// Brings `target` in line with `source_of_truth` without touching files that are already
+/// identical. Files that are missing or differ in size or content are copied over, and files that
+/// don't exist in `source_of_truth` are deleted
+async fn reconcile(
+    source_of_truth: &Path,
+    target: &Path,
+    options: &SyncOptions,
+) -> Result<SyncReport> {
+    hashing::forget();
+    let mut report = SyncReport::default();
+    let found = Found::default();
+    let mut to_copy = differing(source_of_truth, target, options, &found, &mut report).await?;
+
+    to_copy.retain(|path| !limits::is_too_large(path));
+    // Everything has to fit, rather than filling the disk partway through and failing the rest
+    let to_copy_bytes = to_copy
+        .iter()
+        .filter_map(|path| std::fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    space::ensure_room(target, to_copy_bytes).with_context(|| {
+        anyhow!(
+            "Error copying {} files from {} to {}",
+            to_copy.len(),
+            source_of_truth.display(),
+            target.display()
+        )
+    })?;
+
+    let mut copies = futures::stream::iter(to_copy)
+        .map(|path| async move {
+            let result = diff::copy(&path, source_of_truth, target).await;
+            (path, result)
+        })
+        .buffer_unordered(options.init_concurrency);
+    let mut changed = Vec::new();
+    while let Some((path, result)) = copies.next().await {
+        match result {
+            Ok(dst_path) => report.record_copy(&path, &dst_path),
+            Err(err) if races::classify(&path, &err) == Some(races::Race::Changed) => {
+                changed.push(path)
+            }
+            Err(err) => report.record_error(&path, err),
+        }
+    }
+    drop(copies);
+
+    // Files that changed while they were being copied get another go once the rest is done
+    for attempt in 1..=races::ATTEMPTS {
+        for path in std::mem::take(&mut changed) {
+            let result = diff::copy(&path, source_of_truth, target).await;
+            match result {
+                Ok(dst_path) => report.record_copy(&path, &dst_path),
+                Err(err)
+                    if attempt < races::ATTEMPTS
+                        && races::classify(&path, &err) == Some(races::Race::Changed) =>
+                {
+                    changed.push(path)
+                }
+                Err(err) => report.record_error(&path, err),
+            }
+        }
+    }
+
+    let removed = removed_files(
+        source_of_truth,
+        target,
+        options,
+        None,
+        &HashSet::new(),
+        &found,
+    )
+    .await;
+    delete_files(removed, source_of_truth, target, options, &mut report).await;
+    sync_special_files(source_of_truth, target, options, &found, &mut report).await;
+    sync_directories(source_of_truth, target, options, &found, &mut report).await;
+    report.complete();
+
+    Ok(report)
+}
+
+/// The files in `source_of_truth` whose copy in `target` is missing or has other contents, going by
+/// their hashes when the sizes match. Errors comparing a file are recorded in `report`
+async fn differing(
+    source_of_truth: &Path,
+    target: &Path,
+    options: &SyncOptions,
+    found: &Found,
+    report: &mut SyncReport,
+) -> Result<Vec<PathBuf>> {
+    let mut to_copy = Vec::new();
+    let mut same_size = Vec::new();
+
+    for file_info in recursive_dir_finding(source_of_truth, &options.walk, found) {
+        let path = file_info.into_path();
+        // The watch loop copies it once the mirror is done writing it
+        if !consumer::is_settled(&path).await {
+            continue;
+        }
+        let target_path = convert_work_path_to_backup_path(
+            path.clone(),
+            source_of_truth.to_path_buf(),
+            target.to_path_buf(),
+        )?;
+
+        if filters::for_path(&path).is_some() || metadata_only::matches(&path) {
+            match needs_copy(&path, source_of_truth, target).await {
+                Ok(true) => to_copy.push(path),
+                Ok(false) => (),
+                Err(err) => report.record_error(&path, err),
+            }
+            continue;
+        }
+
+        match (fs::metadata(&path).await, fs::metadata(&target_path).await) {
+            (Ok(metadata), Ok(target_metadata)) => match metadata.len() == target_metadata.len() {
+                true => same_size.push((path, target_path)),
+                false => to_copy.push(path),
+            },
+            (Ok(_), Err(err)) if err.kind() == io::ErrorKind::NotFound => to_copy.push(path),
+            (Err(err), _) | (_, Err(err)) => report.record_error(&path, err.into()),
+        }
+    }
+
+    // Only files with matching sizes need their contents compared
+    let compared = tokio::task::spawn_blocking(move || {
+        same_size
+            .into_par_iter()
+            .filter_map(
+                |(path, target_path)| match (hash_file(&path), hash_file(&target_path)) {
+                    (Ok(hash), Ok(target_hash)) => (hash != target_hash).then_some(Ok(path)),
+                    (Err(err), _) | (_, Err(err)) => Some(Err((path, err))),
+                },
+            )
+            .collect::<Vec<_>>()
+    })
+    .await?;
+
+    for result in compared {
+        match result {
+            Ok(path) => to_copy.push(path),
+            Err((path, err)) => report.record_error(&path, err),
+        }
+    }
+
+    Ok(to_copy)
+}
+
+/// Syncs only what changed in work_dir since the last clean shutdown described by `state`, without
+/// walking backup_dir. Returns the modify times of the files that are now in sync, keyed by their
+/// path in work_dir
+async fn warm_start(
+    work_dir: &Path,
+    backup_dir: &Path,
+    options: &SyncOptions,
+    mut state: WatchState,
+) -> (SyncReport, HashMap<PathBuf, u64>) {
+    let mut report = SyncReport::default();
+    let mut known_modify_times = HashMap::new();
+
+    for file_info in recursive_dir(work_dir, &options.walk) {
+        let path = file_info.into_path();
+        let Ok(relative_path) = path.strip_prefix(work_dir).map(Path::to_path_buf) else {
+            continue;
+        };
+
+        let result = async {
+            let current_modify_time = modify_time_secs(&path).await?;
+            let synced_modify_time = state.synced.remove(&relative_path);
+
+            let changed = synced_modify_time != Some(current_modify_time)
+                || state.pending.contains(&relative_path);
+            if changed
+                && !limits::is_too_large(&path)
+                && needs_copy(&path, work_dir, backup_dir).await?
+            {
+                let dst_path = diff::copy(&path, work_dir, backup_dir).await?;
+                report.record_copy(&path, &dst_path);
+            }
+
+            anyhow::Ok(current_modify_time)
+        }
+        .await;
+
+        match result {
+            Ok(modify_time) => {
+                known_modify_times.insert(path, modify_time);
+            }
+            Err(err) => report.record_error(&path, err),
+        }
+    }
+
+    // Whatever was synced last time but wasn't found now has been deleted since
+    for relative_path in state.synced.into_keys() {
+        let backup_path =
+            routes::root_for(backup_dir, &relative_path).join(sanitize::to_backup(&relative_path));
+        let deletion = diff::Change::Delete(backup_path);
+        if let Err(err) = diff::apply(
+            &deletion,
+            work_dir,
+            backup_dir,
+            options.delete_policy,
+            &mut report,
+        )
+        .await
+        {
+            report.record_error(deletion.path(), err);
+        }
+    }
+
+    report.complete();
+
+    (report, known_modify_times)
+}
+
+/// Copies the given paths (or everything) from backup_dir, or one of its snapshots, into work_dir.
+/// Files in work_dir that don't exist in the backup are left alone
+async fn restore(
+    work_dir: &Path,
+    backup_dir: &Path,
+    snapshot: Option<&str>,
+    paths: &[PathBuf],
+    owner_map: &ownership::OwnerMap,
+    options: &SyncOptions,
+) -> Result<(SyncReport, verify::RestoreCheck)> {
+    let source = match snapshot {
+        Some(name) => snapshots::dir(backup_dir, name)?,
+        None => backup_dir.to_path_buf(),
+    };
+
+    // Snapshots are only taken of backup_dir, so restoring from one leaves out the routes
+    let route_dirs = match snapshot {
+        Some(_) => Vec::new(),
+        None => routes::dirs(&source),
+    };
+    let roots = match paths.is_empty() {
+        true => std::iter::once(source.clone())
+            .chain(route_dirs.iter().filter(|dir| dir.exists()).cloned())
+            .collect(),
+        false => paths
+            .iter()
+            .map(|path| {
+                let relative_path = relative_to(path, work_dir)?;
+
+                // A directory can have files in every route's directory
+                let backup_relative_path = sanitize::translate(relative_path, work_dir, &source);
+                let backup_paths: Vec<_> = std::iter::once(&source)
+                    .chain(&route_dirs)
+                    .map(|root| root.join(&backup_relative_path))
+                    .filter(|backup_path| backup_path.exists())
+                    .collect();
+                match backup_paths.is_empty() {
+                    true => Err(anyhow!("{} does not exist in the backup", path.display())),
+                    false => Ok(backup_paths),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?
+            .concat(),
+    };
+
+    let mut report = SyncReport::default();
+    let mut restored = Vec::new();
+    let found = Found::default();
+
+    for root in roots {
+        for file_info in recursive_dir_finding(&root, &options.walk, &found) {
+            let path = file_info.path();
+
+            let result = async {
+                let dst_path =
+                    copy_to_dst(path.to_path_buf(), source.clone(), work_dir.to_path_buf()).await?;
+                owner_map.apply(path, &dst_path)?;
+                anyhow::Ok(dst_path)
+            }
+            .await;
+
+            match result {
+                Ok(dst_path) => {
+                    report.record_copy(path, &dst_path);
+                    restored.push((path.to_path_buf(), dst_path));
+                }
+                Err(err) => report.record_error(path, err),
+            }
+        }
+    }
+    // Empty directories in the backup are restored too, but nothing is removed from work_dir
+    let directories = std::mem::take(&mut *found.directories.lock().unwrap());
+    for path in directories {
+        let Ok(relative_path) = path.strip_prefix(routes::root_of(&source, &path)) else {
+            continue;
+        };
+        let work_path = work_dir.join(sanitize::translate(relative_path, &source, work_dir));
+        if let Err(err) = directories::create(&work_path).await {
+            report.record_error(&work_path, err);
+        }
+    }
+    report.complete();
+
+    let check = verify::check_restore(restored).await?;
+
+    Ok((report, check))
+}
+
+/// Reconciles the subtree `path` of work_dir with its copy in backup_dir, for `resync`
+async fn resync(
+    work_dir: &Path,
+    backup_dir: &Path,
+    path: &Path,
+    options: &SyncOptions,
+) -> Result<SyncReport> {
+    let (work_path, backup_path) = subtree(work_dir, backup_dir, path)?;
+    directories::create(&backup_path).await?;
+
+    let report = reconcile(&work_path, &backup_path, options).await?;
+    read_only::lock_changed().await;
+
+    Ok(report)
+}
+
+/// Like [`resync`] while the watch loop runs, which is asked to copy whatever differs in a full
+/// pass rather than having two passes write to backup_dir at once
+async fn resync_in_pass(
+    work_dir: &Path,
+    backup_dir: &Path,
+    path: &Path,
+    options: &SyncOptions,
+) -> Result<SyncReport> {
+    let (work_path, backup_path) = subtree(work_dir, backup_dir, path)?;
+    let mut compared = SyncReport::default();
+    let differing = differing(
+        &work_path,
+        &backup_path,
+        options,
+        &Found::default(),
+        &mut compared,
+    )
+    .await?;
+
+    let mut report = full_pass::run(differing).await?;
+    report.errors += compared.errors;
+
+    Ok(report)
+}
+
+/// The directory `path` in work_dir is, and where its copy is in backup_dir
+fn subtree(work_dir: &Path, backup_dir: &Path, path: &Path) -> Result<(PathBuf, PathBuf)> {
+    let relative_path = relative_to(path, work_dir)?;
+    let work_path = work_dir.join(relative_path);
+    if !work_path.is_dir() {
+        return Err(anyhow!("{} is not a directory", work_path.display()));
+    }
+
+    Ok((
+        work_path,
+        backup_dir.join(sanitize::to_backup(relative_path)),
+    ))
+}
+
+/// A path given on the command line relative to work_dir, whether it was relative or absolute.
+/// Fails for one that leads out of work_dir with `..`
+fn relative_to<'a>(path: &'a Path, work_dir: &Path) -> Result<&'a Path> {
+    let not_inside = || anyhow!("{} is not inside {}", path.display(), work_dir.display());
+    let relative_path = match path.is_absolute() {
+        false => path,
+        true => match path.strip_prefix(work_dir) {
+            Ok(relative_path) => relative_path,
+            // work_dir may have been given relative to the current directory
+            Err(_) => path
+                .strip_prefix(std::path::absolute(work_dir)?)
+                .map_err(|_| not_inside())?,
+        },
+    };
+
+    match relative_path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        true => Ok(relative_path),
+        false => Err(not_inside()),
+    }
+}
+
+/// Whether the backup copy of a file in work_dir is missing or out of date
+async fn needs_copy(path: &Path, work_dir: &Path, backup_dir: &Path) -> Result<bool> {
+    if metadata_only::matches(path) {
+        return Ok(!metadata_only::is_current(path).await?);
+    }
+
+    let backup_path = convert_work_path_to_backup_path(
+        path.to_path_buf(),
+        work_dir.to_path_buf(),
+        backup_dir.to_path_buf(),
+    )?;
+
+    let backup_metadata = match fs::metadata(&backup_path).await {
+        Ok(metadata) => Some(metadata),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+    // The backup of a filtered file differs from it by design, so only the recorded hash of the
+    // original tells whether it changed
+    if backup_metadata.is_some() && filters::for_path(path).is_some() {
+        return Ok(!filters::is_unchanged(path)?);
+    }
+
+    let work = trace::Stamp::of(&fs::metadata(path).await?)?;
+    let backup = backup_metadata
+        .map(|metadata| trace::Stamp::of(&metadata))
+        .transpose()?;
+    let tolerance = MTIME_TOLERANCE.load(Ordering::Relaxed);
+    let (copy, contents_differ) = match copy_by_metadata(work, backup, tolerance) {
+        Some(copy) => (copy, None),
+        None => {
+            let relative_path = path.strip_prefix(work_dir).unwrap_or(path);
+            let backup = backup.expect("only a file with a copy needs its contents compared");
+            let contents_differ = match same_contents::is_same(relative_path, work, backup) {
+                true => false,
+                false => {
+                    let (path, backup_path) = (path.to_path_buf(), backup_path.clone());
+                    let contents_differ = tokio::task::spawn_blocking(move || {
+                        anyhow::Ok(hash_file(&path)? != hash_file(&backup_path)?)
+                    })
+                    .await??;
+                    if !contents_differ {
+                        same_contents::record(relative_path, work, backup);
+                    }
+                    contents_differ
+                }
+            };
+            (contents_differ, Some(contents_differ))
+        }
+    };
+    trace::record(|| trace::Record::Compare {
+        path: path.to_path_buf(),
+        work,
+        backup,
+        tolerance,
+        contents_differ,
+        copy,
+    });
+
+    Ok(copy)
+}
+
+/// Whether a file needs copying going by the size and modify time of it and of its copy, if there
+/// is one. None when only their contents can tell
+fn copy_by_metadata(
+    work: trace::Stamp,
+    backup: Option<trace::Stamp>,
+    tolerance: u64,
+) -> Option<bool> {
+    let Some(backup) = backup else {
+        return Some(true);
+    };
+    if work.len != backup.len {
+        return Some(true);
+    }
+
+    match tolerance {
+        0 if work.modified == backup.modified => Some(false),
+        // A deduplicated copy shares its modify time with every other link to its blob
+        0 if dedup::is_enabled() => None,
+        0 => Some(true),
+        // Unless the backup is clearly newer the timestamps can't tell, so the contents decide
+        _ if work.modified + Duration::from_secs(tolerance) >= backup.modified => None,
+        _ => Some(false),
+    }
+}
+
+/// How often the watch loop saves stats when nothing is happening, to keep sampling the tree size
+const STATS_SAVE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Saves the stats counted so far, reporting rather than failing on errors
+fn save_stats(backup_dir: &Path, tree_size: Option<stats::TreeSize>) {
+    if let Err(err) = stats::save(backup_dir, tree_size) {
+        output::emit(&Event::Error {
+            path: None,
+            message: format!("Error saving stats: {err:#}"),
+        });
+    }
+}
+
+/// Saves the manifests of metadata only files and of backup_dir, the escaped names and the files
+/// found to match their copies, if they changed, reporting rather than failing on errors
+async fn save_manifest(backup_dir: &Path) {
+    if let Err(err) = metadata_only::save(backup_dir) {
+        output::emit(&Event::Error {
+            path: None,
+            message: format!("Error saving the metadata only manifest: {err:#}"),
+        });
+    }
+    if let Err(err) = signed_manifest::save().await {
+        output::emit(&Event::Error {
+            path: None,
+            message: format!("Error saving the signed manifest: {err:#}"),
+        });
+    }
+    if let Err(err) = sanitize::save() {
+        output::emit(&Event::Error {
+            path: None,
+            message: format!("Error saving the escaped names: {err:#}"),
+        });
+    }
+    if let Err(err) = same_contents::save(backup_dir) {
+        output::emit(&Event::Error {
+            path: None,
+            message: format!("Error saving the files found to match their copies: {err:#}"),
+        });
+    }
+}
+
+/// The most directories the watch loop keeps open to be told about their changes, since each one
+/// takes up a file descriptor. The rest are looked at once the interval is up
+const MAX_WATCHED_DIRS: usize = 512;
+
+/// How often the watch loop saves the snapshot `status` reads when there's no control socket
+const STATUS_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Saves the snapshot for `status`, reporting rather than failing on errors
+fn save_status(work_dir: &Path, backup_dir: &Path, options: &SyncOptions, mode: Option<&str>) {
+    if let Err(err) = status::save(work_dir, backup_dir, options, mode) {
+        output::emit(&Event::Error {
+            path: None,
+            message: format!("Error saving the status: {err:#}"),
+        });
+    }
+}
+
+struct FileSyncInfo {
+    /// The tokio task running in a loop that ensures the time is kept in sync
+    sync_task: JoinHandle<()>,
+    /// The modify time the file had when it was last synced
+    modify_time: Arc<AtomicU64>,
+}
+
+impl Drop for FileSyncInfo {
+    fn drop(&mut self) {
+        // The copy loop is panicking and will be restarted with sync tasks of its own. On a normal
+        // shutdown they're left to finish what they're copying
+        if std::thread::panicking() {
+            self.sync_task.abort();
+        }
     }
-    println!("Cleared {}!", dir_to_init.display());
+}
 
-    println!(
-        "Initializing {} with the contents of {}...",
-        dir_to_init.display(),
-        source_of_truth.display()
-    );
-    for file_info in recursive_dir(&source_of_truth) {
-        let path = file_info.path();
+/// The path each watched file was last seen at, by its id, to notice when one is moved
+#[derive(Default)]
+struct FileIds {
+    /// The path of each file, and the modify time it's known to be in sync at
+    paths: HashMap<(u64, u64), (PathBuf, Arc<AtomicU64>)>,
+    ids: HashMap<PathBuf, (u64, u64)>,
+}
 
-        let file_type = file_type(&path).await.with_context(|| {
-            anyhow!(
-                "Error getting file type of file {} for initialization",
-                file_info.path().display()
-            )
-        })?;
+impl FileIds {
+    /// Remembers that `path` is the file with the id `file_id` now, forgetting the file that was
+    /// there before
+    fn insert(&mut self, path: PathBuf, file_id: Option<(u64, u64)>, modify_time: Arc<AtomicU64>) {
+        if let Some(old_id) = self.ids.remove(&path) {
+            self.paths.remove(&old_id);
+        }
+        if let Some(file_id) = file_id {
+            if let Some((old_path, _)) = self.paths.insert(file_id, (path.clone(), modify_time)) {
+                self.ids.remove(&old_path);
+            }
+            self.ids.insert(path, file_id);
+        }
+    }
 
-        if file_type.is_file() || file_type.is_symlink() {
-            copy_to_dst(
-                path.to_path_buf(),
-                source_of_truth.clone(),
-                dir_to_init.clone(),
-            )
-            .await
-            .with_context(|| anyhow!("Error copying file for initialization"))?;
-        } else if file_type.is_dir() {
-            let convert_dir_fn = match truth_source_kind {
-                TruthSourceKind::WorkDir => convert_work_path_to_backup_path,
-                TruthSourceKind::BackupDir => convert_backup_path_to_work_path,
-            };
+    /// Where the file with the id `file_id` was before it was moved to `path`. An id can be reused
+    /// once its file is deleted, so the file is only taken to have moved if nothing is left where
+    /// it was, and it kept the modify time it was synced at
+    async fn moved_from(&self, file_id: Option<(u64, u64)>, path: &Path) -> Option<&PathBuf> {
+        let (old_path, modify_time) = self.paths.get(&file_id?)?;
+        if old_path == path {
+            return None;
+        }
+        match fs::symlink_metadata(old_path).await {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            _ => return None,
+        }
 
-            let dir_to_init_path = convert_dir_fn(
-                path.to_path_buf(),
-                dir_to_init.clone(),
-                source_of_truth.clone(),
-            )?;
-            fs::create_dir_all(dir_to_init_path).await?;
+        match modify_time_secs(path).await.ok()? == modify_time.load(Ordering::Relaxed) {
+            true => Some(old_path),
+            false => None,
         }
     }
 
-    println!("Initialized {}!", dir_to_init.display());
-
-    let work_dir_clone = work_dir.clone();
-    let backup_dir_clone = backup_dir.clone();
+    /// Forgets the files at paths a scan didn't find, which were deleted or moved elsewhere
+    fn retain(&mut self, scanned: &HashSet<PathBuf>) {
+        self.paths.retain(|_, (path, _)| scanned.contains(path));
+        self.ids.retain(|path, _| scanned.contains(path));
+    }
+}
 
-    tokio::task::spawn(async move {
-        delete_files(work_dir_clone, backup_dir_clone)
-            .await
-            .unwrap()
-    });
-    tokio::task::spawn(async move { copy_files(work_dir, backup_dir).await.unwrap() });
+/// The files in backup_dir that no longer exist in work_dir, to delete with [`delete_files`], or
+/// none if --max-delete holds the deletions back. `scanned` holds every path a scan of work_dir
+/// just saw, if there was one, to compare with the in-memory listing of backup_dir rather than
+/// walking it. The copies in `renamed` are about to be renamed, and aren't deleted
+async fn removed_files(
+    work_dir: &Path,
+    backup_dir: &Path,
+    options: &SyncOptions,
+    scanned: Option<&HashSet<PathBuf>>,
+    renamed: &HashSet<PathBuf>,
+    found: &Found,
+) -> Vec<PathBuf> {
+    metadata_only::prune();
 
-    tokio::signal::ctrl_c().await?;
+    let (files, candidates): (u64, Box<dyn Iterator<Item = PathBuf> + Send>) = match scanned {
+        Some(scanned) => {
+            let listed = listing::files(backup_dir, &options.walk, found);
+            let mut candidates: Vec<_> = listed
+                .iter()
+                .filter(|path| {
+                    let Ok(work_path) = convert_backup_path_to_work_path(
+                        path.to_path_buf(),
+                        work_dir.to_path_buf(),
+                        backup_dir.to_path_buf(),
+                    ) else {
+                        return true;
+                    };
+                    // A copy that has a newer one in the place a route sends it to is left over
+                    let misplaced = convert_work_path_to_backup_path(
+                        work_path.clone(),
+                        work_dir.to_path_buf(),
+                        backup_dir.to_path_buf(),
+                    )
+                    .is_ok_and(|backup_path| {
+                        backup_path != **path && listed.contains(&backup_path)
+                    });
+                    !scanned.contains(&work_path) || misplaced
+                })
+                .cloned()
+                .collect();
+            candidates.sort();
+            (listed.len() as u64, Box::new(candidates.into_iter()))
+        }
+        None => {
+            let roots = std::iter::once(backup_dir.to_path_buf()).chain(routes::dirs(backup_dir));
+            let walked = roots
+                .flat_map(|root| recursive_dir_finding(&root, &options.walk, found))
+                .map(|file_info| file_info.into_path());
+            (0, Box::new(walked))
+        }
+    };
 
-    SHOULD_SHUTDOWN.store(true, Ordering::Relaxed);
-    println!("Waiting 5 seconds for tokio tasks to shutdown...");
+    // Everything gone from work_dir is found first, so that --max-delete holds back the whole pass
+    // rather than stopping partway through it
+    let mut files = files;
+    let mut missing = Vec::new();
+    for path in candidates {
+        if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+            return Vec::new();
+        }
+        if scanned.is_none() {
+            files += 1;
+        }
+        if !retry::is_due(&path) || metadata_only::matches(&path) || renamed.contains(&path) {
+            continue;
+        }
+        // What the sync leaves out of the backup on purpose has no copy there, which doesn't mean
+        // it should go when reconciling work_dir with the backup
+        if presence::is_work_dir(backup_dir) && is_left_out(&path, backup_dir, options) {
+            continue;
+        }
+        // The listing may still have a file that was deleted behind evil_mount's back
+        if scanned.is_some()
+            && fs::symlink_metadata(&path)
+                .await
+                .is_err_and(|err| err.kind() == io::ErrorKind::NotFound)
+        {
+            listing::forget(&path);
+            continue;
+        }
+        let exists = match convert_backup_path_to_work_path(
+            path.clone(),
+            work_dir.to_path_buf(),
+            backup_dir.to_path_buf(),
+        ) {
+            Ok(work_path) => {
+                fs::try_exists(&work_path).await.unwrap_or(false)
+                    && !is_misplaced(&path, &work_path, work_dir, backup_dir)
+                        .await
+                        .unwrap_or(true)
+            }
+            Err(_) => false,
+        };
+        // Errors are left for delete_if_removed to report
+        if !exists {
+            missing.push(path);
+        }
+    }
+    match options.delete_policy == DeletePolicy::Never
+        || deletion::allow(options.max_delete, missing.len() as u64, files, work_dir)
+    {
+        true => missing,
+        false => Vec::new(),
+    }
+}
 
-    tokio::time::sleep(Duration::from_secs(5)).await;
+/// Whether the file `path` in `work_dir` is one the sync doesn't copy, for being too large, filtered
+/// or excluded
+fn is_left_out(path: &Path, work_dir: &Path, options: &SyncOptions) -> bool {
+    limits::is_too_large(path)
+        || filters::for_path(path).is_some()
+        || options
+            .walk
+            .overrides(work_dir)
+            .is_ok_and(|overrides| overrides.matched(path, false).is_ignore())
+}
 
-    println!("Done!");
+/// Deletes the copies of the `removed` files found by [`removed_files`], once the copies of the
+/// same pass are done
+async fn delete_files(
+    removed: Vec<PathBuf>,
+    work_dir: &Path,
+    backup_dir: &Path,
+    options: &SyncOptions,
+    report: &mut SyncReport,
+) {
+    for path in removed {
+        if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+            return;
+        }
 
-    Ok(())
+        let deletion = diff::Change::Delete(path);
+        let result = diff::apply(
+            &deletion,
+            work_dir,
+            backup_dir,
+            options.delete_policy,
+            report,
+        )
+        .await;
+        match result {
+            Ok(()) => retry::succeeded(deletion.path()),
+            Err(err) => {
+                retry::failed(deletion.path(), retry::Operation::Delete, &err);
+                report.record_error(deletion.path(), err);
+            }
+        }
+    }
 }
 
-async fn backup_files() {
-    todo!()
-}
+/// Recreates the special files that the pass's walks found in `source` at the same place in
+/// `target`, and removes the ones in `target` that are gone from `source`. Walks only report
+/// special files with --special-files=recreate, so otherwise this does nothing
+async fn sync_special_files(
+    source: &Path,
+    target: &Path,
+    options: &SyncOptions,
+    found: &Found,
+    report: &mut SyncReport,
+) {
+    let special = std::mem::take(&mut *found.special.lock().unwrap());
+    for path in special {
+        if let Ok(relative_path) = path.strip_prefix(source) {
+            let target_path = target.join(relative_path);
+            let result = async {
+                let _guard = locks::lock(&target_path).await;
+                let recreated = special::recreate(&path, &target_path).await?;
+                if recreated {
+                    ownership::copy_owner(&path, &target_path)?;
+                }
+                anyhow::Ok(recreated)
+            }
+            .await;
 
-struct FileSyncInfo {
-    /// The tokio task running in a loop that ensures the time is kept in sync
-    sync_task: JoinHandle<()>,
+            match result {
+                Ok(true) => report.record_copy(&path, &target_path),
+                Ok(false) => (),
+                Err(err) => report.record_error(&path, err),
+            }
+        } else if path.starts_with(target) {
+            match delete_if_removed(&path, source, target, options.delete_policy).await {
+                Ok(true) => report.record_delete(&path),
+                Ok(false) => (),
+                Err(err) => report.record_error(&path, err),
+            }
+        }
+    }
 }
 
-async fn delete_files(work_dir: PathBuf, backup_dir: PathBuf) -> Result<()> {
-    loop {
-        for file_info in recursive_dir(&backup_dir).into_iter() {
-            // First, check if the path exists in backup_dir
-            if !fs::try_exists(&file_info.path()).await.unwrap() {
-                continue;
+/// Makes the directories that the pass's walks found in `source` at the same place in `target`,
+/// and removes the empty ones in `target` that are gone from `source`. A removed directory that
+/// still has files waiting out --delete=after-grace is left for a later pass
+async fn sync_directories(
+    source: &Path,
+    target: &Path,
+    options: &SyncOptions,
+    found: &Found,
+    report: &mut SyncReport,
+) {
+    let directories = std::mem::take(&mut *found.directories.lock().unwrap());
+    // Deepest first, so that a removed tree empties from the bottom up in a single pass
+    for path in directories.into_iter().rev() {
+        if let Ok(relative_path) = path.strip_prefix(source) {
+            let target_path = routes::root_for(target, relative_path).join(sanitize::translate(
+                relative_path,
+                source,
+                target,
+            ));
+            if let Err(err) = directories::create(&target_path).await {
+                report.record_error(&target_path, err);
             }
-            // If a path exists in backup_dir, but doesn't exist in work_dr, that means the file was deleted in work_dir
-            let work_dir_path = convert_backup_path_to_work_path(
-                file_info.path().to_path_buf(),
-                work_dir.clone(),
-                backup_dir.clone(),
-            )
-            .unwrap();
-
-            if !fs::try_exists(&work_dir_path).await.unwrap() {
-                let file_type = file_type(file_info.path())
-                    .await
-                    .with_context(|| {
-                        anyhow!(
-                            "Error getting file type for {} for deletion",
-                            work_dir_path.display()
-                        )
-                    })
-                    .unwrap();
-
-                if file_type.is_file() || file_type.is_symlink() {
-                    fs::remove_file(file_info.path()).await.unwrap();
-                } else if file_type.is_dir() {
-                    fs::remove_dir_all(file_info.path()).await.unwrap();
-                } else {
-                    panic!("This is a bug, we're missing some file type")
+        } else if path.starts_with(target) {
+            let result = async {
+                if !directories::is_empty(&path).await? {
+                    return Ok(false);
                 }
+                delete_if_removed(&path, source, target, options.delete_policy).await
+            }
+            .await;
+
+            match result {
+                Ok(true) => report.record_delete(&path),
+                Ok(false) => (),
+                // Gone already, like when it was created and removed again between walks
+                Err(err)
+                    if err
+                        .downcast_ref::<io::Error>()
+                        .is_some_and(|err| err.kind() == io::ErrorKind::NotFound) => {}
+                Err(err) => report.record_error(&path, err),
             }
         }
+    }
+}
+
+/// Whether `path` is a copy of `work_path` left behind in one destination after the routes moved it
+/// to another, where it has been copied already
+async fn is_misplaced(
+    path: &Path,
+    work_path: &Path,
+    work_dir: &Path,
+    backup_dir: &Path,
+) -> Result<bool> {
+    let backup_path = convert_work_path_to_backup_path(
+        work_path.to_path_buf(),
+        work_dir.to_path_buf(),
+        backup_dir.to_path_buf(),
+    )?;
+
+    Ok(backup_path != path && fs::try_exists(&backup_path).await?)
+}
+
+/// Deletes `path` from backup_dir if it no longer exists in work_dir and `policy` allows it,
+/// returning whether it did
+async fn delete_if_removed(
+    path: &Path,
+    work_dir: &Path,
+    backup_dir: &Path,
+    policy: DeletePolicy,
+) -> Result<bool> {
+    let _guard = locks::lock(path).await;
+    // Metadata only files are never in the backup, which doesn't mean they should go when
+    // reconciling work_dir with it
+    if metadata_only::matches(path) {
+        return Ok(false);
+    }
+    // First, check if the path exists in backup_dir, where a dangling symlink counts too
+    let metadata = match fs::symlink_metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+    // If a path exists in backup_dir, but doesn't exist in work_dr, that means the file was deleted in work_dir
+    let work_dir_path = convert_backup_path_to_work_path(
+        path.to_path_buf(),
+        work_dir.to_path_buf(),
+        backup_dir.to_path_buf(),
+    )?;
 
-        tokio::time::sleep(Duration::from_secs(5)).await;
+    if fs::try_exists(&work_dir_path).await?
+        && !is_misplaced(path, &work_dir_path, work_dir, backup_dir).await?
+    {
+        deletion::forget(path);
+        return Ok(false);
+    }
+    // Everything is missing from a work_dir that went away, which isn't a reason to delete it
+    if !presence::check(work_dir) {
+        return Ok(false);
+    }
+    if !policy.should_delete(path) {
+        return Ok(false);
+    }
+
+    let file_type = metadata.file_type();
+    match file_type.is_dir() {
+        true => protect::allow_dir(path)?,
+        false => protect::allow(path, protect::Operation::Delete)?,
+    }
+
+    containment::check(path)?;
+    // What the mirror lost stays in the versions, and isn't there to remove anymore
+    read_only::unlock(path)?;
+    consumer::keep_deleted(path).await?;
+    faults::crash_if(faults::Fault::CrashBeforeDelete, path);
+    if fs::symlink_metadata(path).await.is_ok() {
+        // Anything that isn't a directory, like a symlink, a FIFO or a socket, is removed like a file
+        copier::remove(path, file_type.is_dir()).await?;
     }
+    deletion::forget(path);
+
+    Ok(true)
 }
 
 // TODO: gitignore
-async fn copy_files(work_dir: PathBuf, backup_dir: PathBuf) -> Result<()> {
-    println!("Watching for file changes...");
+/// Watches work_dir for new files and starts a sync task for each of them, then removes whatever
+/// was deleted from work_dir. Each cycle finds everything to copy, rename and delete before
+/// applying any of it, deletions last, so the copies of moved files are renamed rather than
+/// deleted. `known_modify_times` holds the files that are known to be in sync already, along with
+/// the modify time they were synced at
+async fn copy_files(
+    work_dir: PathBuf,
+    backup_dir: PathBuf,
+    options: SyncOptions,
+    mut known_modify_times: HashMap<PathBuf, u64>,
+) -> Result<()> {
+    info!("Watching for file changes...");
 
     let mut handles: HashMap<PathBuf, FileSyncInfo> = HashMap::new();
+    let mut file_ids = FileIds::default();
+    // Files that failed to copy, and will be retried
+    let mut pending: HashSet<PathBuf> = HashSet::new();
+    let mut stats_saved_at = Instant::now();
+    let mut status_saved_at: Option<Instant> = None;
+    // Where the platform can tell, a directory changing starts the next cycle right away
+    let mut watcher = platform::DirWatcher::new().ok();
+    let mut watched_dirs = HashSet::new();
 
     // Starts any handles that are necessary
     loop {
-        for file_info in recursive_dir(&work_dir) {
-            if !file_type(file_info.path()).await.unwrap().is_file() {
+        hashing::forget();
+        if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+            let state = WatchState {
+                synced: handles
+                    .iter()
+                    .filter_map(|(path, info)| {
+                        let relative_path = path.strip_prefix(&work_dir).ok()?.to_path_buf();
+                        Some((relative_path, info.modify_time.load(Ordering::Relaxed)))
+                    })
+                    .collect(),
+                pending: pending
+                    .iter()
+                    .filter_map(|path| Some(path.strip_prefix(&work_dir).ok()?.to_path_buf()))
+                    .collect(),
+            };
+            if let Err(err) = state.save(&backup_dir) {
+                output::emit(&Event::Error {
+                    path: None,
+                    message: format!("Error saving the watch state: {err:#}"),
+                });
+            }
+            save_stats(&backup_dir, None);
+            save_manifest(&backup_dir).await;
+            save_status(&work_dir, &backup_dir, &options, Some("stopped"));
+            if let Err(err) = retry::save(&backup_dir) {
+                output::emit(&Event::Error {
+                    path: None,
+                    message: format!("Error saving the retry queue: {err:#}"),
+                });
+            }
+            // Lets the sync tasks waiting for the next pass see the shutdown
+            POLLED.notify_waiters();
+            coalesce::flush();
+
+            return Ok(());
+        }
+
+        let mut report = SyncReport::default();
+
+        if pause::is_paused() {
+            watchdog::set_phase("paused");
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            continue;
+        }
+
+        watchdog::set_phase("walking work_dir");
+        let cycle_started = Instant::now();
+        let pass = full_pass::take();
+        // Files whose sync task is running but that a full pass found out of date
+        let mut tracked_changes = Vec::new();
+        // Small files held back to be copied together, with --target-profile=cloud
+        let mut held_back = Vec::new();
+        // New files that need a sync task, with what they need to be in sync first
+        let mut untracked = Vec::new();
+        let mut transactions = transactional::Transactions::new(&work_dir, &backup_dir);
+        let mut tree_size = stats::TreeSize::default();
+        // Every path the scan saw, for the deletion pass to compare with backup_dir
+        let mut scanned = HashSet::new();
+        let found = Found::default();
+        for file_info in recursive_dir_finding(&work_dir, &options.walk, &found) {
+            if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+                break;
+            }
+            scanned.insert(file_info.path().to_path_buf());
+            tree_size.count(&file_info);
+            if limits::is_exceeded(&tree_size) {
+                continue;
+            }
+            // Walks only yield files, so the directories watched are the ones with files in them
+            if watcher.is_some() && watched_dirs.len() < MAX_WATCHED_DIRS {
+                if let Some(dir) = file_info.path().parent() {
+                    watched_dirs.insert(dir.to_path_buf());
+                }
+            }
+            if !file_type(file_info.path())
+                .await
+                .is_ok_and(|file_type| file_type.is_file())
+            {
                 continue;
             }
 
             match handles.get(file_info.path()) {
-                Some(FileSyncInfo { sync_task }) => {
+                Some(FileSyncInfo { sync_task, .. }) => {
                     // Respawn the sync task next loop iteration if it's crashed or finished
                     if sync_task.is_finished() {
                         handles.remove(file_info.path());
+                    } else if transactional::contains(file_info.path()) || pass.is_some() {
+                        // Sync tasks leave these to the cycle, and a full pass doesn't wait for
+                        // them to notice anything
+                        match find_in_cycle(file_info.path(), &work_dir, &backup_dir, &pass).await {
+                            Ok(Some(change)) => {
+                                if let Some(change) = transactions.take(change) {
+                                    tracked_changes.push(change);
+                                }
+                            }
+                            Ok(None) => (),
+                            Err(err) => {
+                                copy_failed(file_info.into_path(), err, &mut report, &mut pending)
+                            }
+                        }
                     }
                 }
                 None => {
-                    let backup_path = convert_work_path_to_backup_path(
-                        file_info.path().to_path_buf(),
-                        work_dir.clone(),
-                        backup_dir.clone(),
+                    let path = file_info.into_path();
+                    let file_id = fs::metadata(&path)
+                        .await
+                        .ok()
+                        .and_then(|metadata| platform::file_id(&metadata));
+
+                    let forced = pass
+                        .as_ref()
+                        .is_some_and(|pass| pass.forced.contains(&path));
+                    if let Some(modify_time) = known_modify_times.remove(&path).filter(|_| !forced)
+                    {
+                        track(
+                            &mut handles,
+                            &mut file_ids,
+                            path,
+                            file_id,
+                            modify_time,
+                            &work_dir,
+                            &backup_dir,
+                        );
+                        continue;
+                    }
+                    // Too large to sync, or still backing off from the last failure
+                    if limits::is_too_large(&path) || !retry::is_due(&path) {
+                        continue;
+                    }
+                    // Tracked once the mirror is done writing it
+                    if !consumer::is_settled(&path).await {
+                        continue;
+                    }
+
+                    // A transactional directory's files are copied all together, so none are
+                    // renamed or held back
+                    let in_transaction = transactional::contains(&path);
+                    let moved_from =
+                        match options.delete_policy != DeletePolicy::Never && !in_transaction {
+                            true => file_ids.moved_from(file_id, &path).await,
+                            false => None,
+                        };
+                    if moved_from.is_none()
+                        && !in_transaction
+                        && coalesce::is_held_back(&path).await
+                    {
+                        held_back.push(path);
+                        continue;
+                    }
+
+                    // Applied once the deletion pass found what to delete too
+                    let change = match moved_from {
+                        Some(old_path) => convert_work_path_to_backup_path(
+                            old_path.clone(),
+                            work_dir.clone(),
+                            backup_dir.clone(),
+                        )
+                        .map(|from| {
+                            Some(diff::Change::Rename {
+                                from,
+                                to: path.clone(),
+                            })
+                        }),
+                        None => find_in_cycle(&path, &work_dir, &backup_dir, &pass).await,
+                    };
+                    match change {
+                        Ok(change) => {
+                            let change = change.and_then(|change| transactions.take(change));
+                            untracked.push((path, file_id, change));
+                        }
+                        Err(err) => copy_failed(path, err, &mut report, &mut pending),
+                    }
+                }
+            }
+        }
+
+        // A partial scan would make the limits and stats think work_dir shrank
+        if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+            continue;
+        }
+        limits::finish_scan(&tree_size);
+        watchdog::set_phase("finding removed files");
+        let changes: Vec<_> = untracked
+            .iter()
+            .filter_map(|(_, _, change)| change.clone())
+            .collect();
+        // A full pass walks backup_dir, which may have changed behind the listing's back
+        let removed: Vec<_> = removed_files(
+            &work_dir,
+            &backup_dir,
+            &options,
+            pass.is_none().then_some(&scanned),
+            &diff::renamed(&changes),
+            &found,
+        )
+        .await
+        .into_iter()
+        .filter_map(|path| transactions.take(diff::Change::Delete(path)))
+        .map(|deletion| deletion.path().to_path_buf())
+        .collect();
+
+        watchdog::set_phase("copying new files");
+        for (path, file_id, change) in untracked {
+            if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+                break;
+            }
+            let result = async {
+                if let Some(change) = &change {
+                    diff::apply(
+                        change,
+                        &work_dir,
+                        &backup_dir,
+                        options.delete_policy,
+                        &mut report,
                     )
-                    .unwrap();
-                    match fs::metadata(backup_path).await {
-                        Ok(metadata) => {
-                            let modify_time = Arc::new(AtomicU64::new(
-                                metadata
-                                    .modified()
-                                    .unwrap()
-                                    .duration_since(UNIX_EPOCH)
-                                    .unwrap()
-                                    .as_secs(),
-                            ));
-
-                            let modify_time_clone = modify_time.clone();
-                            let path = file_info.path().to_path_buf();
-                            let work_dir = work_dir.clone();
-                            let backup_dir = backup_dir.clone();
-
-                            let sync_task = tokio::task::spawn(spawn_sync_task(
-                                path,
-                                work_dir,
-                                backup_dir,
-                                modify_time_clone,
-                            ));
-
-                            handles.insert(file_info.into_path(), FileSyncInfo { sync_task });
+                    .await?;
+                }
+                modify_time_secs(&path).await
+            }
+            .await;
+
+            match result {
+                Ok(modify_time) => {
+                    retry::succeeded(&path);
+                    pending.remove(&path);
+                    track(
+                        &mut handles,
+                        &mut file_ids,
+                        path,
+                        file_id,
+                        modify_time,
+                        &work_dir,
+                        &backup_dir,
+                    );
+                }
+                Err(err) => copy_failed(path, err, &mut report, &mut pending),
+            }
+        }
+        for change in tracked_changes {
+            if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+                break;
+            }
+            let path = change.path().to_path_buf();
+            let result = async {
+                diff::apply(
+                    &change,
+                    &work_dir,
+                    &backup_dir,
+                    options.delete_policy,
+                    &mut report,
+                )
+                .await?;
+                modify_time_secs(&path).await
+            }
+            .await;
+
+            match result {
+                Ok(modify_time) => {
+                    retry::succeeded(&path);
+                    pending.remove(&path);
+                    if let Some(info) = handles.get(&path) {
+                        info.modify_time.store(modify_time, Ordering::Relaxed);
+                    }
+                }
+                Err(err) => copy_failed(path, err, &mut report, &mut pending),
+            }
+        }
+        watchdog::set_phase("copying small files");
+        copy_held_back(
+            held_back,
+            &work_dir,
+            &backup_dir,
+            &mut report,
+            &mut pending,
+            &mut known_modify_times,
+        )
+        .await;
+        coalesce::flush();
+        file_ids.retain(&scanned);
+        watchdog::set_phase("applying transactional directories");
+        let outcome = transactions.apply(options.delete_policy, &mut report).await;
+        for path in outcome.committed {
+            retry::succeeded(&path);
+            pending.remove(&path);
+            if let (Some(info), Ok(modify_time)) =
+                (handles.get(&path), modify_time_secs(&path).await)
+            {
+                info.modify_time.store(modify_time, Ordering::Relaxed);
+            }
+        }
+        for (path, err) in outcome.failed {
+            copy_failed(path, err, &mut report, &mut pending);
+        }
+        pending.extend(outcome.held_back);
+        watchdog::set_phase("deleting removed files");
+        delete_files(removed, &work_dir, &backup_dir, &options, &mut report).await;
+        watchdog::set_phase("removing unused blobs");
+        dedup::collect_garbage().await;
+        watchdog::set_phase("syncing special files");
+        sync_special_files(&work_dir, &backup_dir, &options, &found, &mut report).await;
+        watchdog::set_phase("syncing directories");
+        sync_directories(&work_dir, &backup_dir, &options, &found, &mut report).await;
+        watchdog::set_phase("locking copies");
+        read_only::lock_changed().await;
+
+        watchdog::set_phase("saving its state");
+        status::LAST_CYCLE_MILLIS.store(
+            cycle_started.elapsed().as_millis() as u64,
+            Ordering::Relaxed,
+        );
+        report.complete();
+        if let Some(pass) = pass {
+            pass.finish(&report);
+        }
+        TRACKED_FILES.store(handles.len() as u64, Ordering::Relaxed);
+        status::PENDING_COPIES.store(pending.len() as u64, Ordering::Relaxed);
+
+        if status_saved_at.is_none_or(|saved_at| saved_at.elapsed() >= STATUS_SAVE_INTERVAL) {
+            save_status(&work_dir, &backup_dir, &options, None);
+            status_saved_at = Some(Instant::now());
+        }
+
+        save_manifest(&backup_dir).await;
+        if stats::has_unsaved() || stats_saved_at.elapsed() >= STATS_SAVE_INTERVAL {
+            save_stats(&backup_dir, Some(tree_size));
+            stats_saved_at = Instant::now();
+        }
+
+        if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+            continue;
+        }
+
+        POLLED.notify_waiters();
+        watchdog::set_phase("waiting for the next cycle");
+        let interval = Duration::from_secs(options.interval.load(Ordering::Relaxed));
+        let waited = match &mut watcher {
+            Some(watcher) => {
+                let dirs = std::mem::take(&mut watched_dirs);
+                tokio::task::block_in_place(|| {
+                    watcher.watch(dirs)?;
+                    let until = Instant::now() + interval;
+                    // Looks up every second to see whether a full pass was asked for
+                    loop {
+                        let left = until.saturating_duration_since(Instant::now());
+                        if left.is_zero() || full_pass::is_asked() {
+                            return io::Result::Ok(false);
                         }
-                        Err(err) => {
-                            match err.kind() {
-                                io::ErrorKind::NotFound => {
-                                    //TODO: catch this
-                                    copy_to_dst(
-                                        file_info.path().to_path_buf(),
-                                        work_dir.clone(),
-                                        backup_dir.clone(),
-                                    )
-                                    .await;
-                                }
-                                _ => todo!("{err}"),
-                            }
+                        if watcher.wait(left.min(Duration::from_secs(1)))? {
+                            return Ok(true);
                         }
                     }
+                })
+            }
+            None => {
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => (),
+                    _ = full_pass::asked() => (),
                 }
+                Ok(false)
             }
+        };
+        if let Err(err) = waited {
+            output::emit(&Event::Error {
+                path: None,
+                message: format!(
+                    "Error watching work_dir for changes, only looking for them every interval from now on: {err}"
+                ),
+            });
+            watcher = None;
+            tokio::time::sleep(interval).await;
         }
 
-        if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
-            return Ok(());
+        watchdog::set_phase("pulling work_dir");
+        // A failed pull was reported, and the cycle goes on with what work_dir has
+        pull::pull().await;
+    }
+}
+
+/// What `path` in work_dir needs in a cycle of the watch loop, which for one of the files a full
+/// `pass` was asked to copy is a copy whatever the modify times say
+async fn find_in_cycle(
+    path: &Path,
+    work_dir: &Path,
+    backup_dir: &Path,
+    pass: &Option<full_pass::Pass>,
+) -> Result<Option<diff::Change>> {
+    match pass.as_ref().is_some_and(|pass| pass.forced.contains(path)) {
+        true => diff::differs(path, work_dir, backup_dir).await.map(Some),
+        false => diff::find(path, work_dir, backup_dir).await,
+    }
+}
+
+/// Copies the small files the watch loop found new during a cycle with `--target-profile=cloud`,
+/// many at once. Their sync tasks are started by the next cycle, once they're in sync
+async fn copy_held_back(
+    paths: Vec<PathBuf>,
+    work_dir: &Path,
+    backup_dir: &Path,
+    report: &mut SyncReport,
+    pending: &mut HashSet<PathBuf>,
+    known_modify_times: &mut HashMap<PathBuf, u64>,
+) {
+    let mut copies = futures::stream::iter(paths)
+        .map(|path| async move {
+            let result = async {
+                let dst_path = match needs_copy(&path, work_dir, backup_dir).await? {
+                    true => Some(diff::copy(&path, work_dir, backup_dir).await?),
+                    false => None,
+                };
+                anyhow::Ok((dst_path, modify_time_secs(&path).await?))
+            }
+            .await;
+            (path, result)
+        })
+        .buffer_unordered(coalesce::CONCURRENCY);
+
+    while let Some((path, result)) = copies.next().await {
+        match result {
+            Ok((dst_path, modify_time)) => {
+                retry::succeeded(&path);
+                pending.remove(&path);
+                if let Some(dst_path) = dst_path {
+                    report.record_copy(&path, &dst_path);
+                }
+                known_modify_times.insert(path, modify_time);
+            }
+            Err(err) => copy_failed(path, err, report, pending),
         }
+    }
+}
+
+/// Starts the sync task that copies `path` with the id `file_id` whenever it changes from now on,
+/// `modify_time` being the modify time it's known to be in sync at
+fn track(
+    handles: &mut HashMap<PathBuf, FileSyncInfo>,
+    file_ids: &mut FileIds,
+    path: PathBuf,
+    file_id: Option<(u64, u64)>,
+    modify_time: u64,
+    work_dir: &Path,
+    backup_dir: &Path,
+) {
+    let modify_time = Arc::new(AtomicU64::new(modify_time));
+    file_ids.insert(path.clone(), file_id, modify_time.clone());
+    let sync_task = tokio::task::spawn(spawn_sync_task(
+        path.clone(),
+        work_dir.to_path_buf(),
+        backup_dir.to_path_buf(),
+        modify_time.clone(),
+    ));
+
+    handles.insert(
+        path,
+        FileSyncInfo {
+            sync_task,
+            modify_time,
+        },
+    );
+}
 
-        tokio::time::sleep(Duration::from_secs(5)).await;
+/// Reports a file the watch loop failed to copy, which is looked at again next cycle like any
+/// other pending file
+fn copy_failed(
+    path: PathBuf,
+    err: anyhow::Error,
+    report: &mut SyncReport,
+    pending: &mut HashSet<PathBuf>,
+) {
+    match races::classify(&path, &err) {
+        Some(races::Race::Gone) => (),
+        Some(races::Race::Changed) => {
+            pending.insert(path);
+        }
+        None => {
+            retry::failed(&path, retry::Operation::Copy, &err);
+            report.record_error(&path, err);
+            pending.insert(path);
+        }
     }
 }
 
-// FIXME: return and handle errors
+/// Copies `path` into backup_dir whenever its modify time changes, until it's deleted. Failed
+/// copies are retried with a backoff
 async fn spawn_sync_task(
     path: PathBuf,
     work_dir: PathBuf,
     backup_dir: PathBuf,
     modify_time: Arc<AtomicU64>,
 ) {
+    // The modify time the last check saw, for waiting for files to settle
+    let mut last_seen = None;
     loop {
-        match fs::metadata(path.clone()).await {
-            Ok(metadata) => {
-                //FIXME: unwrap
-                let current_modify_time = metadata
-                    .modified()
-                    .unwrap()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-
-                if current_modify_time != modify_time.load(Ordering::Relaxed) {
-                    modify_time.store(current_modify_time, Ordering::Relaxed);
-
-                    if let Err(err) =
-                        copy_to_dst(path.clone(), work_dir.clone(), backup_dir.clone()).await
-                    {
-                        if let Ok(err) = err.downcast::<io::Error>() {
-                            if err.kind() == io::ErrorKind::NotFound {
-                                return;
-                            } else {
-                                Err(err)
-                                    .with_context(|| anyhow!("Error syncing file"))
-                                    .unwrap()
-                            }
-                        }
-                    }
+        let result = async {
+            let current_modify_time = modify_time_secs(&path).await?;
+
+            if current_modify_time != modify_time.load(Ordering::Relaxed)
+                && cooperation::settled(&mut last_seen, current_modify_time)
+                && !SHOULD_SHUTDOWN.load(Ordering::Relaxed)
+                && !pause::is_paused()
+                && !limits::exceeded()
+                && retry::is_due(&path)
+                && !limits::is_too_large(&path)
+                && !transactional::contains(&path)
+                && consumer::is_settled(&path).await
+            {
+                trace::record(|| trace::Record::Change {
+                    path: path.clone(),
+                    known: modify_time.load(Ordering::Relaxed),
+                    modified: current_modify_time,
+                });
+                let _turn = coalesce::wait_turn(&path).await;
+                if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+                    return Ok(());
                 }
+                let dst_path = diff::copy(&path, &work_dir, &backup_dir).await?;
+                // Only now is the file known to be in sync at this modify time
+                modify_time.store(current_modify_time, Ordering::Relaxed);
+                retry::succeeded(&path);
+                output::emit(&Event::FileCopied {
+                    source: &path,
+                    destination: &dst_path,
+                });
             }
-            Err(err) => {
-                if err.kind() == io::ErrorKind::NotFound {
+
+            anyhow::Ok(())
+        }
+        .await;
+
+        if let Err(err) = result {
+            match races::classify(&path, &err) {
+                // The file was deleted or moved away, which the watch loop takes care of
+                Some(races::Race::Gone) => {
+                    retry::succeeded(&path);
                     return;
-                } else {
-                    todo!("Handle {err} correctly");
+                }
+                // Tried again next time around, since the modify time it's known at didn't change
+                Some(races::Race::Changed) => (),
+                None => {
+                    retry::failed(&path, retry::Operation::Copy, &err);
+                    output::emit(&Event::Error {
+                        path: Some(&path),
+                        message: format!("Error syncing file: {err:#}"),
+                    });
                 }
             }
-        };
+        }
 
         if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
             return;
         }
 
-        tokio::time::sleep(Duration::from_secs(2)).await;
+        match POLL_ONLY.load(Ordering::Relaxed) {
+            true => POLLED.notified().await,
+            false => tokio::time::sleep(Duration::from_secs(2)).await,
+        }
     }
 }
 
@@ -333,15 +3557,16 @@ fn convert_work_path_to_backup_path(
     work_dir: PathBuf,
     backup_dir: PathBuf,
 ) -> Result<PathBuf> {
-    let new_path = path.strip_prefix(&work_dir).with_context(|| {
+    let root = routes::root_of(&work_dir, &path);
+    let new_path = path.strip_prefix(&root).with_context(|| {
         anyhow!(
             "Error stripping prefix {} from {}",
-            work_dir.display(),
+            root.display(),
             path.display()
         )
     })?;
-    let mut dst_path = backup_dir.clone();
-    dst_path.push(new_path);
+    let mut dst_path = routes::root_for(&backup_dir, new_path);
+    dst_path.push(sanitize::translate(new_path, &work_dir, &backup_dir));
 
     Ok(dst_path)
 }
@@ -350,95 +3575,275 @@ fn convert_backup_path_to_work_path(
     work_dir: PathBuf,
     backup_dir: PathBuf,
 ) -> Result<PathBuf> {
-    let new_path = path.strip_prefix(&backup_dir).with_context(|| {
+    let root = routes::root_of(&backup_dir, &path);
+    let new_path = path.strip_prefix(&root).with_context(|| {
         anyhow!(
             "Error stripping prefix {} from {}",
-            backup_dir.display(),
+            root.display(),
             path.display()
         )
     })?;
-    let mut dst_path = work_dir.clone();
-    dst_path.push(new_path);
+    let mut dst_path = routes::root_for(&work_dir, new_path);
+    dst_path.push(sanitize::translate(new_path, &backup_dir, &work_dir));
 
     Ok(dst_path)
 }
 
-async fn copy_to_dst(path: PathBuf, work_dir: PathBuf, backup_dir: PathBuf) -> Result<()> {
+/// Copies `path` from work_dir to the same place in backup_dir, returning where it was copied to
+async fn copy_to_dst(path: PathBuf, work_dir: PathBuf, backup_dir: PathBuf) -> Result<PathBuf> {
     let dst_path = convert_work_path_to_backup_path(path.clone(), work_dir, backup_dir)?;
+    let _guard = locks::lock(&dst_path).await;
+    let staged = stage_copy(&path, dst_path).await?;
 
-    let backup_dir = {
-        let mut dst_path = dst_path.clone();
-        dst_path.pop();
-        dst_path
-    };
+    commit_copy(staged).await
+}
 
-    fs::create_dir_all(&backup_dir).await?;
+/// A copy of a file, written next to where it goes by [`stage_copy`] and not yet in place
+struct StagedCopy {
+    dst_path: PathBuf,
+    /// Where the copy was written, or None when there's nothing left to move into place, like for
+    /// a copier or a metadata only file
+    temp_path: Option<PathBuf>,
+    /// Whether there was no copy at `dst_path` yet
+    new: bool,
+}
 
-    // Becuase of potential write errors when trying to overwrite a write protected file, we simply remove it before copying to it
-    if let Err(err) = fs::remove_file(&dst_path).await {
-        // We can ignore not found errors, that just means there won't be any conflict
-        if err.kind() != io::ErrorKind::NotFound {
-            return Err(anyhow!("error removing file {}: {err}", dst_path.display()));
+/// Writes a copy of `path` next to `dst_path`, with its permissions, modify time and owner, after
+/// checking everything that could keep it from replacing `dst_path`. Nothing at `dst_path` changes
+/// until [`commit_copy`], so that a copy that fails or is refused halfway leaves the old one as it
+/// was. The destination's lock should be held until then
+async fn stage_copy(path: &Path, dst_path: PathBuf) -> Result<StagedCopy> {
+    containment::check(path)?;
+    containment::check(&dst_path)?;
+    let _slot = slots::acquire().await?;
+    let before = fs::metadata(path).await?;
+    faults::before_copy(path);
+    space::ensure_room(&dst_path, before.len())?;
+    let new = fs::symlink_metadata(&dst_path).await.is_err();
+    if !new {
+        let (from, to) = (path.to_path_buf(), dst_path.clone());
+        tokio::task::spawn_blocking(move || protect::allow_overwrite(&from, &to)).await??;
+    }
+
+    if copier::handles(&dst_path) {
+        copier::write(path, &dst_path, &before)
+            .await
+            .with_context(|| {
+                anyhow!(
+                    "Error copying from {} to {} through the copier",
+                    path.display(),
+                    dst_path.display()
+                )
+            })?;
+        return Ok(StagedCopy {
+            dst_path,
+            temp_path: None,
+            new,
+        });
+    }
+    read_only::unlock(&dst_path)?;
+
+    if metadata_only::matches(path) {
+        // A copy from before the file was metadata only would take up space for nothing
+        if let Err(err) = fs::remove_file(&dst_path).await {
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(err.into());
+            }
         }
+        metadata_only::record(path).await?;
+        return Ok(StagedCopy {
+            dst_path,
+            temp_path: None,
+            new,
+        });
     }
 
-    fs::copy(&path, &dst_path).await.with_context(|| {
-        anyhow!(
-            "Error copying from {} to {}",
-            path.display(),
-            dst_path.display()
-        )
-    })?;
+    if let Some(parent) = dst_path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let temp_path = copy::temp_path(&dst_path)?;
+    // Left behind by a copy that was cut off
+    if let Err(err) = fs::remove_file(&temp_path).await {
+        if err.kind() != io::ErrorKind::NotFound {
+            return Err(anyhow!(
+                "error removing file {}: {err}",
+                temp_path.display()
+            ));
+        }
+    }
 
-    Ok(())
-}
+    let result = async {
+        match filters::for_path(path) {
+            Some(command) => filters::apply(command, path, &temp_path).await?,
+            None => copy::copy(path, &temp_path).await.with_context(|| {
+                anyhow!(
+                    "Error copying from {} to {}",
+                    path.display(),
+                    dst_path.display()
+                )
+            })?,
+        }
+        faults::during_copy(path);
+        // The copy may have parts of both versions, and nothing would tell it apart from a good one
+        races::ensure_unchanged(path, &before)?;
+        ownership::copy_owner(path, &temp_path)?;
+        read_only::give_back(path, &temp_path)
+    }
+    .await;
+    if let Err(err) = result {
+        let _ = fs::remove_file(&temp_path).await;
+        return Err(err);
+    }
 
-async fn file_type<P: AsRef<Path>>(path: P) -> Result<FileType> {
-    Ok(fs::metadata(path).await?.file_type())
+    Ok(StagedCopy {
+        dst_path,
+        temp_path: Some(temp_path),
+        new,
+    })
 }
 
-pub fn hash_directory(dir: PathBuf) -> Result<HashMap<PathBuf, Hash>> {
-    if !dir.exists() {
-        return Err(anyhow!(
-            "Directory {} does not exist for hashing",
-            dir.display()
-        ));
+/// Moves the copy written by [`stage_copy`] into place, keeping the one it replaces as a version,
+/// and returns where it went
+async fn commit_copy(staged: StagedCopy) -> Result<PathBuf> {
+    let StagedCopy {
+        dst_path,
+        temp_path,
+        new,
+    } = staged;
+    if new {
+        digest::copying_new(&dst_path);
     }
+    let Some(temp_path) = temp_path else {
+        return Ok(dst_path);
+    };
 
-    if !dir.is_dir() {
-        return Err(anyhow!("Path {} is not a direectory!", dir.display()));
+    let result = async {
+        versions::keep(&dst_path).await?;
+        faults::crash_if(faults::Fault::CrashBeforeRename, &dst_path);
+        fs::rename(&temp_path, &dst_path)
+            .await
+            .with_context(|| anyhow!("Error moving the copy into place at {}", dst_path.display()))
+    }
+    .await;
+    if let Err(err) = result {
+        let _ = fs::remove_file(&temp_path).await;
+        return Err(err);
     }
+    faults::crash_if(faults::Fault::CrashAfterRename, &dst_path);
+    hashing::moved(&temp_path, &dst_path);
+    dedup::store(&dst_path).await?;
+
+    Ok(dst_path)
+}
+
+async fn modify_time_secs(path: &Path) -> Result<u64> {
+    Ok(fs::metadata(path)
+        .await?
+        .modified()?
+        .duration_since(UNIX_EPOCH)?
+        .as_secs())
+}
 
-    let file_paths: Vec<_> = recursive_dir(dir.as_ref()).collect();
+async fn file_type<P: AsRef<Path>>(path: P) -> Result<FileType> {
+    Ok(fs::metadata(path).await?.file_type())
+}
 
-    file_paths
-        .into_par_iter()
-        .map(|file_info| {
-            let mut hasher = Hasher::new();
+fn recursive_dir(dir: &Path, options: &WalkOptions) -> impl Iterator<Item = DirEntry> {
+    walk(dir, options, None)
+}
 
-            let mut file = std::fs::File::open(file_info.path())?;
-            std::io::copy(&mut file, &mut hasher)?;
+/// What a walk came across besides its files, for the pass that walked to sync it. Each pass has
+/// its own, so that walks of calibration, verify or another pass can't add to the ones it syncs
+#[derive(Clone, Default)]
+struct Found {
+    /// Special files, with --special-files=recreate
+    special: Arc<Mutex<BTreeSet<PathBuf>>>,
+    /// Directories below the one a walk started from
+    directories: Arc<Mutex<BTreeSet<PathBuf>>>,
+}
 
-            Ok((file_info.path().to_path_buf(), hasher.finalize()))
-        })
-        .collect::<Result<HashMap<PathBuf, Hash>>>()
+/// Like [`recursive_dir`], also adding what it comes across to `found`
+fn recursive_dir_finding(
+    dir: &Path,
+    options: &WalkOptions,
+    found: &Found,
+) -> impl Iterator<Item = DirEntry> {
+    walk(dir, options, Some(found.clone()))
 }
 
-fn recursive_dir(dir: &Path) -> impl Iterator<Item = DirEntry> {
+fn walk(dir: &Path, options: &WalkOptions, found: Option<Found>) -> impl Iterator<Item = DirEntry> {
+    let secrets = options
+        .exclude_secrets
+        .then(|| secrets::matcher(dir).expect("the secret patterns are valid"));
+    let stignore = stignore::matcher(dir);
+    let held = open_files::take_for_walk();
+    let root = dir.to_path_buf();
+
     ignore::WalkBuilder::new(dir)
         .hidden(false)
         .follow_links(false)
+        .same_file_system(options.same_file_system)
+        .add_custom_ignore_filename(IGNORE_FILE_NAME)
+        .overrides(
+            options
+                .overrides(dir)
+                .expect("exclude patterns are validated on startup"),
+        )
+        .filter_entry(move |f| {
+            f.file_name() != STATE_DIR_NAME
+                && !copy::is_temp(f.file_name())
+                && f.file_name() != pause::SENTINEL_NAME
+                && f.file_name() != deletion::ALLOW_SENTINEL_NAME
+                && !(f.depth() > 0
+                    && f.file_type().is_some_and(|file_type| file_type.is_dir())
+                    && f.metadata()
+                        .is_ok_and(|metadata| overlap::is_root_again(f.path(), &metadata)))
+                && !secrets.as_ref().is_some_and(|secrets| {
+                    let is_dir = f.file_type().is_some_and(|file_type| file_type.is_dir());
+                    secrets::is_secret(secrets, f.path(), is_dir)
+                })
+                && !stignore.as_ref().is_some_and(|stignore| {
+                    let is_dir = f.file_type().is_some_and(|file_type| file_type.is_dir());
+                    stignore.matched(f.path(), is_dir).is_ignore()
+                })
+        })
         .build()
-        .filter_map(|f| f.ok())
-        .filter(|f| match f.file_type() {
-            Some(file_type) => file_type.is_file(),
+        .filter_map(move |f| {
+            // The walk's share of the open files budget goes back once the walk is dropped
+            let _held = &held;
+            f.inspect_err(unreadable::classify_walk_error).ok()
+        })
+        .filter(move |f| match f.file_type() {
+            Some(file_type) => {
+                cooperation::found(&root, f.path());
+                if let Some(kind) = special::kind(file_type) {
+                    if special::found(f.path(), kind) {
+                        if let Some(found) = &found {
+                            found.special.lock().unwrap().insert(f.path().to_path_buf());
+                        }
+                    }
+                }
+                if let Some(found) = found
+                    .as_ref()
+                    .filter(|_| file_type.is_dir() && f.depth() > 0)
+                {
+                    found
+                        .directories
+                        .lock()
+                        .unwrap()
+                        .insert(f.path().to_path_buf());
+                }
+                file_type.is_file()
+            }
             None => false,
         })
 }
 
-async fn dir_modify_time(work_dir: &Path) -> Result<u64> {
-    let meta_times: Result<Vec<u64>> =
-        futures::future::try_join_all(recursive_dir(work_dir).map(|dir_entry| async move {
+/// The newest modification time of any file in the directory. An empty directory counts as never
+/// modified, so that it's always initialized from the other side
+async fn dir_modify_time(work_dir: &Path, walk_options: &WalkOptions) -> Result<u64> {
+    let meta_times: Result<Vec<u64>> = futures::future::try_join_all(
+        recursive_dir(work_dir, walk_options).map(|dir_entry| async move {
             let file_path = {
                 Ok(fs::metadata(dir_entry.path())
                     .await?
@@ -448,10 +3853,11 @@ async fn dir_modify_time(work_dir: &Path) -> Result<u64> {
             };
 
             file_path
-        }))
-        .await;
+        }),
+    )
+    .await;
 
-    meta_times?
+    Ok(meta_times?
         .into_iter()
         .reduce(
             |newest_mod_time, mod_time| match mod_time > newest_mod_time {
@@ -459,5 +3865,5 @@ async fn dir_modify_time(work_dir: &Path) -> Result<u64> {
                 false => newest_mod_time,
             },
         )
-        .ok_or_else(|| anyhow!("Directory is empty"))
+        .unwrap_or(0))
 }
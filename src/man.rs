@@ -0,0 +1,149 @@
+//! The man page of `evil_mount man`, in roff, written from the same definitions that parse the
+//! command line like the completions are. Every subcommand gets a section of its own, with its
+//! flags listed under it
+//!
+//! Install it like `evil_mount man > /usr/local/share/man/man1/evil_mount.1`
+
+use clap::{builder::StyledStr, Arg, Command};
+use std::fmt::Write;
+
+use crate::completions::{options, positionals, subcommands, value_name, values, walk};
+
+/// The man page of `command`
+pub fn render(mut command: Command) -> String {
+    command.build();
+    let name = command.get_name().to_string();
+    let mut page = String::new();
+
+    let _ = writeln!(
+        page,
+        ".TH {} 1 \"\" \"{name} {}\" \"User Commands\"",
+        name.to_uppercase(),
+        command.get_version().unwrap_or_default()
+    );
+    let _ = writeln!(page, ".SH NAME");
+    let _ = writeln!(
+        page,
+        "{} \\- {}",
+        escape(&name),
+        escape(&help_text(command.get_about()))
+    );
+    let _ = writeln!(page, ".SH SYNOPSIS");
+    let _ = writeln!(page, "{}", synopsis(&[], &name, &command));
+
+    let mut commands = Vec::new();
+    walk(Vec::new(), &command, &mut commands);
+    let mut commands_listed = false;
+    for (path, command) in commands {
+        // The walk starts at the top, so the options of the top come before every subcommand
+        match path.is_empty() {
+            true => {
+                let _ = writeln!(page, ".SH OPTIONS");
+            }
+            false if !commands_listed => {
+                let _ = writeln!(page, ".SH COMMANDS");
+                commands_listed = true;
+            }
+            false => (),
+        }
+        if !path.is_empty() {
+            let _ = writeln!(page, ".SS \"{}\"", escape(&path.join(" ")));
+            let _ = writeln!(page, "{}", synopsis(&path, &name, command));
+            let _ = writeln!(page, ".PP");
+            let about = command.get_long_about().or(command.get_about());
+            let _ = writeln!(page, "{}", escape(&help_text(about)));
+        }
+        for arg in options(command) {
+            describe(&mut page, arg);
+        }
+        for arg in positionals(command) {
+            describe(&mut page, arg);
+        }
+    }
+
+    page
+}
+
+/// How `command`, found at `path` below `name`, is run
+fn synopsis(path: &[&str], name: &str, command: &Command) -> String {
+    let mut synopsis = format!("\\fB{}", escape(name));
+    for name in path {
+        let _ = write!(synopsis, " {}", escape(name));
+    }
+    synopsis.push_str("\\fR");
+    if options(command).next().is_some() {
+        synopsis.push_str(" [\\fIOPTIONS\\fR]");
+    }
+    for arg in positionals(command) {
+        let value = format!("\\fI{}\\fR", escape(&value_name(arg)));
+        let many = arg.get_num_args().is_some_and(|num| num.max_values() > 1);
+        let value = match many {
+            true => format!("{value}..."),
+            false => value,
+        };
+        let _ = match arg.is_required_set() {
+            true => write!(synopsis, " {value}"),
+            false => write!(synopsis, " [{value}]"),
+        };
+    }
+    if subcommands(command).next().is_some() {
+        synopsis.push_str(match command.is_subcommand_required_set() {
+            true => " \\fICOMMAND\\fR",
+            false => " [\\fICOMMAND\\fR]",
+        });
+    }
+    format!(".PP\n{synopsis}")
+}
+
+/// Lists `arg` with its help, the values it takes and its default
+fn describe(page: &mut String, arg: &Arg) {
+    let mut flags = Vec::new();
+    if let Some(short) = arg.get_short() {
+        flags.push(format!("\\fB\\-{short}\\fR"));
+    }
+    if let Some(long) = arg.get_long() {
+        flags.push(format!("\\fB\\-\\-{}\\fR", escape(long)));
+    }
+    let value = format!("\\fI{}\\fR", escape(&value_name(arg)));
+    let term = match (flags.is_empty(), arg.get_action().takes_values()) {
+        (true, _) => value,
+        (false, true) => format!("{} {value}", flags.join(", ")),
+        (false, false) => flags.join(", "),
+    };
+
+    let mut help = help_text(arg.get_long_help().or(arg.get_help()));
+    let values = values(arg);
+    if !values.is_empty() {
+        let _ = write!(help, " [possible values: {}]", values.join(", "));
+    }
+    let defaults: Vec<_> = arg
+        .get_default_values()
+        .iter()
+        .map(|value| value.to_string_lossy())
+        .collect();
+    if !defaults.is_empty() && arg.get_action().takes_values() {
+        let _ = write!(help, " [default: {}]", defaults.join(", "));
+    }
+
+    let _ = writeln!(page, ".TP");
+    let _ = writeln!(page, "{term}");
+    let _ = writeln!(page, "{}", escape(help.trim()));
+}
+
+fn help_text(help: Option<&StyledStr>) -> String {
+    help.map(StyledStr::to_string).unwrap_or_default()
+}
+
+/// `text` as roff, with the characters roff would take for its own escaped
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\e")
+        .replace('-', "\\-")
+        .lines()
+        .map(|line| match line {
+            "" => ".sp".to_string(),
+            line if line.starts_with(['.', '\'']) => format!("\\&{line}"),
+            line => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,182 @@
+//! Files that are only recorded rather than copied, for enormous media that can be downloaded
+//! again. Each file matching `--metadata-only` gets its size, modify time and hash written to a
+//! manifest in the state dir, so that `verify` and `status` still cover it without it taking up
+//! space in the backup
+
+use anyhow::{anyhow, Context, Result};
+use blake3::Hash;
+use ignore::overrides::{Override, OverrideBuilder};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+    time::UNIX_EPOCH,
+};
+
+use crate::{encoded_path, hash_file, state_dir};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub size: u64,
+    /// In seconds since the epoch
+    pub modified: u64,
+    pub hash: String,
+}
+
+struct Matcher {
+    work_dir: PathBuf,
+    globs: Override,
+}
+
+static MATCHER: OnceLock<Matcher> = OnceLock::new();
+
+/// Every recorded file, keyed by its path relative to work_dir
+static MANIFEST: Mutex<BTreeMap<PathBuf, Entry>> = Mutex::new(BTreeMap::new());
+/// Set when the manifest changed since it was last saved
+static UNSAVED: AtomicBool = AtomicBool::new(false);
+
+fn manifest_path(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("metadata_only.json")
+}
+
+/// Sets which files in work_dir are only recorded, and loads the manifest. Must be called before
+/// anything is synced
+pub fn set(work_dir: &Path, backup_dir: &Path, globs: &[String]) -> Result<()> {
+    let mut overrides = OverrideBuilder::new(work_dir);
+    for glob in globs {
+        overrides
+            .add(glob)
+            .with_context(|| anyhow!("Invalid metadata only pattern {glob}"))?;
+    }
+    MATCHER
+        .set(Matcher {
+            work_dir: work_dir.to_path_buf(),
+            globs: overrides.build()?,
+        })
+        .map_err(|_| anyhow!("the metadata only patterns can only be set once"))?;
+
+    let path = manifest_path(backup_dir);
+    let contents = match std::fs::read(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let saved: BTreeMap<PathBuf, Entry> = encoded_path::keys::from_json(&contents)
+        .with_context(|| anyhow!("Error parsing {}", path.display()))?;
+    MANIFEST.lock().unwrap().extend(saved);
+
+    Ok(())
+}
+
+/// The path of `path` relative to work_dir, if it's a file in work_dir that's only recorded
+fn relative_path(path: &Path) -> Option<&Path> {
+    let matcher = MATCHER.get()?;
+    let relative_path = path.strip_prefix(&matcher.work_dir).ok()?;
+
+    matcher
+        .globs
+        .matched(relative_path, false)
+        .is_whitelist()
+        .then_some(relative_path)
+}
+
+/// Whether `path` in work_dir is only recorded instead of copied
+pub fn matches(path: &Path) -> bool {
+    relative_path(path).is_some()
+}
+
+fn modified_secs(metadata: &std::fs::Metadata) -> Result<u64> {
+    Ok(metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Whether the manifest already has `path` in work_dir at its current size and modify time
+pub async fn is_current(path: &Path) -> Result<bool> {
+    let Some(relative_path) = relative_path(path) else {
+        return Ok(false);
+    };
+    let metadata = tokio::fs::metadata(path).await?;
+
+    Ok(MANIFEST
+        .lock()
+        .unwrap()
+        .get(relative_path)
+        .is_some_and(|entry| {
+            entry.size == metadata.len()
+                && modified_secs(&metadata).is_ok_and(|modified| entry.modified == modified)
+        }))
+}
+
+/// Hashes `path` in work_dir and records it in the manifest
+pub async fn record(path: &Path) -> Result<()> {
+    let Some(relative_path) = relative_path(path).map(Path::to_path_buf) else {
+        return Ok(());
+    };
+
+    let path = path.to_path_buf();
+    let entry = tokio::task::spawn_blocking(move || {
+        let metadata = std::fs::metadata(&path)?;
+        let hash = hash_file(&path)?;
+        anyhow::Ok(Entry {
+            size: metadata.len(),
+            modified: modified_secs(&metadata)?,
+            hash: hash.to_hex().to_string(),
+        })
+    })
+    .await??;
+
+    MANIFEST.lock().unwrap().insert(relative_path, entry);
+    UNSAVED.store(true, Ordering::Relaxed);
+
+    Ok(())
+}
+
+/// The recorded hash of a file, by its path relative to work_dir
+pub fn hash(relative_path: &Path) -> Option<Hash> {
+    let manifest = MANIFEST.lock().unwrap();
+    Hash::from_hex(&manifest.get(relative_path)?.hash).ok()
+}
+
+/// Drops the files that no longer exist in work_dir from the manifest
+pub fn prune() {
+    let Some(matcher) = MATCHER.get() else {
+        return;
+    };
+
+    let mut manifest = MANIFEST.lock().unwrap();
+    let count = manifest.len();
+    manifest.retain(|relative_path, _| matcher.work_dir.join(relative_path).exists());
+    if manifest.len() != count {
+        UNSAVED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// How many files are recorded, and how large they are altogether
+pub fn totals() -> (u64, u64) {
+    let manifest = MANIFEST.lock().unwrap();
+    (
+        manifest.len() as u64,
+        manifest.values().map(|entry| entry.size).sum(),
+    )
+}
+
+/// Saves the manifest if it changed
+pub fn save(backup_dir: &Path) -> Result<()> {
+    if !UNSAVED.swap(false, Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let path = manifest_path(backup_dir);
+    let temp_path = path.with_extension("json.tmp");
+    let contents = encoded_path::keys::to_json(&*MANIFEST.lock().unwrap())?;
+
+    std::fs::create_dir_all(state_dir(backup_dir))?;
+    std::fs::write(&temp_path, contents)?;
+    std::fs::rename(&temp_path, &path)
+        .with_context(|| anyhow!("Error saving {}", path.display()))?;
+
+    Ok(())
+}
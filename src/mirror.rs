@@ -0,0 +1,146 @@
+//! Mirroring backup_dir to an rsync server with `--mirror-to`, like `rsync://host/module/path`.
+//! Everything else expects backup_dir to be a local directory, so instead of syncing into the
+//! server directly, rsync copies whatever changed in backup_dir over after every cycle that
+//! changed something, sending only the differences of changed files. The state dir stays local
+//!
+//! Only one rsync runs at a time. Changes made while it runs are picked up by the next one
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use crate::{
+    dedup,
+    output::{self, Event},
+    profiles, STATE_DIR_NAME,
+};
+
+struct Mirror {
+    backup_dir: PathBuf,
+    url: String,
+}
+
+static MIRROR: OnceLock<Mirror> = OnceLock::new();
+/// Set when backup_dir changed since the last rsync started
+static DIRTY: AtomicBool = AtomicBool::new(false);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+/// Set when the last rsync failed
+static FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Mirrors `backup_dir` to the rsync destination `url`, starting with the first cycle
+pub fn set(backup_dir: PathBuf, url: String) -> Result<()> {
+    if !cfg!(feature = "mirror") {
+        return Err(anyhow!(
+            "This evil_mount was built without mirroring, enable the mirror feature to use --mirror-to"
+        ));
+    }
+    if !url.starts_with("rsync://") && !url.contains("::") {
+        return Err(anyhow!(
+            "--mirror-to {url} isn't an rsync daemon, like rsync://host/module/path or host::module/path"
+        ));
+    }
+    let version = Command::new("rsync")
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .context("--mirror-to needs rsync to be installed")?;
+    if !version.success() {
+        return Err(anyhow!(
+            "--mirror-to needs a working rsync, `rsync --version` failed"
+        ));
+    }
+
+    DIRTY.store(true, Ordering::Relaxed);
+    MIRROR
+        .set(Mirror { backup_dir, url })
+        .map_err(|_| anyhow!("the mirror can only be set once"))
+}
+
+/// Notices changes to backup_dir, and mirrors them once a cycle is over. Called for every emitted
+/// event
+pub fn observe(event: &Event) {
+    match event {
+        Event::FileCopied { .. } | Event::FileDeleted { .. } | Event::FileMoved { .. } => {
+            DIRTY.store(true, Ordering::Relaxed);
+        }
+        Event::CycleComplete { .. } => start(),
+        _ => (),
+    }
+}
+
+/// Starts an rsync in the background if anything changed and none is running
+fn start() {
+    let Some(mirror) = MIRROR.get() else {
+        return;
+    };
+    if !DIRTY.load(Ordering::Relaxed) || RUNNING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    std::thread::spawn(|| {
+        while DIRTY.swap(false, Ordering::Relaxed) {
+            let result = run(mirror);
+            FAILED.store(result.is_err(), Ordering::Relaxed);
+            if let Err(err) = result {
+                output::emit(&Event::Error {
+                    path: None,
+                    message: format!("Error mirroring to {}: {err:#}", mirror.url),
+                });
+            }
+        }
+        RUNNING.store(false, Ordering::Relaxed);
+    });
+}
+
+fn run(mirror: &Mirror) -> Result<()> {
+    // The trailing slash copies the contents of backup_dir rather than the directory itself
+    let mut source = mirror.backup_dir.clone().into_os_string();
+    source.push("/");
+
+    let output = Command::new("rsync")
+        .args(["--archive", "--delete", "--partial"])
+        // Otherwise every link to a blob would become a copy of its own on the mirror
+        .args(dedup::is_enabled().then_some("--hard-links"))
+        .arg(format!("--exclude=/{STATE_DIR_NAME}/"))
+        .arg("--")
+        .arg(source)
+        .arg(&mirror.url)
+        .stdin(Stdio::null())
+        .output()
+        .context("Error running rsync")?;
+
+    match output.status.success() {
+        true => Ok(()),
+        false => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(anyhow!(
+                "rsync {}: {}",
+                profiles::describe(output.status),
+                stderr.trim()
+            ))
+        }
+    }
+}
+
+/// Mirrors whatever changed since the last rsync and waits for it, returning whether the mirror
+/// is up to date. Returns right away without `--mirror-to`
+pub async fn wait() -> bool {
+    if MIRROR.get().is_none() {
+        return true;
+    }
+
+    start();
+    while RUNNING.load(Ordering::Relaxed) {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    !FAILED.load(Ordering::Relaxed)
+}
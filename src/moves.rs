@@ -0,0 +1,98 @@
+//! Detecting files that were moved or renamed in work_dir, so that their copy in backup_dir can be
+//! renamed as well instead of being deleted and copied over again
+
+use anyhow::Result;
+use blake3::Hash;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+
+use crate::{
+    convert_backup_path_to_work_path, copier, deletion, hash_file, locks, protect, recursive_dir,
+    WalkOptions,
+};
+
+/// Renames `from` in backup_dir to `to`, creating any missing parent directories
+pub async fn rename(from: &Path, to: &Path) -> Result<()> {
+    let _guards = locks::lock_both(from, to).await;
+    copier::rename(from, to).await?;
+    deletion::forget(from);
+
+    Ok(())
+}
+
+/// Files in backup_dir that no longer exist in work_dir, grouped by size, which new files in
+/// work_dir may have been moved from
+pub struct Orphans {
+    by_size: HashMap<u64, Vec<(PathBuf, Option<Hash>)>>,
+}
+
+impl Orphans {
+    pub async fn find(work_dir: &Path, backup_dir: &Path, walk_options: &WalkOptions) -> Self {
+        let mut by_size: HashMap<u64, Vec<_>> = HashMap::new();
+
+        for file_info in recursive_dir(backup_dir, walk_options) {
+            let backup_path = file_info.into_path();
+            let Ok(work_path) = convert_backup_path_to_work_path(
+                backup_path.clone(),
+                work_dir.to_path_buf(),
+                backup_dir.to_path_buf(),
+            ) else {
+                continue;
+            };
+            // Renaming a protected file away would delete it from its old path
+            if fs::try_exists(&work_path).await.unwrap_or(true)
+                || protect::is_protected(&backup_path)
+            {
+                continue;
+            }
+
+            if let Ok(metadata) = fs::metadata(&backup_path).await {
+                by_size
+                    .entry(metadata.len())
+                    .or_default()
+                    .push((backup_path, None));
+            }
+        }
+
+        Self { by_size }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_size.is_empty()
+    }
+
+    /// Looks for an orphan with the same contents as `path`, and if there is one takes it out of
+    /// the orphans to be renamed to where `path` belongs. Returns where the orphan is
+    pub async fn adopt(&mut self, path: &Path) -> Result<Option<PathBuf>> {
+        let size = fs::metadata(path).await?.len();
+        let Some(candidates) = self.by_size.get_mut(&size) else {
+            return Ok(None);
+        };
+
+        let hash = hash_blocking(path.to_path_buf()).await?;
+        for index in 0..candidates.len() {
+            let (candidate, candidate_hash) = &mut candidates[index];
+            let candidate_hash = match candidate_hash {
+                Some(candidate_hash) => *candidate_hash,
+                None => match hash_blocking(candidate.clone()).await {
+                    Ok(hash) => *candidate_hash.insert(hash),
+                    Err(_) => continue,
+                },
+            };
+
+            if candidate_hash == hash {
+                let (candidate, _) = candidates.swap_remove(index);
+                return Ok(Some(candidate));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+async fn hash_blocking(path: PathBuf) -> Result<Hash> {
+    tokio::task::spawn_blocking(move || hash_file(&path)).await?
+}
@@ -0,0 +1,225 @@
+//! Desktop notifications with `--notify`, for when nobody is watching the output: when errors
+//! pile up, and when the backup falls behind because no cycle finished without errors in a while.
+//! They're raised with notify-send on Linux and the BSDs, and with osascript on macOS
+//!
+//! Each kind of notification is raised at most once per COOLDOWN, so a sync that keeps failing
+//! doesn't flood the desktop
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::VecDeque,
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    health,
+    output::{self, Event},
+    pause,
+};
+
+/// How far back errors are counted towards --notify-errors
+const ERROR_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// How long apart two notifications of the same kind are at least
+const COOLDOWN: Duration = Duration::from_secs(60 * 60);
+/// How often the notifier checks whether the backup fell behind
+const CHECK_EVERY: Duration = Duration::from_secs(60);
+
+struct Notifier {
+    errors: usize,
+    behind: Duration,
+    /// When notifying started, which the backup counts as being behind since until a cycle finishes
+    /// without errors
+    started: u64,
+}
+
+static NOTIFIER: OnceLock<Notifier> = OnceLock::new();
+/// When the recent errors happened
+static ERRORS: Mutex<VecDeque<Instant>> = Mutex::new(VecDeque::new());
+static ERRORS_NOTIFIED_AT: Mutex<Option<Instant>> = Mutex::new(None);
+static BEHIND_NOTIFIED_AT: Mutex<Option<Instant>> = Mutex::new(None);
+/// Set once raising a notification failed, which was reported and isn't tried again
+static BROKEN: AtomicBool = AtomicBool::new(false);
+/// How many notifications are being raised
+static RAISING: AtomicU64 = AtomicU64::new(0);
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+/// Raises a notification once `errors` errors happened within ERROR_WINDOW, or once no cycle
+/// finished without errors for `behind`
+pub fn set(errors: usize, behind: Duration) -> Result<()> {
+    if cfg!(any(windows, target_os = "android")) {
+        return Err(anyhow!(
+            "--notify isn't supported on this platform, watch for errors with --on-error instead"
+        ));
+    }
+    let (program, probe) = match cfg!(target_os = "macos") {
+        true => ("osascript", ["-e", "return"].as_slice()),
+        false => ("notify-send", ["--version"].as_slice()),
+    };
+    let version = Command::new(program)
+        .args(probe)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .with_context(|| anyhow!("--notify needs {program} to be installed"))?;
+    if !version.success() {
+        return Err(anyhow!("--notify needs a working {program}"));
+    }
+
+    NOTIFIER
+        .set(Notifier {
+            errors,
+            behind,
+            started: now_secs(),
+        })
+        .map_err(|_| anyhow!("the notifier can only be set once"))?;
+    std::thread::spawn(|| loop {
+        std::thread::sleep(CHECK_EVERY);
+        check_behind();
+    });
+
+    Ok(())
+}
+
+/// Counts errors towards --notify-errors. Called for every emitted event
+pub fn observe(event: &Event) {
+    let Some(notifier) = NOTIFIER.get() else {
+        return;
+    };
+    let Event::Error { path, message } = event else {
+        return;
+    };
+
+    let now = Instant::now();
+    let count = {
+        let mut errors = ERRORS.lock().unwrap();
+        errors.push_back(now);
+        while errors
+            .front()
+            .is_some_and(|at| now.duration_since(*at) > ERROR_WINDOW)
+        {
+            errors.pop_front();
+        }
+        errors.len()
+    };
+    if count < notifier.errors || !cooled_down(&ERRORS_NOTIFIED_AT) {
+        return;
+    }
+
+    let latest = match path {
+        Some(path) => format!("{}: {message}", path.display()),
+        None => message.clone(),
+    };
+    raise(
+        format!(
+            "{count} errors in the last {} minutes",
+            ERROR_WINDOW.as_secs() / 60
+        ),
+        format!("The latest: {latest}"),
+    );
+}
+
+fn check_behind() {
+    let Some(notifier) = NOTIFIER.get() else {
+        return;
+    };
+    // Falling behind is the point of pausing by hand
+    if pause::PAUSED.load(Ordering::Relaxed) {
+        return;
+    }
+    let since = health::last_successful_cycle().unwrap_or(notifier.started);
+    let behind = now_secs().saturating_sub(since);
+    if behind < notifier.behind.as_secs() || !cooled_down(&BEHIND_NOTIFIED_AT) {
+        return;
+    }
+
+    raise(
+        "The backup is falling behind".to_string(),
+        format!(
+            "No sync finished without errors in the last {} minutes",
+            behind / 60
+        ),
+    );
+}
+
+/// Whether a notification of the kind last raised at `notified_at` can be raised again, marking
+/// it raised now if so
+fn cooled_down(notified_at: &Mutex<Option<Instant>>) -> bool {
+    let mut notified_at = notified_at.lock().unwrap();
+    if notified_at.is_some_and(|at| at.elapsed() < COOLDOWN) {
+        return false;
+    }
+    *notified_at = Some(Instant::now());
+
+    true
+}
+
+/// Raises a notification in the background, since it's raised from wherever an error happened
+fn raise(summary: String, body: String) {
+    if BROKEN.load(Ordering::Relaxed) {
+        return;
+    }
+
+    RAISING.fetch_add(1, Ordering::Relaxed);
+    std::thread::spawn(move || {
+        let mut command = match cfg!(target_os = "macos") {
+            true => {
+                let mut command = Command::new("osascript");
+                command
+                    .args(["-e", "on run argv"])
+                    .args([
+                        "-e",
+                        "display notification (item 2 of argv) with title (item 1 of argv)",
+                    ])
+                    .args(["-e", "end run"]);
+                command
+            }
+            false => {
+                let mut command = Command::new("notify-send");
+                command.args(["--app-name", "evil_mount"]);
+                command
+            }
+        };
+        let result = command
+            .arg(format!("evil_mount: {summary}"))
+            .arg(body)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output();
+
+        let err = match result {
+            Ok(output) if output.status.success() => None,
+            Ok(output) => Some(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+            Err(err) => Some(err.to_string()),
+        };
+        if let Some(err) = err {
+            if !BROKEN.swap(true, Ordering::Relaxed) {
+                output::emit(&Event::Error {
+                    path: None,
+                    message: format!(
+                        "Error raising a desktop notification, not trying again: {err}"
+                    ),
+                });
+            }
+        }
+        RAISING.fetch_sub(1, Ordering::Relaxed);
+    });
+}
+
+/// Waits for the notifications being raised, before exiting
+pub async fn wait() {
+    while RAISING.load(Ordering::Relaxed) > 0 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
@@ -0,0 +1,129 @@
+//! A budget of open files, so that parallel copies, hashing threads and walks of a big tree can't
+//! run the process out of file descriptors. A copy takes two from it for as long as it runs, a
+//! hash one, and a walk WALK_SHARE for the directories it keeps open, each waiting while the
+//! budget is used up. It's `--max-open-files`, or otherwise what's left of the process's limit
+//! after the directories the watch loop keeps open and everything else
+//!
+//! A thread that already holds some of the budget doesn't wait for more, like to hash a copy it's
+//! verifying, since waiting while holding could deadlock. It goes over by those few instead
+
+use anyhow::{anyhow, Result};
+use std::{
+    cell::Cell,
+    sync::{Condvar, Mutex, OnceLock},
+};
+
+use crate::{output::info, platform, MAX_WATCHED_DIRS};
+
+/// The smallest budget that leaves room for a few walks and copies at once
+pub const MIN: usize = 32;
+/// How many directories a walk keeps open at most
+const WALK_SHARE: usize = 10;
+/// How many files outside of the budget are left room for besides the watched directories, like
+/// sockets, the state files and the copy slots
+const RESERVED: usize = 64;
+
+struct Budget {
+    /// Less than nothing while a thread goes over
+    available: Mutex<isize>,
+    returned: Condvar,
+}
+
+static BUDGET: OnceLock<Budget> = OnceLock::new();
+
+thread_local! {
+    /// How much of the budget this thread holds
+    static HOLDING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Part of the budget, given back when dropped
+pub struct Held {
+    count: usize,
+    /// Whether HOLDING counts it, which a walk moving between threads can't be
+    on_thread: bool,
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        if self.on_thread {
+            HOLDING.set(HOLDING.get() - self.count);
+        }
+        if let Some(budget) = BUDGET.get() {
+            *budget.available.lock().unwrap() += self.count as isize;
+            budget.returned.notify_all();
+        }
+    }
+}
+
+/// Limits open files to `max`, or to what the process's limit leaves room for without it. There's
+/// no budget without either
+pub fn set(max: Option<usize>) -> Result<()> {
+    let limit = platform::open_files_limit()
+        .map(|limit| usize::try_from(limit).unwrap_or(usize::MAX))
+        .map(|limit| {
+            limit
+                .saturating_sub(MAX_WATCHED_DIRS + RESERVED)
+                .max(limit / 4)
+        });
+    let total = match (max, limit) {
+        (Some(max), _) if max < MIN => {
+            return Err(anyhow!("--max-open-files must be at least {MIN}"));
+        }
+        (Some(max), Some(limit)) if max > limit => {
+            info!("--max-open-files {max} is more than the {limit} files the process's limit leaves room for, opening files may fail");
+            max
+        }
+        (Some(max), _) => max,
+        (None, Some(limit)) => limit.max(MIN),
+        (None, None) => return Ok(()),
+    };
+
+    BUDGET
+        .set(Budget {
+            available: Mutex::new(isize::try_from(total).unwrap_or(isize::MAX)),
+            returned: Condvar::new(),
+        })
+        .map_err(|_| anyhow!("the open files budget can only be set once"))
+}
+
+/// Waits until `count` files can be opened on this thread, holding them until dropped
+pub fn take(count: usize) -> Held {
+    // Holding some already means not waiting for more
+    let wait = HOLDING.get() == 0;
+    HOLDING.set(HOLDING.get() + count);
+    acquire(count, wait);
+
+    Held {
+        count,
+        on_thread: true,
+    }
+}
+
+/// Waits until a walk can keep its directories open, holding the budget for them until dropped.
+/// Blocks the thread even in the async runtime, since walks are iterated there
+pub fn take_for_walk() -> Held {
+    match tokio::runtime::Handle::try_current() {
+        Ok(_) => tokio::task::block_in_place(|| acquire(WALK_SHARE, true)),
+        Err(_) => acquire(WALK_SHARE, true),
+    }
+
+    Held {
+        count: WALK_SHARE,
+        on_thread: false,
+    }
+}
+
+fn acquire(count: usize, wait: bool) {
+    let Some(budget) = BUDGET.get() else {
+        return;
+    };
+
+    let mut available = budget.available.lock().unwrap();
+    if wait {
+        available = budget
+            .returned
+            .wait_while(available, |available| *available < count as isize)
+            .unwrap();
+    }
+    *available -= count as isize;
+}
@@ -0,0 +1,382 @@
+//! Reporting of what evil_mount is doing, either for humans or, with `--output json`, as newline
+//! delimited JSON events on stdout
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::Arguments,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
+
+use crate::encoded_path;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+
+/// Sets the output format for the rest of the process. Must be called before anything is emitted
+pub fn set_format(format: OutputFormat) {
+    FORMAT
+        .set(format)
+        .expect("the output format can only be set once");
+}
+
+fn format() -> OutputFormat {
+    FORMAT.get().copied().unwrap_or_default()
+}
+
+/// What's printed for humans. JSON events are printed whatever the level, since they're what a
+/// program reading them asked for
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    /// Only errors
+    Error,
+    /// Errors and warnings, like files being skipped
+    Warning,
+    /// Progress messages too
+    #[default]
+    Info,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+/// Sets what's printed from now on, which a reload can change
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+fn shows(level: LogLevel) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    FileCopied {
+        #[serde(serialize_with = "encoded_path::lossy")]
+        source: &'a Path,
+        #[serde(serialize_with = "encoded_path::lossy")]
+        destination: &'a Path,
+    },
+    FileDeleted {
+        #[serde(serialize_with = "encoded_path::lossy")]
+        path: &'a Path,
+    },
+    /// An entry that was skipped because it can't be read
+    Unreadable {
+        #[serde(serialize_with = "encoded_path::lossy")]
+        path: &'a Path,
+    },
+    /// A file that was skipped because it's over --max-file-size
+    TooLarge {
+        #[serde(serialize_with = "encoded_path::lossy")]
+        path: &'a Path,
+        size: u64,
+    },
+    /// A file that was left out because it looks like a secret, with --exclude-secrets
+    Secret {
+        #[serde(serialize_with = "encoded_path::lossy")]
+        path: &'a Path,
+    },
+    /// A FIFO, socket or device file that was skipped because its contents can't be copied
+    SpecialFile {
+        #[serde(serialize_with = "encoded_path::lossy")]
+        path: &'a Path,
+        kind: &'static str,
+    },
+    /// Something to watch out for about another sync tool working on `path`
+    OtherSyncTool {
+        tool: &'static str,
+        #[serde(serialize_with = "encoded_path::lossy")]
+        path: &'a Path,
+        warning: &'static str,
+    },
+    /// A file in backup_dir that was renamed because it was moved in work_dir
+    FileMoved {
+        #[serde(serialize_with = "encoded_path::lossy")]
+        from: &'a Path,
+        #[serde(serialize_with = "encoded_path::lossy")]
+        to: &'a Path,
+    },
+    Error {
+        #[serde(serialize_with = "encoded_path::lossy_option")]
+        path: Option<&'a Path>,
+        message: String,
+    },
+    CycleComplete {
+        copied: u64,
+        moved: u64,
+        deleted: u64,
+        errors: u64,
+    },
+    /// A file that's `missing` from backup_dir, `extra` in backup_dir, or `mismatched` between them
+    VerifyProblem {
+        #[serde(serialize_with = "encoded_path::lossy")]
+        path: &'a Path,
+        problem: &'static str,
+    },
+    VerifyComplete {
+        matched: u64,
+        missing: u64,
+        extra: u64,
+        mismatched: u64,
+        errors: u64,
+    },
+    /// The restored files that were hashed and match the backup, and the ones that don't
+    RestoreVerified {
+        #[serde(serialize_with = "encoded_path::lossy_list")]
+        verified: &'a [PathBuf],
+        #[serde(serialize_with = "encoded_path::lossy_list")]
+        failed: &'a [PathBuf],
+    },
+}
+
+/// The events a subscriber gets, owning what they refer to so that they can be kept around. They
+/// serialize the same way as the events of `--output json`
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum OwnedEvent {
+    FileCopied {
+        #[serde(serialize_with = "encoded_path::lossy")]
+        source: PathBuf,
+        #[serde(serialize_with = "encoded_path::lossy")]
+        destination: PathBuf,
+    },
+    FileDeleted {
+        #[serde(serialize_with = "encoded_path::lossy")]
+        path: PathBuf,
+    },
+    FileMoved {
+        #[serde(serialize_with = "encoded_path::lossy")]
+        from: PathBuf,
+        #[serde(serialize_with = "encoded_path::lossy")]
+        to: PathBuf,
+    },
+    Error {
+        #[serde(serialize_with = "encoded_path::lossy_option")]
+        path: Option<PathBuf>,
+        message: String,
+    },
+    CycleComplete {
+        copied: u64,
+        moved: u64,
+        deleted: u64,
+        errors: u64,
+    },
+}
+
+impl OwnedEvent {
+    /// The subscribed to counterpart of `event`, if subscribers get events of its kind
+    fn of(event: &Event) -> Option<Self> {
+        Some(match event {
+            Event::FileCopied {
+                source,
+                destination,
+            } => Self::FileCopied {
+                source: source.to_path_buf(),
+                destination: destination.to_path_buf(),
+            },
+            Event::FileDeleted { path } => Self::FileDeleted {
+                path: path.to_path_buf(),
+            },
+            Event::FileMoved { from, to } => Self::FileMoved {
+                from: from.to_path_buf(),
+                to: to.to_path_buf(),
+            },
+            Event::Error { path, message } => Self::Error {
+                path: path.map(Path::to_path_buf),
+                message: message.clone(),
+            },
+            &Event::CycleComplete {
+                copied,
+                moved,
+                deleted,
+                errors,
+            } => Self::CycleComplete {
+                copied,
+                moved,
+                deleted,
+                errors,
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// How many events a subscriber can fall behind by before it misses some
+const SUBSCRIBER_BACKLOG: usize = 1024;
+
+static SUBSCRIBERS: OnceLock<broadcast::Sender<OwnedEvent>> = OnceLock::new();
+
+/// Subscribes to the copies, deletions, moves, errors and finished cycles from now on. A subscriber
+/// that falls more than SUBSCRIBER_BACKLOG events behind misses the oldest ones, and is told how
+/// many with [`broadcast::error::RecvError::Lagged`]
+#[cfg_attr(not(feature = "control-socket"), allow(dead_code))]
+pub fn subscribe() -> broadcast::Receiver<OwnedEvent> {
+    SUBSCRIBERS
+        .get_or_init(|| broadcast::channel(SUBSCRIBER_BACKLOG).0)
+        .subscribe()
+}
+
+pub fn emit(event: &Event) {
+    if let Some(subscribers) = SUBSCRIBERS.get() {
+        if subscribers.receiver_count() > 0 {
+            if let Some(event) = OwnedEvent::of(event) {
+                let _ = subscribers.send(event);
+            }
+        }
+    }
+    crate::stats::observe(event);
+    crate::health::observe(event);
+    crate::status::observe(event);
+    crate::crash::observe(event);
+    crate::mirror::observe(event);
+    crate::hooks::observe(event);
+    crate::notify::observe(event);
+    crate::alert::observe(event);
+    crate::digest::observe(event);
+    crate::signed_manifest::observe(event);
+    crate::systemd::observe(event);
+    crate::listing::observe(event);
+    crate::read_only::observe(event);
+
+    match format() {
+        OutputFormat::Json => {
+            if let Ok(line) = serde_json::to_string(event) {
+                println!("{line}");
+            }
+        }
+        // Humans only need to hear about things going wrong
+        OutputFormat::Text => match event {
+            Event::Error { path, message } => print_error(match path {
+                Some(path) => format!("Error with {}: {message}", path.display()),
+                None => format!("Error: {message}"),
+            }),
+            Event::VerifyProblem { path, problem } => {
+                eprintln!("{}: {problem}", path.display())
+            }
+            _ if !shows(LogLevel::Warning) => (),
+            Event::Secret { path } => eprintln!(
+                "Warning: leaving {} out of the backup, it looks like a secret",
+                path.display()
+            ),
+            Event::SpecialFile { path, kind } => eprintln!(
+                "Warning: skipping {}, it's a {kind}. Pass --special-files=recreate to recreate it in the backup",
+                path.display()
+            ),
+            Event::OtherSyncTool {
+                tool,
+                path,
+                warning,
+            } => eprintln!("Warning: {tool} at {}: {warning}", path.display()),
+            Event::TooLarge { path, size } => eprintln!(
+                "Warning: skipping {}, it's {size} bytes which is over --max-file-size",
+                path.display()
+            ),
+            _ => (),
+        },
+    }
+}
+
+/// An error that's printed again within this long is only counted, so that a file failing every
+/// cycle doesn't drown out everything else. JSON events are never throttled, since whatever reads
+/// them can do its own counting
+const REPEAT_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+struct RecentError {
+    printed_at: Instant,
+    /// How many times it was repeated since it was printed
+    suppressed: u64,
+}
+
+static RECENT_ERRORS: Mutex<BTreeMap<String, RecentError>> = Mutex::new(BTreeMap::new());
+
+/// How often the counts of errors that stopped repeating are printed, rather than waiting for the
+/// next error
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Prints an error line to stderr, unless the same line was already printed within REPEAT_WINDOW
+fn print_error(line: String) {
+    let now = Instant::now();
+    let mut recent_errors = RECENT_ERRORS.lock().unwrap();
+    expire(&mut recent_errors, |recent| {
+        now.duration_since(recent.printed_at) >= REPEAT_WINDOW
+    });
+
+    match recent_errors.get_mut(&line) {
+        Some(recent) => recent.suppressed += 1,
+        None => {
+            eprintln!("{line}");
+            recent_errors.insert(
+                line,
+                RecentError {
+                    printed_at: now,
+                    suppressed: 0,
+                },
+            );
+        }
+    }
+}
+
+/// Forgets the errors `expired` says are done with, printing how many times each was suppressed
+fn expire(
+    recent_errors: &mut BTreeMap<String, RecentError>,
+    expired: impl Fn(&RecentError) -> bool,
+) {
+    recent_errors.retain(|line, recent| {
+        let expired = expired(recent);
+        if expired && recent.suppressed > 0 {
+            eprintln!("{line} (suppressed {} similar messages)", recent.suppressed);
+        }
+        !expired
+    });
+}
+
+/// Prints the counts of errors once REPEAT_WINDOW is over for them, even if nothing else goes wrong
+pub async fn expire_errors() {
+    let mut interval = tokio::time::interval(EXPIRE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        expire(&mut RECENT_ERRORS.lock().unwrap(), |recent| {
+            now.duration_since(recent.printed_at) >= REPEAT_WINDOW
+        });
+    }
+}
+
+/// Prints the counts of every error that was suppressed, before exiting
+pub fn flush_errors() {
+    expire(&mut RECENT_ERRORS.lock().unwrap(), |_| true);
+}
+
+/// Prints a progress message, unless the log level is below info. In JSON mode it goes to stderr,
+/// so stdout only ever contains events
+pub fn print_info(args: Arguments) {
+    if !shows(LogLevel::Info) {
+        return;
+    }
+    match format() {
+        OutputFormat::Json => eprintln!("{args}"),
+        OutputFormat::Text => println!("{args}"),
+    }
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::output::print_info(format_args!($($arg)*))
+    };
+}
+pub(crate) use info;
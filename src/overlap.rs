@@ -0,0 +1,114 @@
+//! Keeping work_dir, backup_dir and the directories of the routes apart. One inside another would
+//! have a sync copy the backup into itself, or delete what it just wrote, so startup refuses it,
+//! going by their paths once symlinks are resolved and by the directories themselves, which also
+//! catches one being mounted inside the other with a bind mount
+//!
+//! Walks skip any directory that is one of them reached some other way, like through a bind mount
+//! made after startup, rather than walking a tree twice or the backup as part of work_dir
+
+use anyhow::{anyhow, Result};
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use crate::{output::info, platform};
+
+/// The ids of work_dir, backup_dir and the directories of the routes
+static ROOTS: OnceLock<HashSet<(u64, u64)>> = OnceLock::new();
+/// The directories skipped so far, to mention each once
+static SKIPPED: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+/// `path` made absolute with its symlinks resolved, as far as it exists
+fn resolve(path: &Path) -> io::Result<PathBuf> {
+    let path = std::path::absolute(path)?;
+    for existing in path.ancestors() {
+        if let Ok(resolved) = existing.canonicalize() {
+            let rest = path.strip_prefix(existing).unwrap_or(Path::new(""));
+            return Ok(match rest.as_os_str().is_empty() {
+                true => resolved,
+                false => resolved.join(rest),
+            });
+        }
+    }
+
+    Ok(path)
+}
+
+fn dir_id(path: &Path) -> Option<(u64, u64)> {
+    std::fs::metadata(path)
+        .ok()
+        .filter(|metadata| metadata.is_dir())
+        .and_then(|metadata| platform::file_id(&metadata))
+}
+
+/// How `inner` is inside `outer`, if it is
+fn nested(inner: &Path, outer: &Path) -> io::Result<Option<String>> {
+    if std::path::absolute(inner)?.starts_with(std::path::absolute(outer)?) {
+        return Ok(Some(String::new()));
+    }
+    let (inner, outer) = (resolve(inner)?, resolve(outer)?);
+    if inner.starts_with(&outer) {
+        return Ok(Some(format!(
+            " once symlinks are resolved, as {}",
+            inner.display()
+        )));
+    }
+
+    let Some(outer_id) = dir_id(&outer) else {
+        return Ok(None);
+    };
+    Ok(inner
+        .ancestors()
+        .find(|ancestor| dir_id(ancestor) == Some(outer_id))
+        .map(|ancestor| format!(", since {} is the same directory", ancestor.display())))
+}
+
+/// Fails if the directories `a` and `b`, each with what to call it, are inside one another. Either
+/// may not exist yet
+pub fn check((a_name, a): (&str, &Path), (b_name, b): (&str, &Path)) -> Result<()> {
+    for ((inner_name, inner), (outer_name, outer)) in
+        [((a_name, a), (b_name, b)), ((b_name, b), (a_name, a))]
+    {
+        if let Some(how) = nested(inner, outer)? {
+            return Err(anyhow!(
+                "{inner_name} {} is inside {outer_name} {}{how}, and the two can't be inside each other",
+                inner.display(),
+                outer.display()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Has walks skip `roots` wherever else they show up
+pub fn set(roots: &[&Path]) {
+    let _ = ROOTS.set(roots.iter().filter_map(|root| dir_id(root)).collect());
+}
+
+/// Whether the directory `path`, below the root of a walk, is one of the roots reached some other
+/// way. Takes the metadata the walk already has
+pub fn is_root_again(path: &Path, metadata: &std::fs::Metadata) -> bool {
+    let Some(roots) = ROOTS.get() else {
+        return false;
+    };
+    if !platform::file_id(metadata).is_some_and(|id| roots.contains(&id)) {
+        return false;
+    }
+
+    if SKIPPED
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashSet::new)
+        .insert(path.to_path_buf())
+    {
+        info!(
+            "Skipping {}, which is work_dir, backup_dir or a route's directory mounted or linked again",
+            path.display()
+        );
+    }
+    true
+}
@@ -0,0 +1,182 @@
+//! Keeping track of who owns what. When running as root, copies keep the owner of the original,
+//! and the names of the users and groups are saved next to the backup, so that a restore onto a
+//! machine with different ids can give files back to the same names. A mapping file can override
+//! that for ids that were renumbered on purpose
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use crate::{platform, state_dir};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Names {
+    users: BTreeMap<u32, String>,
+    groups: BTreeMap<u32, String>,
+}
+
+fn names_path(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("owners.json")
+}
+
+/// Reads the ids and names out of a file formatted like /etc/passwd or /etc/group
+fn read_ids(path: &Path) -> BTreeMap<u32, String> {
+    let Ok(contents) = std::fs::read_to_string(path) else {
+        return BTreeMap::new();
+    };
+
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let id = fields.nth(1)?.parse().ok()?;
+            Some((id, name.to_string()))
+        })
+        .collect()
+}
+
+fn local_names() -> Names {
+    Names {
+        users: read_ids(Path::new("/etc/passwd")),
+        groups: read_ids(Path::new("/etc/group")),
+    }
+}
+
+/// Saves the names of this machine's users and groups, so a restore can map owners by name
+pub fn save_names(backup_dir: &Path) -> Result<()> {
+    let names = local_names();
+    if names.users.is_empty() && names.groups.is_empty() {
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(state_dir(backup_dir))?;
+    std::fs::write(names_path(backup_dir), serde_json::to_vec_pretty(&names)?)?;
+
+    Ok(())
+}
+
+/// Only root can change owners, and only where files have them
+fn can_chown() -> bool {
+    platform::is_root() == Some(true)
+}
+
+fn owner(path: &Path) -> Result<Option<(u32, u32)>> {
+    Ok(platform::owner(&std::fs::symlink_metadata(path)?))
+}
+
+fn chown(path: &Path, owner: (u32, u32)) -> Result<()> {
+    platform::set_owner(path, owner)
+        .with_context(|| anyhow!("Error changing the owner of {}", path.display()))
+}
+
+/// Gives `destination` the same owner as `source`. Only root can do that, so for anyone else it
+/// does nothing
+pub fn copy_owner(source: &Path, destination: &Path) -> Result<()> {
+    if !can_chown() {
+        return Ok(());
+    }
+
+    match owner(source)? {
+        Some(owner) => chown(destination, owner),
+        None => Ok(()),
+    }
+}
+
+/// Gives `destination` the owner `owner`, for copies made on behalf of another process. Like
+/// [`copy_owner`], it does nothing for anyone but root
+#[cfg_attr(not(feature = "control-socket"), allow(dead_code))]
+pub fn set_owner(destination: &Path, owner: (u32, u32)) -> Result<()> {
+    match can_chown() {
+        true => chown(destination, owner),
+        false => Ok(()),
+    }
+}
+
+/// How the uids and gids found in a backup translate to the ones on this machine
+#[derive(Debug, Default)]
+pub struct OwnerMap {
+    users: HashMap<u32, u32>,
+    groups: HashMap<u32, u32>,
+}
+
+impl OwnerMap {
+    /// Maps every user and group that was saved by name to the id with that name on this machine,
+    /// then applies `map_file` on top. Each of its lines is `OLD:NEW` or `uid OLD:NEW` for a uid,
+    /// or `gid OLD:NEW` for a gid, and `#` starts a comment
+    pub fn new(backup_dir: &Path, map_file: Option<&Path>) -> Result<Self> {
+        let mut map = Self::default();
+
+        let saved: Names = match std::fs::read(names_path(backup_dir)) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .with_context(|| anyhow!("Error parsing {}", names_path(backup_dir).display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Names::default(),
+            Err(err) => return Err(err.into()),
+        };
+        let local = local_names();
+        for (saved, local, map) in [
+            (&saved.users, &local.users, &mut map.users),
+            (&saved.groups, &local.groups, &mut map.groups),
+        ] {
+            let local_ids: HashMap<&String, u32> =
+                local.iter().map(|(id, name)| (name, *id)).collect();
+            for (old_id, name) in saved {
+                if let Some(&new_id) = local_ids.get(name) {
+                    map.insert(*old_id, new_id);
+                }
+            }
+        }
+
+        if let Some(map_file) = map_file {
+            let contents = std::fs::read_to_string(map_file)
+                .with_context(|| anyhow!("Error reading {}", map_file.display()))?;
+            for (number, line) in contents.lines().enumerate() {
+                let line = line.split('#').next().unwrap_or_default().trim();
+                if line.is_empty() {
+                    continue;
+                }
+
+                let (ids, entry) = match line.strip_prefix("gid ") {
+                    Some(entry) => (&mut map.groups, entry),
+                    None => (&mut map.users, line.strip_prefix("uid ").unwrap_or(line)),
+                };
+                let parsed = entry
+                    .trim()
+                    .split_once(':')
+                    .and_then(|(old, new)| Some((old.parse().ok()?, new.parse().ok()?)));
+                let Some((old, new)) = parsed else {
+                    return Err(anyhow!(
+                        "{}:{}: expected OLD:NEW or gid OLD:NEW, got {line}",
+                        map_file.display(),
+                        number + 1
+                    ));
+                };
+                ids.insert(old, new);
+            }
+        }
+
+        Ok(map)
+    }
+
+    /// Gives `restored` the owner of `backup_path`, translated to this machine's ids. Only root
+    /// can change owners, so for anyone else it does nothing
+    pub fn apply(&self, backup_path: &Path, restored: &Path) -> Result<()> {
+        if !can_chown() {
+            return Ok(());
+        }
+
+        let Some((uid, gid)) = owner(backup_path)? else {
+            return Ok(());
+        };
+        chown(
+            restored,
+            (
+                self.users.get(&uid).copied().unwrap_or(uid),
+                self.groups.get(&gid).copied().unwrap_or(gid),
+            ),
+        )
+    }
+}
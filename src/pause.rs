@@ -0,0 +1,77 @@
+//! Temporarily suspending syncing, so that intermediate states of a git rebase or a bulk refactor
+//! never reach the backup. Syncing can be paused through the control socket, by sending SIGUSR1,
+//! or by creating a sentinel file at the root of work_dir
+
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    full_pass,
+    output::info,
+    platform::{Signal, Signals},
+    presence, space, SHOULD_SHUTDOWN,
+};
+
+/// While a file with this name exists at the root of work_dir, syncing is paused. It's never
+/// synced itself
+pub const SENTINEL_NAME: &str = ".evil_mount_pause";
+
+/// Set through the control socket and toggled by SIGUSR1
+pub static PAUSED: AtomicBool = AtomicBool::new(false);
+/// Set while the sentinel file exists
+static SENTINEL_PRESENT: AtomicBool = AtomicBool::new(false);
+
+/// Whether the sync loops should hold off on copying and deleting anything
+pub fn is_paused() -> bool {
+    PAUSED.load(Ordering::Relaxed)
+        || SENTINEL_PRESENT.load(Ordering::Relaxed)
+        || presence::is_gone()
+        || space::is_low()
+}
+
+/// Keeps track of the sentinel file, of whether work_dir is still there and of the free space in
+/// backup_dir, and asks the watch loop for a full pass whenever syncing resumes so that everything
+/// that changed while paused makes it to the backup
+pub async fn watch(work_dir: PathBuf) {
+    let sentinel = work_dir.join(SENTINEL_NAME);
+    let mut was_paused = is_paused();
+
+    while !SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+        let sentinel_present = tokio::fs::try_exists(&sentinel).await.unwrap_or(false);
+        if SENTINEL_PRESENT.swap(sentinel_present, Ordering::Relaxed) != sentinel_present {
+            match sentinel_present {
+                true => info!("Found {}, pausing syncing", sentinel.display()),
+                false => info!("{} was removed", sentinel.display()),
+            }
+        }
+
+        presence::check(&work_dir);
+        space::check();
+
+        let paused = is_paused();
+        if was_paused && !paused {
+            info!("Resuming syncing, the next pass compares everything with the backup");
+            full_pass::ask();
+        }
+        was_paused = paused;
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// Toggles pausing every time the process receives SIGUSR1
+pub async fn toggle_on_sigusr1() -> std::io::Result<()> {
+    let mut signals = Signals::listen(Signal::User1)?;
+    while signals.recv().await.is_some() {
+        let paused = !PAUSED.fetch_xor(true, Ordering::Relaxed);
+        match paused {
+            true => info!("Received SIGUSR1, pausing syncing"),
+            false => info!("Received SIGUSR1, resuming syncing"),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1059 @@
+//! Everything that works differently from one operating system to the next, so that the rest of
+//! evil_mount doesn't have to care. Each function has a version for the platforms that can do it
+//! and a fallback for the rest, which does the next best thing or reports that it's unsupported,
+//! and [`CAPABILITIES`] tells up front which ones are real. Porting to a new platform means
+//! filling in the functions here
+//!
+//! Linux gets everything, Android the same minus what it keeps from apps, macOS and the BSDs what
+//! POSIX has along with their own way of cloning files where there is one, and anything else only
+//! the portable basics
+
+use std::{
+    collections::HashSet,
+    fs::{File, FileType, Metadata},
+    io,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    time::Duration,
+};
+
+/// What this build can do on the platform it was built for
+#[derive(Debug, serde::Serialize)]
+pub struct Capabilities {
+    /// Copy on write clones, for `--reflink`
+    pub reflink: bool,
+    /// Copying without passing the contents through userspace
+    pub sendfile: bool,
+    /// Asking the kernel to read ahead or drop cached pages
+    pub fadvise: bool,
+    /// Flushing a whole filesystem to disk at once when shutting down
+    pub syncfs: bool,
+    /// Owners, permissions, inodes and link counts in file metadata
+    pub unix_metadata: bool,
+    /// Recreating FIFOs, sockets and device files
+    pub special_files: bool,
+    /// SIGHUP, SIGUSR1 and sending SIGINT to profiles
+    pub signals: bool,
+    /// Unix sockets or named pipes, for the control socket
+    pub control_socket: bool,
+    /// Finding out how long since the last keyboard or mouse input
+    pub input_idle: bool,
+    /// What every thread is doing and waiting in, for the watchdog
+    pub thread_states: bool,
+    /// Telling kernel pseudo filesystems apart, for `--system-backup`
+    pub filesystem_types: bool,
+    /// The immutable attribute, which `--lock-backup` sets on top of taking write permissions away
+    pub immutable_files: bool,
+    /// Being told about changes to directories, so that the watch loop doesn't have to wait for
+    /// the next interval to notice new, removed and renamed files
+    pub dir_changes: bool,
+}
+
+pub const CAPABILITIES: Capabilities = Capabilities {
+    reflink: cfg!(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos"
+    )),
+    sendfile: cfg!(any(target_os = "linux", target_os = "android")),
+    fadvise: cfg!(any(target_os = "linux", target_os = "android")),
+    syncfs: cfg!(any(target_os = "linux", target_os = "android")),
+    unix_metadata: cfg!(unix),
+    special_files: cfg!(unix),
+    signals: cfg!(unix),
+    control_socket: cfg!(any(unix, windows)),
+    input_idle: cfg!(any(target_os = "linux", target_os = "macos")),
+    thread_states: cfg!(target_os = "linux"),
+    filesystem_types: cfg!(target_os = "linux"),
+    immutable_files: cfg!(target_os = "linux"),
+    dir_changes: cfg!(any(
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly",
+        target_os = "macos"
+    )),
+};
+
+#[cfg(unix)]
+fn c_path(path: &Path) -> io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Ok(std::ffi::CString::new(path.as_os_str().as_bytes())?)
+}
+
+/// Whether something failed because the platform or filesystem can't do it, rather than because
+/// of the files involved
+pub fn is_unsupported(err: &io::Error) -> bool {
+    if matches!(
+        err.kind(),
+        io::ErrorKind::Unsupported | io::ErrorKind::CrossesDevices
+    ) {
+        return true;
+    }
+
+    #[cfg(unix)]
+    {
+        matches!(
+            err.raw_os_error(),
+            Some(libc::EXDEV | libc::EINVAL | libc::EOPNOTSUPP | libc::ENOTTY | libc::ENOSYS)
+        )
+    }
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
+
+/// Clones `from` to `to`, which must not exist, along with its permissions
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn clone_file(from: &Path, to: &Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let source = crate::containment::open(from)?;
+    let destination = crate::containment::create(to)?;
+    // SAFETY: both file descriptors stay open for the duration of the call
+    match unsafe { libc::ioctl(destination.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } {
+        0 => destination.set_permissions(source.metadata()?.permissions()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn clone_file(from: &Path, to: &Path) -> io::Result<()> {
+    let (from, to) = (c_path(from)?, c_path(to)?);
+    // SAFETY: both paths are valid nul terminated strings
+    match unsafe { libc::clonefile(from.as_ptr(), to.as_ptr(), 0) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+pub fn clone_file(_from: &Path, _to: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Opens `relative` beneath the directory `root` for reading, or for writing after creating or
+/// truncating it with `create`. Fails if resolving it leads outside of `root`, like through a
+/// symlink to elsewhere. None if the kernel can't resolve paths that way
+#[cfg(target_os = "linux")]
+pub fn open_beneath(root: &File, relative: &Path, create: bool) -> io::Result<Option<File>> {
+    use std::os::fd::{AsRawFd, FromRawFd};
+
+    let relative = c_path(relative)?;
+    let flags = match create {
+        true => libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+        false => libc::O_RDONLY,
+    };
+    // SAFETY: open_how is plain data, for which all zeroes are valid
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = (flags | libc::O_CLOEXEC) as u64;
+    how.mode = if create { 0o666 } else { 0 };
+    how.resolve = libc::RESOLVE_BENEATH;
+
+    // SAFETY: root stays open for the duration of the call, relative is NUL terminated, and how is
+    // an open_how of the size passed
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            root.as_raw_fd(),
+            relative.as_ptr(),
+            &how as *const libc::open_how,
+            std::mem::size_of::<libc::open_how>(),
+        )
+    };
+    if fd >= 0 {
+        // SAFETY: the file descriptor was just opened, and nothing else owns it
+        return Ok(Some(unsafe { File::from_raw_fd(fd as i32) }));
+    }
+
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        // Kernels before 5.6, and sandboxes that don't allow the call
+        Some(libc::ENOSYS | libc::EPERM) => Ok(None),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn open_beneath(_root: &File, _relative: &Path, _create: bool) -> io::Result<Option<File>> {
+    Ok(None)
+}
+
+/// Whether resolving a path failed because it led outside of the directory it had to stay in
+pub fn is_escape(err: &io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        err.raw_os_error() == Some(libc::EXDEV)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = err;
+        false
+    }
+}
+
+/// Opens `path` the way [`open_beneath`] would, without following a symlink at the end of it
+pub fn open_no_follow(path: &Path, create: bool) -> io::Result<File> {
+    let mut options = File::options();
+    match create {
+        true => options.write(true).create(true).truncate(true),
+        false => options.read(true),
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        options.custom_flags(libc::O_NOFOLLOW);
+    }
+
+    options.open(path)
+}
+
+/// Copies up to `len` bytes from the current offset of `source` to `destination` within the
+/// kernel, returning how many were copied, 0 at the end of `source`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn send_file(destination: &File, source: &File, len: usize) -> io::Result<usize> {
+    use std::os::fd::AsRawFd;
+
+    loop {
+        // SAFETY: both file descriptors stay open for the duration of the call, and a null offset
+        // makes sendfile use and advance the source's own
+        let sent = unsafe {
+            libc::sendfile(
+                destination.as_raw_fd(),
+                source.as_raw_fd(),
+                std::ptr::null_mut(),
+                len,
+            )
+        };
+        match sent {
+            sent if sent >= 0 => return Ok(sent as usize),
+            _ => {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() != Some(libc::EINTR) {
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn send_file(_destination: &File, _source: &File, _len: usize) -> io::Result<usize> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Asks the kernel to start reading `len` bytes of `file` from `offset` into the page cache
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn will_need(file: &File, offset: u64, len: u64) {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the file descriptor stays open for the duration of the call. Failing just means
+    // nothing is read ahead
+    unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as _,
+            len as _,
+            libc::POSIX_FADV_WILLNEED,
+        )
+    };
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn will_need(_file: &File, _offset: u64, _len: u64) {}
+
+/// Asks the kernel to forget the cached contents of `file`, so that reading it again reads the
+/// disk. Elsewhere there's no portable way to, and reads keep coming from the cache
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn drop_cached(file: &File) {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the file descriptor stays open for the duration of the call. Failing just means
+    // the cached pages are read
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn drop_cached(_file: &File) {}
+
+/// Writes everything cached for the filesystem `dir` is on to disk
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn sync_filesystem(dir: &Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let dir = File::open(dir)?;
+    // SAFETY: the file descriptor stays open for the duration of the call
+    match unsafe { libc::syncfs(dir.as_raw_fd()) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn sync_filesystem(_dir: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Locks `file` unless something else has it locked, returning whether it did. The lock goes away
+/// when the file is closed
+#[cfg(unix)]
+pub fn try_lock(file: &File) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the file descriptor stays open for the duration of the call
+    match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } {
+        0 => Ok(true),
+        _ => {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EWOULDBLOCK) => Ok(false),
+                _ => Err(err),
+            }
+        }
+    }
+}
+
+/// Without flock every lock is always free
+#[cfg(not(unix))]
+pub fn try_lock(_file: &File) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// The bytes of `path`, which are whatever the filesystem has on Unix
+#[cfg(unix)]
+pub fn path_bytes(path: &Path) -> &[u8] {
+    use std::os::unix::ffi::OsStrExt;
+
+    path.as_os_str().as_bytes()
+}
+
+#[cfg(not(unix))]
+pub fn path_bytes(path: &Path) -> &[u8] {
+    path.as_os_str().as_encoded_bytes()
+}
+
+/// The path [`path_bytes`] returned `bytes` for. Elsewhere only valid UTF-8 comes back
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: Vec<u8>) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+
+    Some(std::ffi::OsString::from_vec(bytes).into())
+}
+
+#[cfg(not(unix))]
+pub fn path_from_bytes(bytes: Vec<u8>) -> Option<PathBuf> {
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+/// Sets the modify time of `path` to now, which works on read only files as long as they're ours
+#[cfg(unix)]
+pub fn touch(path: &Path) -> io::Result<()> {
+    let path = c_path(path)?;
+    // SAFETY: the path is a valid C string, and null times mean now
+    match unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), std::ptr::null(), 0) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn touch(path: &Path) -> io::Result<()> {
+    File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(std::time::SystemTime::now())
+}
+
+/// Sets the modify time of `path` to `modified`, which like [`touch`] only takes owning it
+#[cfg(unix)]
+pub fn set_modified(path: &Path, modified: std::time::SystemTime) -> io::Result<()> {
+    File::open(path)?.set_modified(modified)
+}
+
+#[cfg(not(unix))]
+pub fn set_modified(path: &Path, modified: std::time::SystemTime) -> io::Result<()> {
+    File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(modified)
+}
+
+/// How many bytes are free for unprivileged users on the filesystem `path` is on
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    let path = c_path(path).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: path is NUL terminated and stat is a valid statvfs to write into
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// How many files the process may have open at once, if there's a limit
+#[cfg(unix)]
+pub fn open_files_limit() -> Option<u64> {
+    let mut limit: libc::rlimit = unsafe { std::mem::zeroed() };
+    // SAFETY: limit is a valid rlimit to write into
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    (limit.rlim_cur != libc::RLIM_INFINITY).then_some(limit.rlim_cur as u64)
+}
+
+#[cfg(not(unix))]
+pub fn open_files_limit() -> Option<u64> {
+    None
+}
+
+/// The name of the pseudo filesystem `path` is on, if any
+#[cfg(target_os = "linux")]
+pub fn pseudo_fs_name(path: &Path) -> io::Result<Option<&'static str>> {
+    const PSEUDO_FILESYSTEMS: &[(i64, &str)] = &[
+        (0x9fa0, "proc"),
+        (0x6265_6572, "sysfs"),
+        (0x1cd1, "devpts"),
+        (0x0102_1994, "tmpfs"),
+        (0x2772_6d09, "cgroup"),
+        (0x6367_7270, "cgroup2"),
+        (0x6462_6720, "debugfs"),
+        (0x7472_6163, "tracefs"),
+        (0x7363_6673, "securityfs"),
+        (0xcafe_4a11, "bpf"),
+    ];
+
+    let fs_type = fs_type(path)?;
+    Ok(PSEUDO_FILESYSTEMS
+        .iter()
+        .find(|(magic, _)| *magic == fs_type)
+        .map(|(_, name)| *name))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pseudo_fs_name(_path: &Path) -> io::Result<Option<&'static str>> {
+    Ok(None)
+}
+
+/// The name of the filesystem `path` is on if it only takes names that Windows does, like FAT,
+/// exFAT or NTFS
+#[cfg(target_os = "linux")]
+pub fn windows_names_fs_name(path: &Path) -> io::Result<Option<&'static str>> {
+    const WINDOWS_FILESYSTEMS: &[(i64, &str)] = &[
+        (0x4d44, "FAT"),
+        (0x2011_bab0, "exFAT"),
+        (0x5346_544e, "NTFS"),
+        (0x7366_746e, "NTFS"),
+    ];
+
+    let fs_type = fs_type(path)?;
+    Ok(WINDOWS_FILESYSTEMS
+        .iter()
+        .find(|(magic, _)| *magic == fs_type)
+        .map(|(_, name)| *name))
+}
+
+#[cfg(windows)]
+pub fn windows_names_fs_name(_path: &Path) -> io::Result<Option<&'static str>> {
+    Ok(Some("Windows"))
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+pub fn windows_names_fs_name(_path: &Path) -> io::Result<Option<&'static str>> {
+    Ok(None)
+}
+
+/// The magic number of the filesystem `path` is on
+#[cfg(target_os = "linux")]
+fn fs_type(path: &Path) -> io::Result<i64> {
+    use std::mem::MaybeUninit;
+
+    let c_path = c_path(path)?;
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: c_path is a valid NUL terminated string and stat is large enough for a statfs
+    if unsafe { libc::statfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: statfs succeeded, so it initialized stat
+    // f_type's integer type differs between architectures
+    #[allow(clippy::unnecessary_cast)]
+    Ok(unsafe { stat.assume_init() }.f_type as i64)
+}
+
+/// Identifies a file independently of its path, as its device and inode
+#[cfg(unix)]
+pub fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+pub fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// The device a file is on. Everything counts as one device where that isn't known
+pub fn device(metadata: &Metadata) -> u64 {
+    file_id(metadata).map_or(0, |(device, _)| device)
+}
+
+/// How many hard links to a file there are, including its own
+#[cfg(unix)]
+pub fn link_count(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(metadata.nlink())
+}
+
+#[cfg(not(unix))]
+pub fn link_count(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
+/// The uid and gid of a file
+#[cfg(unix)]
+pub fn owner(metadata: &Metadata) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some((metadata.uid(), metadata.gid()))
+}
+
+#[cfg(not(unix))]
+pub fn owner(_metadata: &Metadata) -> Option<(u32, u32)> {
+    None
+}
+
+/// The file type and permission bits of a file, along with the device number of device files
+#[cfg(unix)]
+pub fn mode(metadata: &Metadata) -> Option<(u32, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some((metadata.mode(), metadata.rdev()))
+}
+
+#[cfg(not(unix))]
+pub fn mode(_metadata: &Metadata) -> Option<(u32, u64)> {
+    None
+}
+
+/// Gives `path` itself, rather than what it links to, a new owner
+#[cfg(unix)]
+pub fn set_owner(path: &Path, (uid, gid): (u32, u32)) -> io::Result<()> {
+    std::os::unix::fs::lchown(path, Some(uid), Some(gid))
+}
+
+#[cfg(not(unix))]
+pub fn set_owner(_path: &Path, _owner: (u32, u32)) -> io::Result<()> {
+    Ok(())
+}
+
+/// Sets the permission bits of `path` to those in `mode`. Does nothing where files don't have them
+#[cfg_attr(not(feature = "control-socket"), allow(dead_code))]
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777))
+}
+
+#[cfg_attr(not(feature = "control-socket"), allow(dead_code))]
+#[cfg(not(unix))]
+pub fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Takes every write permission away from `path`, returning the permission bits it had
+#[cfg(unix)]
+pub fn make_read_only(path: &Path) -> io::Result<u32> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let mode = std::fs::symlink_metadata(path)?.mode() & 0o7777;
+    let write = mode & 0o222;
+    if write != 0 {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & !0o222))?;
+    }
+    Ok(write)
+}
+
+#[cfg(not(unix))]
+pub fn make_read_only(path: &Path) -> io::Result<u32> {
+    let mut permissions = std::fs::symlink_metadata(path)?.permissions();
+    let write = match permissions.readonly() {
+        true => 0,
+        false => 0o200,
+    };
+    permissions.set_readonly(true);
+    std::fs::set_permissions(path, permissions)?;
+    Ok(write)
+}
+
+/// Gives `path` back the write permission bits `write` that [`make_read_only`] took away
+#[cfg(unix)]
+pub fn make_writable(path: &Path, write: u32) -> io::Result<()> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let mode = std::fs::symlink_metadata(path)?.mode() & 0o7777;
+    match mode & write == write {
+        true => Ok(()),
+        false => std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode | write)),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn make_writable(path: &Path, write: u32) -> io::Result<()> {
+    let mut permissions = std::fs::symlink_metadata(path)?.permissions();
+    if write == 0 || !permissions.readonly() {
+        return Ok(());
+    }
+    // Only an attribute here, rather than making it writable for everyone
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    std::fs::set_permissions(path, permissions)
+}
+
+/// FS_IOC_GETFLAGS and FS_IOC_SETFLAGS, which the libc crate leaves out. Their numbers encode the
+/// size of a long, and which bits mean reading and writing depends on the architecture
+#[cfg(target_os = "linux")]
+mod inode_flags {
+    const SIZE: libc::c_ulong = std::mem::size_of::<libc::c_long>() as libc::c_ulong;
+    const OTHER_BITS: bool = cfg!(any(
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "sparc64"
+    ));
+    const READ: libc::c_ulong = match OTHER_BITS {
+        true => 2 << 29,
+        false => 2 << 30,
+    };
+    const WRITE: libc::c_ulong = match OTHER_BITS {
+        true => 4 << 29,
+        false => 1 << 30,
+    };
+
+    pub const GET: libc::c_ulong = READ | SIZE << 16 | (b'f' as libc::c_ulong) << 8 | 1;
+    pub const SET: libc::c_ulong = WRITE | SIZE << 16 | (b'f' as libc::c_ulong) << 8 | 2;
+    pub const IMMUTABLE: libc::c_int = 0x10;
+}
+
+/// Sets or clears the immutable attribute of the file `path`, which keeps everyone, root
+/// included, from changing, renaming or deleting it. Changing it takes root
+#[cfg(target_os = "linux")]
+pub fn set_immutable(path: &Path, immutable: bool) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let file = File::open(path)?;
+    let mut flags: libc::c_int = 0;
+    // SAFETY: the file descriptor stays open and flags outlives the call
+    if unsafe { libc::ioctl(file.as_raw_fd(), inode_flags::GET as _, &mut flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let wanted = match immutable {
+        true => flags | inode_flags::IMMUTABLE,
+        false => flags & !inode_flags::IMMUTABLE,
+    };
+    if wanted == flags {
+        return Ok(());
+    }
+    // SAFETY: as above
+    match unsafe { libc::ioctl(file.as_raw_fd(), inode_flags::SET as _, &wanted) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_immutable(_path: &Path, _immutable: bool) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// What kind of special file `file_type` is, if it's one at all
+#[cfg(unix)]
+pub fn special_kind(file_type: FileType) -> Option<&'static str> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if file_type.is_fifo() {
+        Some("FIFO")
+    } else if file_type.is_socket() {
+        Some("socket")
+    } else if file_type.is_block_device() {
+        Some("block device")
+    } else if file_type.is_char_device() {
+        Some("character device")
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+pub fn special_kind(_file_type: FileType) -> Option<&'static str> {
+    None
+}
+
+/// Makes a node at `path` with the file type, permissions and device number `mode` returns. The
+/// umask still applies to the permissions
+#[cfg(unix)]
+pub fn make_node(path: &Path, (mode, device): (u32, u64)) -> io::Result<()> {
+    let path = c_path(path)?;
+    // SAFETY: the path is a valid nul terminated string
+    match unsafe { libc::mknod(path.as_ptr(), mode as _, device as _) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn make_node(_path: &Path, _mode: (u32, u64)) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Whether the process runs as root, where there's such a thing
+#[cfg(unix)]
+pub fn is_root() -> Option<bool> {
+    // SAFETY: geteuid has no preconditions and can't fail
+    Some(unsafe { libc::geteuid() == 0 })
+}
+
+#[cfg(not(unix))]
+pub fn is_root() -> Option<bool> {
+    None
+}
+
+/// The one minute load average divided by the number of CPUs, if that can be found out. Android
+/// has no getloadavg, and keeps apps from reading the load anyway
+#[cfg(all(unix, not(target_os = "android")))]
+pub fn load_per_cpu() -> Option<f64> {
+    let mut load = [0.0];
+    // SAFETY: load has room for the single sample that's asked for
+    if unsafe { libc::getloadavg(load.as_mut_ptr(), 1) } != 1 {
+        return None;
+    }
+    let cpus = std::thread::available_parallelism().ok()?.get();
+
+    Some(load[0] / cpus as f64)
+}
+
+#[cfg(any(not(unix), target_os = "android"))]
+pub fn load_per_cpu() -> Option<f64> {
+    None
+}
+
+/// How long since the last keyboard or mouse input, if that can be found out
+#[cfg(target_os = "linux")]
+pub fn input_idle_time() -> Option<Duration> {
+    // xprintidle prints the X11 idle time in milliseconds, and isn't installed on headless machines
+    let output = Command::new("xprintidle").output().ok()?;
+    let millis = String::from_utf8(output.stdout).ok()?.trim().parse().ok()?;
+
+    Some(Duration::from_millis(millis))
+}
+
+#[cfg(target_os = "macos")]
+pub fn input_idle_time() -> Option<Duration> {
+    let output = Command::new("ioreg")
+        .args(["-c", "IOHIDSystem", "-d", "4"])
+        .output()
+        .ok()?;
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    let nanos = stdout
+        .lines()
+        .find_map(|line| line.split("\"HIDIdleTime\" = ").nth(1))?
+        .trim()
+        .parse()
+        .ok()?;
+
+    Some(Duration::from_nanos(nanos))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn input_idle_time() -> Option<Duration> {
+    None
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Signal {
+    Interrupt,
+    Hangup,
+    User1,
+}
+
+/// Sends `signal` to the process `pid`, which may have exited already. Does nothing without signals
+#[cfg(unix)]
+pub fn send_signal(pid: u32, signal: Signal) {
+    let signal = match signal {
+        Signal::Interrupt => libc::SIGINT,
+        Signal::Hangup => libc::SIGHUP,
+        Signal::User1 => libc::SIGUSR1,
+    };
+    // SAFETY: kill has no memory safety requirements
+    unsafe { libc::kill(pid as libc::pid_t, signal) };
+}
+
+#[cfg(not(unix))]
+pub fn send_signal(_pid: u32, _signal: Signal) {}
+
+/// Receives `signal` every time the process gets it
+#[cfg(unix)]
+pub struct Signals(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl Signals {
+    pub fn listen(signal: Signal) -> io::Result<Self> {
+        use tokio::signal::unix::{signal as listen, SignalKind};
+
+        Ok(Self(listen(match signal {
+            Signal::Interrupt => SignalKind::interrupt(),
+            Signal::Hangup => SignalKind::hangup(),
+            Signal::User1 => SignalKind::user_defined1(),
+        })?))
+    }
+
+    /// Waits for the next time the signal arrives
+    pub async fn recv(&mut self) -> Option<()> {
+        self.0.recv().await
+    }
+}
+
+#[cfg(not(unix))]
+pub struct Signals;
+
+#[cfg(not(unix))]
+impl Signals {
+    pub fn listen(_signal: Signal) -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub async fn recv(&mut self) -> Option<()> {
+        None
+    }
+}
+
+/// Starts `command` in a process group of its own, so that a Ctrl-C in the terminal only reaches
+/// this process
+pub fn own_process_group(command: &mut Command) {
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(command, 0);
+    #[cfg(not(unix))]
+    let _ = command;
+}
+
+/// Kills `child` along with everything it started, for a child started with [`own_process_group`]
+pub fn kill_process_group(child: &mut Child) {
+    #[cfg(unix)]
+    {
+        // SAFETY: kill has no memory safety requirements
+        unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+    }
+    #[cfg(not(unix))]
+    let _ = child.kill();
+}
+
+/// What one of the threads of this process is doing, as the kernel sees it
+pub struct ThreadState {
+    pub id: String,
+    pub name: String,
+    /// Like R for running, S for sleeping or D for stuck in uninterruptible IO
+    pub state: String,
+    /// The kernel function it's waiting in, if any
+    pub wchan: Option<String>,
+    /// Its kernel stack, which only root can read
+    pub stack: Option<String>,
+}
+
+/// The kernel's view of every thread, which shows the ones stuck in uninterruptible IO like a
+/// hung NFS mount and what they're waiting in
+#[cfg(target_os = "linux")]
+pub fn threads() -> Option<Vec<ThreadState>> {
+    let tasks = std::fs::read_dir("/proc/self/task").ok()?;
+
+    Some(
+        tasks
+            .filter_map(|task| task.ok())
+            .map(|task| {
+                let dir = task.path();
+                let read = |name: &str| std::fs::read_to_string(dir.join(name)).ok();
+                // The state follows the name in parentheses, which can itself contain anything
+                let state = read("stat")
+                    .and_then(|stat| {
+                        let (_, rest) = stat.rsplit_once(')')?;
+                        Some(rest.split_whitespace().next()?.to_string())
+                    })
+                    .unwrap_or_else(|| "?".to_string());
+
+                ThreadState {
+                    id: task.file_name().to_string_lossy().into_owned(),
+                    name: read("comm").unwrap_or_default().trim().to_string(),
+                    state,
+                    wchan: read("wchan").filter(|wchan| wchan != "0"),
+                    stack: read("stack").filter(|stack| !stack.is_empty()),
+                }
+            })
+            .collect(),
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn threads() -> Option<Vec<ThreadState>> {
+    None
+}
+
+/// Notices entries being added to, removed from or renamed in a set of directories, with kqueue.
+/// Each watched directory stays open
+#[cfg(any(
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+    target_os = "macos"
+))]
+pub struct DirWatcher {
+    queue: std::os::fd::OwnedFd,
+    dirs: std::collections::HashMap<PathBuf, File>,
+}
+
+#[cfg(any(
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+    target_os = "macos"
+))]
+impl DirWatcher {
+    pub fn new() -> io::Result<Self> {
+        use std::os::fd::FromRawFd;
+
+        // SAFETY: kqueue has no preconditions
+        let queue = unsafe { libc::kqueue() };
+        if queue < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            // SAFETY: the descriptor was just created, and nothing else owns it
+            queue: unsafe { std::os::fd::OwnedFd::from_raw_fd(queue) },
+            dirs: std::collections::HashMap::new(),
+        })
+    }
+
+    /// Watches exactly `dirs` from now on. Directories that are gone already are left out
+    pub fn watch(&mut self, dirs: HashSet<PathBuf>) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        // Closing the descriptor of a directory stops watching it
+        self.dirs.retain(|dir, _| dirs.contains(dir));
+        for dir in dirs {
+            if self.dirs.contains_key(&dir) {
+                continue;
+            }
+            let Ok(file) = File::open(&dir) else {
+                continue;
+            };
+
+            // SAFETY: kevent is plain data, for which all zeroes is valid
+            let mut change: libc::kevent = unsafe { std::mem::zeroed() };
+            change.ident = file.as_raw_fd() as _;
+            change.filter = libc::EVFILT_VNODE as _;
+            change.flags = (libc::EV_ADD | libc::EV_CLEAR) as _;
+            change.fflags =
+                (libc::NOTE_WRITE | libc::NOTE_EXTEND | libc::NOTE_DELETE | libc::NOTE_RENAME) as _;
+            // SAFETY: the queue and the directory stay open for the duration of the call, change
+            // is a valid kevent and no events are asked for
+            let added = unsafe {
+                libc::kevent(
+                    self.queue.as_raw_fd(),
+                    &change,
+                    1,
+                    std::ptr::null_mut(),
+                    0,
+                    std::ptr::null(),
+                )
+            };
+            if added < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.dirs.insert(dir, file);
+        }
+
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for any of the directories to change, returning whether one did
+    pub fn wait(&self, timeout: Duration) -> io::Result<bool> {
+        use std::os::fd::AsRawFd;
+
+        let timeout = libc::timespec {
+            tv_sec: timeout.as_secs() as _,
+            tv_nsec: timeout.subsec_nanos() as _,
+        };
+        // SAFETY: kevent is plain data, for which all zeroes is valid
+        let mut event: libc::kevent = unsafe { std::mem::zeroed() };
+        loop {
+            // SAFETY: the queue stays open for the duration of the call, and event has room for
+            // the single event asked for
+            let received = unsafe {
+                libc::kevent(
+                    self.queue.as_raw_fd(),
+                    std::ptr::null(),
+                    0,
+                    &mut event,
+                    1,
+                    &timeout,
+                )
+            };
+            match received {
+                received if received >= 0 => return Ok(received > 0),
+                _ => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Elsewhere directories are only looked at once the interval is up
+#[cfg(not(any(
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+    target_os = "macos"
+)))]
+pub struct DirWatcher;
+
+#[cfg(not(any(
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+    target_os = "macos"
+)))]
+impl DirWatcher {
+    pub fn new() -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn watch(&mut self, _dirs: HashSet<PathBuf>) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn wait(&self, timeout: Duration) -> io::Result<bool> {
+        std::thread::sleep(timeout);
+        Ok(false)
+    }
+}
@@ -0,0 +1,111 @@
+//! Noticing work_dir going away, like when the mount it's on is unmounted or a network share drops.
+//! A missing work_dir looks just like one whose files were all deleted, so instead of emptying the
+//! backup to match, syncing is paused with an error until work_dir is back
+//!
+//! work_dir counts as gone when it can't be listed, when it's on a different filesystem than at
+//! startup, which is what an unmounted mount point looks like, or with `--mount-marker` when the
+//! marker file is missing
+
+use anyhow::{anyhow, Result};
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+};
+
+use crate::{
+    output::{self, info, Event},
+    platform,
+};
+
+struct Root {
+    path: PathBuf,
+    /// The filesystem work_dir was on at startup, where the platform can tell
+    device: Option<u64>,
+    marker: Option<PathBuf>,
+}
+
+static ROOT: OnceLock<Root> = OnceLock::new();
+/// Set while work_dir is gone
+static GONE: AtomicBool = AtomicBool::new(false);
+
+/// Keeps an eye on `work_dir`, which must be there now, along with `marker` relative to it if given
+pub fn set(work_dir: &Path, marker: Option<PathBuf>) -> Result<()> {
+    let root = Root {
+        path: work_dir.to_path_buf(),
+        device: std::fs::metadata(work_dir)
+            .ok()
+            .and_then(|metadata| platform::file_id(&metadata))
+            .map(|(device, _)| device),
+        marker: marker.map(|marker| work_dir.join(marker)),
+    };
+    if let Some(reason) = why_gone(&root) {
+        return Err(anyhow!(
+            "work_dir {reason}, is the filesystem it's on mounted?"
+        ));
+    }
+
+    ROOT.set(root)
+        .map_err(|_| anyhow!("work_dir can only be watched once"))
+}
+
+/// Whether syncing is paused because work_dir is gone
+pub fn is_gone() -> bool {
+    GONE.load(Ordering::Relaxed)
+}
+
+/// Whether `dir` is work_dir
+pub fn is_work_dir(dir: &Path) -> bool {
+    ROOT.get().is_some_and(|root| root.path == dir)
+}
+
+/// Looks at whether `dir` is still there if it's work_dir, returning whether it is. Going away
+/// raises an error once, and coming back is logged. Any other directory always counts as there
+pub fn check(dir: &Path) -> bool {
+    let Some(root) = ROOT.get().filter(|root| root.path == dir) else {
+        return true;
+    };
+
+    let reason = why_gone(root);
+    let gone = reason.is_some();
+    if GONE.swap(gone, Ordering::Relaxed) != gone {
+        match reason {
+            Some(reason) => output::emit(&Event::Error {
+                path: None,
+                message: format!(
+                    "work_dir {reason}, so syncing is paused until it's back rather than deleting the backup to match"
+                ),
+            }),
+            None => info!("work_dir {} is back", root.path.display()),
+        }
+    }
+
+    !gone
+}
+
+fn why_gone(root: &Root) -> Option<String> {
+    let metadata = match std::fs::metadata(&root.path) {
+        Ok(metadata) => metadata,
+        Err(err) => return Some(format!("{} can't be accessed: {err}", root.path.display())),
+    };
+    if !metadata.is_dir() {
+        return Some(format!("{} isn't a directory anymore", root.path.display()));
+    }
+    if let Err(err) = std::fs::read_dir(&root.path) {
+        return Some(format!("{} can't be listed: {err}", root.path.display()));
+    }
+    let device = platform::file_id(&metadata).map(|(device, _)| device);
+    if root.device.is_some() && device != root.device {
+        return Some(format!(
+            "{} is on a different filesystem than at startup, as if it was unmounted",
+            root.path.display()
+        ));
+    }
+    if let Some(marker) = root.marker.as_ref().filter(|marker| !marker.exists()) {
+        return Some(format!("has no {}", marker.display()));
+    }
+
+    None
+}
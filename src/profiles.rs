@@ -0,0 +1,539 @@
+//! Several independent work_dir to backup_dir pairs managed by one `evil_mount --profiles FILE`.
+//! Most of what a sync keeps track of is process wide, so every profile runs as its own sync
+//! underneath, with its own flags on top of the ones given along with `--profiles`. Their output
+//! is merged and tagged with the profile's name, and stopping evil_mount stops all of them
+//!
+//! The file is a JSON object of profiles by name, like
+//!
+//! ```json
+//! {
+//!     "documents": {
+//!         "work_dir": "/home/me/Documents",
+//!         "backup_dir": "/mnt/backup/documents",
+//!         "exclude": ["*.tmp"],
+//!         "route": ["*.psd=/mnt/nas/design"],
+//!         "interval": 30,
+//!         "bandwidth_limit": "10M",
+//!         "log_level": "warning",
+//!         "on_error": "notify-send evil_mount \"$EVIL_MOUNT_MESSAGE\"",
+//!         "args": ["--delete", "after-grace"]
+//!     }
+//! }
+//! ```
+
+use anyhow::{anyhow, Context, Result};
+use clap::{parser::ValueSource, ArgAction, ArgMatches};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    io::{BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, ExitCode, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use crate::{
+    dr_test,
+    output::{self, info, Event, LogLevel, OutputFormat},
+    platform::{self, Signal, Signals},
+    status,
+    supervisor::{sleep_unless, Backoff},
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub work_dir: PathBuf,
+    pub backup_dir: PathBuf,
+    /// Passed as `--exclude`
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Passed as `--route`, each like `*.psd=/mnt/nas/design`
+    #[serde(default)]
+    pub route: Vec<String>,
+    /// Passed as `--interval`
+    pub interval: Option<u64>,
+    /// Passed as `--bandwidth-limit`
+    pub bandwidth_limit: Option<String>,
+    /// Passed as `--log-level`
+    pub log_level: Option<LogLevel>,
+    /// Passed as `--on-copy`
+    pub on_copy: Option<String>,
+    /// Passed as `--on-delete`
+    pub on_delete: Option<String>,
+    /// Passed as `--on-error`
+    pub on_error: Option<String>,
+    /// Any other flags, passed as they are
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/// The profile `name` in the profiles file at `path`
+pub fn load_one(path: &Path, name: &str) -> Result<Profile> {
+    load(path)?
+        .remove(name)
+        .ok_or_else(|| anyhow!("{} no longer has a profile named {name}", path.display()))
+}
+
+fn load(path: &Path) -> Result<BTreeMap<String, Profile>> {
+    let contents =
+        std::fs::read(path).with_context(|| anyhow!("Error reading {}", path.display()))?;
+    let profiles: BTreeMap<String, Profile> = serde_json::from_slice(&contents)
+        .with_context(|| anyhow!("Error parsing {}", path.display()))?;
+
+    match profiles.is_empty() {
+        true => Err(anyhow!("{} doesn't have any profiles", path.display())),
+        false => Ok(profiles),
+    }
+}
+
+/// The flags --profiles passes on in its own way, or that make no sense for a profile
+const HANDLED: &[&str] = &[
+    "profiles",
+    "profile-file",
+    "profile-name",
+    "work-dir",
+    "backup-dir",
+    "output",
+    "max-concurrent-copies",
+];
+/// The flags that would have every profile use the same socket, address, destination or file at
+/// once, so that each profile that needs one has to be given its own in its `args`
+const NOT_SHARED: &[&str] = &[
+    "control-socket",
+    "health-addr",
+    "copier",
+    "copy-slots",
+    "mirror-to",
+    "pull-from",
+    "trace",
+];
+
+/// A flag given along with `--profiles`, which every profile's sync is started with too
+pub struct SharedFlag {
+    long: String,
+    args: Vec<OsString>,
+    /// Whether it adds to what a profile gives itself, like `--exclude`, rather than being
+    /// overridden by it
+    repeatable: bool,
+}
+
+/// The flags in `matches` that were given on the command line along with `--profiles`, to pass
+/// on to every profile. Fails for one that profiles can't share
+pub fn shared_flags(matches: &ArgMatches, command: &clap::Command) -> Result<Vec<SharedFlag>> {
+    let mut shared = Vec::new();
+    for arg in command.get_arguments() {
+        let (id, Some(long)) = (arg.get_id().as_str(), arg.get_long()) else {
+            continue;
+        };
+        if HANDLED.contains(&long) || matches.value_source(id) != Some(ValueSource::CommandLine) {
+            continue;
+        }
+        if NOT_SHARED.contains(&long) {
+            return Err(anyhow!(
+                "--{long} can't be shared by every profile, give it to the ones that need it in their args instead"
+            ));
+        }
+
+        let mut args = Vec::new();
+        match arg.get_action() {
+            action if action.takes_values() => {
+                for values in matches.get_raw_occurrences(id).into_iter().flatten() {
+                    let values: Vec<_> = values.collect();
+                    match values.as_slice() {
+                        // Joined, so that a value starting with - isn't taken for a flag
+                        [value] => {
+                            let mut arg = OsString::from(format!("--{long}="));
+                            arg.push(value);
+                            args.push(arg);
+                        }
+                        values => {
+                            args.push(format!("--{long}").into());
+                            args.extend(values.iter().map(|value| value.to_os_string()));
+                        }
+                    }
+                }
+            }
+            ArgAction::Count => {
+                for _ in 0..matches.get_count(id) {
+                    args.push(format!("--{long}").into());
+                }
+            }
+            _ => args.push(format!("--{long}").into()),
+        }
+        shared.push(SharedFlag {
+            long: format!("--{long}"),
+            args,
+            repeatable: matches!(arg.get_action(), ArgAction::Append | ArgAction::Count),
+        });
+    }
+
+    Ok(shared)
+}
+
+/// What every profile's sync is started with, and what's running right now
+struct Supervisor {
+    exe: PathBuf,
+    path: PathBuf,
+    once: bool,
+    format: OutputFormat,
+    shared: Vec<SharedFlag>,
+    max_concurrent_copies: Option<usize>,
+    copy_slots: PathBuf,
+    /// The sync of each profile that's running
+    pids: Mutex<BTreeMap<String, u32>>,
+    stopping: AtomicBool,
+}
+
+/// Runs a sync for every profile in the file at `path` until they all exit or evil_mount is
+/// stopped. `once` runs a single pass of each, like `sync --once`. Each is started with the
+/// `shared` flags, and `max_concurrent_copies` is shared by all of them
+///
+/// Each profile is supervised on its own, so one that can't run, like one whose backup_dir is
+/// missing, is started again with a growing wait while the others keep syncing
+pub async fn run(
+    path: &Path,
+    once: bool,
+    format: OutputFormat,
+    shared: Vec<SharedFlag>,
+    max_concurrent_copies: Option<usize>,
+) -> Result<ExitCode> {
+    let profiles = load(path)?;
+    let supervisor = Arc::new(Supervisor {
+        exe: std::env::current_exe()?,
+        path: path.to_path_buf(),
+        once,
+        format,
+        shared,
+        max_concurrent_copies,
+        copy_slots: std::env::temp_dir()
+            .join(format!("evil_mount-copy-slots-{}", std::process::id())),
+        pids: Mutex::new(BTreeMap::new()),
+        stopping: AtomicBool::new(false),
+    });
+
+    if platform::CAPABILITIES.signals {
+        let (supervisor, names) = (supervisor.clone(), profiles.keys().cloned().collect());
+        tokio::task::spawn(async move {
+            if let Err(err) = reload_on_sighup(&supervisor, names).await {
+                output::emit(&Event::Error {
+                    path: None,
+                    message: format!("Error listening for SIGHUP: {err}"),
+                });
+            }
+        });
+    }
+
+    let waits = futures::future::join_all(profiles.into_iter().map(|(name, profile)| {
+        let supervisor = supervisor.clone();
+        async move {
+            let exit = supervisor.supervise(&name, &profile).await;
+            (name, exit)
+        }
+    }));
+    tokio::pin!(waits);
+    let exits = tokio::select! {
+        exits = &mut waits => exits,
+        result = tokio::signal::ctrl_c() => {
+            result?;
+            info!("Stopping every profile...");
+            supervisor.stopping.store(true, Ordering::Relaxed);
+            for pid in supervisor.pids.lock().unwrap().values() {
+                stop(*pid);
+            }
+            waits.await
+        }
+    };
+    let _ = std::fs::remove_dir_all(&supervisor.copy_slots);
+
+    let mut exit_code = ExitCode::SUCCESS;
+    for (name, exit) in exits {
+        match exit {
+            Ok(status) if status.success() => (),
+            Ok(status) => {
+                // The first profile to fail tells why, with the exit code of its sync
+                if exit_code == ExitCode::SUCCESS {
+                    exit_code = status
+                        .code()
+                        .map_or(ExitCode::FAILURE, |code| ExitCode::from(code as u8));
+                }
+                output::emit(&Event::Error {
+                    path: None,
+                    message: format!("Profile {name} {}", describe(status)),
+                });
+            }
+            Err(err) => {
+                exit_code = ExitCode::FAILURE;
+                output::emit(&Event::Error {
+                    path: None,
+                    message: format!("Error running profile {name}: {err:#}"),
+                });
+            }
+        }
+    }
+
+    Ok(exit_code)
+}
+
+impl Supervisor {
+    /// Runs the sync of a profile, starting it again whenever it exits until evil_mount is stopped.
+    /// Returns how it exited the last time
+    async fn supervise(&self, name: &str, profile: &Profile) -> Result<ExitStatus> {
+        let mut backoff = Backoff::new();
+        loop {
+            backoff.started();
+            let exit = self.run_once(name, profile).await;
+            if self.once || self.stopping.load(Ordering::Relaxed) {
+                return exit;
+            }
+
+            let wait = backoff.next();
+            let reason = match &exit {
+                Ok(status) => describe(*status),
+                Err(err) => format!("failed: {err:#}"),
+            };
+            output::emit(&Event::Error {
+                path: None,
+                message: format!(
+                    "Profile {name} {reason}, starting it again in {}s",
+                    wait.as_secs()
+                ),
+            });
+
+            if !sleep_unless(wait, || self.stopping.load(Ordering::Relaxed)).await {
+                return exit;
+            }
+        }
+    }
+
+    /// Starts the sync of a profile and waits for it to exit
+    async fn run_once(&self, name: &str, profile: &Profile) -> Result<ExitStatus> {
+        let mut child = self
+            .command(name, profile)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| anyhow!("Error starting profile {name}"))?;
+        info!(
+            "Syncing {} into {} as profile {name}",
+            profile.work_dir.display(),
+            profile.backup_dir.display()
+        );
+
+        let mut forwarders = Vec::new();
+        if let Some(stdout) = child.stdout.take() {
+            forwarders.push(forward(name.to_string(), stdout, self.format, false));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            forwarders.push(forward(name.to_string(), stderr, self.format, true));
+        }
+        self.pids
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), child.id());
+        // Stopping may have begun while it was starting, after the running syncs were signalled
+        if self.stopping.load(Ordering::Relaxed) {
+            stop(child.id());
+        }
+
+        let status = tokio::task::spawn_blocking(move || {
+            let status = child.wait();
+            // So that its last lines make it out before it's started again or evil_mount exits
+            for forwarder in forwarders {
+                let _ = forwarder.join();
+            }
+            status
+        })
+        .await;
+        self.pids.lock().unwrap().remove(name);
+
+        Ok(status??)
+    }
+
+    fn command(&self, name: &str, profile: &Profile) -> Command {
+        let mut command = Command::new(&self.exe);
+        command
+            .arg("--work-dir")
+            .arg(&profile.work_dir)
+            .arg("--backup-dir")
+            .arg(&profile.backup_dir)
+            .args(["--output", format_arg(self.format)]);
+        for glob in &profile.exclude {
+            command.args(["--exclude", glob]);
+        }
+        for route in &profile.route {
+            command.args(["--route", route]);
+        }
+        if let Some(interval) = profile.interval {
+            command.args(["--interval", &interval.to_string()]);
+        }
+        if let Some(bandwidth_limit) = &profile.bandwidth_limit {
+            command.args(["--bandwidth-limit", bandwidth_limit]);
+        }
+        if let Some(log_level) = profile.log_level {
+            command.args(["--log-level", log_level_arg(log_level)]);
+        }
+        for (flag, hook) in [
+            ("--on-copy", &profile.on_copy),
+            ("--on-delete", &profile.on_delete),
+            ("--on-error", &profile.on_error),
+        ] {
+            if let Some(hook) = hook {
+                command.args([flag, hook]);
+            }
+        }
+        // What a profile sets itself wins over what's given to every profile
+        let own: Vec<&str> = [
+            (profile.interval.is_some(), "--interval"),
+            (profile.bandwidth_limit.is_some(), "--bandwidth-limit"),
+            (profile.log_level.is_some(), "--log-level"),
+            (profile.on_copy.is_some(), "--on-copy"),
+            (profile.on_delete.is_some(), "--on-delete"),
+            (profile.on_error.is_some(), "--on-error"),
+        ]
+        .into_iter()
+        .filter_map(|(set, flag)| set.then_some(flag))
+        .chain(profile.args.iter().map(|arg| match arg.split_once('=') {
+            Some((flag, _)) => flag,
+            None => arg,
+        }))
+        .collect();
+        for flag in &self.shared {
+            if flag.repeatable || !own.contains(&flag.long.as_str()) {
+                command.args(&flag.args);
+            }
+        }
+        if let Some(max_concurrent_copies) = self.max_concurrent_copies {
+            command
+                .args([
+                    "--max-concurrent-copies",
+                    &max_concurrent_copies.to_string(),
+                ])
+                .arg("--copy-slots")
+                .arg(&self.copy_slots);
+        }
+        command
+            .args(&profile.args)
+            .arg("--profile-file")
+            .arg(&self.path)
+            .args(["--profile-name", name])
+            .arg("sync");
+        if self.once {
+            command.arg("--once");
+        }
+        // Ctrl-C in a terminal only reaches evil_mount, which stops the profiles itself. Otherwise
+        // they could exit before it knows it's stopping, and be started again
+        platform::own_process_group(&mut command);
+
+        command
+    }
+}
+
+/// The status of every profile, from the snapshots their syncs save in backup_dir. A profile that
+/// has none, like one whose backup_dir is missing, shows why instead of hiding the others
+pub fn status(path: &Path) -> Result<serde_json::Map<String, serde_json::Value>> {
+    Ok(load(path)?
+        .into_iter()
+        .map(|(name, profile)| {
+            let snapshot = status::load(&profile.backup_dir).and_then(|mut snapshot| {
+                snapshot["last_dr_test"] =
+                    serde_json::to_value(dr_test::last(&profile.backup_dir))?;
+                Ok(snapshot)
+            });
+            let snapshot = match snapshot {
+                Ok(snapshot) => snapshot,
+                Err(err) => serde_json::json!({ "error": format!("{err:#}") }),
+            };
+            (name, snapshot)
+        })
+        .collect())
+}
+
+fn format_arg(format: OutputFormat) -> &'static str {
+    match format {
+        OutputFormat::Text => "text",
+        OutputFormat::Json => "json",
+    }
+}
+
+fn log_level_arg(level: LogLevel) -> &'static str {
+    match level {
+        LogLevel::Error => "error",
+        LogLevel::Warning => "warning",
+        LogLevel::Info => "info",
+    }
+}
+
+/// How a process exited, like `exited with status 1`
+pub fn describe(status: ExitStatus) -> String {
+    match status.code() {
+        Some(code) => format!("exited with status {code}"),
+        None => format!("was killed ({status})"),
+    }
+}
+
+/// Copies the lines a profile prints to ours, tagging them with its name. JSON events get a
+/// `profile` field so that they stay parseable
+fn forward(
+    name: String,
+    stream: impl Read + Send + 'static,
+    format: OutputFormat,
+    stderr: bool,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            let Ok(line) = line else {
+                return;
+            };
+
+            let line = match (format, serde_json::from_str::<serde_json::Value>(&line)) {
+                (OutputFormat::Json, Ok(serde_json::Value::Object(mut event))) => {
+                    event.insert("profile".to_string(), name.clone().into());
+                    serde_json::Value::Object(event).to_string()
+                }
+                _ => format!("[{name}] {line}"),
+            };
+            // Nothing can be done about our own output being closed
+            let _ = match stderr {
+                true => writeln!(std::io::stderr(), "{line}"),
+                false => writeln!(std::io::stdout(), "{line}"),
+            };
+        }
+    })
+}
+
+/// Passes SIGHUP on to every profile, so that they reload their settings. Profiles that were added
+/// to or removed from the file only get started or stopped by a restart
+async fn reload_on_sighup(supervisor: &Supervisor, names: Vec<String>) -> std::io::Result<()> {
+    let mut signals = Signals::listen(Signal::Hangup)?;
+    while signals.recv().await.is_some() {
+        info!("Received SIGHUP, reloading every profile");
+        for pid in supervisor.pids.lock().unwrap().values() {
+            platform::send_signal(*pid, Signal::Hangup);
+        }
+
+        if let Ok(profiles) = load(&supervisor.path) {
+            if !profiles.keys().eq(&names) {
+                output::emit(&Event::Error {
+                    path: None,
+                    message: format!(
+                        "The profiles in {} changed, restart evil_mount to start or stop them",
+                        supervisor.path.display()
+                    ),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Asks a profile to shut down the same way Ctrl-C does, so that it saves its state first
+fn stop(pid: u32) {
+    platform::send_signal(pid, Signal::Interrupt);
+}
@@ -0,0 +1,216 @@
+//! Protected paths in backup_dir, matching `--protect` globs like `**/*.kdbx` or `photos/**`.
+//! Their copies are never deleted or overwritten without a confirmation for that one file, on top
+//! of whatever `--delete` and `--max-delete` allow. An operation that needs one is held back and
+//! reported as an error every pass until `ctl confirm` lets it through
+//!
+//! Deleting a directory counts as deleting every protected file in it, and a protected file isn't
+//! renamed for a move either, it's copied to its new path while the deletion of the old one waits
+
+use anyhow::{anyhow, Context, Result};
+use ignore::overrides::{Override, OverrideBuilder};
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use crate::{hash_file, output::info};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Delete,
+    Overwrite,
+}
+
+impl Operation {
+    fn verb(self) -> &'static str {
+        match self {
+            Operation::Delete => "delete",
+            Operation::Overwrite => "overwrite",
+        }
+    }
+
+    fn done(self) -> &'static str {
+        match self {
+            Operation::Delete => "deleted",
+            Operation::Overwrite => "overwritten",
+        }
+    }
+}
+
+struct Protector {
+    backup_dir: PathBuf,
+    globs: Override,
+}
+
+static PROTECTOR: OnceLock<Protector> = OnceLock::new();
+/// The operations waiting for a confirmation, by path relative to backup_dir
+static HELD: Mutex<BTreeMap<PathBuf, Operation>> = Mutex::new(BTreeMap::new());
+/// The paths confirmed for their next operation
+static CONFIRMED: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+/// Protects the files in `backup_dir` matching `globs`. Does nothing without any
+pub fn set(backup_dir: &Path, globs: &[String]) -> Result<()> {
+    if globs.is_empty() {
+        return Ok(());
+    }
+
+    let mut overrides = OverrideBuilder::new(backup_dir);
+    for glob in globs {
+        overrides
+            .add(glob)
+            .with_context(|| anyhow!("Invalid protected pattern {glob}"))?;
+    }
+    PROTECTOR
+        .set(Protector {
+            backup_dir: backup_dir.to_path_buf(),
+            globs: overrides.build()?,
+        })
+        .map_err(|_| anyhow!("the protected patterns can only be set once"))
+}
+
+/// The path of `path` relative to backup_dir, if it's a protected file in backup_dir
+fn relative_path(path: &Path) -> Option<&Path> {
+    let protector = PROTECTOR.get()?;
+    let relative_path = path.strip_prefix(&protector.backup_dir).ok()?;
+
+    protector
+        .globs
+        .matched(relative_path, false)
+        .is_whitelist()
+        .then_some(relative_path)
+}
+
+/// Whether `path` in backup_dir is protected
+pub fn is_protected(path: &Path) -> bool {
+    relative_path(path).is_some()
+}
+
+/// Fails unless `operation` on `path` in backup_dir is allowed, which for a protected file takes a
+/// confirmation. The confirmation is used up once the operation is allowed
+pub fn allow(path: &Path, operation: Operation) -> Result<()> {
+    let Some(relative_path) = relative_path(path) else {
+        return Ok(());
+    };
+
+    let mut confirmed = CONFIRMED.lock().unwrap();
+    let mut held = HELD.lock().unwrap();
+    if confirmed
+        .as_mut()
+        .is_some_and(|confirmed| confirmed.remove(relative_path))
+    {
+        held.remove(relative_path);
+        return Ok(());
+    }
+
+    held.insert(relative_path.to_path_buf(), operation);
+    Err(anyhow!(
+        "Not going to {} {}, it's protected. Send `ctl confirm {}` to let it happen",
+        operation.verb(),
+        path.display(),
+        relative_path.display()
+    ))
+}
+
+/// Fails unless overwriting the protected copy `to` with `from` is allowed, which it always is when
+/// the contents are the same anyway
+pub fn allow_overwrite(from: &Path, to: &Path) -> Result<()> {
+    if !is_protected(to) {
+        return Ok(());
+    }
+    let same_len = match (std::fs::metadata(from), std::fs::metadata(to)) {
+        (Ok(from), Ok(to)) => from.len() == to.len(),
+        _ => false,
+    };
+    if same_len && hash_file(from)? == hash_file(to)? {
+        return Ok(());
+    }
+
+    allow(to, Operation::Overwrite)
+}
+
+/// Fails unless deleting the directory `dir` in backup_dir is allowed, which takes a confirmation
+/// for every protected file in it. Each one that isn't confirmed yet is held back
+pub fn allow_dir(dir: &Path) -> Result<()> {
+    if PROTECTOR.get().is_none() {
+        return Ok(());
+    }
+
+    let mut protected = Vec::new();
+    find_protected(dir, &mut protected);
+
+    // The confirmations are only used up once all of them are there
+    let mut confirmed = CONFIRMED.lock().unwrap();
+    let confirmed = confirmed.get_or_insert_with(HashSet::new);
+    let mut held = HELD.lock().unwrap();
+    let unconfirmed: Vec<_> = protected
+        .iter()
+        .filter(|path| !confirmed.contains(*path))
+        .collect();
+
+    match unconfirmed.len() {
+        0 => {
+            for path in &protected {
+                confirmed.remove(path);
+                held.remove(path);
+            }
+            Ok(())
+        }
+        unconfirmed_count => {
+            for path in unconfirmed {
+                held.insert(path.clone(), Operation::Delete);
+            }
+            Err(anyhow!(
+                "Not going to delete {}, it holds {unconfirmed_count} protected files. Send `ctl confirm` to let it happen",
+                dir.display()
+            ))
+        }
+    }
+}
+
+fn find_protected(dir: &Path, protected: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        match entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            true => find_protected(&path, protected),
+            false => protected.extend(relative_path(&path).map(Path::to_path_buf)),
+        }
+    }
+}
+
+/// Confirms the held back operation on `path`, relative to backup_dir, or every held back one
+/// without a path. Returns the paths confirmed
+pub fn confirm(path: Option<&Path>) -> Result<Vec<PathBuf>> {
+    let mut confirmed = CONFIRMED.lock().unwrap();
+    let held = HELD.lock().unwrap();
+    let paths = match path {
+        Some(path) if held.contains_key(path) => vec![path.to_path_buf()],
+        Some(path) => return Err(anyhow!("Nothing is held back for {}", path.display())),
+        None => held.keys().cloned().collect(),
+    };
+
+    for path in &paths {
+        info!(
+            "Confirmed that {} can be {}",
+            path.display(),
+            held[path].done()
+        );
+    }
+    confirmed
+        .get_or_insert_with(HashSet::new)
+        .extend(paths.iter().cloned());
+
+    Ok(paths)
+}
+
+/// The operations waiting for a confirmation, for `status`
+pub fn held() -> serde_json::Value {
+    HELD.lock()
+        .unwrap()
+        .iter()
+        .map(|(path, operation)| serde_json::json!({ "path": path.to_string_lossy(), "operation": operation.verb() }))
+        .collect()
+}
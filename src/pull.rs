@@ -0,0 +1,171 @@
+//! Pulling work_dir from another machine with `--pull-from [USER@]HOST:PATH`, so that the backup
+//! host starts every sync rather than the machine being backed up pushing to it. A laptop then
+//! needs neither inbound connections nor credentials to the backup, only sshd and rsync, which is
+//! the thin helper on its end that sends the differences of changed files
+//!
+//! work_dir is the local copy rsync keeps of PATH, brought up to date over SSH before the startup
+//! reconciliation and every cycle, and backed up from there like with `--mirror-consumer`, which
+//! pulling implies. SSH runs in batch mode, so it needs a key that works without a prompt, unless
+//! `RSYNC_RSH` says otherwise. A failed pull is an error, and the pass goes on with what work_dir
+//! last had. rsync only deletes once the whole transfer is done, and not at all after an I/O
+//! error, so a pull cut short leaves work_dir as it was. A remote PATH with nothing in it, like a
+//! disk that isn't mounted there, is refused rather than pulled, unless work_dir is empty too
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::OnceLock,
+};
+
+use crate::{
+    output::{self, info, Event},
+    profiles, STATE_DIR_NAME,
+};
+
+/// How long rsync waits on a stalled connection before giving up, in seconds
+const TIMEOUT_SECS: u32 = 300;
+
+struct Remote {
+    source: String,
+    work_dir: PathBuf,
+}
+
+static REMOTE: OnceLock<Remote> = OnceLock::new();
+
+/// Pulls `source`, like `laptop:/home/me`, into `work_dir` before every pass
+pub fn set(source: String, work_dir: PathBuf) -> Result<()> {
+    let remote = match source.split_once(':') {
+        // rsync takes `HOST::MODULE` and `rsync://` for its own daemon rather than SSH
+        Some((host, rest)) => {
+            !host.is_empty()
+                && !host.contains('/')
+                && !rest.starts_with(':')
+                && !rest.starts_with("//")
+        }
+        None => false,
+    };
+    if !remote {
+        return Err(anyhow!(
+            "--pull-from {source} isn't a path on another machine over SSH, like laptop:/home/me or me@laptop:/home/me"
+        ));
+    }
+    let version = Command::new("rsync")
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .context("--pull-from needs rsync to be installed")?;
+    if !version.success() {
+        return Err(anyhow!(
+            "--pull-from needs a working rsync, `rsync --version` failed"
+        ));
+    }
+
+    REMOTE
+        .set(Remote { source, work_dir })
+        .map_err(|_| anyhow!("the machine to pull from can only be set once"))
+}
+
+/// Brings work_dir up to date with the machine it's pulled from, returning whether it is. Returns
+/// right away without `--pull-from`
+pub async fn pull() -> bool {
+    let Some(remote) = REMOTE.get() else {
+        return true;
+    };
+
+    info!("Pulling {}...", remote.source);
+    let err = match tokio::task::spawn_blocking(|| run(remote)).await {
+        Ok(Ok(())) => return true,
+        Ok(Err(err)) => err,
+        Err(err) => anyhow!("it panicked: {err}"),
+    };
+    output::emit(&Event::Error {
+        path: None,
+        message: format!(
+            "Error pulling from {}, keeping what work_dir had: {err:#}",
+            remote.source
+        ),
+    });
+
+    false
+}
+
+fn run(remote: &Remote) -> Result<()> {
+    // The trailing slashes copy the contents of the directories rather than the directories
+    let mut source = remote.source.clone();
+    if !source.ends_with('/') {
+        source.push('/');
+    }
+    let mut destination = remote.work_dir.clone().into_os_string();
+    destination.push("/");
+
+    // --delete-after would empty work_dir with it
+    if is_empty(&source)? && !is_empty_dir(&remote.work_dir)? {
+        return Err(anyhow!(
+            "{} has nothing in it, which looks like it isn't mounted, so work_dir isn't emptied to match",
+            remote.source
+        ));
+    }
+
+    let mut command = rsync();
+    command
+        .args(["--archive", "--delete-after"])
+        .arg("--")
+        .arg(source)
+        .arg(destination);
+    output(command).map(|_| ())
+}
+
+/// Whether the remote directory `source` has nothing in it, besides what pulling leaves out
+fn is_empty(source: &str) -> Result<bool> {
+    let mut command = rsync();
+    command.args(["--list-only", "--"]).arg(source);
+    let listing = output(command)?;
+
+    // The listing of a directory starts with the directory itself, named `.`
+    Ok(String::from_utf8_lossy(&listing)
+        .lines()
+        .all(|line| line.trim().is_empty() || line.ends_with(" .")))
+}
+
+fn is_empty_dir(dir: &Path) -> Result<bool> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Error reading {}", dir.display()))?
+        .filter_map(|entry| entry.ok());
+    Ok(entries.all(|entry| entry.file_name() == STATE_DIR_NAME))
+}
+
+/// rsync with the options every run of it shares
+fn rsync() -> Command {
+    let mut command = Command::new("rsync");
+    command
+        .arg(format!("--timeout={TIMEOUT_SECS}"))
+        // The state of an evil_mount backing up into the remote directory isn't part of it
+        .arg(format!("--exclude=/{STATE_DIR_NAME}/"));
+    if std::env::var_os("RSYNC_RSH").is_none() {
+        command.arg("--rsh=ssh -o BatchMode=yes -o ConnectTimeout=30");
+    }
+
+    command
+}
+
+/// Runs `command` to the end, returning its stdout if it succeeded
+fn output(mut command: Command) -> Result<Vec<u8>> {
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .context("Error running rsync")?;
+
+    match output.status.success() {
+        true => Ok(output.stdout),
+        false => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(anyhow!(
+                "rsync {}: {}",
+                profiles::describe(output.status),
+                stderr.trim()
+            ))
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Files changing under a sync while it looks at them. Between a file being listed and copied, it
+//! can be deleted, renamed or written to, which isn't an error. A file that's gone by the time
+//! it's looked at is left to the next pass, which deletes its copy if it's still gone. One that
+//! changed while it was being copied is tried again once the rest of the pass is done, up to
+//! ATTEMPTS times, and only reported if it keeps changing or still fails while it's there
+
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+/// How many times a pass tries a file that changed while it was being synced again
+pub const ATTEMPTS: usize = 3;
+
+/// A file that changed while it was being copied, so that the copy may be of neither version
+#[derive(Debug)]
+pub struct Changed {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for Changed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} changed while it was being copied",
+            self.path.display()
+        )
+    }
+}
+
+impl std::error::Error for Changed {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Race {
+    /// The file went away
+    Gone,
+    /// The file, or something on the way to its copy, changed
+    Changed,
+}
+
+/// Whether `err`, from syncing `path`, came from the file changing under the sync rather than
+/// from something being wrong
+pub fn classify(path: &Path, err: &anyhow::Error) -> Option<Race> {
+    let changed = err.chain().any(|cause| cause.is::<Changed>());
+    let not_found = err.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|err| err.kind() == io::ErrorKind::NotFound)
+    });
+    if !changed && !not_found {
+        return None;
+    }
+
+    match std::fs::symlink_metadata(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Some(Race::Gone),
+        // Something else went missing, like a directory in backup_dir deleted at the same time
+        _ => Some(Race::Changed),
+    }
+}
+
+/// Fails with [`Changed`] unless `path` still has the size and modify time of `before`
+pub fn ensure_unchanged(path: &Path, before: &std::fs::Metadata) -> anyhow::Result<()> {
+    let after = std::fs::metadata(path)?;
+    match after.len() == before.len() && after.modified().ok() == before.modified().ok() {
+        true => Ok(()),
+        false => Err(Changed {
+            path: path.to_path_buf(),
+        }
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    /// An empty directory of its own for the test called `name`
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("evil_mount-races-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn unchanged_file_passes() {
+        let dir = temp_dir("unchanged");
+        let path = dir.join("file");
+        std::fs::write(&path, "contents").unwrap();
+
+        let before = std::fs::metadata(&path).unwrap();
+        assert!(ensure_unchanged(&path, &before).is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file_written_after_the_stat_changed() {
+        let dir = temp_dir("written");
+        let path = dir.join("file");
+        std::fs::write(&path, "contents").unwrap();
+
+        let before = std::fs::metadata(&path).unwrap();
+        std::fs::write(&path, "longer contents").unwrap();
+        let err = ensure_unchanged(&path, &before).unwrap_err();
+        assert!(err.is::<Changed>());
+        assert_eq!(classify(&path, &err), Some(Race::Changed));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file_deleted_after_the_stat_is_gone() {
+        let dir = temp_dir("deleted");
+        let path = dir.join("file");
+        std::fs::write(&path, "contents").unwrap();
+
+        let before = std::fs::metadata(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let err = ensure_unchanged(&path, &before).unwrap_err();
+        assert_eq!(classify(&path, &err), Some(Race::Gone));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_file_is_gone_through_context() {
+        let dir = temp_dir("context");
+        let path = dir.join("file");
+
+        let err = std::fs::read(&path)
+            .context("Error reading the file")
+            .unwrap_err();
+        assert_eq!(classify(&path, &err), Some(Race::Gone));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn something_else_missing_is_a_change() {
+        let dir = temp_dir("elsewhere");
+        let path = dir.join("file");
+        std::fs::write(&path, "contents").unwrap();
+
+        let err = std::fs::read(dir.join("missing/copy")).unwrap_err().into();
+        assert_eq!(classify(&path, &err), Some(Race::Changed));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn other_errors_are_not_races() {
+        let dir = temp_dir("other");
+        let path = dir.join("file");
+
+        let err = anyhow::anyhow!("the disk is full");
+        assert_eq!(classify(&path, &err), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
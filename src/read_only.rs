@@ -0,0 +1,299 @@
+//! `--lock-backup`, which keeps the copies in backup_dir read-only between syncs, so that nothing
+//! edits a copy by accident only for the edit to be overwritten by the next sync, or to make the
+//! backup look newer than work_dir. After every pass, and every cycle of the watch loop, the
+//! copies made since lose their write permissions, and on Linux as root also get the immutable
+//! attribute. Before evil_mount replaces, renames or deletes a copy it unlocks it again
+//!
+//! Hard linked copies, like those of `--dedup`, are never made immutable, since that would keep
+//! new links to them from being made. The write permissions each copy had are kept in
+//! `read_only.json` in the state dir, so that restoring gives them back to the files it copies out
+//! of the backup
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{
+    encoded_path, locks,
+    output::{self, info, Event},
+    platform, recursive_dir, routes, state_dir, WalkOptions,
+};
+
+/// How a copy was locked, to undo it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Lock {
+    /// The write permission bits it had
+    write: u32,
+    immutable: bool,
+}
+
+struct Locked {
+    path: PathBuf,
+    /// backup_dir and the directories of the routes
+    roots: Vec<PathBuf>,
+    state_dir: PathBuf,
+    /// Whether copies are locked at all, rather than only given back their permissions on restore
+    enabled: bool,
+    locks: BTreeMap<PathBuf, Lock>,
+    /// The copies made or unlocked since they were last locked
+    pending: HashSet<PathBuf>,
+    changed: bool,
+}
+
+static LOCKED: Mutex<Option<Locked>> = Mutex::new(None);
+/// Cleared once setting the immutable attribute turns out to take root, to stop trying
+static IMMUTABLE: AtomicBool = AtomicBool::new(platform::CAPABILITIES.immutable_files);
+
+/// Loads how the copies in `backup_dir` were locked, and locks them from now on if `enabled`
+pub fn set(backup_dir: &Path, enabled: bool) -> Result<()> {
+    let path = state_dir(backup_dir).join("read_only.json");
+    let locks = match std::fs::read(&path) {
+        Ok(contents) => encoded_path::keys::from_json(&contents)
+            .with_context(|| anyhow!("Error parsing {}", path.display()))?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut roots = vec![backup_dir.to_path_buf()];
+    roots.extend(routes::dirs(backup_dir));
+    *LOCKED.lock().unwrap() = Some(Locked {
+        path,
+        roots,
+        state_dir: state_dir(backup_dir),
+        enabled,
+        locks,
+        pending: HashSet::new(),
+        changed: false,
+    });
+
+    Ok(())
+}
+
+impl Locked {
+    fn contains(&self, path: &Path) -> bool {
+        self.roots.iter().any(|root| path.starts_with(root)) && !path.starts_with(&self.state_dir)
+    }
+
+    /// Takes the write permissions away from the file `path`, and makes it immutable where it can
+    fn lock(&mut self, path: &Path) -> io::Result<()> {
+        let metadata = match std::fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        // A symlink would lock what it points to, and a special file's permissions are its backup.
+        // A copy linked to a --dedup blob shares its permissions with every other copy of the same
+        // contents, and with the blob, whose name says what they are
+        if !metadata.is_file() || platform::link_count(&metadata).unwrap_or(1) > 1 {
+            return Ok(());
+        }
+
+        let previous = self.locks.get(path).copied();
+        // Nothing is taken away from a copy that's still locked from before
+        let write = platform::make_read_only(path)? | previous.map_or(0, |lock| lock.write);
+        let immutable = match IMMUTABLE.load(Ordering::Relaxed) {
+            true => match platform::set_immutable(path, true) {
+                Ok(()) => true,
+                Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                    IMMUTABLE.store(false, Ordering::Relaxed);
+                    false
+                }
+                Err(err) if platform::is_unsupported(&err) => false,
+                Err(err) => return Err(err),
+            },
+            false => false,
+        };
+
+        let lock = Lock { write, immutable };
+        if (write != 0 || immutable) && previous != Some(lock) {
+            self.locks.insert(path.to_path_buf(), lock);
+            self.changed = true;
+        }
+
+        Ok(())
+    }
+
+    /// Undoes [`Locked::lock`] for `path`, and for everything in it if it's a directory
+    fn unlock(&mut self, path: &Path) -> io::Result<()> {
+        let metadata = match std::fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        if metadata.is_dir() {
+            for entry in std::fs::read_dir(path)? {
+                self.unlock(&entry?.path())?;
+            }
+            return Ok(());
+        }
+
+        let Some(lock) = self.locks.get(path).copied() else {
+            return Ok(());
+        };
+        // Replaced by a link to a --dedup blob since it was locked, which isn't this copy's to change
+        if platform::link_count(&metadata).unwrap_or(1) > 1 {
+            self.locks.remove(path);
+            self.changed = true;
+            return Ok(());
+        }
+        if lock.immutable {
+            platform::set_immutable(path, false)?;
+        }
+        platform::make_writable(path, lock.write)?;
+        // Locked again after the next pass, if it's still there
+        self.pending.insert(path.to_path_buf());
+
+        Ok(())
+    }
+}
+
+/// Unlocks the copy `path` in backup_dir, or everything in the directory `path`, before it's
+/// replaced, renamed or deleted. Does nothing without `--lock-backup`
+pub fn unlock(path: &Path) -> Result<()> {
+    let mut locked = LOCKED.lock().unwrap();
+    match locked.as_mut() {
+        Some(locked) if locked.enabled && locked.contains(path) => locked
+            .unlock(path)
+            .with_context(|| anyhow!("Error unlocking {}", path.display())),
+        _ => Ok(()),
+    }
+}
+
+/// Gives `to`, copied out of the backup from `from`, the write permissions `from` had before it
+/// was locked
+pub fn give_back(from: &Path, to: &Path) -> Result<()> {
+    let write = match LOCKED.lock().unwrap().as_ref() {
+        Some(locked) => locked.locks.get(from).map(|lock| lock.write),
+        None => None,
+    };
+    match write {
+        Some(write) => platform::make_writable(to, write)
+            .with_context(|| anyhow!("Error making {} writable", to.display())),
+        None => Ok(()),
+    }
+}
+
+fn is_enabled() -> bool {
+    LOCKED
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|locked| locked.enabled)
+}
+
+/// Locks the copies made since the last time, once a pass or cycle is done
+pub async fn lock_changed() {
+    let pending = match LOCKED.lock().unwrap().as_mut() {
+        Some(locked) if locked.enabled => std::mem::take(&mut locked.pending),
+        _ => return,
+    };
+    lock_paths(pending).await;
+}
+
+/// Locks every copy in `backup_dir`, after a pass that went over all of it
+pub async fn lock_all(backup_dir: &Path, walk: &WalkOptions) {
+    if !is_enabled() {
+        return;
+    }
+    let paths: Vec<_> = std::iter::once(backup_dir.to_path_buf())
+        .chain(routes::dirs(backup_dir))
+        .flat_map(|root| recursive_dir(&root, walk))
+        .filter(|entry| {
+            entry
+                .file_type()
+                .is_some_and(|file_type| file_type.is_file())
+        })
+        .map(|entry| entry.into_path())
+        .collect();
+    lock_paths(paths).await;
+}
+
+async fn lock_paths(paths: impl IntoIterator<Item = PathBuf>) {
+    let immutable = IMMUTABLE.load(Ordering::Relaxed);
+    for path in paths {
+        // Not while the copy is being replaced
+        let _guard = locks::lock(&path).await;
+        // Emitting an event takes the lock again
+        let result = match LOCKED.lock().unwrap().as_mut() {
+            Some(locked) => {
+                locked.pending.remove(&path);
+                locked.lock(&path)
+            }
+            None => Ok(()),
+        };
+        if let Err(err) = result {
+            output::emit(&Event::Error {
+                path: Some(&path),
+                message: format!("Error making {} read-only: {err}", path.display()),
+            });
+        }
+    }
+    if immutable && !IMMUTABLE.load(Ordering::Relaxed) {
+        info!("Only taking write permissions away from the copies in backup_dir, since making them immutable takes root");
+    }
+
+    if let Err(err) = save() {
+        output::emit(&Event::Error {
+            path: None,
+            message: format!("Error saving how the copies in backup_dir were locked: {err:#}"),
+        });
+    }
+}
+
+fn save() -> Result<()> {
+    let mut locked = LOCKED.lock().unwrap();
+    let Some(locked) = locked.as_mut().filter(|locked| locked.changed) else {
+        return Ok(());
+    };
+
+    let temp_path = locked.path.with_extension("json.tmp");
+    std::fs::create_dir_all(&locked.state_dir)?;
+    std::fs::write(&temp_path, encoded_path::keys::to_json(&locked.locks)?)?;
+    std::fs::rename(&temp_path, &locked.path)
+        .with_context(|| anyhow!("Error saving {}", locked.path.display()))?;
+    locked.changed = false;
+
+    Ok(())
+}
+
+/// Keeps track of the copies to lock next, and of how the locked ones move. Called for every
+/// emitted event
+pub fn observe(event: &Event) {
+    let mut locked = LOCKED.lock().unwrap();
+    let Some(locked) = locked.as_mut().filter(|locked| locked.enabled) else {
+        return;
+    };
+
+    match event {
+        Event::FileCopied { destination, .. } if locked.contains(destination) => {
+            // A new copy has the permissions of its file
+            locked.changed |= locked.locks.remove(*destination).is_some();
+            locked.pending.insert(destination.to_path_buf());
+        }
+        Event::FileMoved { from, to } if locked.contains(to) => {
+            if let Some(lock) = locked.locks.remove(*from) {
+                locked.locks.insert(to.to_path_buf(), lock);
+                locked.changed = true;
+            }
+            locked.pending.remove(*from);
+            locked.pending.insert(to.to_path_buf());
+        }
+        Event::FileDeleted { path } if locked.contains(path) => {
+            let count = locked.locks.len();
+            locked
+                .locks
+                .retain(|locked_path, _| !locked_path.starts_with(path));
+            locked.changed |= locked.locks.len() != count;
+            locked.pending.retain(|pending| !pending.starts_with(path));
+        }
+        _ => (),
+    }
+}
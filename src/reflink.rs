@@ -0,0 +1,28 @@
+//! Copy on write clones. On filesystems like Btrfs, XFS and APFS a file can be cloned instead of
+//! copied, which is instant and shares the space of the original until either is changed
+
+use clap::ValueEnum;
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Reflink {
+    /// Clone when the filesystem supports it, and copy otherwise
+    #[default]
+    Auto,
+    /// Fail instead of copying when a file can't be cloned
+    Always,
+    /// Always copy the contents
+    Never,
+}
+
+static MODE: OnceLock<Reflink> = OnceLock::new();
+
+/// Sets how files are copied for the rest of the process. Must be called before anything is copied
+pub fn set_mode(mode: Reflink) {
+    MODE.set(mode)
+        .expect("the reflink mode can only be set once");
+}
+
+pub fn mode() -> Reflink {
+    MODE.get().copied().unwrap_or_default()
+}
@@ -0,0 +1,160 @@
+//! Reloading the settings of a profile without restarting its sync, on SIGHUP or `ctl reload`.
+//! Restarting would mean saving and loading the watch state at best, and the destructive startup
+//! reconciliation at worst. Only a profile's `exclude`, `interval`, `bandwidth_limit` and
+//! `log_level` can change this way, since everything else, like where it syncs and its other
+//! flags, shapes the sync from the start
+//!
+//! `--profiles` signals every sync it started, which each read their own profile from the file
+
+use anyhow::{anyhow, Result};
+use std::{
+    path::PathBuf,
+    sync::{atomic::Ordering, Mutex, OnceLock},
+};
+
+use crate::{
+    bandwidth,
+    output::{self, info, Event},
+    platform::{Signal, Signals},
+    profiles::{self, Profile},
+    units, SyncOptions,
+};
+
+/// Where the profile of this sync comes from
+struct Source {
+    file: PathBuf,
+    name: String,
+}
+
+static SOURCE: OnceLock<Source> = OnceLock::new();
+/// SIGHUP, listened for since the process started, until the reloading takes over
+static HANGUPS: Mutex<Option<Signals>> = Mutex::new(None);
+/// The profile as of the last reload, to tell its excludes from the ones given on the command line
+static LOADED: Mutex<Option<Profile>> = Mutex::new(None);
+
+/// Reads the profile `name` from the profiles `file`, and remembers where it came from for reloads
+pub fn set_source(file: PathBuf, name: String) -> Result<()> {
+    let profile = profiles::load_one(&file, &name)?;
+    *LOADED.lock().unwrap() = Some(profile);
+
+    SOURCE
+        .set(Source { file, name })
+        .map_err(|_| anyhow!("the profile to reload can only be set once"))
+}
+
+/// Whether this sync was started for a profile, and so has something to reload
+pub fn has_source() -> bool {
+    SOURCE.get().is_some()
+}
+
+/// Applies the excludes, interval, bandwidth limit and log level of the profile as it is in the
+/// file now, returning what changed
+pub fn reload(options: &SyncOptions) -> Result<serde_json::Value> {
+    let Some(source) = SOURCE.get() else {
+        return Err(anyhow!(
+            "Nothing to reload, only syncs started by --profiles have a config file"
+        ));
+    };
+    let profile = profiles::load_one(&source.file, &source.name)?;
+    let bandwidth_limit = profile
+        .bandwidth_limit
+        .as_deref()
+        .map(units::parse_size)
+        .transpose()
+        .map_err(|err| anyhow!("Invalid bandwidth_limit in profile {}: {err}", source.name))?;
+    let mut loaded = LOADED.lock().unwrap();
+    let old = loaded.as_ref().expect("loaded along with the source");
+
+    let mut changed = Vec::new();
+    if profile.exclude != old.exclude {
+        // Excludes from the command line, like the ones --system-backup adds, stay
+        let mut excludes: Vec<_> = options
+            .walk
+            .excludes()
+            .into_iter()
+            .filter(|exclude| !old.exclude.contains(exclude))
+            .collect();
+        excludes.extend(profile.exclude.iter().cloned());
+        options.walk.set_excludes(excludes)?;
+        crate::listing::invalidate();
+        changed.push("exclude");
+    }
+    if let Some(interval) = profile
+        .interval
+        .filter(|_| profile.interval != old.interval)
+    {
+        options.interval.store(interval, Ordering::Relaxed);
+        changed.push("interval");
+    }
+    if bandwidth_limit.is_some() && profile.bandwidth_limit != old.bandwidth_limit {
+        bandwidth::set_limit(bandwidth_limit);
+        changed.push("bandwidth_limit");
+    }
+    let log_level = profile
+        .log_level
+        .filter(|_| profile.log_level != old.log_level);
+    if log_level.is_some() {
+        changed.push("log_level");
+    }
+    if profile.work_dir != old.work_dir
+        || profile.backup_dir != old.backup_dir
+        || profile.args != old.args
+    {
+        output::emit(&Event::Error {
+            path: None,
+            message: format!(
+                "Profile {} changed more than its exclude, interval, bandwidth_limit and log_level, restart evil_mount to apply the rest",
+                source.name
+            ),
+        });
+    }
+
+    match changed.is_empty() {
+        true => info!("Reloaded {}, nothing changed", source.file.display()),
+        false => info!(
+            "Reloaded {}, applied the new {}",
+            source.file.display(),
+            changed.join(", ")
+        ),
+    }
+    // Only now, so that lowering it doesn't hide that it was reloaded
+    if let Some(log_level) = log_level {
+        output::set_level(log_level);
+    }
+    *loaded = Some(profile);
+
+    Ok(serde_json::json!({ "changed": changed }))
+}
+
+/// Listens for SIGHUP from now on instead of letting it kill the process, for a sync started by
+/// `--profiles`, which passes the signal on to every sync it started. That includes ones still
+/// starting up and ones running once, which only reload after their startup, if at all
+pub fn catch_sighup() {
+    match Signals::listen(Signal::Hangup) {
+        Ok(signals) => *HANGUPS.lock().unwrap() = Some(signals),
+        Err(err) => output::emit(&Event::Error {
+            path: None,
+            message: format!("Error listening for SIGHUP: {err}"),
+        }),
+    }
+}
+
+/// Reloads every time the process receives SIGHUP, starting with one received since
+/// [`catch_sighup`]
+pub async fn reload_on_sighup(options: SyncOptions) -> std::io::Result<()> {
+    let caught = HANGUPS.lock().unwrap().take();
+    let mut signals = match caught {
+        Some(signals) => signals,
+        None => Signals::listen(Signal::Hangup)?,
+    };
+    while signals.recv().await.is_some() {
+        if let Err(err) = reload(&options) {
+            output::emit(&Event::Error {
+                path: None,
+                message: format!("Error reloading: {err:#}"),
+            });
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,130 @@
+//! Backing off from paths that keep failing to sync. Every failed copy or delete is queued with
+//! the time it may next be attempted, doubling the wait after each failure, so a broken file is
+//! neither hammered every cycle nor forgotten. The queue is saved in the state dir on shutdown so
+//! that it survives restarts
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{encoded_path, state_dir};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+/// After this many failures in a row a path is reported as permanently failing. It's still
+/// retried every MAX_BACKOFF, in case whatever is wrong gets fixed
+const PERMANENT_AFTER: u32 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Copy,
+    Delete,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub operation: Operation,
+    /// How many times in a row it failed
+    pub attempts: u32,
+    /// When it may be attempted again, in seconds since the epoch
+    pub next_attempt: u64,
+    pub last_error: String,
+}
+
+static QUEUE: Mutex<BTreeMap<PathBuf, Entry>> = Mutex::new(BTreeMap::new());
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+/// Queues `path` to be retried after a backoff that grows with every failure
+pub fn failed(path: &Path, operation: Operation, err: &anyhow::Error) {
+    let mut queue = QUEUE.lock().unwrap();
+    let entry = queue.entry(path.to_path_buf()).or_insert(Entry {
+        operation,
+        attempts: 0,
+        next_attempt: 0,
+        last_error: String::new(),
+    });
+
+    entry.operation = operation;
+    entry.attempts += 1;
+    let backoff = INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(entry.attempts - 1))
+        .min(MAX_BACKOFF);
+    entry.next_attempt = now_secs() + backoff.as_secs();
+    entry.last_error = format!("{err:#}");
+}
+
+/// Takes `path` off the queue once syncing it worked
+pub fn succeeded(path: &Path) {
+    QUEUE.lock().unwrap().remove(path);
+}
+
+/// Whether `path` may be attempted now, which is always the case unless it's backing off
+pub fn is_due(path: &Path) -> bool {
+    match QUEUE.lock().unwrap().get(path) {
+        Some(entry) => entry.next_attempt <= now_secs(),
+        None => true,
+    }
+}
+
+/// How many paths are waiting to be retried, and the ones that have failed so often they probably
+/// need a human to look at them
+pub fn status() -> serde_json::Value {
+    let queue = QUEUE.lock().unwrap();
+    // Paths that aren't UTF-8 would keep it from being JSON
+    let failing: BTreeMap<_, _> = queue
+        .iter()
+        .filter(|(_, entry)| entry.attempts >= PERMANENT_AFTER)
+        .map(|(path, entry)| (path.to_string_lossy(), entry))
+        .collect();
+
+    serde_json::json!({
+        "queued": queue.len(),
+        "permanently_failing": failing,
+    })
+}
+
+fn queue_path(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("retry_queue.json")
+}
+
+/// Loads the queue saved by the last run, if there is one
+pub fn load(backup_dir: &Path) -> Result<()> {
+    let path = queue_path(backup_dir);
+
+    let contents = match std::fs::read(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let saved: BTreeMap<PathBuf, Entry> = encoded_path::keys::from_json(&contents)
+        .with_context(|| anyhow!("Error parsing {}", path.display()))?;
+
+    QUEUE.lock().unwrap().extend(saved);
+
+    Ok(())
+}
+
+pub fn save(backup_dir: &Path) -> Result<()> {
+    let path = queue_path(backup_dir);
+    let temp_path = path.with_extension("json.tmp");
+    let contents = encoded_path::keys::to_json(&*QUEUE.lock().unwrap())?;
+
+    std::fs::create_dir_all(state_dir(backup_dir))?;
+    std::fs::write(&temp_path, contents)?;
+    std::fs::rename(&temp_path, &path)
+        .with_context(|| anyhow!("Error saving {}", path.display()))?;
+
+    Ok(())
+}
@@ -0,0 +1,140 @@
+//! Backing up some of work_dir somewhere other than backup_dir with `--route GLOB=DIR`, like
+//! design files onto a NAS and source code onto a local SSD. A file that matches a route's glob is
+//! copied to the same place below DIR as it would have gone below backup_dir, and the first route
+//! that matches wins. Everything else goes to backup_dir as usual
+//!
+//! backup_dir stays where the state lives, and the deletions and verification cover the routes'
+//! directories like they do backup_dir. A copy left in the wrong place after the routes changed is
+//! deleted once the file has its copy in the right one
+
+use anyhow::{anyhow, Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+use crate::overlap;
+
+/// A `--route GLOB=DIR`
+#[derive(Clone, Debug)]
+pub struct Route {
+    glob: String,
+    dir: PathBuf,
+}
+
+impl FromStr for Route {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.rsplit_once('=') {
+            Some((glob, dir)) if !glob.is_empty() && !dir.is_empty() => Ok(Self {
+                glob: glob.to_string(),
+                dir: PathBuf::from(dir),
+            }),
+            _ => Err(anyhow!("expected GLOB=DIR, got {s}")),
+        }
+    }
+}
+
+struct Routes {
+    backup_dir: PathBuf,
+    /// The glob of every route, matched against paths relative to work_dir, in the order given
+    routes: Vec<(Gitignore, PathBuf)>,
+}
+
+static ROUTES: OnceLock<Routes> = OnceLock::new();
+
+/// Copies the files matching each of `routes` to its directory rather than `backup_dir` from now
+/// on. Must be called before anything is copied, and followed by [`create_dirs`] once everything
+/// else checks out
+pub fn set(routes: Vec<Route>, work_dir: &Path, backup_dir: &Path) -> Result<()> {
+    if routes.is_empty() {
+        return Ok(());
+    }
+
+    let mut matchers = Vec::with_capacity(routes.len());
+    for Route { glob, dir } in routes {
+        let name = format!("the directory of --route {glob}");
+        for other in [("work_dir", work_dir), ("backup_dir", backup_dir)] {
+            overlap::check((&name, &dir), other)?;
+        }
+        let mut builder = GitignoreBuilder::new("");
+        builder
+            .add_line(None, &glob)
+            .with_context(|| anyhow!("Invalid route pattern {glob}"))?;
+        matchers.push((builder.build()?, dir));
+    }
+
+    ROUTES
+        .set(Routes {
+            backup_dir: backup_dir.to_path_buf(),
+            routes: matchers,
+        })
+        .map_err(|_| anyhow!("the routes can only be set once"))
+}
+
+/// Creates the directories of the routes that don't exist yet
+pub fn create_dirs() -> Result<()> {
+    for dir in all_dirs() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| anyhow!("Error creating {} for --route", dir.display()))?;
+    }
+
+    Ok(())
+}
+
+/// The directories of the routes, for keeping copies and deletions inside them
+pub fn all_dirs() -> Vec<&'static Path> {
+    ROUTES.get().map_or(Vec::new(), |routes| {
+        routes.routes.iter().map(|(_, dir)| dir.as_path()).collect()
+    })
+}
+
+/// Where `dir` in backup_dir is in every route's directory, nothing if it isn't in backup_dir
+pub fn dirs(dir: &Path) -> Vec<PathBuf> {
+    let Some(routes) = ROUTES.get() else {
+        return Vec::new();
+    };
+    let Ok(base) = dir.strip_prefix(&routes.backup_dir) else {
+        return Vec::new();
+    };
+
+    routes
+        .routes
+        .iter()
+        .map(|(_, route_dir)| route_dir.join(base))
+        .collect()
+}
+
+/// The directory the copy of `relative_path` below `to` goes into: `to` itself, unless `to` is in
+/// backup_dir and a route takes the path
+pub fn root_for(to: &Path, relative_path: &Path) -> PathBuf {
+    let Some(routes) = ROUTES.get() else {
+        return to.to_path_buf();
+    };
+    let Ok(base) = to.strip_prefix(&routes.backup_dir) else {
+        return to.to_path_buf();
+    };
+
+    let work_path = base.join(relative_path);
+    routes
+        .routes
+        .iter()
+        .find(|(matcher, _)| {
+            matcher
+                .matched_path_or_any_parents(&work_path, false)
+                .is_ignore()
+        })
+        .map_or(to.to_path_buf(), |(_, route_dir)| route_dir.join(base))
+}
+
+/// The directory `path` is below, out of `from` and, when `from` is in backup_dir, where it is in
+/// every route's directory
+pub fn root_of(from: &Path, path: &Path) -> PathBuf {
+    dirs(from)
+        .into_iter()
+        .find(|dir| path.starts_with(dir))
+        .unwrap_or_else(|| from.to_path_buf())
+}
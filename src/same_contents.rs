@@ -0,0 +1,82 @@
+//! Files found to have the same contents as their copy when `--mtime-tolerance` or `--dedup`, whose
+//! copies share the modify time of their blob, left the modify times unable to tell. The answer is kept along with the size and modify time of both, and saved
+//! in the state dir, so that neither is read again every pass or after a restart while both stay
+//! the same
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{encoded_path, state_dir, trace::Stamp};
+
+/// The stamps of each file and of its copy when they were found to match, keyed by the file's path
+/// relative to work_dir
+static SAME: Mutex<BTreeMap<PathBuf, (Stamp, Stamp)>> = Mutex::new(BTreeMap::new());
+/// Set when what's known changed since it was last saved
+static UNSAVED: AtomicBool = AtomicBool::new(false);
+
+fn same_path(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("same_contents.json")
+}
+
+/// Loads what earlier runs found
+pub fn load(backup_dir: &Path) -> Result<()> {
+    let path = same_path(backup_dir);
+    let contents = match std::fs::read(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let saved: BTreeMap<PathBuf, (Stamp, Stamp)> = encoded_path::keys::from_json(&contents)
+        .with_context(|| anyhow!("Error parsing {}", path.display()))?;
+    SAME.lock().unwrap().extend(saved);
+
+    Ok(())
+}
+
+/// Whether the file at `relative_path` was found to have the same contents as its copy while they
+/// looked like `work` and `backup`. What's known about it is forgotten once either changed
+pub fn is_same(relative_path: &Path, work: Stamp, backup: Stamp) -> bool {
+    let mut same = SAME.lock().unwrap();
+    match same.get(relative_path) {
+        Some(&stamps) if stamps == (work, backup) => true,
+        Some(_) => {
+            same.remove(relative_path);
+            UNSAVED.store(true, Ordering::Relaxed);
+            false
+        }
+        None => false,
+    }
+}
+
+/// Remembers that the file at `relative_path` has the same contents as its copy
+pub fn record(relative_path: &Path, work: Stamp, backup: Stamp) {
+    SAME.lock()
+        .unwrap()
+        .insert(relative_path.to_path_buf(), (work, backup));
+    UNSAVED.store(true, Ordering::Relaxed);
+}
+
+/// Saves what's known if it changed
+pub fn save(backup_dir: &Path) -> Result<()> {
+    if !UNSAVED.swap(false, Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let path = same_path(backup_dir);
+    let temp_path = path.with_extension("json.tmp");
+    let contents = encoded_path::keys::to_json(&*SAME.lock().unwrap())?;
+
+    std::fs::create_dir_all(state_dir(backup_dir))?;
+    std::fs::write(&temp_path, contents)?;
+    std::fs::rename(&temp_path, &path)
+        .with_context(|| anyhow!("Error saving {}", path.display()))?;
+
+    Ok(())
+}
@@ -0,0 +1,390 @@
+//! Backing up into a filesystem that takes fewer names than work_dir's, like from Linux onto an
+//! exFAT drive or an NTFS partition. A name it can't store is escaped instead of failing to copy:
+//!
+//! - `<>:"\|?*`, control characters and bytes that aren't UTF-8 become `%` and their two hex
+//!   digits, like `a:b` becoming `a%3Ab`, and so does the `%` of a name that already looks escaped
+//! - trailing dots and spaces are escaped the same way, and so is the first letter of a name
+//!   reserved by Windows, like `CON` or `com1.txt`
+//! - a name over NAME_MAX bytes is cut short, keeping its extension, and ends in `~` and part of
+//!   its hash instead
+//!
+//! Escaping on its own can be undone, but cutting a name short can't, so every escaped name is
+//! recorded in `names.json` in the state directory, keyed by the path of its copy. Restores,
+//! deletions and verification look the original names up there
+//!
+//! By default, names are only escaped on FAT, exFAT and NTFS, and on Windows, set with
+//! `--sanitize-names`
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    ffi::{OsStr, OsString},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+};
+
+use crate::{output::info, platform, state_dir};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SanitizeNames {
+    /// Where backup_dir is on a filesystem that takes fewer names
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// The longest name, in bytes, that every filesystem takes
+const NAME_MAX: usize = 255;
+/// How many hex digits of its hash a name that was cut short ends in
+const HASH_DIGITS: usize = 16;
+/// The longest extension a name that's cut short keeps
+const MAX_EXTENSION: usize = 32;
+
+/// Names Windows keeps for devices, whatever extension follows them
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+struct Sanitizer {
+    backup_dir: PathBuf,
+    /// The original name of every escaped one, escaped but not cut short, keyed by the path of its
+    /// copy relative to backup_dir
+    names: Mutex<BTreeMap<String, String>>,
+    /// Set when a name was escaped since the names were last saved
+    unsaved: AtomicBool,
+}
+
+static SANITIZER: OnceLock<Sanitizer> = OnceLock::new();
+
+fn names_path(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("names.json")
+}
+
+/// Escapes the names backup_dir can't store from now on, as `mode` says, loading the ones escaped
+/// by earlier runs. Must be called before anything is copied
+pub fn set(backup_dir: &Path, mode: SanitizeNames) -> Result<()> {
+    let fs_name = platform::windows_names_fs_name(backup_dir).unwrap_or(None);
+    match (mode, fs_name) {
+        (SanitizeNames::Never, _) | (SanitizeNames::Auto, None) => return Ok(()),
+        (SanitizeNames::Auto, Some(fs_name)) => {
+            info!("backup_dir is on {fs_name}, escaping the names it can't store")
+        }
+        (SanitizeNames::Always, _) => (),
+    }
+
+    let path = names_path(backup_dir);
+    let names = match std::fs::read(&path) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .with_context(|| anyhow!("Error parsing {}", path.display()))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(err) => return Err(err.into()),
+    };
+
+    SANITIZER
+        .set(Sanitizer {
+            backup_dir: backup_dir.to_path_buf(),
+            names: Mutex::new(names),
+            unsaved: AtomicBool::new(false),
+        })
+        .map_err(|_| anyhow!("name sanitizing can only be set once"))
+}
+
+/// `relative_path`, relative to `from`, as it's named relative to `to`. Only differs when one of
+/// them is in backup_dir
+pub fn translate(relative_path: &Path, from: &Path, to: &Path) -> PathBuf {
+    let Some(sanitizer) = SANITIZER.get() else {
+        return relative_path.to_path_buf();
+    };
+
+    match (
+        to.strip_prefix(&sanitizer.backup_dir),
+        from.strip_prefix(&sanitizer.backup_dir),
+    ) {
+        (Ok(base), _) => escape_below(sanitizer, base, relative_path),
+        (_, Ok(base)) => unescape_below(sanitizer, base, relative_path),
+        _ => relative_path.to_path_buf(),
+    }
+}
+
+/// The path of the copy of `relative_path`, relative to backup_dir
+pub fn to_backup(relative_path: &Path) -> PathBuf {
+    match SANITIZER.get() {
+        Some(sanitizer) => escape_below(sanitizer, Path::new(""), relative_path),
+        None => relative_path.to_path_buf(),
+    }
+}
+
+/// The original path, relative to work_dir, of `relative_path` in backup_dir
+pub fn to_work(relative_path: &Path) -> PathBuf {
+    match SANITIZER.get() {
+        Some(sanitizer) => unescape_below(sanitizer, Path::new(""), relative_path),
+        None => relative_path.to_path_buf(),
+    }
+}
+
+/// `relative_path` below the directory `base` of backup_dir as it's named there, recording the
+/// names escaped on the way
+fn escape_below(sanitizer: &Sanitizer, base: &Path, relative_path: &Path) -> PathBuf {
+    let mut backup_path = base.to_path_buf();
+    for component in relative_path.components() {
+        let Component::Normal(name) = component else {
+            backup_path.push(component);
+            continue;
+        };
+        let Some(escaped) = escape(name) else {
+            backup_path.push(name);
+            continue;
+        };
+
+        backup_path.push(&escaped);
+        let key = backup_path.to_string_lossy().into_owned();
+        if let Entry::Vacant(entry) = sanitizer.names.lock().unwrap().entry(key) {
+            entry.insert(escape_characters(name));
+            sanitizer.unsaved.store(true, Ordering::Relaxed);
+        }
+    }
+
+    backup_path
+        .strip_prefix(base)
+        .expect("the path starts at base")
+        .to_path_buf()
+}
+
+/// The original name of `relative_path` below the directory `base` of backup_dir
+fn unescape_below(sanitizer: &Sanitizer, base: &Path, relative_path: &Path) -> PathBuf {
+    let names = sanitizer.names.lock().unwrap();
+    let mut backup_path = base.to_path_buf();
+    let mut work_path = PathBuf::new();
+    for component in relative_path.components() {
+        backup_path.push(component);
+        let Component::Normal(name) = component else {
+            work_path.push(component);
+            continue;
+        };
+        match names.get(backup_path.to_string_lossy().as_ref()) {
+            Some(original) => work_path.push(unescape(original)),
+            // An escaped name from before the map was saved, which only lacks names cut short
+            None => work_path.push(unescape_name(name)),
+        }
+    }
+
+    work_path
+}
+
+fn unescape_name(name: &OsStr) -> OsString {
+    match name.to_str() {
+        Some(name) => unescape(name),
+        None => name.to_os_string(),
+    }
+}
+
+/// Saves the names escaped since the last call, once per pass rather than with every escape
+pub fn save() -> Result<()> {
+    let Some(sanitizer) = SANITIZER.get() else {
+        return Ok(());
+    };
+    if !sanitizer.unsaved.swap(false, Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let names = sanitizer.names.lock().unwrap();
+    let path = names_path(&sanitizer.backup_dir);
+    let temp_path = path.with_extension("json.tmp");
+    std::fs::create_dir_all(state_dir(&sanitizer.backup_dir))?;
+    std::fs::write(&temp_path, serde_json::to_vec(&*names)?)?;
+    std::fs::rename(&temp_path, &path).with_context(|| anyhow!("Error saving {}", path.display()))
+}
+
+/// `name` as a name every filesystem takes, None if it already is one
+fn escape(name: &OsStr) -> Option<String> {
+    let mut escaped = escape_characters(name);
+
+    // Windows drops trailing dots and spaces
+    let kept = escaped.trim_end_matches(['.', ' ']).len();
+    if kept < escaped.len() {
+        let trailing: String = escaped[kept..]
+            .bytes()
+            .map(|byte| format!("%{byte:02X}"))
+            .collect();
+        escaped.truncate(kept);
+        escaped.push_str(&trailing);
+    }
+
+    let stem = escaped.split('.').next().unwrap_or_default();
+    if RESERVED
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        escaped = format!("%{:02X}{}", escaped.as_bytes()[0], &escaped[1..]);
+    }
+
+    if escaped.len() > NAME_MAX {
+        let hash = blake3::hash(name.as_encoded_bytes()).to_hex();
+        let tag = format!("~{}", &hash[..HASH_DIGITS]);
+        let extension = escaped
+            .rfind('.')
+            .map(|dot| &escaped[dot..])
+            .filter(|extension| extension.len() <= MAX_EXTENSION)
+            .unwrap_or_default();
+        let mut kept = NAME_MAX - tag.len() - extension.len();
+        while !escaped.is_char_boundary(kept) {
+            kept -= 1;
+        }
+        escaped = format!("{}{tag}{extension}", &escaped[..kept]);
+    }
+
+    (escaped.as_bytes() != name.as_encoded_bytes()).then_some(escaped)
+}
+
+/// `name` with the characters and bytes that no filesystem should get escaped, and the `%` of
+/// whatever would look escaped already
+fn escape_characters(name: &OsStr) -> String {
+    let mut escaped = String::new();
+    for chunk in name.as_encoded_bytes().utf8_chunks() {
+        let valid = chunk.valid();
+        for (at, character) in valid.char_indices() {
+            let looks_escaped = || {
+                let digits = valid.as_bytes().get(at + 1..at + 3);
+                digits.is_some_and(|digits| digits.iter().all(u8::is_ascii_hexdigit))
+            };
+            match character {
+                '<' | '>' | ':' | '"' | '\\' | '|' | '?' | '*' => {
+                    escaped.push_str(&format!("%{:02X}", character as u8))
+                }
+                character if character.is_ascii_control() => {
+                    escaped.push_str(&format!("%{:02X}", character as u8))
+                }
+                '%' if looks_escaped() => escaped.push_str("%25"),
+                character => escaped.push(character),
+            }
+        }
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("%{byte:02X}"));
+        }
+    }
+
+    escaped
+}
+
+/// Undoes [`escape_characters`]
+fn unescape(escaped: &str) -> OsString {
+    let bytes = escaped.as_bytes();
+    let mut name = Vec::with_capacity(bytes.len());
+    let mut at = 0;
+    while at < bytes.len() {
+        let byte = bytes
+            .get(at + 1..at + 3)
+            .filter(|digits| bytes[at] == b'%' && digits.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+        match byte {
+            Some(byte) => {
+                name.push(byte);
+                at += 3;
+            }
+            None => {
+                name.push(bytes[at]);
+                at += 1;
+            }
+        }
+    }
+
+    os_string(name, escaped)
+}
+
+#[cfg(unix)]
+fn os_string(name: Vec<u8>, _escaped: &str) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+
+    OsString::from_vec(name)
+}
+
+/// Names elsewhere aren't just bytes, so ones that don't unescape to UTF-8 stay escaped
+#[cfg(not(unix))]
+fn os_string(name: Vec<u8>, escaped: &str) -> OsString {
+    match String::from_utf8(name) {
+        Ok(name) => name.into(),
+        Err(_) => escaped.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(name: &OsStr) -> OsString {
+        let escaped = escape(name).expect("the name needs escaping");
+        unescape(&escaped)
+    }
+
+    #[test]
+    fn names_that_need_no_escaping() {
+        for name in [
+            "plain",
+            "with space",
+            "dots.in.it",
+            "100%",
+            "%zz",
+            "CONSOLE",
+            "ünïcode",
+        ] {
+            assert_eq!(escape(OsStr::new(name)), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn escaped_names() {
+        let escaped = |name: &str| escape(OsStr::new(name)).unwrap();
+        assert_eq!(escaped("a:b"), "a%3Ab");
+        assert_eq!(escaped("what?"), "what%3F");
+        assert_eq!(escaped("trailing. "), "trailing%2E%20");
+        assert_eq!(escaped("CON"), "%43ON");
+        assert_eq!(escaped("com1.txt"), "%63om1.txt");
+        assert_eq!(escaped("a%3Ab"), "a%253Ab");
+    }
+
+    #[test]
+    fn escaping_round_trips() {
+        for name in [
+            "a:b",
+            "<>:\"\\|?*",
+            "tab\there",
+            "trailing. ",
+            "CON",
+            "nul.tar.gz",
+            "a%3Ab:",
+            "100%:",
+        ] {
+            assert_eq!(round_trip(OsStr::new(name)), OsStr::new(name), "{name}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bytes_that_arent_utf8_round_trip() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let name = OsStr::from_bytes(b"bad\xff:");
+        assert_eq!(escape(name).unwrap(), "bad%FF%3A");
+        assert_eq!(round_trip(name), name);
+    }
+
+    #[test]
+    fn long_names_are_cut_short() {
+        let name = format!("{}.txt", "a".repeat(300));
+        let escaped = escape(OsStr::new(&name)).unwrap();
+        assert!(escaped.len() <= NAME_MAX);
+        assert!(escaped.ends_with(".txt"));
+        assert!(escaped.contains('~'));
+
+        // Different names cut short to the same start stay apart
+        let other = format!("{}b.txt", "a".repeat(300));
+        assert_ne!(escape(OsStr::new(&other)).unwrap(), escaped);
+    }
+}
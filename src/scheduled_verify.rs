@@ -0,0 +1,80 @@
+//! Full verifications run in the background every `--verify-interval`, to catch silent corruption
+//! of the backup and anything the watch loop missed. They read one file per device at a time and
+//! wait for the machine to be idle, and every problem found is repaired by asking the watch loop
+//! for a full pass, which copies the missing and mismatched files and deletes the extra ones
+
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    full_pass, idle,
+    output::{self, info, Event},
+    pause, verify, SyncOptions, SHOULD_SHUTDOWN,
+};
+
+/// Verifies work_dir against backup_dir every `interval` until shutdown, repairing what doesn't
+/// match
+pub async fn watch(
+    work_dir: PathBuf,
+    backup_dir: PathBuf,
+    options: SyncOptions,
+    interval: Duration,
+    idle_wait: bool,
+) {
+    let mut next_at = Instant::now() + interval;
+
+    while !SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+        if Instant::now() < next_at || pause::is_paused() {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+        }
+
+        if idle_wait {
+            idle::wait_for_idle("verifying").await;
+        }
+        info!("Starting the scheduled verification...");
+        if let Err(err) = verify_and_repair(&work_dir, &backup_dir, &options).await {
+            output::emit(&Event::Error {
+                path: None,
+                message: format!("Scheduled verification failed: {err:#}"),
+            });
+        }
+        next_at = Instant::now() + interval;
+    }
+}
+
+async fn verify_and_repair(
+    work_dir: &Path,
+    backup_dir: &Path,
+    options: &SyncOptions,
+) -> anyhow::Result<()> {
+    let report = verify::verify(work_dir, backup_dir, &options.walk, 1, false, false).await?;
+    if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let repairs = full_pass::run(
+        report
+            .missing
+            .iter()
+            .chain(&report.mismatched)
+            .map(|relative_path| work_dir.join(relative_path)),
+    )
+    .await?;
+
+    info!(
+        "Scheduled verification done: {} files match, {} missing, {} extra, {} mismatched, {} errors. Repaired {} files, {} errors repairing",
+        report.matched,
+        report.missing.len(),
+        report.extra.len(),
+        report.mismatched.len(),
+        report.errors,
+        repairs.copied + repairs.deleted,
+        repairs.errors
+    );
+
+    Ok(())
+}
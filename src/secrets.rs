@@ -0,0 +1,73 @@
+//! Leaving obviously sensitive files out of the backup with `--exclude-secrets`, like private
+//! keys, browser cookie and password databases, and cryptocurrency wallets. Each one that's left
+//! out is warned about once, so it's clear what the backup doesn't have
+
+use anyhow::Result;
+use ignore::overrides::{Override, OverrideBuilder};
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::output::{self, Event};
+
+/// Gitignore style globs of files that look like secrets
+pub const PATTERNS: &[&str] = &[
+    // Private keys
+    "id_rsa",
+    "id_dsa",
+    "id_ecdsa",
+    "id_ed25519",
+    "*.pem",
+    "*.key",
+    "*.p12",
+    "*.pfx",
+    "*.jks",
+    "*.keystore",
+    "**/.gnupg/private-keys-v1.d",
+    "**/.gnupg/secring.gpg",
+    // Credentials
+    ".netrc",
+    ".pgpass",
+    "**/.aws/credentials",
+    "**/.docker/config.json",
+    // Browser cookies and saved passwords
+    "Cookies",
+    "Cookies-journal",
+    "cookies.sqlite",
+    "Login Data",
+    "logins.json",
+    "key4.db",
+    // Wallets
+    "wallet.dat",
+    "*.wallet",
+    "**/keystore/UTC--*",
+];
+
+/// Paths relative to the walk root that were already warned about, so that walking backup_dir
+/// doesn't warn about the same file again
+static EXCLUDED: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Matches [`PATTERNS`] below `root`
+pub fn matcher(root: &Path) -> Result<Override> {
+    let mut overrides = OverrideBuilder::new(root);
+    for pattern in PATTERNS {
+        overrides.add(pattern)?;
+    }
+
+    Ok(overrides.build()?)
+}
+
+/// Whether `path` looks like a secret, warning the first time it does
+pub fn is_secret(matcher: &Override, path: &Path, is_dir: bool) -> bool {
+    if !matcher.matched(path, is_dir).is_whitelist() {
+        return false;
+    }
+
+    let relative_path = path.strip_prefix(matcher.path()).unwrap_or(path);
+    if EXCLUDED.lock().unwrap().insert(relative_path.to_path_buf()) {
+        output::emit(&Event::Secret { path });
+    }
+    true
+}
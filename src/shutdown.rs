@@ -0,0 +1,104 @@
+//! Making a clean shutdown stick, and telling it apart from a crash on the next start. Once the
+//! watch loop saved its state, everything written to backup_dir is flushed to disk, and a marker
+//! is left in the state dir. The next start takes the marker away again, so a start without one
+//! follows a crash, a kill or a shutdown that timed out, and only trusts what it can check
+//! itself. A power cut right after a clean shutdown can't lose the state it depends on either
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use std::{
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{platform, state_dir};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LastRun {
+    /// Nothing ever ran on this backup_dir
+    First,
+    Clean,
+    /// Crashed, was killed, or timed out shutting down
+    Unclean,
+}
+
+static LAST_RUN: OnceLock<LastRun> = OnceLock::new();
+
+fn marker_path(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("clean_shutdown")
+}
+
+/// How the last run on `backup_dir` ended, if this process has looked yet
+pub fn last_run() -> Option<LastRun> {
+    LAST_RUN.get().copied()
+}
+
+/// Finds out how the last run on `backup_dir` ended, removing its marker so that this run has to
+/// leave one of its own
+pub fn take_marker(backup_dir: &Path) -> Result<LastRun> {
+    let path = marker_path(backup_dir);
+    let last_run = match std::fs::remove_file(&path) {
+        Ok(()) => LastRun::Clean,
+        // The status snapshot is written within the first cycle of every run
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            match state_dir(backup_dir).join("status.json").exists() {
+                true => LastRun::Unclean,
+                false => LastRun::First,
+            }
+        }
+        Err(err) => {
+            return Err(anyhow!(err).context(format!("Error removing {}", path.display())));
+        }
+    };
+    let _ = LAST_RUN.set(last_run);
+
+    Ok(last_run)
+}
+
+/// Leaves the marker of a clean shutdown, after flushing everything in backup_dir to disk first
+/// unless `fsync` is false
+pub fn mark_clean(backup_dir: &Path, fsync: bool) -> Result<()> {
+    if fsync {
+        flush(backup_dir)?;
+    }
+
+    let path = marker_path(backup_dir);
+    let at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs());
+    std::fs::write(&path, serde_json::to_vec(&serde_json::json!({ "at": at }))?)
+        .with_context(|| anyhow!("Error writing {}", path.display()))?;
+    if fsync {
+        File::open(&path)?.sync_all()?;
+        File::open(state_dir(backup_dir))?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Writes everything cached for the filesystem of backup_dir to disk, covering the copies as
+/// well as the state. Where that isn't possible only the state is flushed, file by file
+fn flush(backup_dir: &Path) -> Result<()> {
+    match platform::sync_filesystem(backup_dir) {
+        Ok(()) => return Ok(()),
+        Err(err) if platform::is_unsupported(&err) => (),
+        Err(err) => {
+            return Err(anyhow!(err).context(format!("Error flushing {}", backup_dir.display())))
+        }
+    }
+
+    let dir = state_dir(backup_dir);
+    for entry in std::fs::read_dir(&dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            File::open(entry.path())?.sync_all()?;
+        }
+    }
+    File::open(&dir)?.sync_all()?;
+
+    Ok(())
+}
@@ -0,0 +1,515 @@
+//! A signed manifest of backup_dir with `--sign-manifest KEY`, listing the size and blake3 hash of
+//! every file, so that `verify --manifest` can tell when the backup medium rotted or was tampered
+//! with, without needing work_dir. Signing is left to `ssh-keygen -Y sign` with an ed25519 key,
+//! which also makes the signature checkable with nothing but OpenSSH
+//!
+//! The manifest is kept up to date from the copies, moves and deletions of every cycle, hashing
+//! only what changed, and saved and signed again after each cycle that changed it. On startup,
+//! files whose size or modify time no longer match it are counted as changed outside evil_mount
+//! and hashed again
+
+use anyhow::{anyhow, Context, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    encoded_path, hash_file, hashing,
+    output::{self, info, Event},
+    profiles, recursive_dir, state_dir,
+    verify::VerifyReport,
+    WalkOptions,
+};
+
+/// Keeps signatures of manifests from being mistaken for signatures of anything else
+const NAMESPACE: &str = "evil_mount-manifest";
+/// The identity the trusted key is given when checking a signature
+const IDENTITY: &str = "evil_mount";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    size: u64,
+    /// Nanoseconds since the epoch
+    modified: u128,
+    hash: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    /// When it was signed, in seconds since the epoch
+    signed_at: u64,
+    /// Every file, keyed by its path relative to backup_dir
+    #[serde(with = "encoded_path::keys")]
+    files: BTreeMap<PathBuf, Entry>,
+}
+
+struct Signer {
+    backup_dir: PathBuf,
+    key: PathBuf,
+}
+
+static SIGNER: OnceLock<Signer> = OnceLock::new();
+static FILES: Mutex<BTreeMap<PathBuf, Entry>> = Mutex::new(BTreeMap::new());
+/// Files that changed since they were last hashed, relative to backup_dir
+static PENDING: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+/// Set when the manifest changed since it was last signed
+static UNSAVED: AtomicBool = AtomicBool::new(false);
+
+fn manifest_path(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("manifest.json")
+}
+
+fn signature_path(manifest_path: &Path) -> PathBuf {
+    let mut path = manifest_path.as_os_str().to_owned();
+    path.push(".sig");
+    path.into()
+}
+
+/// Keeps a manifest of `backup_dir` signed with the ed25519 SSH private key at `key`, and finds
+/// the files that changed since it was last signed
+pub fn set(backup_dir: &Path, key: PathBuf, walk: &WalkOptions) -> Result<()> {
+    // Signing something up front catches a missing ssh-keygen, a wrong key or one with a passphrase
+    let probe = state_dir(backup_dir).join("manifest.probe");
+    std::fs::create_dir_all(state_dir(backup_dir))?;
+    std::fs::write(&probe, b"")?;
+    let signed = sign(&key, &probe);
+    let _ = std::fs::remove_file(&probe);
+    let _ = std::fs::remove_file(signature_path(&probe));
+    signed.context(
+        "--sign-manifest needs a working ssh-keygen and an ed25519 key without a passphrase",
+    )?;
+
+    let path = manifest_path(backup_dir);
+    let mut files = match std::fs::read(&path) {
+        Ok(contents) => {
+            serde_json::from_slice::<Manifest>(&contents)
+                .with_context(|| anyhow!("Error parsing {}", path.display()))?
+                .files
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(err) => return Err(err.into()),
+    };
+    let had_manifest = !files.is_empty();
+
+    let mut pending = BTreeSet::new();
+    let mut existing = BTreeSet::new();
+    let mut changed = 0;
+    for entry in recursive_dir(backup_dir, walk) {
+        if !entry
+            .file_type()
+            .is_some_and(|file_type| file_type.is_file())
+        {
+            continue;
+        }
+        let Ok(relative_path) = entry.path().strip_prefix(backup_dir) else {
+            continue;
+        };
+        let relative_path = relative_path.to_path_buf();
+        let (size, modified) = entry
+            .metadata()
+            .ok()
+            .and_then(|metadata| Some((metadata.len(), modified_nanos(&metadata)?)))
+            .unwrap_or_default();
+        match files.get(&relative_path) {
+            Some(entry) if entry.size == size && entry.modified == modified => (),
+            Some(_) => {
+                changed += 1;
+                pending.insert(relative_path.clone());
+            }
+            None => {
+                pending.insert(relative_path.clone());
+            }
+        }
+        existing.insert(relative_path);
+    }
+    let count = files.len();
+    files.retain(|relative_path, _| existing.contains(relative_path));
+    let missing = count - files.len();
+
+    if had_manifest && (changed > 0 || missing > 0) {
+        info!(
+            "{changed} files in {} changed and {missing} went missing since the manifest was last signed, signing them as they are now",
+            backup_dir.display()
+        );
+    }
+    UNSAVED.store(!pending.is_empty() || missing > 0, Ordering::Relaxed);
+    *FILES.lock().unwrap() = files;
+    *PENDING.lock().unwrap() = pending;
+
+    SIGNER
+        .set(Signer {
+            backup_dir: backup_dir.to_path_buf(),
+            key,
+        })
+        .map_err(|_| anyhow!("the manifest key can only be set once"))
+}
+
+fn modified_nanos(metadata: &std::fs::Metadata) -> Option<u128> {
+    Some(
+        metadata
+            .modified()
+            .ok()?
+            .duration_since(UNIX_EPOCH)
+            .ok()?
+            .as_nanos(),
+    )
+}
+
+/// Notices the files that changed in backup_dir. Called for every emitted event
+pub fn observe(event: &Event) {
+    let Some(signer) = SIGNER.get() else {
+        return;
+    };
+    let relative = |path: &Path| Some(path.strip_prefix(&signer.backup_dir).ok()?.to_path_buf());
+
+    match event {
+        Event::FileCopied { destination, .. } => {
+            if let Some(path) = relative(destination) {
+                PENDING.lock().unwrap().insert(path);
+                UNSAVED.store(true, Ordering::Relaxed);
+            }
+        }
+        Event::FileDeleted { path } => {
+            if let Some(path) = relative(path) {
+                remove(&path);
+                UNSAVED.store(true, Ordering::Relaxed);
+            }
+        }
+        Event::FileMoved { from, to } => {
+            if let (Some(from), Some(to)) = (relative(from), relative(to)) {
+                // Directories are moved as a whole, along with everything in them
+                let moved_path = |path: &Path| match path.strip_prefix(&from) {
+                    Ok(rest) if !rest.as_os_str().is_empty() => to.join(rest),
+                    _ => to.clone(),
+                };
+                let (files, pending) = remove(&from);
+                FILES.lock().unwrap().extend(
+                    files
+                        .into_iter()
+                        .map(|(path, entry)| (moved_path(&path), entry)),
+                );
+                PENDING
+                    .lock()
+                    .unwrap()
+                    .extend(pending.iter().map(|path| moved_path(path)));
+                UNSAVED.store(true, Ordering::Relaxed);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Drops `path` and everything under it from the manifest, returning the entries and the files
+/// waiting to be hashed that were dropped
+fn remove(path: &Path) -> (Vec<(PathBuf, Entry)>, Vec<PathBuf>) {
+    let mut pending = PENDING.lock().unwrap();
+    let removed_pending: Vec<PathBuf> = pending
+        .iter()
+        .filter(|pending| pending.starts_with(path))
+        .cloned()
+        .collect();
+    for removed in &removed_pending {
+        pending.remove(removed);
+    }
+
+    let mut files = FILES.lock().unwrap();
+    let removed_files: Vec<PathBuf> = files
+        .keys()
+        .filter(|file| file.starts_with(path))
+        .cloned()
+        .collect();
+    let removed_files = removed_files
+        .into_iter()
+        .filter_map(|file| Some((file.clone(), files.remove(&file)?)))
+        .collect();
+
+    (removed_files, removed_pending)
+}
+
+/// Hashes whatever changed, then saves and signs the manifest if it changed. Does nothing without
+/// `--sign-manifest`
+pub async fn save() -> Result<()> {
+    let Some(signer) = SIGNER.get() else {
+        return Ok(());
+    };
+    if !UNSAVED.swap(false, Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let result = tokio::task::spawn_blocking(|| save_blocking(signer)).await?;
+    if result.is_err() {
+        UNSAVED.store(true, Ordering::Relaxed);
+    }
+
+    result
+}
+
+fn save_blocking(signer: &Signer) -> Result<()> {
+    let pending = std::mem::take(&mut *PENDING.lock().unwrap());
+    let hashed: Vec<_> = pending
+        .into_par_iter()
+        .map(|relative_path| {
+            let entry = hash_entry(&signer.backup_dir.join(&relative_path));
+            (relative_path, entry)
+        })
+        .collect();
+
+    let manifest = {
+        let mut files = FILES.lock().unwrap();
+        for (relative_path, entry) in hashed {
+            match entry {
+                Ok(Some(entry)) => {
+                    files.insert(relative_path, entry);
+                }
+                // Gone again
+                Ok(None) => {
+                    files.remove(&relative_path);
+                }
+                Err(err) => {
+                    output::emit(&Event::Error {
+                        path: Some(&signer.backup_dir.join(&relative_path)),
+                        message: format!("Error hashing it for the manifest: {err:#}"),
+                    });
+                    PENDING.lock().unwrap().insert(relative_path);
+                }
+            }
+        }
+
+        Manifest {
+            signed_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_secs()),
+            files: files.clone(),
+        }
+    };
+
+    let path = manifest_path(&signer.backup_dir);
+    let temp_path = path.with_extension("json.tmp");
+    std::fs::write(&temp_path, serde_json::to_vec(&manifest)?)?;
+    sign(&signer.key, &temp_path)?;
+    std::fs::rename(signature_path(&temp_path), signature_path(&path))?;
+    std::fs::rename(&temp_path, &path)
+        .with_context(|| anyhow!("Error saving {}", path.display()))?;
+
+    Ok(())
+}
+
+/// The manifest entry for the file at `path`, or None when there's no regular file there
+fn hash_entry(path: &Path) -> Result<Option<Entry>> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+
+    Ok(Some(Entry {
+        size: metadata.len(),
+        modified: modified_nanos(&metadata).unwrap_or_default(),
+        hash: hash_file(path)?.to_hex().to_string(),
+    }))
+}
+
+/// Signs the file at `path` into `path.sig` with `ssh-keygen -Y sign`
+fn sign(key: &Path, path: &Path) -> Result<()> {
+    let _ = std::fs::remove_file(signature_path(path));
+    let output = Command::new("ssh-keygen")
+        .args(["-Y", "sign", "-n", NAMESPACE, "-f"])
+        .arg(key)
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .context("Error running ssh-keygen")?;
+
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(anyhow!(
+            "ssh-keygen {}: {}",
+            profiles::describe(output.status),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
+
+/// Checks the signature of the manifest of `backup_dir`, against `trusted_key` when given, and
+/// then every file in backup_dir against the manifest
+pub async fn verify(
+    backup_dir: &Path,
+    trusted_key: Option<&Path>,
+    walk: &WalkOptions,
+) -> Result<VerifyReport> {
+    let path = manifest_path(backup_dir);
+    let contents = std::fs::read(&path).with_context(|| {
+        anyhow!(
+            "Error reading {}, was backup_dir synced with --sign-manifest?",
+            path.display()
+        )
+    })?;
+    check_signature(&path, &contents, trusted_key)?;
+    let manifest: Manifest = serde_json::from_slice(&contents)
+        .with_context(|| anyhow!("Error parsing {}", path.display()))?;
+    info!(
+        "The signature of the manifest of {} files signed at {} checks out",
+        manifest.files.len(),
+        manifest.signed_at
+    );
+
+    let mut paths = Vec::new();
+    for entry in recursive_dir(backup_dir, walk) {
+        if entry
+            .file_type()
+            .is_some_and(|file_type| file_type.is_file())
+        {
+            paths.push(entry.into_path());
+        }
+    }
+    let backup_dir_owned = backup_dir.to_path_buf();
+    let hashed = tokio::task::spawn_blocking(move || {
+        paths
+            .into_par_iter()
+            .map(|path| {
+                let hash = hashing::hash_fresh(&path).map(|hash| hash.to_hex().to_string());
+                let relative_path = path
+                    .strip_prefix(&backup_dir_owned)
+                    .map(Path::to_path_buf)
+                    .unwrap_or_default();
+                (relative_path, hash)
+            })
+            .collect::<Vec<_>>()
+    })
+    .await?;
+
+    let mut report = VerifyReport {
+        matched: 0,
+        missing: Vec::new(),
+        extra: Vec::new(),
+        mismatched: Vec::new(),
+        errors: 0,
+    };
+    let mut seen = BTreeSet::new();
+    for (relative_path, hash) in hashed {
+        let full_path = backup_dir.join(&relative_path);
+        match (hash, manifest.files.get(&relative_path)) {
+            (Err(err), _) => {
+                report.errors += 1;
+                output::emit(&Event::Error {
+                    path: Some(&full_path),
+                    message: format!("Error hashing it: {err:#}"),
+                });
+            }
+            (Ok(_), None) => {
+                output::emit(&Event::VerifyProblem {
+                    path: &full_path,
+                    problem: "extra",
+                });
+                report.extra.push(full_path);
+            }
+            (Ok(hash), Some(entry)) => match hash == entry.hash {
+                true => report.matched += 1,
+                false => {
+                    output::emit(&Event::VerifyProblem {
+                        path: &full_path,
+                        problem: "mismatched",
+                    });
+                    report.mismatched.push(full_path);
+                }
+            },
+        }
+        seen.insert(relative_path);
+    }
+    for relative_path in manifest.files.keys() {
+        if !seen.contains(relative_path) {
+            let full_path = backup_dir.join(relative_path);
+            output::emit(&Event::VerifyProblem {
+                path: &full_path,
+                problem: "missing",
+            });
+            report.missing.push(full_path);
+        }
+    }
+
+    output::emit(&Event::VerifyComplete {
+        matched: report.matched,
+        missing: report.missing.len() as u64,
+        extra: report.extra.len() as u64,
+        mismatched: report.mismatched.len() as u64,
+        errors: report.errors,
+    });
+
+    Ok(report)
+}
+
+/// Checks that the signature of the manifest at `path` is valid, and made by `trusted_key` when
+/// given. Without one, anyone could have signed it
+fn check_signature(path: &Path, contents: &[u8], trusted_key: Option<&Path>) -> Result<()> {
+    // ssh-keygen only takes trusted keys from a file of allowed signers
+    let allowed_signers = trusted_key
+        .map(|trusted_key| {
+            let key = std::fs::read_to_string(trusted_key)
+                .with_context(|| anyhow!("Error reading {}", trusted_key.display()))?;
+            let allowed_signers = std::env::temp_dir()
+                .join(format!("evil_mount-allowed-signers-{}", std::process::id()));
+            std::fs::write(
+                &allowed_signers,
+                format!("{IDENTITY} namespaces=\"{NAMESPACE}\" {}\n", key.trim()),
+            )?;
+            anyhow::Ok(allowed_signers)
+        })
+        .transpose()?;
+
+    let mut command = Command::new("ssh-keygen");
+    match &allowed_signers {
+        Some(allowed_signers) => command
+            .args(["-Y", "verify", "-I", IDENTITY, "-f"])
+            .arg(allowed_signers),
+        None => {
+            info!("Checking the signature without --trusted-key, which catches rot but not a manifest signed again by someone else");
+            command.args(["-Y", "check-novalidate"])
+        }
+    };
+
+    let mut child = command
+        .args(["-n", NAMESPACE, "-s"])
+        .arg(signature_path(path))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Error running ssh-keygen")?;
+    let written = child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(contents);
+    let output = child.wait_with_output();
+    if let Some(allowed_signers) = &allowed_signers {
+        let _ = std::fs::remove_file(allowed_signers);
+    }
+    let output = output.context("Error running ssh-keygen")?;
+
+    match output.status.success() {
+        true => Ok(written?),
+        false => {
+            // ssh-keygen explains some failures on stdout and others on stderr
+            let mut explanation = String::from_utf8_lossy(&output.stderr).into_owned();
+            explanation += &String::from_utf8_lossy(&output.stdout);
+            Err(anyhow!(
+                "The signature of {} doesn't check out, the manifest may have been tampered with: {}",
+                path.display(),
+                explanation.trim()
+            ))
+        }
+    }
+}
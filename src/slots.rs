@@ -0,0 +1,57 @@
+//! A limit on how many files are copied at once with `--max-concurrent-copies`, which can be
+//! shared between processes. Each slot is a file in a directory that's locked while a copy holds
+//! it, so every profile started by `--profiles` draws from the same slots and one that's idle
+//! leaves its share to the busy ones. A process that dies gives its slots back with its locks
+
+use anyhow::{anyhow, Context, Result};
+use std::{fs::File, path::PathBuf, sync::OnceLock, time::Duration};
+
+use crate::platform;
+
+struct Slots {
+    dir: PathBuf,
+    count: usize,
+}
+
+static SLOTS: OnceLock<Slots> = OnceLock::new();
+
+/// Held while copying a file
+pub struct Slot {
+    _file: File,
+}
+
+/// Limits copies to `count` at once, across every process using the slots in `dir`
+pub fn set(dir: PathBuf, count: usize) -> Result<()> {
+    if count == 0 {
+        return Err(anyhow!("--max-concurrent-copies must be more than 0"));
+    }
+    std::fs::create_dir_all(&dir).with_context(|| anyhow!("Error creating {}", dir.display()))?;
+
+    SLOTS
+        .set(Slots { dir, count })
+        .map_err(|_| anyhow!("the copy slots can only be set once"))
+}
+
+/// Waits for a free slot, or returns none right away without a limit
+pub async fn acquire() -> Result<Option<Slot>> {
+    let Some(slots) = SLOTS.get() else {
+        return Ok(None);
+    };
+
+    loop {
+        for index in 0..slots.count {
+            let path = slots.dir.join(format!("slot-{index}"));
+            let file = File::options()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .with_context(|| anyhow!("Error opening {}", path.display()))?;
+            if platform::try_lock(&file)? {
+                return Ok(Some(Slot { _file: file }));
+            }
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
@@ -0,0 +1,187 @@
+//! Named copies of backup_dir as it was at one point, for `restore --at` and `dr-test --at` to go
+//! back to once a sync has copied a mistake over the backup. `snapshot` takes one as a tree of hard
+//! links to the files in backup_dir, so it only takes up space for the files changed since. A sync
+//! replaces a copy by renaming a new file over it rather than writing into it, which leaves the
+//! snapshot's link with the old contents. Files that can't be linked, like on filesystems without
+//! hard links or ones made immutable by `--lock-backup`, are copied instead
+//!
+//! Snapshots are kept in the state dir as `snapshots/NAME`, which syncs leave alone. Removing one is
+//! removing its directory
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{copy, platform, special, state_dir, stats, STATE_DIR_NAME};
+
+/// How a snapshot was taken
+#[derive(Debug, Default, Serialize)]
+pub struct Taken {
+    pub name: String,
+    /// Files that are hard links to the ones in backup_dir
+    pub linked: u64,
+    /// Files that had to be copied
+    pub copied: u64,
+}
+
+fn snapshots_dir(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("snapshots")
+}
+
+/// The directory holding the contents of the snapshot `name` of backup_dir
+pub fn dir(backup_dir: &Path, name: &str) -> Result<PathBuf> {
+    check_name(name)?;
+
+    let dir = snapshots_dir(backup_dir).join(name);
+    match (dir.is_dir(), list(backup_dir).join(", ")) {
+        (true, _) => Ok(dir),
+        (false, names) if names.is_empty() => Err(anyhow!(
+            "No snapshot named {name} in {}, and no others either. Take one with `snapshot`",
+            backup_dir.display()
+        )),
+        (false, names) => Err(anyhow!(
+            "No snapshot named {name} in {}, the snapshots are {names}",
+            backup_dir.display()
+        )),
+    }
+}
+
+/// The names of the snapshots of backup_dir, in order. Ones named after the time they were taken
+/// come oldest first
+pub fn list(backup_dir: &Path) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(snapshots_dir(backup_dir)) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| !name.starts_with('.'))
+        .collect();
+    names.sort();
+
+    names
+}
+
+fn check_name(name: &str) -> Result<()> {
+    match !name.starts_with('.') && Path::new(name).file_name() == Some(name.as_ref()) {
+        true => Ok(()),
+        false => Err(anyhow!("Invalid snapshot name {name}")),
+    }
+}
+
+/// A name for a snapshot taken at `time`, like `2026-10-14T173500Z`
+fn name_at(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    let secs_of_day = secs % (24 * 60 * 60);
+
+    format!(
+        "{}T{:02}{:02}{:02}Z",
+        stats::date(secs / (24 * 60 * 60)),
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// Snapshots what backup_dir holds now as `name`, or after the current time. It's taken into a
+/// hidden directory and only renamed into place once it's complete, so a snapshot that's listed
+/// is never missing files
+pub async fn take(backup_dir: &Path, name: Option<&str>) -> Result<Taken> {
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => name_at(SystemTime::now()),
+    };
+    check_name(&name)?;
+    let snapshot_dir = snapshots_dir(backup_dir).join(&name);
+    if tokio::fs::try_exists(&snapshot_dir).await? {
+        return Err(anyhow!("There's already a snapshot named {name}"));
+    }
+
+    let partial_dir = snapshots_dir(backup_dir).join(format!(".{name}.partial"));
+    if tokio::fs::try_exists(&partial_dir).await? {
+        tokio::fs::remove_dir_all(&partial_dir).await?;
+    }
+    tokio::fs::create_dir_all(&partial_dir).await?;
+
+    let mut taken = Taken {
+        name,
+        ..Taken::default()
+    };
+    match link_tree(backup_dir, &partial_dir, &mut taken).await {
+        Ok(()) => tokio::fs::rename(&partial_dir, &snapshot_dir).await?,
+        Err(err) => {
+            let _ = tokio::fs::remove_dir_all(&partial_dir).await;
+            return Err(err);
+        }
+    }
+
+    Ok(taken)
+}
+
+/// Fills `to` with links to, or copies of, everything in backup_dir except the state dir and the
+/// temporary files of copies in flight
+async fn link_tree(backup_dir: &Path, to: &Path, taken: &mut Taken) -> Result<()> {
+    let mut dirs = vec![(backup_dir.to_path_buf(), to.to_path_buf())];
+    while let Some((from_dir, to_dir)) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&from_dir)
+            .await
+            .with_context(|| anyhow!("Error listing {}", from_dir.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            if (from_dir == backup_dir && name == STATE_DIR_NAME) || copy::is_temp(&name) {
+                continue;
+            }
+            let (from, to) = (entry.path(), to_dir.join(&name));
+
+            if entry.file_type().await?.is_dir() {
+                tokio::fs::create_dir(&to).await?;
+                dirs.push((from, to));
+                continue;
+            }
+            // Links don't follow symlinks, so every kind of file can be linked
+            match tokio::fs::hard_link(&from, &to).await {
+                Ok(()) => taken.linked += 1,
+                Err(_) => {
+                    let copied = match platform::special_kind(entry.file_type().await?) {
+                        Some(_) => special::recreate(&from, &to).await.map(|_| ()),
+                        None => copy::copy(&from, &to).await.map_err(anyhow::Error::from),
+                    };
+                    copied.with_context(|| anyhow!("Error snapshotting {}", from.display()))?;
+                    taken.copied += 1;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn names_after_the_time_sort_oldest_first() {
+        let earlier = name_at(UNIX_EPOCH + Duration::from_secs(1_791_999_304));
+        let later = name_at(UNIX_EPOCH + Duration::from_secs(1_792_000_000));
+
+        assert_eq!(earlier, "2026-10-14T173504Z");
+        assert!(earlier < later);
+    }
+
+    #[test]
+    fn names_have_to_stay_in_the_snapshots_dir() {
+        assert!(check_name("before-upgrade").is_ok());
+        assert!(check_name("../names.json").is_err());
+        assert!(check_name(".partial").is_err());
+        assert!(check_name("a/b").is_err());
+    }
+}
@@ -0,0 +1,109 @@
+//! Keeping copies from filling up the filesystem backup_dir is on. A copy only starts if it leaves
+//! at least `--min-free` free, and while there's less than that syncing pauses with an error,
+//! rather than copies failing halfway through with out of space errors. The startup reconciliation
+//! checks that everything it's about to copy fits before copying any of it
+
+use anyhow::{anyhow, Result};
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+
+use crate::{
+    output::{self, info, Event},
+    platform,
+};
+
+static BACKUP_DIR: OnceLock<PathBuf> = OnceLock::new();
+static MIN_FREE: AtomicU64 = AtomicU64::new(0);
+/// Set while backup_dir has less than --min-free free
+static LOW: AtomicBool = AtomicBool::new(false);
+
+/// `bytes` in the largest unit there's at least one of
+pub fn size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+/// Keeps at least `min_free` bytes free on the filesystem of `backup_dir`
+pub fn set(backup_dir: &Path, min_free: u64) {
+    MIN_FREE.store(min_free, Ordering::Relaxed);
+    BACKUP_DIR
+        .set(backup_dir.to_path_buf())
+        .expect("backup_dir's free space can only be watched once");
+}
+
+/// How much is free on backup_dir's filesystem, if that can be found out
+pub fn free() -> Option<u64> {
+    platform::free_space(BACKUP_DIR.get()?)
+}
+
+/// Whether syncing is paused because backup_dir is low on space
+pub fn is_low() -> bool {
+    LOW.load(Ordering::Relaxed)
+}
+
+/// Looks at the free space on backup_dir's filesystem, returning whether there's at least
+/// --min-free. Going under raises an error once, and coming back is logged
+pub fn check() -> bool {
+    let min_free = MIN_FREE.load(Ordering::Relaxed);
+    let Some(free) = free() else {
+        return true;
+    };
+
+    let low = free < min_free;
+    if LOW.swap(low, Ordering::Relaxed) != low {
+        match low {
+            true => output::emit(&Event::Error {
+                path: None,
+                message: format!(
+                    "backup_dir has only {} free, less than --min-free {}, so syncing is paused until there's more",
+                    size(free),
+                    size(min_free)
+                ),
+            }),
+            false => info!("backup_dir has {} free again, resuming syncing", size(free)),
+        }
+    }
+
+    !low
+}
+
+/// Fails unless copying `bytes` to `dst_path` would leave at least --min-free free. Anything
+/// outside backup_dir, like restores, is left alone
+pub fn ensure_room(dst_path: &Path, bytes: u64) -> Result<()> {
+    let Some(backup_dir) = BACKUP_DIR.get() else {
+        return Ok(());
+    };
+    if !dst_path.starts_with(backup_dir) {
+        return Ok(());
+    }
+    let Some(free) = platform::free_space(backup_dir) else {
+        return Ok(());
+    };
+
+    let min_free = MIN_FREE.load(Ordering::Relaxed);
+    match free.saturating_sub(bytes) >= min_free {
+        true => Ok(()),
+        false => {
+            check();
+            Err(anyhow!(
+                "Not enough free space in backup_dir, copying {} would leave less than --min-free {} of the {} free",
+                size(bytes),
+                size(min_free),
+                size(free)
+            ))
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! FIFOs, sockets and device files, like the socket a running dev server leaves in its project.
+//! Their contents can't be copied, so by default they're skipped with a warning, and
+//! `--special-files=recreate` makes an equivalent node in the backup instead
+
+use anyhow::Result;
+use clap::ValueEnum;
+use std::{
+    collections::BTreeSet,
+    fs::FileType,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use crate::{
+    containment,
+    output::{self, Event},
+    platform, read_only,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SpecialFiles {
+    /// Leave them out of the backup, warning about each once
+    #[default]
+    Skip,
+    /// Make a node of the same type, permissions and device number in the backup
+    Recreate,
+}
+
+static MODE: OnceLock<SpecialFiles> = OnceLock::new();
+
+/// Special files that were already warned about
+static SKIPPED: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Sets what happens to special files for the rest of the process
+pub fn set_mode(mode: SpecialFiles) {
+    MODE.set(mode)
+        .expect("the special files mode can only be set once");
+}
+
+fn mode() -> SpecialFiles {
+    MODE.get().copied().unwrap_or_default()
+}
+
+/// What kind of special file `file_type` is, if it's one at all
+pub fn kind(file_type: FileType) -> Option<&'static str> {
+    platform::special_kind(file_type)
+}
+
+/// Called by walks for every special file they come across, returning whether the pass that
+/// walked should sync it
+pub fn found(path: &Path, kind: &'static str) -> bool {
+    match mode() {
+        SpecialFiles::Skip => {
+            if SKIPPED.lock().unwrap().insert(path.to_path_buf()) {
+                output::emit(&Event::SpecialFile { path, kind });
+            }
+            false
+        }
+        SpecialFiles::Recreate => true,
+    }
+}
+
+/// Makes `to` a node of the same type, permissions and device number as `from`, returning whether
+/// anything had to change. Does nothing where there are no special files
+pub async fn recreate(from: &Path, to: &Path) -> Result<bool> {
+    let metadata = tokio::fs::symlink_metadata(from).await?;
+    let Some(mode) = platform::mode(&metadata) else {
+        return Ok(false);
+    };
+    containment::check(to)?;
+    if let Ok(existing) = tokio::fs::symlink_metadata(to).await {
+        if platform::mode(&existing) == Some(mode) {
+            return Ok(false);
+        }
+        read_only::unlock(to)?;
+        match existing.is_dir() {
+            true => tokio::fs::remove_dir_all(to).await?,
+            false => tokio::fs::remove_file(to).await?,
+        }
+    }
+    if let Some(parent) = to.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    platform::make_node(to, mode)?;
+    // mknod leaves out whatever the umask masks
+    tokio::fs::set_permissions(to, metadata.permissions()).await?;
+
+    Ok(true)
+}
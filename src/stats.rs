@@ -0,0 +1,503 @@
+//! Per-day totals of what evil_mount did, persisted in the state dir, so that `report` can show
+//! trends like a growing tree, rising error rates or shrinking free space before they become
+//! emergencies. Days are in UTC
+//!
+//! Which files were copied is kept apart, in `churn.json`, with the files that made up the most
+//! copied bytes each day, so that `report --churn` can point at the ones worth excluding, like
+//! browser profiles and VM images rewritten all day
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{output::Event, platform, state_dir};
+
+/// Days older than this are dropped from the stats file
+const KEEP_DAYS: u64 = 366;
+/// How many files churn.json keeps for each day, the ones with the most copied bytes
+const CHURN_FILES_PER_DAY: usize = 200;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DayStats {
+    pub cycles: u64,
+    pub copied_files: u64,
+    pub copied_bytes: u64,
+    pub moved: u64,
+    pub deleted: u64,
+    pub errors: u64,
+    pub verify_runs: u64,
+    /// Files found missing, extra or mismatched by verification
+    pub verify_problems: u64,
+    /// How many files work_dir held, as of the last full scan that day
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tree_files: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tree_bytes: Option<u64>,
+    /// Free space in backup_dir, as of the last time the stats were saved that day
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub free_bytes: Option<u64>,
+}
+
+impl DayStats {
+    /// Whether nothing worth saving right away happened. Cycles alone don't count, or the daemon
+    /// would rewrite the stats file after every idle cycle
+    fn is_empty(&self) -> bool {
+        self.copied_files == 0
+            && self.moved == 0
+            && self.deleted == 0
+            && self.errors == 0
+            && self.verify_runs == 0
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.cycles += other.cycles;
+        self.copied_files += other.copied_files;
+        self.copied_bytes += other.copied_bytes;
+        self.moved += other.moved;
+        self.deleted += other.deleted;
+        self.errors += other.errors;
+        self.verify_runs += other.verify_runs;
+        self.verify_problems += other.verify_problems;
+    }
+}
+
+/// The size of work_dir as seen by a full scan
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TreeSize {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl TreeSize {
+    pub fn count(&mut self, entry: &ignore::DirEntry) {
+        self.files += 1;
+        self.bytes += entry.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+    }
+}
+
+/// How often a file was copied, and how many bytes that came to
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct Churn {
+    pub copies: u64,
+    pub bytes: u64,
+}
+
+/// The copies since the stats were last saved, by the path they were copied from
+static UNSAVED_CHURN: Mutex<BTreeMap<String, Churn>> = Mutex::new(BTreeMap::new());
+
+/// What happened since the stats were last saved
+static UNSAVED: Mutex<DayStats> = Mutex::new(DayStats {
+    cycles: 0,
+    copied_files: 0,
+    copied_bytes: 0,
+    moved: 0,
+    deleted: 0,
+    errors: 0,
+    verify_runs: 0,
+    verify_problems: 0,
+    tree_files: None,
+    tree_bytes: None,
+    free_bytes: None,
+});
+
+/// Counts an event towards today's totals. Called for every emitted event
+pub fn observe(event: &Event) {
+    let mut unsaved = UNSAVED.lock().unwrap();
+
+    match event {
+        Event::FileCopied {
+            source,
+            destination,
+        } => {
+            let bytes = std::fs::metadata(destination)
+                .map(|metadata| metadata.len())
+                .unwrap_or(0);
+            unsaved.copied_files += 1;
+            unsaved.copied_bytes += bytes;
+
+            let mut churn = UNSAVED_CHURN.lock().unwrap();
+            let churn = churn
+                .entry(source.to_string_lossy().into_owned())
+                .or_default();
+            churn.copies += 1;
+            churn.bytes += bytes;
+        }
+        Event::FileMoved { .. } => unsaved.moved += 1,
+        Event::FileDeleted { .. } => unsaved.deleted += 1,
+        Event::Error { .. } => unsaved.errors += 1,
+        Event::CycleComplete { .. } => unsaved.cycles += 1,
+        Event::VerifyComplete {
+            missing,
+            extra,
+            mismatched,
+            ..
+        } => {
+            unsaved.verify_runs += 1;
+            unsaved.verify_problems += missing + extra + mismatched;
+        }
+        Event::VerifyProblem { .. }
+        | Event::Unreadable { .. }
+        | Event::TooLarge { .. }
+        | Event::SpecialFile { .. }
+        | Event::Secret { .. }
+        | Event::OtherSyncTool { .. }
+        | Event::RestoreVerified { .. } => {}
+    }
+}
+
+/// Whether anything was counted since the stats were last saved
+pub fn has_unsaved() -> bool {
+    !UNSAVED.lock().unwrap().is_empty()
+}
+
+fn stats_path(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("stats.json")
+}
+
+fn churn_path(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("churn.json")
+}
+
+fn load_churn(backup_dir: &Path) -> Result<BTreeMap<String, BTreeMap<String, Churn>>> {
+    let path = churn_path(backup_dir);
+
+    match std::fs::read(&path) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .with_context(|| anyhow!("Error parsing {}", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn write(backup_dir: &Path, path: &Path, contents: &impl Serialize) -> Result<()> {
+    let temp_path = path.with_extension("json.tmp");
+    std::fs::create_dir_all(state_dir(backup_dir))?;
+    std::fs::write(&temp_path, serde_json::to_vec(contents)?)?;
+    std::fs::rename(&temp_path, path).with_context(|| anyhow!("Error saving {}", path.display()))
+}
+
+pub fn load(backup_dir: &Path) -> Result<BTreeMap<String, DayStats>> {
+    let path = stats_path(backup_dir);
+
+    match std::fs::read(&path) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .with_context(|| anyhow!("Error parsing {}", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Adds everything counted since the last save to today's totals in backup_dir, along with the
+/// size of work_dir if a full scan just measured it
+pub fn save(backup_dir: &Path, tree_size: Option<TreeSize>) -> Result<()> {
+    let mut days = load(backup_dir)?;
+    let unsaved = std::mem::take(&mut *UNSAVED.lock().unwrap());
+
+    let today = today();
+    let day = days.entry(date(today)).or_default();
+    day.add(&unsaved);
+    if let Some(tree_size) = tree_size {
+        day.tree_files = Some(tree_size.files);
+        day.tree_bytes = Some(tree_size.bytes);
+    }
+    if let Some(free_bytes) = platform::free_space(backup_dir) {
+        day.free_bytes = Some(free_bytes);
+    }
+
+    let oldest = date(today.saturating_sub(KEEP_DAYS));
+    days.retain(|date, _| *date >= oldest);
+    write(backup_dir, &stats_path(backup_dir), &days)?;
+
+    let unsaved_churn = std::mem::take(&mut *UNSAVED_CHURN.lock().unwrap());
+    if !unsaved_churn.is_empty() {
+        let mut churn = load_churn(backup_dir)?;
+        let day = churn.entry(date(today)).or_default();
+        for (path, unsaved) in unsaved_churn {
+            let file = day.entry(path).or_default();
+            file.copies += unsaved.copies;
+            file.bytes += unsaved.bytes;
+        }
+        if day.len() > CHURN_FILES_PER_DAY {
+            let mut files: Vec<_> = std::mem::take(day).into_iter().collect();
+            files.sort_by_key(|(_, churn)| std::cmp::Reverse(churn.bytes));
+            files.truncate(CHURN_FILES_PER_DAY);
+            *day = files.into_iter().collect();
+        }
+        churn.retain(|date, _| *date >= oldest);
+        write(backup_dir, &churn_path(backup_dir), &churn)?;
+    }
+
+    Ok(())
+}
+
+/// Days since the epoch
+pub fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() / (24 * 60 * 60))
+        .unwrap_or(0)
+}
+
+/// Formats days since the epoch as YYYY-MM-DD
+pub fn date(days: u64) -> String {
+    // Howard Hinnant's civil_from_days, shifted so that years start in March
+    let days = days as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = match shifted_month < 10 {
+        true => shifted_month + 3,
+        false => shifted_month - 9,
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// The inverse of [`date`]
+fn day_number(date: &str) -> Option<i64> {
+    let mut parts = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (
+        parts.next()?.ok()?,
+        parts.next()?.ok()?,
+        parts.next()?.ok()?,
+    );
+
+    // Howard Hinnant's days_from_civil
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = (month + 9) % 12;
+    let day_of_year = (153 * shifted_month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    Some(era * 146_097 + day_of_era - 719_468)
+}
+
+/// When backup_dir is expected to be full, going by how fast its free space shrank
+#[derive(Debug, Serialize)]
+pub struct Forecast {
+    /// Free space as of the latest sample, counting the quota if there is one
+    pub free_bytes: u64,
+    /// How much free space is lost per day on average. Negative if it's growing
+    pub bytes_per_day: f64,
+    /// How many days are left until backup_dir is full, if free space is shrinking at all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub days_left: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub full_on: Option<String>,
+}
+
+/// Fits a line through the daily free space samples. With a `quota`, the space used by the tree
+/// counts against it as well, whichever runs out first
+pub fn forecast(days: &[(String, DayStats)], quota: Option<u64>) -> Option<Forecast> {
+    let samples = days
+        .iter()
+        .filter_map(|(date, day)| {
+            let quota_free = match (quota, day.tree_bytes) {
+                (Some(quota), Some(tree_bytes)) => Some(quota.saturating_sub(tree_bytes)),
+                _ => None,
+            };
+            let free = match (day.free_bytes, quota_free) {
+                (Some(free), Some(quota_free)) => free.min(quota_free),
+                (free, quota_free) => free.or(quota_free)?,
+            };
+
+            Some((day_number(date)? as f64, free as f64))
+        })
+        .collect::<Vec<_>>();
+    if samples.len() < 2 {
+        return None;
+    }
+
+    let count = samples.len() as f64;
+    let mean_day = samples.iter().map(|(day, _)| day).sum::<f64>() / count;
+    let mean_free = samples.iter().map(|(_, free)| free).sum::<f64>() / count;
+    let covariance: f64 = samples
+        .iter()
+        .map(|(day, free)| (day - mean_day) * (free - mean_free))
+        .sum();
+    let variance: f64 = samples
+        .iter()
+        .map(|(day, _)| (day - mean_day).powi(2))
+        .sum();
+    let bytes_per_day = -covariance / variance;
+
+    let (last_day, free_bytes) = *samples.last()?;
+    let free_bytes = free_bytes as u64;
+    let days_left = (bytes_per_day > 0.0).then(|| free_bytes as f64 / bytes_per_day);
+
+    Some(Forecast {
+        free_bytes,
+        bytes_per_day,
+        days_left,
+        full_on: days_left.map(|days_left| date((last_day + days_left) as u64)),
+    })
+}
+
+/// The stats of the last `days` days, oldest first
+pub fn last_days(backup_dir: &Path, days: u64) -> Result<Vec<(String, DayStats)>> {
+    let oldest = date(today().saturating_sub(days.saturating_sub(1)));
+
+    Ok(load(backup_dir)?
+        .into_iter()
+        .filter(|(date, _)| *date >= oldest)
+        .collect())
+}
+
+/// The `count` files copied the most bytes of over the last `days` days, most first
+pub fn most_churned(backup_dir: &Path, days: u64, count: usize) -> Result<Vec<(String, Churn)>> {
+    let oldest = date(today().saturating_sub(days.saturating_sub(1)));
+
+    let mut totals: BTreeMap<String, Churn> = BTreeMap::new();
+    for (_, files) in load_churn(backup_dir)?
+        .into_iter()
+        .filter(|(date, _)| *date >= oldest)
+    {
+        for (path, churn) in files {
+            let total = totals.entry(path).or_default();
+            total.copies += churn.copies;
+            total.bytes += churn.bytes;
+        }
+    }
+
+    let mut files: Vec<_> = totals.into_iter().collect();
+    files.sort_by_key(|(_, churn)| std::cmp::Reverse(churn.bytes));
+    files.truncate(count);
+    Ok(files)
+}
+
+/// A table of the files from [`most_churned`] and how much of the copied bytes they make up
+pub fn render_churn(files: &[(String, Churn)], days: &[(String, DayStats)]) -> String {
+    const MIB: f64 = 1024.0 * 1024.0;
+
+    let copied_bytes: u64 = days.iter().map(|(_, day)| day.copied_bytes).sum();
+    let mut report = format!(
+        "\nMost copied files:\n{:>7} {:>10} {:>6}  {}\n",
+        "copies", "MiB", "share", "file"
+    );
+    for (path, churn) in files {
+        let share = match copied_bytes {
+            0 => "-".to_string(),
+            _ => format!("{:.0}%", churn.bytes as f64 * 100.0 / copied_bytes as f64),
+        };
+        report += &format!(
+            "{:>7} {:>10.1} {:>6}  {path}\n",
+            churn.copies,
+            churn.bytes as f64 / MIB,
+            share
+        );
+    }
+    if files.is_empty() {
+        report += "No files were copied in this period\n";
+    }
+
+    report
+}
+
+/// A table of the given days, followed by how the tree, errors and free space changed over them
+/// and when backup_dir will be full at this rate
+pub fn render_report(days: &[(String, DayStats)], forecast: Option<&Forecast>) -> String {
+    const MIB: f64 = 1024.0 * 1024.0;
+    const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+    let optional = |value: Option<u64>, scale: f64| match value {
+        Some(value) => format!("{:.1}", value as f64 / scale),
+        None => "-".to_string(),
+    };
+
+    let mut report = format!(
+        "{:<10} {:>7} {:>8} {:>10} {:>6} {:>7} {:>6} {:>8} {:>9} {:>9} {:>9}\n",
+        "date",
+        "cycles",
+        "copied",
+        "MiB",
+        "moved",
+        "deleted",
+        "errors",
+        "files",
+        "tree GiB",
+        "free GiB",
+        "verify"
+    );
+    for (date, day) in days {
+        let verify = match day.verify_runs {
+            0 => "-".to_string(),
+            _ => format!("{} bad", day.verify_problems),
+        };
+        report += &format!(
+            "{:<10} {:>7} {:>8} {:>10.1} {:>6} {:>7} {:>6} {:>8} {:>9} {:>9} {:>9}\n",
+            date,
+            day.cycles,
+            day.copied_files,
+            day.copied_bytes as f64 / MIB,
+            day.moved,
+            day.deleted,
+            day.errors,
+            day.tree_files
+                .map_or_else(|| "-".to_string(), |files| files.to_string()),
+            optional(day.tree_bytes, GIB),
+            optional(day.free_bytes, GIB),
+            verify,
+        );
+    }
+
+    let first_and_last = |field: fn(&DayStats) -> Option<u64>| {
+        let mut samples = days.iter().filter_map(|(_, day)| field(day));
+        let first = samples.next()?;
+        Some((first, samples.next_back().unwrap_or(first)))
+    };
+
+    report += "\n";
+    if let Some((first, last)) = first_and_last(|day| day.tree_bytes) {
+        report += &format!(
+            "Tree grew by {:.2} GiB ({:.1} to {:.1} GiB)\n",
+            (last as f64 - first as f64) / GIB,
+            first as f64 / GIB,
+            last as f64 / GIB
+        );
+    }
+    if let Some((first, last)) = first_and_last(|day| day.free_bytes) {
+        report += &format!(
+            "Free space in backup_dir went from {:.1} to {:.1} GiB\n",
+            first as f64 / GIB,
+            last as f64 / GIB
+        );
+    }
+
+    let half = days.len() / 2;
+    let errors = |days: &[(String, DayStats)]| days.iter().map(|(_, day)| day.errors).sum::<u64>();
+    let (earlier, later) = (errors(&days[..half]), errors(&days[half..]));
+    report += &format!("{} errors in total", earlier + later);
+    if half > 0 && later > earlier {
+        report += &format!(", rising from {earlier} in the first half of the period to {later}");
+    }
+    report += "\n";
+
+    match forecast {
+        Some(Forecast {
+            days_left: Some(days_left),
+            full_on: Some(full_on),
+            bytes_per_day,
+            ..
+        }) => {
+            report += &format!(
+                "Losing {:.2} GiB of free space a day, backup_dir will be full in about {days_left:.0} days ({full_on})\n",
+                bytes_per_day / GIB
+            )
+        }
+        Some(_) => report += "Free space isn't shrinking\n",
+        None => (),
+    }
+
+    report
+}
@@ -0,0 +1,294 @@
+//! What a running sync is doing, for `status`. The daemon answers through its control socket,
+//! and also writes the same snapshot to the state dir every so often, so that `status` has
+//! something to show when there's no socket or the daemon isn't running
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    deletion, dr_test, health, limits, metadata_only, output::Event, pause, presence, protect,
+    retry, shutdown, space, state_dir, stats, unreadable, SyncOptions, TRACKED_FILES,
+};
+
+/// How many of the latest errors are kept around
+const RECENT_ERRORS: usize = 10;
+/// How many days of stats the capacity forecast is based on
+const FORECAST_DAYS: u64 = 30;
+
+/// How long the watch loop's last cycle took, in milliseconds
+pub static LAST_CYCLE_MILLIS: AtomicU64 = AtomicU64::new(0);
+/// How many new files the watch loop couldn't copy yet
+pub static PENDING_COPIES: AtomicU64 = AtomicU64::new(0);
+
+/// The latest errors, oldest first, along with when they happened in seconds since the epoch
+static ERRORS: Mutex<VecDeque<(u64, String)>> = Mutex::new(VecDeque::new());
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+/// Keeps the latest errors. Called for every emitted event
+pub fn observe(event: &Event) {
+    if let Event::Error { path, message } = event {
+        let message = match path {
+            Some(path) => format!("{}: {message}", path.display()),
+            None => message.clone(),
+        };
+
+        let mut errors = ERRORS.lock().unwrap();
+        if errors.len() == RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back((now_secs(), message));
+    }
+}
+
+/// Everything `status` shows about this process
+pub fn snapshot(
+    work_dir: &Path,
+    backup_dir: &Path,
+    options: &SyncOptions,
+) -> Result<serde_json::Value> {
+    let mode = match (health::READY.load(Ordering::Relaxed), pause::is_paused()) {
+        (false, _) => "starting",
+        (true, true) if presence::is_gone() => "work_dir missing",
+        (true, true) if space::is_low() => "low on space",
+        (true, true) => "paused",
+        (true, false) if limits::exceeded() => "over limits",
+        (true, false) => "watching",
+    };
+
+    Ok(serde_json::json!({
+        "mode": mode,
+        "as_of": now_secs(),
+        "work_dir": work_dir.to_string_lossy(),
+        "backup_dir": backup_dir.to_string_lossy(),
+        "paused": pause::is_paused(),
+        "tracked_files": TRACKED_FILES.load(Ordering::Relaxed),
+        "pending_copies": PENDING_COPIES.load(Ordering::Relaxed),
+        "pending_deletions": deletion::pending(),
+        "held_back_deletions": deletion::held_back(),
+        "held_back_protected": protect::held(),
+        "retry": retry::status(),
+        "last_cycle_at": health::last_cycle(),
+        "last_cycle_millis": LAST_CYCLE_MILLIS.load(Ordering::Relaxed),
+        "last_shutdown": shutdown::last_run(),
+        "health": health::status(),
+        "recent_errors": ERRORS
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(time, message)| serde_json::json!({ "time": time, "message": message }))
+            .collect::<Vec<_>>(),
+        "unreadable": unreadable::list()
+            .iter()
+            .map(|path| path.to_string_lossy())
+            .collect::<Vec<_>>(),
+        "metadata_only": {
+            "files": metadata_only::totals().0,
+            "bytes": metadata_only::totals().1,
+        },
+        "last_dr_test": dr_test::last(backup_dir),
+        "forecast": stats::forecast(
+            &stats::last_days(backup_dir, FORECAST_DAYS)?,
+            options.quota,
+        ),
+    }))
+}
+
+fn status_path(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("status.json")
+}
+
+/// Writes the snapshot for `status` to read when it can't ask the daemon. `mode` overrides the
+/// mode, for marking the snapshot written on shutdown as stopped
+pub fn save(
+    work_dir: &Path,
+    backup_dir: &Path,
+    options: &SyncOptions,
+    mode: Option<&str>,
+) -> Result<()> {
+    let mut snapshot = snapshot(work_dir, backup_dir, options)?;
+    if let Some(mode) = mode {
+        snapshot["mode"] = mode.into();
+    }
+
+    let path = status_path(backup_dir);
+    let temp_path = path.with_extension("json.tmp");
+    std::fs::create_dir_all(state_dir(backup_dir))?;
+    std::fs::write(&temp_path, serde_json::to_vec(&snapshot)?)?;
+    std::fs::rename(&temp_path, &path)
+        .with_context(|| anyhow!("Error saving {}", path.display()))?;
+
+    Ok(())
+}
+
+pub fn load(backup_dir: &Path) -> Result<serde_json::Value> {
+    let path = status_path(backup_dir);
+    let contents = std::fs::read(&path).with_context(|| {
+        anyhow!(
+            "Error reading {}, has a sync ever run on this backup?",
+            path.display()
+        )
+    })?;
+
+    serde_json::from_slice(&contents).with_context(|| anyhow!("Error parsing {}", path.display()))
+}
+
+/// Lays a snapshot out as a table for humans
+pub fn render(snapshot: &serde_json::Value) -> String {
+    let now = now_secs();
+    let ago = |secs: u64| match now.saturating_sub(secs) {
+        secs if secs < 120 => format!("{secs}s ago"),
+        secs if secs < 2 * 60 * 60 => format!("{}m ago", secs / 60),
+        secs => format!("{}h ago", secs / (60 * 60)),
+    };
+    let u64_at = |pointer: &str| snapshot.pointer(pointer).and_then(|value| value.as_u64());
+    let len_at = |pointer: &str| {
+        snapshot.pointer(pointer).map_or(0, |value| match value {
+            serde_json::Value::Array(values) => values.len(),
+            serde_json::Value::Object(values) => values.len(),
+            _ => 0,
+        })
+    };
+
+    let mut rows = vec![
+        (
+            "Mode",
+            format!(
+                "{}{}",
+                snapshot["mode"].as_str().unwrap_or("unknown"),
+                u64_at("/as_of").map_or(String::new(), |as_of| format!(" (as of {})", ago(as_of)))
+            ),
+        ),
+        (
+            "Work dir",
+            snapshot["work_dir"].as_str().unwrap_or("-").to_string(),
+        ),
+        (
+            "Backup dir",
+            snapshot["backup_dir"].as_str().unwrap_or("-").to_string(),
+        ),
+        (
+            "Tracked files",
+            u64_at("/tracked_files").unwrap_or(0).to_string(),
+        ),
+        (
+            "Pending copies",
+            u64_at("/pending_copies").unwrap_or(0).to_string(),
+        ),
+        (
+            "Pending deletions",
+            match u64_at("/held_back_deletions").unwrap_or(0) {
+                0 => u64_at("/pending_deletions").unwrap_or(0).to_string(),
+                held_back => format!(
+                    "{}, {held_back} held back by --max-delete",
+                    u64_at("/pending_deletions").unwrap_or(0)
+                ),
+            },
+        ),
+        (
+            "Protected",
+            match snapshot["held_back_protected"].as_array().map(Vec::len) {
+                None | Some(0) => "nothing held back".to_string(),
+                Some(held) => format!("{held} changes waiting for `ctl confirm`"),
+            },
+        ),
+        (
+            "Retry queue",
+            format!(
+                "{} ({} permanently failing)",
+                u64_at("/retry/queued").unwrap_or(0),
+                len_at("/retry/permanently_failing")
+            ),
+        ),
+        (
+            "Last cycle",
+            match u64_at("/last_cycle_at") {
+                Some(last_cycle_at) => format!(
+                    "took {:.1}s, finished {}",
+                    u64_at("/last_cycle_millis").unwrap_or(0) as f64 / 1000.0,
+                    ago(last_cycle_at)
+                ),
+                None => "-".to_string(),
+            },
+        ),
+        (
+            "Last shutdown",
+            match snapshot["last_shutdown"].as_str() {
+                Some("first") => "none, this is the first run".to_string(),
+                Some(last_shutdown) => last_shutdown.to_string(),
+                None => "-".to_string(),
+            },
+        ),
+        ("Unreadable", len_at("/unreadable").to_string()),
+        (
+            "Metadata only",
+            format!(
+                "{} files, {} bytes",
+                u64_at("/metadata_only/files").unwrap_or(0),
+                u64_at("/metadata_only/bytes").unwrap_or(0)
+            ),
+        ),
+        (
+            "Last DR test",
+            match u64_at("/last_dr_test/time") {
+                Some(time) => format!(
+                    "{} {}, {} files restored",
+                    match u64_at("/last_dr_test/errors") == Some(0)
+                        && u64_at("/last_dr_test/failed") == Some(0)
+                    {
+                        true => "passed",
+                        false => "FAILED",
+                    },
+                    ago(time),
+                    u64_at("/last_dr_test/restored").unwrap_or(0)
+                ),
+                None => "never".to_string(),
+            },
+        ),
+    ];
+    if let Some(days_left) = snapshot
+        .pointer("/forecast/days_left")
+        .and_then(|days_left| days_left.as_f64())
+    {
+        rows.push((
+            "Full in",
+            format!(
+                "about {days_left:.0} days ({})",
+                snapshot["forecast"]["full_on"].as_str().unwrap_or("-")
+            ),
+        ));
+    }
+
+    let mut table: String = rows
+        .into_iter()
+        .map(|(name, value)| format!("{name:<18} {value}\n"))
+        .collect();
+
+    if let Some(errors) = snapshot["recent_errors"].as_array() {
+        if !errors.is_empty() {
+            table += "Recent errors\n";
+        }
+        for error in errors {
+            table += &format!(
+                "  {:>8}  {}\n",
+                ago(error["time"].as_u64().unwrap_or(0)),
+                error["message"].as_str().unwrap_or_default()
+            );
+        }
+    }
+
+    table
+}
@@ -0,0 +1,167 @@
+//! Reading the `.stignore` at the root of work_dir with `--stignore`, so that a folder Syncthing
+//! also syncs can keep one ignore file for both. Its patterns are turned into their gitignore
+//! equivalents:
+//!
+//! - `//` starts a comment, and `#include FILE` reads the patterns in FILE too
+//! - `!` includes what matches rather than ignoring it, `(?i)` matches regardless of case and
+//!   `(?d)`, which lets Syncthing delete what's in the way, does nothing here
+//! - the first pattern that matches decides, rather than the last
+//! - a pattern that doesn't start with `/` matches at any depth, even with a `/` in it
+//!
+//! The file is read again for every walk, so changes to it apply from the next cycle on
+
+use anyhow::{anyhow, Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use crate::output::{self, Event};
+
+pub const FILE_NAME: &str = ".stignore";
+/// How deep `#include`s can nest, so that files including each other don't loop forever
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+static PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Reads the `.stignore` of `work_dir` for every walk from now on
+pub fn set(work_dir: &Path) -> Result<()> {
+    PATH.set(work_dir.join(FILE_NAME))
+        .map_err(|_| anyhow!("the .stignore to read can only be set once"))
+}
+
+/// Matches the patterns in `.stignore` below `root`, None if there aren't any. A `.stignore` that
+/// can't be read is reported, and ignores nothing
+pub fn matcher(root: &Path) -> Option<Gitignore> {
+    let path = PATH.get()?;
+    let result = read(path, 0).and_then(|mut lines| {
+        // Gitignore lets the last match decide
+        lines.reverse();
+        let mut builder = GitignoreBuilder::new(root);
+        for line in &lines {
+            builder
+                .add_line(None, line)
+                .with_context(|| anyhow!("Invalid pattern {line}"))?;
+        }
+        Ok(builder.build()?)
+    });
+
+    match result {
+        Ok(matcher) => (!matcher.is_empty()).then_some(matcher),
+        Err(err) => {
+            output::emit(&Event::Error {
+                path: Some(path),
+                message: format!("Error reading the Syncthing ignore patterns: {err:#}"),
+            });
+            None
+        }
+    }
+}
+
+/// The patterns in the file at `path` and everything it includes, as gitignore lines
+fn read(path: &Path, depth: usize) -> Result<Vec<String>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if depth == 0 && err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Vec::new())
+        }
+        Err(err) => return Err(anyhow!("Error reading {}: {err}", path.display())),
+    };
+
+    let mut lines = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with("//") {
+            continue;
+        }
+        if let Some(included) = line.strip_prefix("#include ") {
+            if depth == MAX_INCLUDE_DEPTH {
+                return Err(anyhow!(
+                    "{} nests #include more than {MAX_INCLUDE_DEPTH} deep",
+                    path.display()
+                ));
+            }
+            let dir = path.parent().unwrap_or(Path::new(""));
+            lines.extend(read(&dir.join(included.trim()), depth + 1)?);
+            continue;
+        }
+        lines.push(translate(line));
+    }
+
+    Ok(lines)
+}
+
+/// The gitignore line that matches what the `.stignore` pattern `line` does
+fn translate(mut line: &str) -> String {
+    let mut include = false;
+    let mut case_insensitive = false;
+    loop {
+        if let Some(rest) = line.strip_prefix('!') {
+            include = true;
+            line = rest;
+        } else if let Some(rest) = line.strip_prefix("(?i)") {
+            case_insensitive = true;
+            line = rest;
+        } else if let Some(rest) = line.strip_prefix("(?d)") {
+            line = rest;
+        } else {
+            break;
+        }
+    }
+
+    let mut pattern = match case_insensitive {
+        true => either_case(line),
+        false => line.to_string(),
+    };
+    // Gitignore only matches patterns with a slash in the middle from the root
+    if !pattern.starts_with('/') && pattern.trim_end_matches('/').contains('/') {
+        pattern.insert_str(0, "**/");
+    }
+    // Which gitignore would otherwise take for a comment
+    if pattern.starts_with('#') {
+        pattern.insert(0, '\\');
+    }
+
+    match include {
+        true => format!("!{pattern}"),
+        false => pattern,
+    }
+}
+
+/// `pattern` with every letter outside a character class matching either case
+fn either_case(pattern: &str) -> String {
+    let mut converted = String::with_capacity(pattern.len() * 4);
+    let mut in_class = false;
+    let mut characters = pattern.chars();
+    while let Some(character) = characters.next() {
+        match character {
+            '\\' => {
+                converted.push(character);
+                if let Some(escaped) = characters.next() {
+                    converted.push(escaped);
+                }
+            }
+            '[' if !in_class => {
+                in_class = true;
+                converted.push(character);
+            }
+            ']' if in_class => {
+                in_class = false;
+                converted.push(character);
+            }
+            character if !in_class && character.is_alphabetic() => {
+                let (lower, upper) = (character.to_lowercase(), character.to_uppercase());
+                // Letters like ß that change length between cases are left as they are
+                match lower.len() == 1 && upper.len() == 1 && lower.to_string() != upper.to_string()
+                {
+                    true => converted.push_str(&format!("[{lower}{upper}]")),
+                    false => converted.push(character),
+                }
+            }
+            character => converted.push(character),
+        }
+    }
+
+    converted
+}
@@ -0,0 +1,118 @@
+//! Restarting the long running tasks of a sync when they die. A panic inside a spawned task only
+//! ends that task, so without this the daemon would keep running while silently no longer
+//! copying or deleting anything. A task that died gets started again after a wait that doubles
+//! every time it dies soon after starting
+
+use anyhow::{anyhow, Result};
+use std::{
+    any::Any,
+    future::Future,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    output::{self, Event},
+    SHOULD_SHUTDOWN,
+};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// Something that ran for this long before dying starts over with the shortest wait
+const HEALTHY_AFTER: Duration = Duration::from_secs(60);
+
+/// How long to wait before starting something that died again, doubling every time it dies soon
+/// after starting. Shared by the tasks here and the profiles of `--profiles`
+pub struct Backoff {
+    wait: Duration,
+    started: Instant,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self {
+            wait: INITIAL_BACKOFF,
+            started: Instant::now(),
+        }
+    }
+
+    /// Called every time it's started
+    pub fn started(&mut self) {
+        self.started = Instant::now();
+    }
+
+    /// How long to wait before starting it again, now that it died
+    pub fn next(&mut self) -> Duration {
+        if self.started.elapsed() >= HEALTHY_AFTER {
+            self.wait = INITIAL_BACKOFF;
+        }
+        let wait = self.wait;
+        self.wait = (wait * 2).min(MAX_BACKOFF);
+
+        wait
+    }
+}
+
+/// Sleeps for `wait`, or until `stopping` says to stop. Returns whether it slept the whole time
+pub async fn sleep_unless(wait: Duration, stopping: impl Fn() -> bool) -> bool {
+    let until = Instant::now() + wait;
+    while !stopping() {
+        let left = until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return true;
+        }
+        tokio::time::sleep(left.min(Duration::from_millis(100))).await;
+    }
+
+    false
+}
+
+/// Runs the task `start` returns until it finishes without an error, starting it again whenever
+/// it panics or fails. `name` is what the task is called in errors, like `copy loop`. Gives up
+/// once evil_mount is shutting down
+pub async fn supervise<F, Fut>(name: &'static str, mut start: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let mut backoff = Backoff::new();
+    loop {
+        backoff.started();
+        let err = match tokio::task::spawn(start()).await {
+            Ok(Ok(())) => return,
+            Ok(Err(err)) => format!("failed: {err:#}"),
+            Err(err) => match err.try_into_panic() {
+                Ok(panic) => match panic_message(&*panic) {
+                    Some(message) => format!("panicked: {message}"),
+                    None => "panicked".to_string(),
+                },
+                Err(err) => format!("was cancelled: {:#}", anyhow!(err)),
+            },
+        };
+
+        if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+            output::emit(&Event::Error {
+                path: None,
+                message: format!("The {name} {err}"),
+            });
+            return;
+        }
+        let wait = backoff.next();
+        output::emit(&Event::Error {
+            path: None,
+            message: format!("The {name} {err}, restarting it in {}s", wait.as_secs()),
+        });
+
+        if !sleep_unless(wait, || SHOULD_SHUTDOWN.load(Ordering::Relaxed)).await {
+            return;
+        }
+    }
+}
+
+/// What a panic was raised with, for the usual `panic!` and `unwrap` payloads
+pub fn panic_message(panic: &(dyn Any + Send)) -> Option<&str> {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+}
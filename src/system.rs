@@ -0,0 +1,236 @@
+//! Safeguards for `--system-backup`, where work_dir is the root of a whole operating system
+
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+
+use crate::platform;
+
+/// Paths, relative to work_dir, that never make sense to back up from a running system
+pub const DEFAULT_EXCLUDES: &[&str] = &[
+    "/proc",
+    "/sys",
+    "/dev",
+    "/run",
+    "/tmp",
+    "/lost+found",
+    "/swapfile",
+    "/swap.img",
+    "*.swap",
+];
+
+/// Refuses to back up a tree that lives on a kernel pseudo filesystem, and warns when the
+/// process can't read every file on the system
+pub fn check(work_dir: &Path) -> Result<()> {
+    if let Some(fs_name) = platform::pseudo_fs_name(work_dir)? {
+        return Err(anyhow!(
+            "{} is on a {fs_name} pseudo filesystem, refusing to back it up",
+            work_dir.display()
+        ));
+    }
+
+    // Where there's no root, there are no files only other users can read either
+    if platform::is_root() == Some(false) {
+        eprintln!(
+            "Warning: not running as root, files only readable by other users will fail to sync"
+        );
+    }
+
+    Ok(())
+}
+
+/// Where the captured system metadata lives inside backup_dir
+fn metadata_dir(backup_dir: &Path) -> PathBuf {
+    crate::state_dir(backup_dir).join("system")
+}
+
+/// Records the partition layout, fstab and bootloader of the system rooted at work_dir in
+/// backup_dir, so that `restore --system-plan` can describe how to rebuild the machine
+pub fn capture_metadata(work_dir: &Path, backup_dir: &Path) -> Result<()> {
+    let dir = metadata_dir(backup_dir);
+    std::fs::create_dir_all(dir.join("sfdisk"))?;
+
+    let lsblk = command_output(
+        "lsblk",
+        &[
+            "--paths",
+            "--output",
+            "NAME,SIZE,TYPE,FSTYPE,LABEL,UUID,PARTUUID,PARTTYPE,MOUNTPOINT",
+        ],
+    )?;
+    std::fs::write(dir.join("lsblk"), &lsblk)?;
+
+    // sfdisk dumps can be fed straight back into sfdisk, but reading them usually needs root
+    let disks = command_output(
+        "lsblk",
+        &[
+            "--paths",
+            "--nodeps",
+            "--noheadings",
+            "--output",
+            "NAME,TYPE",
+        ],
+    )?;
+    for disk in
+        disks.lines().filter_map(
+            |line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [name, "disk"] => Some(name),
+                _ => None,
+            },
+        )
+    {
+        match command_output("sfdisk", &["--dump", disk]) {
+            Ok(dump) => std::fs::write(
+                dir.join("sfdisk").join(disk.trim_start_matches("/dev/")),
+                dump,
+            )?,
+            Err(err) => eprintln!("Warning: couldn't dump the partition table of {disk}: {err}"),
+        }
+    }
+
+    match std::fs::read(work_dir.join("etc/fstab")) {
+        Ok(fstab) => std::fs::write(dir.join("fstab"), fstab)?,
+        Err(err) => eprintln!(
+            "Warning: couldn't read {}/etc/fstab: {err}",
+            work_dir.display()
+        ),
+    }
+
+    let firmware = match Path::new("/sys/firmware/efi").exists() {
+        true => "uefi",
+        false => "bios",
+    };
+    let loader = detect_bootloader(work_dir);
+    std::fs::write(
+        dir.join("bootloader"),
+        format!("firmware={firmware}\nloader={loader}\n"),
+    )?;
+
+    if firmware == "uefi" {
+        if let Ok(entries) = command_output("efibootmgr", &["--verbose"]) {
+            std::fs::write(dir.join("efibootmgr"), entries)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn detect_bootloader(work_dir: &Path) -> &'static str {
+    let exists = |path: &str| work_dir.join(path).exists();
+
+    if exists("boot/grub/grub.cfg") || exists("boot/grub2/grub.cfg") {
+        "grub"
+    } else if exists("boot/loader/loader.conf")
+        || exists("boot/efi/loader/loader.conf")
+        || exists("efi/loader/loader.conf")
+    {
+        "systemd-boot"
+    } else if exists("boot/limine.conf") || exists("boot/limine/limine.conf") {
+        "limine"
+    } else {
+        "unknown"
+    }
+}
+
+fn command_output(program: &str, args: &[&str]) -> Result<String> {
+    let output = std::process::Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| anyhow!("Error running {program}"))?;
+
+    match output.status.success() {
+        true => Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+        false => Err(anyhow!(
+            "{program} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
+
+/// Describes, step by step, how to rebuild the machine captured in backup_dir onto new disks
+pub fn restore_plan(backup_dir: &Path) -> Result<String> {
+    let dir = metadata_dir(backup_dir);
+    if !dir.is_dir() {
+        return Err(anyhow!(
+            "{} has no system metadata, was it created with --system-backup?",
+            backup_dir.display()
+        ));
+    }
+
+    let read = |name: &str| std::fs::read_to_string(dir.join(name)).ok();
+    let bootloader = read("bootloader").unwrap_or_default();
+    let bootloader_value = |key: &str| {
+        bootloader
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+            .unwrap_or("unknown")
+            .to_string()
+    };
+    let (firmware, loader) = (bootloader_value("firmware"), bootloader_value("loader"));
+
+    let mut plan = String::new();
+
+    plan.push_str("1. Partition the new disks to match the original layout:\n\n");
+    if let Some(lsblk) = read("lsblk") {
+        plan.extend(lsblk.lines().map(|line| format!("       {line}\n")));
+        plan.push('\n');
+    }
+    let mut dumps: Vec<_> = std::fs::read_dir(dir.join("sfdisk"))
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .collect()
+        })
+        .unwrap_or_default();
+    dumps.sort();
+    match dumps.is_empty() {
+        true => plan.push_str(
+            "   No partition table dumps were captured, recreate the partitions by hand\n",
+        ),
+        false => {
+            for dump in dumps {
+                let disk = dump.file_name().unwrap_or_default().to_string_lossy();
+                plan.push_str(&format!("   sfdisk /dev/{disk} < {}\n", dump.display()));
+            }
+        }
+    }
+
+    plan.push_str("\n2. Create the filesystems and mount them under /mnt as described by the original fstab:\n\n");
+    match read("fstab") {
+        Some(fstab) => plan.extend(
+            fstab
+                .lines()
+                .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+                .map(|line| format!("       {line}\n")),
+        ),
+        None => plan.push_str("       (no fstab was captured)\n"),
+    }
+
+    plan.push_str(&format!(
+        "\n3. Restore the files:\n\n   evil_mount --work-dir=/mnt --backup-dir={} restore\n",
+        backup_dir.display()
+    ));
+
+    plan.push_str(
+        "\n4. If the new filesystems have different UUIDs, update /mnt/etc/fstab to match\n",
+    );
+
+    plan.push_str(&format!(
+        "\n5. Reinstall the bootloader ({loader}, {firmware} firmware) from a chroot into /mnt:\n\n"
+    ));
+    plan.push_str(match (loader.as_str(), firmware.as_str()) {
+        ("grub", "uefi") => "   grub-install --target=x86_64-efi --efi-directory=/boot/efi\n   grub-mkconfig -o /boot/grub/grub.cfg\n",
+        ("grub", _) => "   grub-install /dev/[disk]\n   grub-mkconfig -o /boot/grub/grub.cfg\n",
+        ("systemd-boot", _) => "   bootctl install\n",
+        ("limine", _) => "   limine bios-install /dev/[disk] (or copy BOOTX64.EFI to the ESP on UEFI)\n",
+        _ => "   The bootloader couldn't be detected, install one by hand\n",
+    });
+    if read("efibootmgr").is_some() {
+        plan.push_str(&format!(
+            "\n   The original UEFI boot entries are listed in {}\n",
+            dir.join("efibootmgr").display()
+        ));
+    }
+
+    Ok(plan)
+}
@@ -0,0 +1,158 @@
+//! Running as a systemd service of `Type=notify`. systemd is told the sync is ready once the
+//! startup reconciliation is done, every cycle updates the status `systemctl status` shows, and
+//! with `WatchdogSec=` the watchdog is pinged for as long as syncing isn't stuck, so that systemd
+//! restarts a sync that hangs. A `.socket` unit can pass in the control socket too
+//!
+//! Outside of systemd none of the variables it sets are there, and all of this does nothing
+
+use std::{
+    ffi::OsString,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use crate::{
+    output::{self, Event},
+    watchdog,
+};
+
+/// The socket systemd listens for notifications on, from NOTIFY_SOCKET
+static SOCKET: OnceLock<Option<OsString>> = OnceLock::new();
+/// Set once sending a notification failed, which is only reported once
+static REPORTED: AtomicBool = AtomicBool::new(false);
+/// Set when the control socket came from systemd, which then keeps it around between runs
+pub static SOCKET_ACTIVATED: AtomicBool = AtomicBool::new(false);
+
+/// Starts talking to systemd if it started this process, pinging its watchdog from now on if it
+/// has one
+pub fn set() {
+    let socket = SOCKET.get_or_init(|| std::env::var_os("NOTIFY_SOCKET"));
+    if socket.is_none() {
+        return;
+    }
+    notify("STATUS=Reconciling work_dir with backup_dir");
+
+    let Some(every) = watchdog_interval() else {
+        return;
+    };
+    std::thread::spawn(move || loop {
+        std::thread::sleep(every);
+        // Once the watchdog finds the sync stuck, systemd is left to restart it
+        if !watchdog::is_stalled() {
+            notify("WATCHDOG=1");
+        }
+    });
+}
+
+/// How often to ping the watchdog, half of WatchdogSec= as systemd recommends. None without one,
+/// or if it's meant for another process
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// Tells systemd the startup reconciliation is done
+pub fn ready() {
+    notify("READY=1\nSTATUS=Watching work_dir for changes");
+}
+
+/// Tells systemd the sync is shutting down
+pub fn stopping() {
+    notify("STOPPING=1\nSTATUS=Shutting down");
+}
+
+/// Shows how the last cycle went in `systemctl status`. Called for every emitted event
+pub fn observe(event: &Event) {
+    if let Event::CycleComplete {
+        copied,
+        moved,
+        deleted,
+        errors,
+    } = event
+    {
+        notify(&format!(
+            "STATUS=Last cycle: copied {copied}, moved {moved}, deleted {deleted} files, {errors} errors"
+        ));
+    }
+}
+
+fn notify(state: &str) {
+    let Some(Some(socket)) = SOCKET.get() else {
+        return;
+    };
+
+    if let Err(err) = send(socket, state) {
+        if !REPORTED.swap(true, Ordering::Relaxed) {
+            output::emit(&Event::Error {
+                path: None,
+                message: format!(
+                    "Error notifying systemd through {}: {err}",
+                    socket.to_string_lossy()
+                ),
+            });
+        }
+    }
+}
+
+#[cfg(unix)]
+fn send(socket: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let datagram = UnixDatagram::unbound()?;
+    // A name starting with @ is in the abstract namespace
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::{linux::net::SocketAddrExt, unix::ffi::OsStrExt, unix::net::SocketAddr};
+
+        if let Some(name) = socket.as_bytes().strip_prefix(b"@") {
+            datagram.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)?;
+            return Ok(());
+        }
+    }
+    datagram.send_to(state.as_bytes(), socket)?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_socket: &std::ffi::OsStr, _state: &str) -> std::io::Result<()> {
+    Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+}
+
+/// The first socket systemd passed in with socket activation, if it did. The variables passing
+/// it are taken away, so that commands run from hooks don't mistake it for theirs
+#[cfg(all(unix, feature = "control-socket"))]
+pub fn take_listener() -> Option<std::os::unix::net::UnixListener> {
+    use std::os::fd::FromRawFd;
+
+    /// The first file descriptor systemd passes sockets in as
+    const LISTEN_FDS_START: i32 = 3;
+
+    let pid = std::env::var("LISTEN_PID").ok()?;
+    let fds = std::env::var("LISTEN_FDS").ok()?;
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    if pid.parse() != Ok(std::process::id()) || !fds.parse::<u32>().is_ok_and(|fds| fds > 0) {
+        return None;
+    }
+
+    SOCKET_ACTIVATED.store(true, Ordering::Relaxed);
+    // SAFETY: systemd hands over the descriptors from LISTEN_FDS_START on to this process, and
+    // nothing else in it takes them
+    unsafe {
+        libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC);
+        Some(std::os::unix::net::UnixListener::from_raw_fd(
+            LISTEN_FDS_START,
+        ))
+    }
+}
@@ -0,0 +1,288 @@
+//! A debug trace of what a sync saw and what it decided, written with `--trace FILE` as one JSON
+//! object per line, for bugs that only show up on somebody else's machine. Every decision is
+//! recorded along with everything it was based on, so `evil_mount replay FILE` can run the same
+//! logic against the trace again without touching any disk, and point out where it decides
+//! differently:
+//!
+//! - `compare`: the size and modify time of a file and of its copy, whether their contents differed
+//!   if that had to be looked at, and whether the file was copied
+//! - `change`: a new modify time the watch loop noticed for a file, which it synced
+//! - `missing`: a copy whose file is gone from work_dir, how long it has been gone for, and whether
+//!   it was deleted
+//! - `deletions`: how many of the copies in backup_dir a pass found to delete, and whether
+//!   `--max-delete` let it
+//!
+//! Filtered and metadata only files are compared through the hashes recorded for them instead,
+//! which aren't part of the trace
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, LineWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    deletion::{DeletePolicy, MaxDelete},
+    encoded_path,
+    output::{self, info, Event},
+};
+
+/// What a decision knows about a file without reading it
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stamp {
+    pub len: u64,
+    pub modified: SystemTime,
+}
+
+impl Stamp {
+    pub fn of(metadata: &std::fs::Metadata) -> std::io::Result<Self> {
+        Ok(Self {
+            len: metadata.len(),
+            modified: metadata.modified()?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum Record {
+    /// Tracing started
+    Start {
+        version: String,
+        #[serde(with = "encoded_path")]
+        work_dir: PathBuf,
+        #[serde(with = "encoded_path")]
+        backup_dir: PathBuf,
+    },
+    Compare {
+        #[serde(with = "encoded_path")]
+        path: PathBuf,
+        work: Stamp,
+        /// None if there's no copy yet
+        backup: Option<Stamp>,
+        /// --mtime-tolerance, in seconds
+        tolerance: u64,
+        contents_differ: Option<bool>,
+        copy: bool,
+    },
+    Change {
+        #[serde(with = "encoded_path")]
+        path: PathBuf,
+        /// The modify times, in seconds since the epoch
+        known: u64,
+        modified: u64,
+    },
+    Missing {
+        #[serde(with = "encoded_path")]
+        path: PathBuf,
+        policy: String,
+        /// In seconds
+        missing_for: u64,
+        delete: bool,
+    },
+    Deletions {
+        max_delete: String,
+        deletions: u64,
+        files: u64,
+        within: bool,
+    },
+}
+
+/// A line of the trace
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// When it was recorded, in seconds since the epoch
+    at: u64,
+    #[serde(flatten)]
+    record: Record,
+}
+
+static TRACE: OnceLock<Mutex<LineWriter<File>>> = OnceLock::new();
+/// Set once writing to the trace failed, which was reported and isn't tried again
+static BROKEN: AtomicBool = AtomicBool::new(false);
+
+/// Appends what's decided from now on to the trace at `path`
+pub fn set(path: &Path, work_dir: &Path, backup_dir: &Path) -> Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| anyhow!("Error opening the trace {}", path.display()))?;
+    TRACE
+        .set(Mutex::new(LineWriter::new(file)))
+        .map_err(|_| anyhow!("the trace can only be set once"))?;
+    info!("Tracing decisions to {}", path.display());
+
+    record(|| Record::Start {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        work_dir: work_dir.to_path_buf(),
+        backup_dir: backup_dir.to_path_buf(),
+    });
+
+    Ok(())
+}
+
+/// Adds the record made by `record` to the trace, if there is one
+pub fn record(record: impl FnOnce() -> Record) {
+    let Some(trace) = TRACE.get() else {
+        return;
+    };
+    if BROKEN.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let entry = Entry {
+        at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or(0),
+        record: record(),
+    };
+    let result = serde_json::to_vec(&entry)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut line| {
+            line.push(b'\n');
+            Ok(trace.lock().unwrap().write_all(&line)?)
+        });
+    if let Err(err) = result {
+        BROKEN.store(true, Ordering::Relaxed);
+        output::emit(&Event::Error {
+            path: None,
+            message: format!("Error writing to the trace, not tracing anymore: {err:#}"),
+        });
+    }
+}
+
+/// What replaying a trace found
+pub struct Replay {
+    pub decisions: u64,
+    pub diverged: u64,
+}
+
+/// Runs every decision in the trace at `path` again, reporting each one that comes out differently
+pub fn replay(path: &Path) -> Result<Replay> {
+    let file =
+        File::open(path).with_context(|| anyhow!("Error opening the trace {}", path.display()))?;
+    let mut replay = Replay {
+        decisions: 0,
+        diverged: 0,
+    };
+
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // The last line of a trace cut short by a crash can be incomplete
+        let entry: Entry = match serde_json::from_str(&line) {
+            Ok(entry) => entry,
+            Err(err) => {
+                output::emit(&Event::Error {
+                    path: Some(path),
+                    message: format!("Skipping line {} of the trace: {err}", number + 1),
+                });
+                continue;
+            }
+        };
+        let Some(result) = decide_again(&entry.record) else {
+            continue;
+        };
+
+        replay.decisions += 1;
+        if let Err(difference) = result {
+            replay.diverged += 1;
+            info!("Line {}: {difference}", number + 1);
+        }
+    }
+
+    Ok(replay)
+}
+
+/// Runs the decision in `record` again, failing with how it comes out differently. None if it
+/// isn't a decision
+fn decide_again(record: &Record) -> Option<Result<(), String>> {
+    let differs = |path: &Path, recorded: &str, replayed: &str| {
+        Err(format!(
+            "{} was {recorded}, but replaying it {replayed}",
+            path.display()
+        ))
+    };
+
+    let result = match record {
+        Record::Start { .. } => return None,
+        Record::Compare {
+            path,
+            work,
+            backup,
+            tolerance,
+            contents_differ,
+            copy,
+        } => {
+            let replayed = match (
+                crate::copy_by_metadata(*work, *backup, *tolerance),
+                contents_differ,
+            ) {
+                (Some(copy), _) => copy,
+                (None, Some(contents_differ)) => *contents_differ,
+                (None, None) => {
+                    return Some(Err(format!(
+                        "{} needs its contents compared to decide, but the trace has no record of that",
+                        path.display()
+                    )))
+                }
+            };
+            match (*copy, replayed) {
+                (true, false) => differs(path, "copied", "leaves it alone"),
+                (false, true) => differs(path, "left alone", "copies it"),
+                _ => Ok(()),
+            }
+        }
+        Record::Change {
+            path,
+            known,
+            modified,
+        } => match known == modified {
+            true => differs(path, "synced", "sees no change to sync"),
+            false => Ok(()),
+        },
+        Record::Missing {
+            path,
+            policy,
+            missing_for,
+            delete,
+        } => match policy.parse::<DeletePolicy>() {
+            Ok(policy) => match (*delete, policy.decide(*missing_for)) {
+                (true, false) => differs(path, "deleted", "keeps it"),
+                (false, true) => differs(path, "kept", "deletes it"),
+                _ => Ok(()),
+            },
+            Err(err) => Err(format!("Invalid delete policy {policy}: {err}")),
+        },
+        Record::Deletions {
+            max_delete,
+            deletions,
+            files,
+            within,
+        } => match max_delete.parse::<MaxDelete>() {
+            Ok(max_delete) => match (*within, max_delete.within(*deletions, *files)) {
+                (true, false) => Err(format!(
+                    "Deleting {deletions} of {files} files was within --max-delete {max_delete}, but replaying it isn't"
+                )),
+                (false, true) => Err(format!(
+                    "Deleting {deletions} of {files} files went over --max-delete {max_delete}, but replaying it doesn't"
+                )),
+                _ => Ok(()),
+            },
+            Err(err) => Err(format!("Invalid --max-delete {max_delete}: {err}")),
+        },
+    };
+
+    Some(result)
+}
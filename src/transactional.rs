@@ -0,0 +1,259 @@
+//! Directories given with `--transactional`, whose changes reach backup_dir all together or not
+//! at all within a cycle, for things saved as several files at once like a project or a photo and
+//! its sidecar. The changed files of such a directory are each copied to a temporary file next to
+//! where they go first, the way every copy is, and only once every one of them made it, and none
+//! of them was refused by `--protect` or anything else, are they renamed into place and the removed
+//! ones deleted. If any of them fails, the copies in backup_dir stay as they were and the whole
+//! directory is tried again next cycle
+//!
+//! Nothing renames several files at once, but the renames are written to the journal first, so a
+//! crash while moving them into place is finished by the next start. Files in a transactional
+//! directory are only copied by the cycle, not whenever they change on their own. The startup
+//! reconciliation and the repairs of the scheduled verification copy them one by one like any
+//! other file
+
+use anyhow::{anyhow, Result};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, OnceLock},
+};
+use tokio::fs;
+
+use crate::{
+    commit_copy, convert_backup_path_to_work_path, convert_work_path_to_backup_path,
+    deletion::DeletePolicy,
+    diff::{self, Change},
+    filters, journal, locks, metadata_only,
+    output::info,
+    relative_to, stage_copy, StagedCopy, SyncReport, SHOULD_SHUTDOWN,
+};
+
+/// The transactional directories, in work_dir
+static DIRS: OnceLock<Vec<PathBuf>> = OnceLock::new();
+
+/// Sets the transactional directories, given relative to work_dir or inside it
+pub fn set(work_dir: &Path, dirs: &[PathBuf]) -> Result<()> {
+    let dirs = dirs
+        .iter()
+        .map(|dir| Ok(work_dir.join(relative_to(dir, work_dir)?)))
+        .collect::<Result<Vec<_>>>()?;
+    DIRS.set(dirs)
+        .map_err(|_| anyhow!("the transactional directories can only be set once"))
+}
+
+/// The transactional directory `path` in work_dir is in, if any
+pub fn dir_of(path: &Path) -> Option<&'static Path> {
+    DIRS.get()?
+        .iter()
+        .find(|dir| path.starts_with(dir))
+        .map(PathBuf::as_path)
+}
+
+/// Whether `path` in work_dir is copied with the rest of its transactional directory. Filtered and
+/// metadata only files are never staged, since their copy isn't the file itself
+pub fn contains(path: &Path) -> bool {
+    dir_of(path).is_some() && filters::for_path(path).is_none() && !metadata_only::matches(path)
+}
+
+/// The changes to one transactional directory
+#[derive(Default)]
+struct Transaction {
+    /// Files in work_dir to copy
+    copies: Vec<PathBuf>,
+    /// Copies in backup_dir to delete
+    deletions: Vec<PathBuf>,
+}
+
+/// The changes of a cycle to the transactional directories
+pub struct Transactions {
+    work_dir: PathBuf,
+    backup_dir: PathBuf,
+    by_dir: BTreeMap<&'static Path, Transaction>,
+}
+
+/// What became of the copies of [`Transactions::apply`]
+#[derive(Default)]
+pub struct Outcome {
+    /// Copied into backup_dir
+    pub committed: Vec<PathBuf>,
+    /// Failed to copy, and the reason
+    pub failed: Vec<(PathBuf, anyhow::Error)>,
+    /// Left alone because another file of the same directory failed
+    pub held_back: Vec<PathBuf>,
+}
+
+impl Transactions {
+    pub fn new(work_dir: &Path, backup_dir: &Path) -> Self {
+        Self {
+            work_dir: work_dir.to_path_buf(),
+            backup_dir: backup_dir.to_path_buf(),
+            by_dir: BTreeMap::new(),
+        }
+    }
+
+    /// Takes `change` into the transaction of its directory, or gives it back if it isn't in a
+    /// transactional one
+    pub fn take(&mut self, change: Change) -> Option<Change> {
+        match &change {
+            Change::Create(path) | Change::Update(path) => match dir_of(path) {
+                Some(dir) if contains(path) => {
+                    self.by_dir
+                        .entry(dir)
+                        .or_default()
+                        .copies
+                        .push(path.clone());
+                    None
+                }
+                _ => Some(change),
+            },
+            Change::Delete(path) => {
+                let dir = convert_backup_path_to_work_path(
+                    path.clone(),
+                    self.work_dir.clone(),
+                    self.backup_dir.clone(),
+                )
+                .ok()
+                .and_then(|work_path| dir_of(&work_path));
+                match dir {
+                    Some(dir) => {
+                        self.by_dir
+                            .entry(dir)
+                            .or_default()
+                            .deletions
+                            .push(path.clone());
+                        None
+                    }
+                    None => Some(change),
+                }
+            }
+            Change::Rename { .. } => Some(change),
+        }
+    }
+
+    /// Applies the transaction of every directory, each one all together or not at all
+    pub async fn apply(self, policy: DeletePolicy, report: &mut SyncReport) -> Outcome {
+        let mut outcome = Outcome::default();
+        for (dir, transaction) in self.by_dir {
+            if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+                outcome.held_back.extend(transaction.copies);
+                continue;
+            }
+            apply_one(
+                dir,
+                transaction,
+                &self.work_dir,
+                &self.backup_dir,
+                policy,
+                report,
+                &mut outcome,
+            )
+            .await;
+        }
+
+        outcome
+    }
+}
+
+async fn apply_one(
+    dir: &Path,
+    transaction: Transaction,
+    work_dir: &Path,
+    backup_dir: &Path,
+    policy: DeletePolicy,
+    report: &mut SyncReport,
+    outcome: &mut Outcome,
+) {
+    // Held until the copies are in place, so that nothing else writes them in between
+    let mut guards = Vec::new();
+    let mut staged = Vec::new();
+    let mut failed = None;
+    for path in &transaction.copies {
+        if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+            break;
+        }
+        match stage(path, work_dir, backup_dir, &mut guards).await {
+            Ok(copy) => staged.push((path.clone(), copy)),
+            Err(err) => {
+                failed = Some((path.clone(), err));
+                break;
+            }
+        }
+    }
+
+    if failed.is_some() || staged.len() < transaction.copies.len() {
+        for (_, copy) in &staged {
+            if let Some(temp_path) = &copy.temp_path {
+                let _ = fs::remove_file(temp_path).await;
+            }
+        }
+        if let Some((path, err)) = failed {
+            info!(
+                "Left {} as it was in backup_dir, since {} failed to copy",
+                dir.display(),
+                path.display()
+            );
+            outcome.held_back.extend(
+                transaction
+                    .copies
+                    .into_iter()
+                    .filter(|other| *other != path),
+            );
+            outcome.failed.push((path, err));
+        } else {
+            outcome.held_back.extend(transaction.copies);
+        }
+        return;
+    }
+
+    let writing = diff::hold().await;
+    let files = staged
+        .iter()
+        .filter_map(|(path, copy)| {
+            Some(journal::Staged {
+                path: path.clone(),
+                staged_path: copy.temp_path.clone()?,
+                dst_path: copy.dst_path.clone(),
+            })
+        })
+        .collect();
+    let in_flight = journal::begin(journal::Operation::Commit { files });
+    for (path, copy) in staged {
+        match commit_copy(copy).await {
+            Ok(dst_path) => {
+                report.record_copy(&path, &dst_path);
+                outcome.committed.push(path);
+            }
+            Err(err) => outcome.failed.push((path, err)),
+        }
+    }
+    drop(in_flight);
+    drop(guards);
+    drop(writing);
+
+    for path in transaction.deletions {
+        let deletion = Change::Delete(path);
+        if let Err(err) = diff::apply(&deletion, work_dir, backup_dir, policy, report).await {
+            report.record_error(deletion.path(), err);
+        }
+    }
+}
+
+/// Copies `path` next to where its copy goes like any other copy, with every check that could
+/// refuse to replace the old one made now rather than halfway through the commit. The lock of
+/// where it goes is added to `guards`
+async fn stage(
+    path: &Path,
+    work_dir: &Path,
+    backup_dir: &Path,
+    guards: &mut Vec<locks::PathGuard>,
+) -> Result<StagedCopy> {
+    let dst_path = convert_work_path_to_backup_path(
+        path.to_path_buf(),
+        work_dir.to_path_buf(),
+        backup_dir.to_path_buf(),
+    )?;
+    guards.push(locks::lock(&dst_path).await);
+
+    stage_copy(path, dst_path).await
+}
@@ -0,0 +1,200 @@
+//! Parsing the durations and sizes flags take, the same way for all of them. A duration is a
+//! number with a unit, like `90s`, `1.5h` or `1h30m`, out of `ms`, `s`, `m`, `h`, `d` and `w`,
+//! and a plain number is seconds. A size is a number of bytes with an optional unit: `K`, `M`, `G`
+//! and `T`, with or without `iB` like `2TiB`, are powers of 1024, and `KB`, `MB`, `GB` and `TB`
+//! powers of 1000
+//!
+//! The parsers return a `String` error for clap to show next to the flag
+
+use std::time::Duration;
+
+/// Splits `text` into the leading number, which may have a fraction, and the rest
+fn split_number(text: &str) -> (&str, &str) {
+    let end = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    text.split_at(end)
+}
+
+/// Parses a number of digits with at most one `.` between them
+fn parse_number(number: &str) -> Option<f64> {
+    let valid = number.chars().all(|c| c.is_ascii_digit() || c == '.')
+        && number.matches('.').count() <= 1
+        && !number.starts_with('.')
+        && !number.ends_with('.');
+    match valid && !number.is_empty() {
+        true => number.parse().ok(),
+        false => None,
+    }
+}
+
+/// Parses a duration like `90s`, `1.5h`, `1h30m` or a plain number of seconds
+pub fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let invalid = || format!("expected a duration like 90s, 30m, 1.5h or 1h30m, got {duration}");
+
+    let trimmed = duration.trim();
+    if let Some(secs) = parse_number(trimmed) {
+        return Duration::try_from_secs_f64(secs).map_err(|_| invalid());
+    }
+
+    let mut total = 0.0;
+    let mut rest = trimmed;
+    while !rest.is_empty() {
+        let (number, after) = split_number(rest);
+        let number = parse_number(number).ok_or_else(invalid)?;
+        let unit_end = after
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(after.len());
+        let (unit, after) = after.split_at(unit_end);
+        let unit_secs = match unit.trim() {
+            "ms" => 0.001,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 60.0 * 60.0,
+            "d" => 24.0 * 60.0 * 60.0,
+            "w" => 7.0 * 24.0 * 60.0 * 60.0,
+            _ => {
+                return Err(format!(
+                    "unknown unit {unit:?} in {duration}, expected ms, s, m, h, d or w"
+                ))
+            }
+        };
+        total += number * unit_secs;
+        rest = after;
+    }
+
+    Duration::try_from_secs_f64(total).map_err(|_| invalid())
+}
+
+/// Parses a duration with [`parse_duration`] into whole seconds, rounding up
+pub fn parse_secs(duration: &str) -> Result<u64, String> {
+    let duration = parse_duration(duration)?;
+    Ok(duration.as_secs() + u64::from(duration.subsec_nanos() > 0))
+}
+
+/// Parses a duration with [`parse_duration`] that can't be 0, like how often something runs
+pub fn parse_interval(interval: &str) -> Result<Duration, String> {
+    match parse_duration(interval)? {
+        Duration::ZERO => Err("the interval must be more than 0".to_string()),
+        interval => Ok(interval),
+    }
+}
+
+/// Parses a period like `30d`, `4w` or `36h` into a number of days, rounding up. A plain number
+/// is days here rather than seconds
+pub fn parse_days(period: &str) -> Result<u64, String> {
+    const DAY: u64 = 24 * 60 * 60;
+
+    if let Ok(days) = period.trim().parse::<u64>() {
+        return Ok(days);
+    }
+    let secs = parse_secs(period).map_err(|_| {
+        format!("expected a number of days like 30d or weeks like 4w, got {period}")
+    })?;
+    Ok(secs.div_ceil(DAY))
+}
+
+/// Parses a size like `250MB`, `2TiB`, `1.5G` or a plain number of bytes
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let invalid = || format!("expected a size like 500G or 250MB, got {size}");
+    let (number, unit) = split_number(size.trim());
+    if number.is_empty() {
+        return Err(invalid());
+    }
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KIB" => 1 << 10,
+        "M" | "MIB" => 1 << 20,
+        "G" | "GIB" => 1 << 30,
+        "T" | "TIB" => 1 << 40,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        _ => {
+            return Err(format!(
+                "unknown unit in {size}, expected K, M, G or T, optionally followed by iB or B"
+            ))
+        }
+    };
+
+    // Whole numbers are multiplied exactly, since a float can't hold every number of bytes
+    if let Ok(number) = number.parse::<u64>() {
+        return number
+            .checked_mul(multiplier)
+            .ok_or_else(|| format!("{size} is too large"));
+    }
+    let bytes = parse_number(number).ok_or_else(invalid)? * multiplier as f64;
+    match bytes < u64::MAX as f64 {
+        true => Ok(bytes.round() as u64),
+        false => Err(format!("{size} is too large")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1.5h"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("1h 30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(
+            parse_duration("2w"),
+            Ok(Duration::from_secs(14 * 24 * 60 * 60))
+        );
+        assert_eq!(parse_duration("45"), Ok(Duration::from_secs(45)));
+        assert_eq!(parse_duration(" 45 "), Ok(Duration::from_secs(45)));
+    }
+
+    #[test]
+    fn invalid_durations() {
+        for duration in ["h", "5x", "1..5s", ".5s", "5.s", "-5s", "1h30"] {
+            assert!(parse_duration(duration).is_err(), "{duration}");
+        }
+    }
+
+    #[test]
+    fn secs_round_up() {
+        assert_eq!(parse_secs("1.5s"), Ok(2));
+        assert_eq!(parse_secs("500ms"), Ok(1));
+        assert_eq!(parse_secs("2m"), Ok(120));
+    }
+
+    #[test]
+    fn intervals_must_be_more_than_0() {
+        assert_eq!(parse_interval("10m"), Ok(Duration::from_secs(600)));
+        assert!(parse_interval("0s").is_err());
+        assert!(parse_interval("0").is_err());
+    }
+
+    #[test]
+    fn days() {
+        assert_eq!(parse_days("30"), Ok(30));
+        assert_eq!(parse_days("30d"), Ok(30));
+        assert_eq!(parse_days("4w"), Ok(28));
+        assert_eq!(parse_days("36h"), Ok(2));
+        assert!(parse_days("soon").is_err());
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("500"), Ok(500));
+        assert_eq!(parse_size("500B"), Ok(500));
+        assert_eq!(parse_size("1k"), Ok(1024));
+        assert_eq!(parse_size("2TiB"), Ok(2 << 40));
+        assert_eq!(parse_size("250MB"), Ok(250_000_000));
+        assert_eq!(parse_size("1.5G"), Ok(3 << 29));
+        assert_eq!(parse_size("10 GiB"), Ok(10 << 30));
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        for size in ["", "G", "5X", "1.2.3M", "20000000T", "18446744073709551616"] {
+            assert!(parse_size(size).is_err(), "{size}");
+        }
+    }
+}
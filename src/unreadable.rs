@@ -0,0 +1,108 @@
+//! Entries that can't be read because of their permissions, like other users' home directories
+//! when a shared directory is backed up as a normal user. They're skipped and summarized once
+//! instead of failing every cycle, unless `--require-all-readable` asks for strictness
+
+use anyhow::{anyhow, Result};
+use std::{
+    collections::BTreeSet,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use crate::output::{self, info, Event};
+
+/// Set by `--require-all-readable`, which turns unreadable entries into errors
+pub static REQUIRE_ALL: AtomicBool = AtomicBool::new(false);
+
+static UNREADABLE: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+/// How many unreadable entries there were when they were last summarized
+static SUMMARIZED: AtomicUsize = AtomicUsize::new(0);
+
+/// Records that `path` couldn't be read. Each path is only reported the first time
+pub fn record(path: &Path) {
+    if !UNREADABLE.lock().unwrap().insert(path.to_path_buf()) {
+        return;
+    }
+
+    match REQUIRE_ALL.load(Ordering::Relaxed) {
+        true => output::emit(&Event::Error {
+            path: Some(path),
+            message: "Permission denied, and --require-all-readable is set".to_string(),
+        }),
+        false => output::emit(&Event::Unreadable { path }),
+    }
+}
+
+/// Records `path` as unreadable if that's why `err` happened, returning whether it was. Failing
+/// to write the backup is a real error, so only paths that can't even be opened count
+pub fn classify(path: &Path, err: &anyhow::Error) -> bool {
+    let permission_denied = err.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|err| err.kind() == io::ErrorKind::PermissionDenied)
+    });
+    let unreadable = permission_denied
+        && std::fs::File::open(path)
+            .is_err_and(|err| err.kind() == io::ErrorKind::PermissionDenied);
+
+    if unreadable {
+        record(path);
+    }
+    unreadable
+}
+
+/// Records the directory an error from walking a tree was about, if it was unreadable
+pub fn classify_walk_error(err: &ignore::Error) {
+    fn error_path(err: &ignore::Error) -> Option<&Path> {
+        match err {
+            ignore::Error::WithPath { path, .. } => Some(path),
+            ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => {
+                error_path(err)
+            }
+            _ => None,
+        }
+    }
+
+    if err
+        .io_error()
+        .is_some_and(|err| err.kind() == io::ErrorKind::PermissionDenied)
+    {
+        if let Some(path) = error_path(err) {
+            record(path);
+        }
+    }
+}
+
+/// Mentions how many entries were skipped, unless that was already said. With
+/// `--require-all-readable` each of them was already reported as an error
+pub fn summarize() {
+    if REQUIRE_ALL.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let count = UNREADABLE.lock().unwrap().len();
+    if count > SUMMARIZED.swap(count, Ordering::Relaxed) {
+        info!(
+            "Skipped {count} entries that couldn't be read. They're listed with --output json and in `ctl status`"
+        );
+    }
+}
+
+pub fn list() -> Vec<PathBuf> {
+    UNREADABLE.lock().unwrap().iter().cloned().collect()
+}
+
+/// Fails if anything was unreadable and `--require-all-readable` is set
+pub fn check() -> Result<()> {
+    let count = UNREADABLE.lock().unwrap().len();
+    match count > 0 && REQUIRE_ALL.load(Ordering::Relaxed) {
+        true => Err(anyhow!(
+            "{count} entries couldn't be read, and --require-all-readable is set"
+        )),
+        false => Ok(()),
+    }
+}
@@ -0,0 +1,495 @@
+//! Checking that backup_dir holds exactly the same contents as work_dir, by hashing both trees,
+//! and that a restore put back exactly what was in the backup
+
+use anyhow::{anyhow, Context, Result};
+use blake3::Hash;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::{
+    encoded_path, filters, hashing, metadata_only,
+    output::{self, info},
+    platform, recursive_dir, routes, sanitize, state_dir, Event, WalkOptions,
+};
+
+/// What a verification pass found
+#[derive(Debug)]
+pub struct VerifyReport {
+    pub matched: u64,
+    pub missing: Vec<PathBuf>,
+    pub extra: Vec<PathBuf>,
+    pub mismatched: Vec<PathBuf>,
+    pub errors: u64,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty()
+            && self.extra.is_empty()
+            && self.mismatched.is_empty()
+            && self.errors == 0
+    }
+}
+
+/// What checking the files put back by a restore found
+#[derive(Debug, Default)]
+pub struct RestoreCheck {
+    pub verified: Vec<PathBuf>,
+    pub failed: Vec<PathBuf>,
+}
+
+/// Hashes every restored file along with the backup file it came from, so that a restore is known
+/// to have worked instead of assumed to. `restored` pairs each backup file with where it went
+pub async fn check_restore(restored: Vec<(PathBuf, PathBuf)>) -> Result<RestoreCheck> {
+    let results = tokio::task::spawn_blocking(move || {
+        restored
+            .into_iter()
+            .map(|(backup_path, restored_path)| {
+                let matches = hashing::hash_fresh(&backup_path).and_then(|backup_hash| {
+                    Ok(backup_hash == hashing::hash_fresh(&restored_path)?)
+                });
+                (restored_path, matches)
+            })
+            .collect::<Vec<_>>()
+    })
+    .await?;
+
+    let mut check = RestoreCheck::default();
+    for (path, matches) in results {
+        match matches {
+            Ok(true) => check.verified.push(path),
+            Ok(false) => {
+                output::emit(&Event::VerifyProblem {
+                    path: &path,
+                    problem: "mismatched",
+                });
+                check.failed.push(path);
+            }
+            Err(err) => {
+                output::emit(&Event::Error {
+                    path: Some(&path),
+                    message: format!("Error verifying the restored file: {err:#}"),
+                });
+                check.failed.push(path);
+            }
+        }
+    }
+
+    output::emit(&Event::RestoreVerified {
+        verified: &check.verified,
+        failed: &check.failed,
+    });
+
+    Ok(check)
+}
+
+/// Bounds how many files are read at once from each device, so that a slow disk isn't thrashed
+/// while a fast one sits idle
+struct DeviceLimits {
+    per_device: usize,
+    semaphores: Mutex<HashMap<u64, Arc<Semaphore>>>,
+}
+
+impl DeviceLimits {
+    fn new(per_device: usize) -> Self {
+        Self {
+            per_device: per_device.max(1),
+            semaphores: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn for_device(&self, device: u64) -> Arc<Semaphore> {
+        self.semaphores
+            .lock()
+            .unwrap()
+            .entry(device)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.per_device)))
+            .clone()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Tree {
+    Work,
+    Backup,
+}
+
+/// One hashed file, as persisted in the cursor
+#[derive(Serialize, Deserialize)]
+struct CursorEntry {
+    tree: Tree,
+    #[serde(with = "encoded_path")]
+    path: PathBuf,
+    size: u64,
+    modified_nanos: u64,
+    hash: String,
+}
+
+/// The hashes computed so far by an unfinished verification, so that an interrupted run can pick
+/// up where it left off. Every hash is appended to the cursor file as soon as it's computed
+struct Cursor {
+    path: PathBuf,
+    file: Mutex<File>,
+    hashes: HashMap<(Tree, PathBuf), (u64, u64, Hash)>,
+}
+
+impl Cursor {
+    fn open(backup_dir: &Path, restart: bool) -> Result<Self> {
+        let path = state_dir(backup_dir).join("verify_cursor.jsonl");
+        std::fs::create_dir_all(state_dir(backup_dir))?;
+
+        if restart {
+            if let Err(err) = std::fs::remove_file(&path) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    return Err(err.into());
+                }
+            }
+        }
+
+        let mut hashes = HashMap::new();
+        if let Ok(file) = File::open(&path) {
+            for line in BufReader::new(file).lines() {
+                // A torn final line from an interrupted write just means that file is hashed again
+                let Ok(entry) = serde_json::from_str::<CursorEntry>(&line?) else {
+                    continue;
+                };
+                let Ok(hash) = Hash::from_hex(&entry.hash) else {
+                    continue;
+                };
+
+                hashes.insert(
+                    (entry.tree, entry.path),
+                    (entry.size, entry.modified_nanos, hash),
+                );
+            }
+        }
+
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| anyhow!("Error opening {}", path.display()))?;
+
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+            hashes,
+        })
+    }
+
+    /// The previously computed hash of a file, if it hasn't changed since
+    fn lookup(&self, tree: Tree, path: &Path, size: u64, modified_nanos: u64) -> Option<Hash> {
+        match self.hashes.get(&(tree, path.to_path_buf())) {
+            Some(&(cached_size, cached_modified, hash))
+                if cached_size == size && cached_modified == modified_nanos =>
+            {
+                Some(hash)
+            }
+            _ => None,
+        }
+    }
+
+    fn record(&self, entry: CursorEntry) -> Result<()> {
+        let line = serde_json::to_string(&entry)?;
+        writeln!(self.file.lock().unwrap(), "{line}")?;
+
+        Ok(())
+    }
+
+    /// Removes the cursor once verification has run to completion
+    fn finish(self) -> Result<()> {
+        drop(self.file);
+        std::fs::remove_file(&self.path)?;
+
+        Ok(())
+    }
+}
+
+/// How far along hashing one tree is
+#[derive(Default)]
+struct Progress {
+    total: AtomicU64,
+    hashed: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Progress {
+    fn summary(&self) -> String {
+        format!(
+            "{}/{} files ({:.1} MiB)",
+            self.hashed.load(Ordering::Relaxed),
+            self.total.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed) as f64 / (1024.0 * 1024.0)
+        )
+    }
+}
+
+/// Hashes work_dir and backup_dir concurrently and compares them file by file. Unless `restart`
+/// is set, hashes left over from an interrupted verification are reused for unchanged files.
+/// `show_progress` draws a progress line when stderr is a terminal
+pub async fn verify(
+    work_dir: &Path,
+    backup_dir: &Path,
+    walk_options: &WalkOptions,
+    jobs_per_device: usize,
+    restart: bool,
+    show_progress: bool,
+) -> Result<VerifyReport> {
+    let cursor = Arc::new(Cursor::open(backup_dir, restart)?);
+    if !cursor.hashes.is_empty() {
+        info!(
+            "Resuming verification, {} files were already hashed",
+            cursor.hashes.len()
+        );
+    }
+
+    let limits = Arc::new(DeviceLimits::new(jobs_per_device));
+    let work_progress = Arc::new(Progress::default());
+    let backup_progress = Arc::new(Progress::default());
+
+    let done = Arc::new(AtomicBool::new(false));
+    let progress_task = (show_progress && std::io::stderr().is_terminal()).then(|| {
+        let (work_progress, backup_progress, done) =
+            (work_progress.clone(), backup_progress.clone(), done.clone());
+        tokio::task::spawn(async move {
+            while !done.load(Ordering::Relaxed) {
+                eprint!(
+                    "\r\x1b[KHashing work_dir {}, backup_dir {}",
+                    work_progress.summary(),
+                    backup_progress.summary()
+                );
+                tokio::time::sleep(Duration::from_millis(250)).await;
+            }
+            eprintln!(
+                "\r\x1b[KHashed work_dir {}, backup_dir {}",
+                work_progress.summary(),
+                backup_progress.summary()
+            );
+        })
+    });
+
+    let (work_hashes, backup_hashes) = tokio::join!(
+        hash_tree(
+            Tree::Work,
+            work_dir,
+            walk_options,
+            limits.clone(),
+            cursor.clone(),
+            work_progress
+        ),
+        hash_tree(
+            Tree::Backup,
+            backup_dir,
+            walk_options,
+            limits.clone(),
+            cursor.clone(),
+            backup_progress.clone()
+        ),
+    );
+    // The files routed elsewhere are matched up with their copies there instead
+    let mut route_hashes = Vec::new();
+    for route_dir in routes::dirs(backup_dir) {
+        if route_dir.is_dir() {
+            let hashes = hash_tree(
+                Tree::Backup,
+                &route_dir,
+                walk_options,
+                limits.clone(),
+                cursor.clone(),
+                backup_progress.clone(),
+            )
+            .await;
+            route_hashes.push((route_dir, hashes));
+        }
+    }
+
+    done.store(true, Ordering::Relaxed);
+    if let Some(progress_task) = progress_task {
+        progress_task.await?;
+    }
+
+    let (work_hashes, mut backup_hashes) = (work_hashes?, backup_hashes?);
+    backup_hashes.retain(|path, _| routes::root_for(backup_dir, path) == backup_dir);
+    for (route_dir, hashes) in route_hashes {
+        backup_hashes.extend(
+            hashes?
+                .into_iter()
+                .filter(|(path, _)| routes::root_for(backup_dir, path) == route_dir),
+        );
+    }
+
+    Arc::into_inner(cursor)
+        .expect("every hashing task has finished")
+        .finish()?;
+
+    let mut errors = 0;
+    let mut record_error = |path: PathBuf, err: anyhow::Error| {
+        errors += 1;
+        output::emit(&Event::Error {
+            path: Some(&path),
+            message: format!("{err:#}"),
+        });
+    };
+
+    let mut missing = Vec::new();
+    let mut mismatched = Vec::new();
+    let mut matched = 0;
+    for (path, work_hash) in work_hashes {
+        match (work_hash, backup_hashes.remove(&path)) {
+            (Err(err), _) => record_error(work_dir.join(&path), err),
+            (_, Some(Err(err))) => record_error(backup_path(backup_dir, &path), err),
+            // Metadata only files are never in the backup, so their recorded hash stands in
+            (Ok(work_hash), None) => match metadata_only::hash(&path) {
+                Some(hash) if hash == work_hash => matched += 1,
+                Some(_) => mismatched.push(path),
+                None => missing.push(path),
+            },
+            (Ok(work_hash), Some(Ok(backup_hash))) => {
+                // A filtered file matches if it's unchanged since it was filtered into the backup
+                match work_hash == backup_hash || filters::original_hash(&path) == Some(work_hash) {
+                    true => matched += 1,
+                    false => mismatched.push(path),
+                }
+            }
+        }
+    }
+
+    let mut extra = Vec::new();
+    for (path, backup_hash) in backup_hashes {
+        match backup_hash {
+            Ok(_) => extra.push(path),
+            Err(err) => record_error(backup_path(backup_dir, &path), err),
+        }
+    }
+
+    missing.sort();
+    extra.sort();
+    mismatched.sort();
+    let report = VerifyReport {
+        matched,
+        missing,
+        extra,
+        mismatched,
+        errors,
+    };
+
+    for (problem, paths) in [
+        ("missing", &report.missing),
+        ("extra", &report.extra),
+        ("mismatched", &report.mismatched),
+    ] {
+        for path in paths {
+            output::emit(&Event::VerifyProblem { path, problem });
+        }
+    }
+
+    output::emit(&Event::VerifyComplete {
+        matched: report.matched,
+        missing: report.missing.len() as u64,
+        extra: report.extra.len() as u64,
+        mismatched: report.mismatched.len() as u64,
+        errors: report.errors,
+    });
+
+    Ok(report)
+}
+
+/// Where the copy of `relative_path` in work_dir is
+fn backup_path(backup_dir: &Path, relative_path: &Path) -> PathBuf {
+    routes::root_for(backup_dir, relative_path).join(sanitize::to_backup(relative_path))
+}
+
+/// Hashes every file under `root`, keyed by its path relative to `root`
+async fn hash_tree(
+    tree: Tree,
+    root: &Path,
+    walk_options: &WalkOptions,
+    limits: Arc<DeviceLimits>,
+    cursor: Arc<Cursor>,
+    progress: Arc<Progress>,
+) -> Result<HashMap<PathBuf, Result<Hash>>> {
+    if !root.is_dir() {
+        return Err(anyhow!("{} is not a directory", root.display()));
+    }
+
+    let mut tasks = JoinSet::new();
+    let mut hashes = HashMap::new();
+
+    for file_info in recursive_dir(root, walk_options) {
+        progress.total.fetch_add(1, Ordering::Relaxed);
+
+        // Copies are matched up with their originals by the names the originals have
+        let relative_path = file_info.path().strip_prefix(root)?;
+        let relative_path = match tree {
+            Tree::Work => relative_path.to_path_buf(),
+            Tree::Backup => sanitize::to_work(relative_path),
+        };
+        let metadata = file_info.metadata();
+        let semaphore = limits.for_device(match &metadata {
+            Ok(metadata) => platform::device(metadata),
+            Err(_) => 0,
+        });
+        let size = metadata
+            .as_ref()
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        let modified_nanos = metadata
+            .ok()
+            .and_then(|metadata| metadata.modified().ok())
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_nanos() as u64)
+            .unwrap_or(0);
+
+        if let Some(hash) = cursor.lookup(tree, &relative_path, size, modified_nanos) {
+            progress.hashed.fetch_add(1, Ordering::Relaxed);
+            progress.bytes.fetch_add(size, Ordering::Relaxed);
+            hashes.insert(relative_path, Ok(hash));
+            continue;
+        }
+
+        let path = file_info.into_path();
+        let cursor = cursor.clone();
+        let progress = progress.clone();
+        // Taken before spawning, so that the walk waits rather than queueing a task for every file
+        let permit = semaphore.acquire_owned().await?;
+
+        tasks.spawn(async move {
+            let _permit = permit;
+            let hash = tokio::task::spawn_blocking(move || hashing::hash_fresh(&path)).await?;
+
+            if let Ok(hash) = &hash {
+                cursor.record(CursorEntry {
+                    tree,
+                    path: relative_path.clone(),
+                    size,
+                    modified_nanos,
+                    hash: hash.to_hex().to_string(),
+                })?;
+            }
+
+            progress.hashed.fetch_add(1, Ordering::Relaxed);
+            progress.bytes.fetch_add(size, Ordering::Relaxed);
+
+            anyhow::Ok((relative_path, hash))
+        });
+    }
+
+    while let Some(result) = tasks.join_next().await {
+        let (path, hash) = result??;
+        hashes.insert(path, hash);
+    }
+
+    Ok(hashes)
+}
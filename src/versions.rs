@@ -0,0 +1,88 @@
+//! Keeping the copies a sync overwrites with `--versions N`, so that a file that got corrupted in
+//! work_dir and synced over its only good copy can still be recovered. The previous copies of a
+//! file live in the state dir under its relative path, as `path.~1~` for the newest up to
+//! `path.~N~` for the oldest, out of the way of walks, verification and deletion
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+use tokio::fs;
+
+use crate::state_dir;
+
+struct Versions {
+    backup_dir: PathBuf,
+    count: usize,
+}
+
+static VERSIONS: OnceLock<Versions> = OnceLock::new();
+
+/// Keeps `count` previous versions of every file a sync overwrites in `backup_dir`
+pub fn set(backup_dir: &Path, count: usize) -> Result<()> {
+    VERSIONS
+        .set(Versions {
+            backup_dir: backup_dir.to_path_buf(),
+            count,
+        })
+        .map_err(|_| anyhow!("the number of versions can only be set once"))
+}
+
+fn versions_dir(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("versions")
+}
+
+/// Where version `n` of the file at `relative_path` in backup_dir is kept
+pub fn version_path(backup_dir: &Path, relative_path: &Path, n: usize) -> PathBuf {
+    let mut path = OsString::from(versions_dir(backup_dir).join(relative_path));
+    path.push(format!(".~{n}~"));
+    path.into()
+}
+
+/// Moves the copy at `dst_path` out of the way as its newest version before it's overwritten,
+/// shifting the older ones back and dropping the oldest. Does nothing without `--versions`, for
+/// files outside backup_dir like the ones restored, or when there's no regular file to keep
+pub async fn keep(dst_path: &Path) -> Result<()> {
+    let Some(versions) = VERSIONS.get().filter(|versions| versions.count > 0) else {
+        return Ok(());
+    };
+    let Ok(relative_path) = dst_path.strip_prefix(&versions.backup_dir) else {
+        return Ok(());
+    };
+    match fs::symlink_metadata(dst_path).await {
+        Ok(metadata) if metadata.is_file() => (),
+        Ok(_) => return Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    }
+
+    let path = |n| version_path(&versions.backup_dir, relative_path, n);
+    ignore_not_found(fs::remove_file(path(versions.count)).await)?;
+    for n in (1..versions.count).rev() {
+        ignore_not_found(fs::rename(path(n), path(n + 1)).await)?;
+    }
+
+    let newest = path(1);
+    if let Some(parent) = newest.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    fs::rename(dst_path, &newest).await.with_context(|| {
+        anyhow!(
+            "Error keeping the previous version of {} as {}",
+            dst_path.display(),
+            newest.display()
+        )
+    })?;
+
+    Ok(())
+}
+
+fn ignore_not_found(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
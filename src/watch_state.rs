@@ -0,0 +1,57 @@
+//! What the daemon knows about work_dir, persisted on clean shutdown so that the next start only
+//! has to look at what changed in between instead of reconciling everything
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use crate::{encoded_path, state_dir};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct WatchState {
+    /// The modify time each file in work_dir had when it was last synced, keyed by its path
+    /// relative to work_dir
+    #[serde(with = "encoded_path::keys")]
+    pub synced: HashMap<PathBuf, u64>,
+    /// Files, relative to work_dir, that still need to be copied
+    #[serde(with = "encoded_path::set")]
+    pub pending: HashSet<PathBuf>,
+}
+
+fn state_path(backup_dir: &Path) -> PathBuf {
+    state_dir(backup_dir).join("watch_state.json")
+}
+
+impl WatchState {
+    /// Loads the state left behind by the last clean shutdown, if there was one. The file is
+    /// removed, so that a crash can never leave an outdated view behind for the next start
+    pub fn take(backup_dir: &Path) -> Result<Option<Self>> {
+        let path = state_path(backup_dir);
+
+        let contents = match std::fs::read(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        std::fs::remove_file(&path)?;
+
+        serde_json::from_slice(&contents)
+            .map(Some)
+            .with_context(|| anyhow!("Error parsing {}", path.display()))
+    }
+
+    pub fn save(&self, backup_dir: &Path) -> Result<()> {
+        let path = state_path(backup_dir);
+        let temp_path = path.with_extension("json.tmp");
+
+        std::fs::create_dir_all(state_dir(backup_dir))?;
+        std::fs::write(&temp_path, serde_json::to_vec(self)?)?;
+        std::fs::rename(&temp_path, &path)
+            .with_context(|| anyhow!("Error saving {}", path.display()))?;
+
+        Ok(())
+    }
+}
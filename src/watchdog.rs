@@ -0,0 +1,157 @@
+//! A watchdog for hangs, like a network filesystem that stopped answering. A thread of its own,
+//! so that it keeps running when the async runtime doesn't, notices when the copy loop hasn't
+//! finished a cycle in several intervals or when the runtime stopped running tasks at all. It then
+//! logs what the copy loop, the writes in flight and every thread are doing, and raises an error.
+//! `/healthz` counts the sync as stalled until it recovers
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    health, locks,
+    output::{self, info, Event},
+    pause, platform, status, SHOULD_SHUTDOWN,
+};
+
+/// How often the watchdog checks on the sync
+const CHECK_EVERY: Duration = Duration::from_secs(10);
+/// How many intervals, or durations of the last cycle, may pass without a cycle finishing
+const STALL_CYCLES: u64 = 5;
+/// The shortest a cycle may take before it counts as stuck, however short the interval is
+const MIN_STALL: Duration = Duration::from_secs(60);
+
+/// What the copy loop is doing right now
+static PHASE: Mutex<&'static str> = Mutex::new("starting");
+/// Set while the sync looks stuck
+static STALLED: AtomicBool = AtomicBool::new(false);
+/// When the runtime last ran a task the watchdog spawned, in seconds since the epoch
+static HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+/// Records what the copy loop is doing, for when it gets stuck doing it
+pub fn set_phase(phase: &'static str) {
+    *PHASE.lock().unwrap() = phase;
+}
+
+/// Whether the watchdog found the sync stuck and it hasn't recovered yet
+pub fn is_stalled() -> bool {
+    STALLED.load(Ordering::Relaxed)
+}
+
+/// Starts watching the copy loop, which waits `interval` seconds between cycles. Must be called
+/// from within the runtime
+pub fn start(interval: Arc<AtomicU64>) {
+    let runtime = tokio::runtime::Handle::current();
+    HEARTBEAT.store(now_secs(), Ordering::Relaxed);
+
+    if let Err(err) = std::thread::Builder::new()
+        .name("watchdog".to_string())
+        .spawn(move || watch(runtime, interval))
+    {
+        output::emit(&Event::Error {
+            path: None,
+            message: format!("Error starting the watchdog: {err}"),
+        });
+    }
+}
+
+fn watch(runtime: tokio::runtime::Handle, interval: Arc<AtomicU64>) {
+    // Cycles only count from when the watchdog started, and from whenever syncing was paused
+    let mut since = now_secs();
+
+    while !SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+        // Runs right away unless every worker of the runtime is stuck
+        runtime.spawn(async { HEARTBEAT.store(now_secs(), Ordering::Relaxed) });
+        std::thread::sleep(CHECK_EVERY);
+        if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let now = now_secs();
+        if pause::is_paused() {
+            since = now;
+        }
+        let allowed = (STALL_CYCLES * interval.load(Ordering::Relaxed))
+            .max(STALL_CYCLES * status::LAST_CYCLE_MILLIS.load(Ordering::Relaxed) / 1000)
+            .max(MIN_STALL.as_secs());
+        let cycle_lag = now.saturating_sub(health::last_cycle().unwrap_or(0).max(since));
+        let heartbeat_lag = now.saturating_sub(HEARTBEAT.load(Ordering::Relaxed));
+
+        let stall = match (
+            heartbeat_lag > 2 * CHECK_EVERY.as_secs(),
+            cycle_lag > allowed,
+        ) {
+            (true, _) => Some(format!(
+                "the async runtime hasn't run anything in {heartbeat_lag}s"
+            )),
+            (false, true) => Some(format!(
+                "the copy loop hasn't finished a cycle in {cycle_lag}s, which should take at most {allowed}s"
+            )),
+            (false, false) => None,
+        };
+
+        match (stall, STALLED.load(Ordering::Relaxed)) {
+            (Some(stall), false) => {
+                STALLED.store(true, Ordering::Relaxed);
+                dump();
+                output::emit(&Event::Error {
+                    path: None,
+                    message: format!(
+                        "Syncing looks stuck, {stall}. What it's doing was logged above"
+                    ),
+                });
+            }
+            (None, true) => {
+                STALLED.store(false, Ordering::Relaxed);
+                info!("Syncing is no longer stuck");
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Logs what the copy loop, the writes in flight and every thread of the process are doing
+fn dump() {
+    info!("Watchdog: the copy loop is {}", PHASE.lock().unwrap());
+    let writing = locks::held();
+    info!("Watchdog: {} writes in flight", writing.len());
+    for path in &writing {
+        info!("Watchdog:   writing {}", path.display());
+    }
+    dump_threads();
+}
+
+/// What every thread of the process is doing, which shows the ones stuck in uninterruptible IO
+/// like a hung NFS mount (state D) and what they're waiting in
+fn dump_threads() {
+    let Some(threads) = platform::threads() else {
+        info!("Watchdog: the state of threads isn't available on this platform");
+        return;
+    };
+
+    for thread in threads {
+        info!(
+            "Watchdog: thread {} ({}) is in state {}, waiting in {}",
+            thread.id,
+            thread.name,
+            thread.state,
+            thread.wchan.as_deref().unwrap_or("-")
+        );
+        if let Some(stack) = thread.stack {
+            for frame in stack.lines() {
+                info!("Watchdog:     {frame}");
+            }
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Running the evil_mount binary against a work_dir and a backup_dir made up for each test
+
+// Each test binary only uses some of these
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    time::SystemTime,
+};
+
+/// A work_dir and a backup_dir of a test's own, removed once it's dropped
+pub struct Trees {
+    root: PathBuf,
+    pub work_dir: PathBuf,
+    pub backup_dir: PathBuf,
+}
+
+impl Trees {
+    /// Empty trees for the test `name`, which has to be unique among the tests
+    pub fn new(name: &str) -> Self {
+        let root =
+            std::env::temp_dir().join(format!("evil_mount-test-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let trees = Self {
+            work_dir: root.join("work"),
+            backup_dir: root.join("backup"),
+            root,
+        };
+        fs::create_dir_all(&trees.work_dir).unwrap();
+        fs::create_dir_all(&trees.backup_dir).unwrap();
+
+        trees
+    }
+
+    /// Writes `contents` to `relative_path` in `dir`, creating the directories on the way
+    pub fn write(dir: &Path, relative_path: &str, contents: &[u8]) -> PathBuf {
+        let path = dir.join(relative_path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+
+        path
+    }
+
+    pub fn write_work(&self, relative_path: &str, contents: &[u8]) -> PathBuf {
+        Self::write(&self.work_dir, relative_path, contents)
+    }
+
+    pub fn write_backup(&self, relative_path: &str, contents: &[u8]) -> PathBuf {
+        Self::write(&self.backup_dir, relative_path, contents)
+    }
+
+    /// The contents of `relative_path` in `dir`, or None if there's nothing there
+    pub fn read(dir: &Path, relative_path: &str) -> Option<Vec<u8>> {
+        fs::read(dir.join(relative_path)).ok()
+    }
+
+    /// evil_mount with these trees as its work_dir and backup_dir, and `args` after them
+    pub fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_evil_mount"));
+        command
+            .arg("--no-idle-wait")
+            .arg("--work-dir")
+            .arg(&self.work_dir)
+            .arg("--backup-dir")
+            .arg(&self.backup_dir)
+            .args(args);
+
+        command
+    }
+
+    /// Like [`Trees::command`], with the faults in `faults` injected like `EVIL_MOUNT_FAULTS` says
+    pub fn faulty(&self, faults: &str, args: &[&str]) -> Command {
+        let mut command = self.command(args);
+        command.env("EVIL_MOUNT_FAULTS", faults);
+
+        command
+    }
+
+    /// Runs evil_mount with `args` until it exits
+    pub fn run(&self, args: &[&str]) -> Output {
+        self.command(args).output().unwrap()
+    }
+
+    /// Starts the watch loop with `args`, stops it the way Ctrl+C would once it printed a line
+    /// containing `line`, and returns everything it printed
+    pub fn watch_until(&self, args: &[&str], line: &str) -> String {
+        let mut child = self.command(args).stdout(Stdio::piped()).spawn().unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+
+        let mut printed = String::new();
+        loop {
+            let start = printed.len();
+            if stdout.read_line(&mut printed).unwrap() == 0 {
+                break;
+            }
+            if printed[start..].contains(line) {
+                // SAFETY: the child hasn't been waited for, so its pid is still its own
+                unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+                break;
+            }
+        }
+        std::io::Read::read_to_string(&mut stdout, &mut printed).unwrap();
+        child.wait().unwrap();
+
+        printed
+    }
+}
+
+impl Drop for Trees {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Makes `path` look modified at `modified`
+pub fn set_modified(path: &Path, modified: SystemTime) {
+    fs::File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+}
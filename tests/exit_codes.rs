@@ -0,0 +1,29 @@
+mod common;
+
+use common::Trees;
+
+#[test]
+fn a_bad_flag_is_a_config_error() {
+    let trees = Trees::new("exit-config");
+    let output = trees.run(&["--exclude", "[", "sync", "--once"]);
+
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn a_missing_work_dir_is_unreachable() {
+    let trees = Trees::new("exit-unreachable");
+    std::fs::remove_dir(&trees.work_dir).unwrap();
+    let output = trees.run(&["sync", "--once"]);
+
+    assert_eq!(output.status.code(), Some(4));
+}
+
+#[test]
+fn a_backup_dir_that_cant_be_set_up_is_an_init_error() {
+    let trees = Trees::new("exit-init");
+    std::fs::create_dir_all(trees.backup_dir.join(".evil_mount/journal.jsonl")).unwrap();
+    let output = trees.run(&["sync", "--once"]);
+
+    assert_eq!(output.status.code(), Some(3));
+}
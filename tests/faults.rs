@@ -0,0 +1,167 @@
+mod common;
+
+use common::Trees;
+use std::time::{Duration, SystemTime};
+
+fn stdout(output: &std::process::Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn a_file_that_vanishes_before_its_copied_isnt_an_error() {
+    let trees = Trees::new("fault-vanish");
+    trees.write_work("a.txt", b"a");
+    trees.write_work("b.txt", b"b");
+
+    let output = trees
+        .faulty("vanish=a.txt", &["sync", "--once"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert!(stdout(&output).contains(" 0 errors"));
+    assert_eq!(Trees::read(&trees.backup_dir, "a.txt"), None);
+    assert_eq!(
+        Trees::read(&trees.backup_dir, "b.txt").as_deref(),
+        Some(&b"b"[..])
+    );
+}
+
+#[test]
+fn a_file_that_changes_while_its_copied_is_copied_again() {
+    let trees = Trees::new("fault-change");
+    trees.write_work("a.txt", b"first");
+
+    let output = trees
+        .faulty("change=a.txt", &["sync", "--once"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        Trees::read(&trees.backup_dir, "a.txt").as_deref(),
+        Some(&b"first changed"[..])
+    );
+    assert!(!trees.backup_dir.join(".a.txt.evil_mount.tmp").exists());
+}
+
+#[test]
+fn a_crash_before_a_copy_is_moved_into_place_is_cleaned_up_by_the_next_run() {
+    let trees = Trees::new("fault-crash-copy");
+    let a = trees.write_work("a.txt", b"a");
+
+    let output = trees
+        .faulty("crash-before-rename=a.txt", &["sync", "--once"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(trees.backup_dir.join(".a.txt.evil_mount.tmp").exists());
+    assert_eq!(Trees::read(&trees.backup_dir, "a.txt"), None);
+
+    std::fs::remove_file(a).unwrap();
+    let output = trees.run(&["sync", "--once"]);
+
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("Finishing 1 changes"));
+    assert!(!trees.backup_dir.join(".a.txt.evil_mount.tmp").exists());
+    assert_eq!(Trees::read(&trees.backup_dir, "a.txt"), None);
+}
+
+#[test]
+fn a_crash_in_the_middle_of_a_transactional_commit_is_finished_by_the_next_run() {
+    let trees = Trees::new("fault-transactional");
+    let args = ["--transactional", "proj", "sync", "--once"];
+    trees.write_work("proj/a.txt", b"v1");
+    trees.write_work("proj/b.txt", b"v1");
+    assert!(trees.run(&args).status.success());
+
+    let later = SystemTime::now() + Duration::from_secs(60);
+    for name in ["proj/a.txt", "proj/b.txt"] {
+        common::set_modified(&trees.write_work(name, b"v2"), later);
+    }
+    let output = trees
+        .faulty("crash-after-rename=proj/a.txt", &args)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+
+    let output = trees.run(&args);
+
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("Finishing 1 changes"));
+    for name in ["proj/a.txt", "proj/b.txt"] {
+        assert_eq!(
+            Trees::read(&trees.backup_dir, name).as_deref(),
+            Some(&b"v2"[..])
+        );
+    }
+    assert!(!trees.backup_dir.join("proj/.a.txt.evil_mount.tmp").exists());
+    assert!(!trees.backup_dir.join("proj/.b.txt.evil_mount.tmp").exists());
+}
+
+#[test]
+fn a_file_within_its_grace_period_keeps_its_copy() {
+    let trees = Trees::new("fault-grace-kept");
+    let args = ["--delete", "after-grace:1h", "sync", "--once"];
+    let a = trees.write_work("a.txt", b"a");
+    assert!(trees.run(&args).status.success());
+
+    std::fs::remove_file(a).unwrap();
+    assert!(trees.run(&args).status.success());
+
+    assert_eq!(
+        Trees::read(&trees.backup_dir, "a.txt").as_deref(),
+        Some(&b"a"[..])
+    );
+}
+
+#[test]
+fn a_crash_before_a_grace_deletion_is_finished_by_the_next_run() {
+    let trees = Trees::new("fault-grace-crash");
+    let args = ["--delete", "after-grace:1s", "sync", "--once"];
+    let a = trees.write_work("a.txt", b"a");
+    assert!(trees.run(&args).status.success());
+
+    std::fs::remove_file(a).unwrap();
+    assert!(trees.run(&args).status.success());
+    assert!(trees.backup_dir.join("a.txt").exists());
+
+    std::thread::sleep(Duration::from_secs(2));
+    let output = trees
+        .faulty("crash-before-delete=a.txt", &args)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(trees.backup_dir.join("a.txt").exists());
+
+    let output = trees.run(&args);
+
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("Finishing 1 changes"));
+    assert_eq!(Trees::read(&trees.backup_dir, "a.txt"), None);
+}
+
+#[test]
+fn a_crash_before_a_reconcile_deletion_is_finished_by_the_next_run() {
+    let trees = Trees::new("fault-reconcile");
+    let args = ["--interval", "1s", "sync"];
+    let extra = trees.write_backup("extra.txt", b"extra");
+    let a = trees.write_work("a.txt", b"a");
+    common::set_modified(&a, SystemTime::now() + Duration::from_secs(60 * 60));
+
+    let output = trees
+        .faulty("crash-before-delete=extra.txt", &args)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(extra.exists());
+
+    let printed = trees.watch_until(&args, "Watching for file changes");
+
+    assert!(printed.contains("Finishing 1 changes"));
+    assert!(!extra.exists());
+    assert_eq!(
+        Trees::read(&trees.backup_dir, "a.txt").as_deref(),
+        Some(&b"a"[..])
+    );
+}
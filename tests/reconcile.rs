@@ -0,0 +1,40 @@
+mod common;
+
+use common::Trees;
+use std::time::{Duration, SystemTime};
+
+#[test]
+fn reconciling_with_a_newer_backup_keeps_what_was_left_out_of_it() {
+    let trees = Trees::new("reconcile-left-out");
+    trees.write_work("big", &[0; 4096]);
+    trees.write_work("notes.log", b"excluded");
+    trees.write_work("app.env", b"KEY=filtered");
+    trees.write_work("gone.txt", b"deleted from the backup since");
+    let newer = trees.write_backup("newer.txt", b"newer");
+    common::set_modified(&newer, SystemTime::now() + Duration::from_secs(3600));
+
+    let printed = trees.watch_until(
+        &[
+            "--max-file-size",
+            "1K",
+            "--exclude",
+            "*.log",
+            "--filter",
+            "*.env=cat",
+            "sync",
+        ],
+        "Watching for file changes",
+    );
+
+    assert!(
+        printed.contains(&format!("Reconciling {}", trees.work_dir.display())),
+        "{printed}"
+    );
+    for kept in ["big", "notes.log", "app.env", "newer.txt"] {
+        assert!(
+            Trees::read(&trees.work_dir, kept).is_some(),
+            "{kept} is gone"
+        );
+    }
+    assert_eq!(Trees::read(&trees.work_dir, "gone.txt"), None);
+}
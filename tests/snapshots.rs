@@ -0,0 +1,43 @@
+mod common;
+
+use common::Trees;
+use std::time::{Duration, SystemTime};
+
+#[test]
+fn restoring_at_a_snapshot_brings_back_what_a_later_sync_overwrote() {
+    let trees = Trees::new("snapshot-restore");
+    trees.write_work("notes.txt", b"before");
+    assert!(trees.run(&["sync", "--once"]).status.success());
+    assert!(trees.run(&["snapshot", "before"]).status.success());
+
+    let notes = trees.write_work("notes.txt", b"after");
+    common::set_modified(&notes, SystemTime::now() + Duration::from_secs(60));
+    assert!(trees.run(&["sync", "--once"]).status.success());
+    assert_eq!(
+        Trees::read(&trees.backup_dir, "notes.txt").as_deref(),
+        Some(&b"after"[..])
+    );
+
+    let output = trees.run(&["restore", "--at", "before"]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        Trees::read(&trees.work_dir, "notes.txt").as_deref(),
+        Some(&b"before"[..])
+    );
+}
+
+#[test]
+fn restoring_at_a_snapshot_that_doesnt_exist_fails() {
+    let trees = Trees::new("snapshot-missing");
+    trees.write_work("notes.txt", b"before");
+    assert!(trees.run(&["sync", "--once"]).status.success());
+
+    let output = trees.run(&["restore", "--at", "never-taken"]);
+
+    assert!(!output.status.success());
+    assert_eq!(
+        Trees::read(&trees.work_dir, "notes.txt").as_deref(),
+        Some(&b"before"[..])
+    );
+}
@@ -0,0 +1,52 @@
+mod common;
+
+use common::Trees;
+use std::time::{Duration, SystemTime};
+
+#[test]
+fn one_pass_copies_new_and_changed_files_deletes_removed_ones_and_exits() {
+    let trees = Trees::new("once-pass");
+    trees.write_work("kept.txt", b"kept");
+    trees.write_work("changed.txt", b"before");
+    let removed = trees.write_work("removed.txt", b"removed");
+    assert!(trees.run(&["sync", "--once"]).status.success());
+
+    trees.write_work("dir/new.txt", b"new");
+    common::set_modified(
+        &trees.write_work("changed.txt", b"after"),
+        SystemTime::now() + Duration::from_secs(60),
+    );
+    std::fs::remove_file(removed).unwrap();
+    let output = trees.run(&["sync", "--once"]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stdout).contains(" 0 errors"));
+    for (name, contents) in [
+        ("kept.txt", &b"kept"[..]),
+        ("changed.txt", b"after"),
+        ("dir/new.txt", b"new"),
+    ] {
+        assert_eq!(
+            Trees::read(&trees.backup_dir, name).as_deref(),
+            Some(contents)
+        );
+    }
+    assert_eq!(Trees::read(&trees.backup_dir, "removed.txt"), None);
+}
+
+#[test]
+fn a_file_that_fails_to_copy_exits_with_1_after_copying_the_rest() {
+    let trees = Trees::new("once-errors");
+    trees.write_work("sub/blocked.txt", b"blocked");
+    trees.write_work("fine.txt", b"fine");
+    // A file where its directory goes can't be copied into
+    trees.write_backup("sub", b"in the way");
+
+    let output = trees.run(&["sync", "--once"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        Trees::read(&trees.backup_dir, "fine.txt").as_deref(),
+        Some(&b"fine"[..])
+    );
+}